@@ -1,21 +1,70 @@
 //! Driver for VirtIO block devices.
 
-use crate::config::{read_config, ReadOnly};
+use super::common::DriverTransport;
+use crate::config::{read_config, write_config, ReadOnly, ReadWrite};
+#[cfg(feature = "alloc")]
+use crate::hal::DeviceHal;
 use crate::hal::Hal;
+#[cfg(feature = "alloc")]
+use crate::queue::DeviceVirtQueue;
 use crate::queue::VirtQueue;
-use crate::transport::{InterruptStatus, Transport};
+#[cfg(feature = "alloc")]
+use crate::transport::DeviceTransport;
+use crate::transport::{DeviceStatus, InterruptStatus, Transport};
 use crate::{Error, Result};
+#[cfg(feature = "alloc")]
+use alloc::{boxed::Box, vec};
 use bitflags::bitflags;
-use log::info;
-use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
+use core::array;
+use log::{info, warn};
+use zerocopy::{FromBytes, FromZeros, Immutable, IntoBytes, KnownLayout};
+
+#[cfg(feature = "alloc")]
+mod cache;
+#[cfg(all(feature = "std", target_os = "linux"))]
+mod file;
+#[cfg(feature = "alloc")]
+mod partition;
+#[cfg(all(feature = "std", target_os = "linux"))]
+mod qcow2;
+#[cfg(feature = "alloc")]
+mod ram;
+
+#[cfg(feature = "alloc")]
+pub use cache::BlkCache;
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub use file::FileBackend;
+#[cfg(feature = "alloc")]
+pub use partition::{
+    Guid, Partition, PartitionError, PartitionHandle, PartitionKind, PartitionScheme,
+    PartitionTable,
+};
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub use qcow2::{Qcow2Backend, Qcow2Error};
+#[cfg(feature = "alloc")]
+pub use ram::RamDisk;
 
-const QUEUE: u16 = 0;
 const QUEUE_SIZE: u16 = 16;
+/// The maximum number of data segments in a single request, leaving room in the queue for the
+/// request header and the response.
+const MAX_SEGMENTS: usize = QUEUE_SIZE as usize - 2;
 const SUPPORTED_FEATURES: BlkFeature = BlkFeature::RO
+    .union(BlkFeature::CONFIG_WCE)
+    .union(BlkFeature::LIFETIME)
+    .union(BlkFeature::SIZE_MAX)
+    .union(BlkFeature::SEG_MAX)
+    .union(BlkFeature::BLK_SIZE)
+    .union(BlkFeature::TOPOLOGY)
     .union(BlkFeature::FLUSH)
     .union(BlkFeature::RING_INDIRECT_DESC)
     .union(BlkFeature::RING_EVENT_IDX)
-    .union(BlkFeature::VERSION_1);
+    .union(BlkFeature::VERSION_1)
+    .union(BlkFeature::SUSPEND)
+    .union(BlkFeature::MQ)
+    .union(BlkFeature::DISCARD)
+    .union(BlkFeature::WRITE_ZEROES)
+    .union(BlkFeature::SECURE_ERASE)
+    .union(BlkFeature::ZONED);
 
 /// Driver for a VirtIO block device.
 ///
@@ -43,39 +92,143 @@ const SUPPORTED_FEATURES: BlkFeature = BlkFeature::RO
 /// # Ok(())
 /// # }
 /// ```
-pub struct VirtIOBlk<H: Hal, T: Transport> {
-    transport: T,
-    queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
+pub struct VirtIOBlk<H: Hal, T: Transport, const QUEUES: usize = 1> {
+    transport: DriverTransport<T>,
+    queues: [VirtQueue<H, { QUEUE_SIZE as usize }>; QUEUES],
     capacity: u64,
     negotiated_features: BlkFeature,
+    limits: BlkLimits,
+    /// The requests submitted to each queue with `submit_*` which haven't yet completed, indexed by
+    /// token. This must be dropped after `transport`, so that the device is reset before the
+    /// buffers are freed.
+    #[cfg(feature = "alloc")]
+    in_flight: [InFlightTable; QUEUES],
 }
 
-impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
+/// Requests which have been submitted to a queue but not yet completed, indexed by token.
+#[cfg(feature = "alloc")]
+type InFlightTable = [Option<InFlightRequest>; QUEUE_SIZE as usize];
+
+impl<H: Hal, T: Transport, const QUEUES: usize> VirtIOBlk<H, T, QUEUES> {
+    const QUEUES_OK: () = assert!(QUEUES > 0 && QUEUES <= u16::MAX as usize);
+
     /// Create a new VirtIO-Blk driver.
+    ///
+    /// If `QUEUES` is more than 1 then the device must support `VIRTIO_BLK_F_MQ` with at least that
+    /// many queues, or this will fail with [`Error::Unsupported`].
     pub fn new(mut transport: T) -> Result<Self> {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::QUEUES_OK;
+
+        let (negotiated_features, capacity, limits, queues) = Self::init(&mut transport)?;
+        Ok(VirtIOBlk {
+            transport: DriverTransport::with_queue_count(transport, QUEUES as u16),
+            queues,
+            capacity,
+            negotiated_features,
+            limits,
+            #[cfg(feature = "alloc")]
+            in_flight: array::from_fn(|_| array::from_fn(|_| None)),
+        })
+    }
+
+    /// Resets the device and returns its transport, so that it can be used by another driver.
+    ///
+    /// Any requests which were submitted but not yet completed are abandoned.
+    pub fn into_transport(self) -> T {
+        self.transport.into_inner()
+    }
+
+    /// Negotiates features, reads the configuration and sets up the queues, leaving the device
+    /// ready to use.
+    fn init(
+        transport: &mut impl Transport,
+    ) -> Result<(
+        BlkFeature,
+        u64,
+        BlkLimits,
+        [VirtQueue<H, { QUEUE_SIZE as usize }>; QUEUES],
+    )> {
         let negotiated_features = transport.begin_init(SUPPORTED_FEATURES)?;
 
         // Read configuration space.
         let capacity = transport.read_consistent(|| {
-            Ok(read_config!(transport, BlkConfig, capacity_low)? as u64
-                | (read_config!(transport, BlkConfig, capacity_high)? as u64) << 32)
+            Ok(read_config!(*transport, BlkConfig, capacity_low)? as u64
+                | (read_config!(*transport, BlkConfig, capacity_high)? as u64) << 32)
         })?;
         info!("found a block device of size {}KB", capacity / 2);
+        let limits = BlkLimits::read(transport, negotiated_features)?;
+
+        if QUEUES > 1 {
+            let num_queues = if negotiated_features.contains(BlkFeature::MQ) {
+                read_config!(*transport, BlkConfig, num_queues)?
+            } else {
+                1
+            };
+            if usize::from(num_queues) < QUEUES {
+                warn!(
+                    "Device only supports {} queues, but {} were requested",
+                    num_queues, QUEUES
+                );
+                return Err(Error::Unsupported);
+            }
+        }
 
-        let queue = VirtQueue::new(
-            &mut transport,
-            QUEUE,
-            negotiated_features.contains(BlkFeature::RING_INDIRECT_DESC),
-            negotiated_features.contains(BlkFeature::RING_EVENT_IDX),
-        )?;
+        let mut queues: [Option<VirtQueue<H, { QUEUE_SIZE as usize }>>; QUEUES] =
+            array::from_fn(|_| None);
+        for (index, queue) in queues.iter_mut().enumerate() {
+            *queue = Some(VirtQueue::new(
+                transport,
+                index as u16,
+                negotiated_features.contains(BlkFeature::RING_INDIRECT_DESC),
+                negotiated_features.contains(BlkFeature::RING_EVENT_IDX),
+            )?);
+        }
         transport.finish_init();
 
-        Ok(VirtIOBlk {
-            transport,
-            queue,
-            capacity,
+        Ok((
             negotiated_features,
-        })
+            capacity,
+            limits,
+            queues.map(Option::unwrap),
+        ))
+    }
+
+    /// Returns whether the device has reported an error from which it can't recover, so must be
+    /// [`reset`](Self::reset) before it can be used again.
+    ///
+    /// This should be checked after a configuration change interrupt, or when a request fails with
+    /// [`Error::IoError`]. Blocking requests fail with [`Error::DeviceNeedsReset`] if this happens
+    /// while they are waiting.
+    pub fn needs_reset(&self) -> bool {
+        // A request which was waiting when the device failed will have reset it already.
+        self.transport.needs_reset()
+            || !self
+                .transport
+                .get_status()
+                .contains(DeviceStatus::DRIVER_OK)
+    }
+
+    /// Resets the device and reinitialises it, including renegotiating features and rereading the
+    /// capacity.
+    ///
+    /// Any requests which were submitted with the non-blocking methods but not yet completed are
+    /// abandoned: the device won't complete them, and their tokens are no longer valid. Once this
+    /// returns their buffers may be reused.
+    pub fn reset(&mut self) -> Result {
+        self.transport.teardown();
+        // The device won't touch the buffers of any in-flight requests now that it has been reset.
+        #[cfg(feature = "alloc")]
+        self.in_flight
+            .iter_mut()
+            .flatten()
+            .for_each(|request| *request = None);
+        let (negotiated_features, capacity, limits, queues) = Self::init(&mut self.transport)?;
+        self.negotiated_features = negotiated_features;
+        self.capacity = capacity;
+        self.limits = limits;
+        self.queues = queues;
+        Ok(())
     }
 
     /// Gets the capacity of the block device, in 512 byte ([`SECTOR_SIZE`]) sectors.
@@ -88,6 +241,111 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         self.negotiated_features.contains(BlkFeature::RO)
     }
 
+    /// Returns the logical block size of the device in bytes.
+    ///
+    /// Sector numbers are always in units of [`SECTOR_SIZE`], but reads and writes must start and
+    /// end on a logical block boundary.
+    pub fn block_size(&self) -> u32 {
+        self.limits.block_size
+    }
+
+    /// Returns the physical block size of the device in bytes, i.e. the smallest unit which it can
+    /// write without a read-modify-write cycle.
+    pub fn physical_block_size(&self) -> u32 {
+        match self.limits.topology {
+            Some(topology) => self.limits.block_size << topology.physical_block_exp,
+            None => self.limits.block_size,
+        }
+    }
+
+    /// Returns the I/O topology of the device, if it provides it.
+    pub fn topology(&self) -> Option<BlkTopology> {
+        self.limits.topology
+    }
+
+    /// Returns the zoned characteristics of the device, or `None` if it is not a zoned device.
+    pub fn zoned(&self) -> Option<ZonedCharacteristics> {
+        self.limits.zoned
+    }
+
+    /// Returns the limits on discard requests, or `None` if the device doesn't support them.
+    pub fn discard_limits(&self) -> Option<RangeLimits> {
+        self.limits.discard
+    }
+
+    /// Returns the limits on write zeroes requests, or `None` if the device doesn't support them.
+    pub fn write_zeroes_limits(&self) -> Option<RangeLimits> {
+        self.limits.write_zeroes
+    }
+
+    /// Returns the limits on secure erase requests, or `None` if the device doesn't support them.
+    pub fn secure_erase_limits(&self) -> Option<RangeLimits> {
+        self.limits.secure_erase
+    }
+
+    /// Returns a handle to the queue with the given index, which must be less than `QUEUES`.
+    ///
+    /// Requests made through the handle are submitted to that queue.
+    pub fn queue(&mut self, index: usize) -> BlkQueue<'_, H, T> {
+        BlkQueue {
+            transport: &self.transport,
+            queue: &mut self.queues[index],
+            queue_index: index as u16,
+            negotiated_features: self.negotiated_features,
+            limits: self.limits,
+            #[cfg(feature = "alloc")]
+            in_flight: &mut self.in_flight[index],
+        }
+    }
+
+    /// Returns handles to all the queues of the device.
+    ///
+    /// Each handle can submit requests independently of the others, so they can be used
+    /// concurrently, e.g. with one queue per CPU core.
+    pub fn queues(&mut self) -> [BlkQueue<'_, H, T>; QUEUES] {
+        let transport = &self.transport;
+        let negotiated_features = self.negotiated_features;
+        let limits = self.limits;
+        #[cfg(feature = "alloc")]
+        let mut in_flight = self.in_flight.iter_mut();
+        let mut queue_index = 0;
+        self.queues.each_mut().map(|queue| {
+            let handle = BlkQueue {
+                transport,
+                queue,
+                queue_index,
+                negotiated_features,
+                limits,
+                #[cfg(feature = "alloc")]
+                in_flight: in_flight.next().unwrap(),
+            };
+            queue_index += 1;
+            handle
+        })
+    }
+
+    /// Waits for any requests in flight to complete and then suspends the device, e.g. before
+    /// taking a snapshot of the guest or entering a sleep state.
+    ///
+    /// Requests submitted with the non-blocking methods must still be completed by the caller as
+    /// usual. No new requests may be submitted until the device is [resumed](Self::resume).
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support `VIRTIO_F_SUSPEND`.
+    pub fn suspend(&mut self) -> Result {
+        if !self.negotiated_features.contains(BlkFeature::SUSPEND) {
+            return Err(Error::Unsupported);
+        }
+        for queue in &self.queues {
+            queue.wait_until_idle(&self.transport)?;
+        }
+        self.transport.suspend()
+    }
+
+    /// Resumes the device after it was suspended with [`suspend`](Self::suspend).
+    pub fn resume(&mut self) -> Result {
+        self.transport.resume()
+    }
+
     /// Acknowledges a pending interrupt, if any.
     ///
     /// Returns true if there was an interrupt to acknowledge.
@@ -97,59 +355,54 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
 
     /// Enables interrupts from the device.
     pub fn enable_interrupts(&mut self) {
-        self.queue.set_dev_notify(true);
+        for queue in &mut self.queues {
+            queue.set_dev_notify(true);
+        }
     }
 
     /// Disables interrupts from the device.
     pub fn disable_interrupts(&mut self) {
-        self.queue.set_dev_notify(false);
+        for queue in &mut self.queues {
+            queue.set_dev_notify(false);
+        }
     }
 
-    /// Sends the given request to the device and waits for a response, with no extra data.
-    fn request(&mut self, request: BlkReq) -> Result {
-        let mut resp = BlkResp::default();
-        self.queue.add_notify_wait_pop(
-            &[request.as_bytes()],
-            &mut [resp.as_mut_bytes()],
-            &mut self.transport,
-        )?;
-        resp.status.into()
+    /// Returns true if the device's cache is in writeback mode, so writes must be followed by a
+    /// [`flush`](Self::flush) to make them durable, or false if it is in writethrough mode.
+    pub fn writeback(&self) -> Result<bool> {
+        if self.negotiated_features.contains(BlkFeature::CONFIG_WCE) {
+            Ok(read_config!(self.transport, BlkConfig, writeback)? != 0)
+        } else {
+            // Without `VIRTIO_BLK_F_CONFIG_WCE`, the device uses writeback mode iff it supports
+            // flushing.
+            Ok(self.negotiated_features.contains(BlkFeature::FLUSH))
+        }
     }
 
-    /// Sends the given request to the device and waits for a response, including the given data.
-    fn request_read(&mut self, request: BlkReq, data: &mut [u8]) -> Result {
-        let mut resp = BlkResp::default();
-        self.queue.add_notify_wait_pop(
-            &[request.as_bytes()],
-            &mut [data, resp.as_mut_bytes()],
-            &mut self.transport,
-        )?;
-        resp.status.into()
+    /// Switches the device's cache between writeback and writethrough modes.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support the `VIRTIO_BLK_F_CONFIG_WCE`
+    /// feature.
+    pub fn set_writeback(&mut self, writeback: bool) -> Result {
+        if !self.negotiated_features.contains(BlkFeature::CONFIG_WCE) {
+            return Err(Error::Unsupported);
+        }
+        write_config!(self.transport, BlkConfig, writeback, writeback.into())
     }
 
-    /// Sends the given request and data to the device and waits for a response.
-    fn request_write(&mut self, request: BlkReq, data: &[u8]) -> Result {
-        let mut resp = BlkResp::default();
-        self.queue.add_notify_wait_pop(
-            &[request.as_bytes(), data],
-            &mut [resp.as_mut_bytes()],
-            &mut self.transport,
-        )?;
-        resp.status.into()
+    /// Gets the lifetime and wear information of the device, e.g. for eMMC or UFS backed storage.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support the `VIRTIO_BLK_F_LIFETIME`
+    /// feature.
+    pub fn lifetime(&mut self) -> Result<BlkLifetime> {
+        self.queue(0).lifetime()
     }
 
     /// Requests the device to flush any pending writes to storage.
     ///
     /// This will be ignored if the device doesn't support the `VIRTIO_BLK_F_FLUSH` feature.
     pub fn flush(&mut self) -> Result {
-        if self.negotiated_features.contains(BlkFeature::FLUSH) {
-            self.request(BlkReq {
-                type_: ReqType::Flush,
-                ..Default::default()
-            })
-        } else {
-            Ok(())
-        }
+        self.queue(0).flush()
     }
 
     /// Gets the device ID.
@@ -157,34 +410,112 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
     /// The ID is written as ASCII into the given buffer, which must be 20 bytes long, and the used
     /// length returned.
     pub fn device_id(&mut self, id: &mut [u8; 20]) -> Result<usize> {
-        self.request_read(
-            BlkReq {
-                type_: ReqType::GetId,
-                ..Default::default()
-            },
-            id,
-        )?;
+        self.queue(0).device_id(id)
+    }
 
-        let length = id.iter().position(|&x| x == 0).unwrap_or(20);
-        Ok(length)
+    /// Tells the device that the given ranges of sectors are no longer in use, e.g. to implement
+    /// TRIM for a filesystem.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support discard, or
+    /// [`Error::InvalidParam`] if the ranges exceed the device's [`discard_limits`], are not
+    /// aligned to its discard sector alignment, or request unmapping.
+    ///
+    /// [`discard_limits`]: Self::discard_limits
+    pub fn discard(&mut self, ranges: &[BlkRange]) -> Result {
+        self.queue(0).discard(ranges)
+    }
+
+    /// Writes zeroes to the given ranges of sectors.
+    ///
+    /// If a range has [`BlkRange::with_unmap`] set then the device may deallocate the sectors
+    /// rather than actually writing zeroes, as long as subsequent reads return zeroes.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support write zeroes, or
+    /// [`Error::InvalidParam`] if the ranges exceed the device's [`write_zeroes_limits`].
+    ///
+    /// [`write_zeroes_limits`]: Self::write_zeroes_limits
+    pub fn write_zeroes(&mut self, ranges: &[BlkRange]) -> Result {
+        self.queue(0).write_zeroes(ranges)
+    }
+
+    /// Securely erases the given ranges of sectors, so that their previous contents can't be
+    /// recovered.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support secure erase, or
+    /// [`Error::InvalidParam`] if the ranges exceed the device's [`secure_erase_limits`], are not
+    /// aligned to its secure erase sector alignment, or request unmapping.
+    ///
+    /// [`secure_erase_limits`]: Self::secure_erase_limits
+    pub fn secure_erase(&mut self, ranges: &[BlkRange]) -> Result {
+        self.queue(0).secure_erase(ranges)
+    }
+
+    /// Reports the zones of a zoned device, starting from the zone containing the given sector.
+    ///
+    /// The zone descriptors are written to `zones`, and the number of zones reported is returned.
+    /// Returns [`Error::Unsupported`] if the device is not zoned.
+    pub fn report_zones(&mut self, sector: u64, zones: &mut [BlkZone]) -> Result<usize> {
+        self.queue(0).report_zones(sector, zones)
+    }
+
+    /// Explicitly opens the zone starting at the given sector.
+    ///
+    /// Returns [`Error::Unsupported`] if the device is not zoned, or [`ZoneError::OpenResource`] or
+    /// [`ZoneError::ActiveResource`] if the device's limit on open or active zones would be
+    /// exceeded.
+    pub fn zone_open(&mut self, sector: u64) -> Result {
+        self.queue(0).zone_open(sector)
+    }
+
+    /// Closes the zone starting at the given sector.
+    ///
+    /// Returns [`Error::Unsupported`] if the device is not zoned.
+    pub fn zone_close(&mut self, sector: u64) -> Result {
+        self.queue(0).zone_close(sector)
+    }
+
+    /// Finishes the zone starting at the given sector, moving its write pointer to the end.
+    ///
+    /// Returns [`Error::Unsupported`] if the device is not zoned.
+    pub fn zone_finish(&mut self, sector: u64) -> Result {
+        self.queue(0).zone_finish(sector)
+    }
+
+    /// Resets the write pointer of the zone starting at the given sector.
+    ///
+    /// Returns [`Error::Unsupported`] if the device is not zoned.
+    pub fn zone_reset(&mut self, sector: u64) -> Result {
+        self.queue(0).zone_reset(sector)
+    }
+
+    /// Resets the write pointers of all the sequential zones of the device.
+    ///
+    /// Returns [`Error::Unsupported`] if the device is not zoned.
+    pub fn zone_reset_all(&mut self) -> Result {
+        self.queue(0).zone_reset_all()
+    }
+
+    /// Appends the contents of the given buffer to the zone starting at the given sector, and
+    /// returns the sector at which the device wrote it.
+    ///
+    /// The buffer length must be a non-zero multiple of the [`block_size`](Self::block_size), no
+    /// more than the device's `max_append_sectors`, or this returns [`Error::InvalidParam`].
+    /// Returns [`Error::Unsupported`] if the device is not zoned, or a [`ZoneError`] if the device
+    /// rejects the append.
+    pub fn zone_append(&mut self, sector: u64, buf: &[u8]) -> Result<u64> {
+        self.queue(0).zone_append(sector, buf)
     }
 
     /// Reads one or more blocks into the given buffer.
     ///
-    /// The buffer length must be a non-zero multiple of [`SECTOR_SIZE`].
+    /// The buffer length must be a non-zero multiple of [`SECTOR_SIZE`]. If the start or length
+    /// is not aligned to the device's [`block_size`](Self::block_size) then this returns
+    /// [`Error::InvalidParam`]. Large reads are split into as many requests as needed to respect
+    /// the device's segment size and count limits.
     ///
     /// Blocks until the read completes or there is an error.
     pub fn read_blocks(&mut self, block_id: usize, buf: &mut [u8]) -> Result {
-        assert_ne!(buf.len(), 0);
-        assert_eq!(buf.len() % SECTOR_SIZE, 0);
-        self.request_read(
-            BlkReq {
-                type_: ReqType::In,
-                reserved: 0,
-                sector: block_id as u64,
-            },
-            buf,
-        )
+        self.queue(0).read_blocks(block_id, buf)
     }
 
     /// Submits a request to read one or more blocks, but returns immediately without waiting for
@@ -197,7 +528,10 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
     ///   contents don't matter as `read_blocks_nb` will initialise it, but like the other buffers
     ///   it needs to be valid (and not otherwise used) until the corresponding
     ///   `complete_read_blocks` call. Its length must be a non-zero multiple of [`SECTOR_SIZE`].
-    /// * `buf` - The buffer in memory into which the block should be read.
+    /// * `buf` - The buffer in memory into which the block should be read. It is sent as a single
+    ///   segment, so must be no larger than the device's maximum segment size, and must be
+    ///   aligned to its [`block_size`](Self::block_size), or this returns
+    ///   [`Error::InvalidParam`].
     /// * `resp` - A mutable reference to a variable provided by the caller
     ///   to contain the status of the request. The caller can safely
     ///   read the variable only after the request is complete.
@@ -251,20 +585,8 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         buf: &mut [u8],
         resp: &mut BlkResp,
     ) -> Result<u16> {
-        assert_ne!(buf.len(), 0);
-        assert_eq!(buf.len() % SECTOR_SIZE, 0);
-        *req = BlkReq {
-            type_: ReqType::In,
-            reserved: 0,
-            sector: block_id as u64,
-        };
-        let token = self
-            .queue
-            .add(&[req.as_bytes()], &mut [buf, resp.as_mut_bytes()])?;
-        if self.queue.should_notify() {
-            self.transport.notify(QUEUE);
-        }
-        Ok(token)
+        // SAFETY: Delegated to the caller.
+        unsafe { self.queue(0).read_blocks_nb(block_id, req, buf, resp) }
     }
 
     /// Completes a read operation which was started by `read_blocks_nb`.
@@ -280,27 +602,19 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         buf: &mut [u8],
         resp: &mut BlkResp,
     ) -> Result<()> {
-        self.queue
-            .pop_used(token, &[req.as_bytes()], &mut [buf, resp.as_mut_bytes()])?;
-        resp.status.into()
+        // SAFETY: Delegated to the caller.
+        unsafe { self.queue(0).complete_read_blocks(token, req, buf, resp) }
     }
 
     /// Writes the contents of the given buffer to a block or blocks.
     ///
-    /// The buffer length must be a non-zero multiple of [`SECTOR_SIZE`].
+    /// The buffer length must be a non-zero multiple of [`SECTOR_SIZE`]. As for
+    /// [`read_blocks`](Self::read_blocks), it must be aligned to the device's block size, and large
+    /// writes are split into several requests as needed.
     ///
     /// Blocks until the write is complete or there is an error.
     pub fn write_blocks(&mut self, block_id: usize, buf: &[u8]) -> Result {
-        assert_ne!(buf.len(), 0);
-        assert_eq!(buf.len() % SECTOR_SIZE, 0);
-        self.request_write(
-            BlkReq {
-                type_: ReqType::Out,
-                sector: block_id as u64,
-                ..Default::default()
-            },
-            buf,
-        )
+        self.queue(0).write_blocks(block_id, buf)
     }
 
     /// Submits a request to write one or more blocks, but returns immediately without waiting for
@@ -314,7 +628,8 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
     ///   it needs to be valid (and not otherwise used) until the corresponding
     ///   `complete_write_blocks` call.
     /// * `buf` - The buffer in memory containing the data to write to the blocks. Its length must
-    ///   be a non-zero multiple of [`SECTOR_SIZE`].
+    ///   be a non-zero multiple of [`SECTOR_SIZE`], and it has the same size and alignment
+    ///   restrictions as for [`read_blocks_nb`](Self::read_blocks_nb).
     /// * `resp` - A mutable reference to a variable provided by the caller
     ///   to contain the status of the request. The caller can safely
     ///   read the variable only after the request is complete.
@@ -333,20 +648,8 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         buf: &[u8],
         resp: &mut BlkResp,
     ) -> Result<u16> {
-        assert_ne!(buf.len(), 0);
-        assert_eq!(buf.len() % SECTOR_SIZE, 0);
-        *req = BlkReq {
-            type_: ReqType::Out,
-            reserved: 0,
-            sector: block_id as u64,
-        };
-        let token = self
-            .queue
-            .add(&[req.as_bytes(), buf], &mut [resp.as_mut_bytes()])?;
-        if self.queue.should_notify() {
-            self.transport.notify(QUEUE);
-        }
-        Ok(token)
+        // SAFETY: Delegated to the caller.
+        unsafe { self.queue(0).write_blocks_nb(block_id, req, buf, resp) }
     }
 
     /// Completes a write operation which was started by `write_blocks_nb`.
@@ -362,15 +665,69 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         buf: &[u8],
         resp: &mut BlkResp,
     ) -> Result<()> {
-        self.queue
-            .pop_used(token, &[req.as_bytes(), buf], &mut [resp.as_mut_bytes()])?;
-        resp.status.into()
+        // SAFETY: Delegated to the caller.
+        unsafe { self.queue(0).complete_write_blocks(token, req, buf, resp) }
     }
 
     /// Fetches the token of the next completed request from the used ring and returns it, without
     /// removing it from the used ring. If there are no pending completed requests returns `None`.
     pub fn peek_used(&mut self) -> Option<u16> {
-        self.queue.peek_used()
+        self.queue(0).peek_used()
+    }
+
+    /// Submits a request to read one or more blocks into the given buffer, and returns a token
+    /// identifying it without waiting for it to complete.
+    ///
+    /// The driver keeps ownership of the buffer, along with the request header and status, until
+    /// the request completes and it is returned from [`poll_completions`](Self::poll_completions).
+    /// Many requests may be in flight at once, up to the queue size. The buffer must meet the same
+    /// requirements as for [`read_blocks_nb`](Self::read_blocks_nb).
+    ///
+    /// If the request can't be submitted, e.g. because the queue is full, then the buffer is
+    /// returned along with the error.
+    ///
+    /// These requests must not be mixed with the other non-blocking methods on the same queue.
+    /// Blocking methods return [`Error::AlreadyUsed`] while any are in flight.
+    #[cfg(feature = "alloc")]
+    pub fn submit_read(
+        &mut self,
+        block_id: usize,
+        buf: Box<[u8]>,
+    ) -> core::result::Result<u16, SubmitError> {
+        self.queue(0).submit_read(block_id, buf)
+    }
+
+    /// Submits a request to write the contents of the given buffer to one or more blocks, and
+    /// returns a token identifying it without waiting for it to complete.
+    ///
+    /// See [`submit_read`](Self::submit_read) for how the buffer is owned.
+    #[cfg(feature = "alloc")]
+    pub fn submit_write(
+        &mut self,
+        block_id: usize,
+        buf: Box<[u8]>,
+    ) -> core::result::Result<u16, SubmitError> {
+        self.queue(0).submit_write(block_id, buf)
+    }
+
+    /// Submits a request to flush any pending writes to storage, and returns a token identifying it
+    /// without waiting for it to complete.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support the `VIRTIO_BLK_F_FLUSH`
+    /// feature.
+    #[cfg(feature = "alloc")]
+    pub fn submit_flush(&mut self) -> Result<u16> {
+        self.queue(0).submit_flush()
+    }
+
+    /// Calls the given callback for each request submitted with `submit_*` which the device has
+    /// completed, and returns the number of completions reported.
+    ///
+    /// This should be called after [`ack_interrupt`](Self::ack_interrupt) reports a used buffer
+    /// notification.
+    #[cfg(feature = "alloc")]
+    pub fn poll_completions(&mut self, callback: impl FnMut(BlkCompletion)) -> usize {
+        self.queue(0).poll_completions(callback)
     }
 
     /// Returns the size of the device's VirtQueue.
@@ -381,53 +738,1123 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
     }
 }
 
-impl<H: Hal, T: Transport> Drop for VirtIOBlk<H, T> {
-    fn drop(&mut self) {
-        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
-        // after they have been freed.
-        self.transport.queue_unset(QUEUE);
-    }
+/// A handle to one of the queues of a [`VirtIOBlk`], returned by [`VirtIOBlk::queue`] or
+/// [`VirtIOBlk::queues`].
+///
+/// Handles for different queues can be used concurrently, e.g. from different CPU cores.
+pub struct BlkQueue<'a, H: Hal, T: Transport> {
+    transport: &'a DriverTransport<T>,
+    queue: &'a mut VirtQueue<H, { QUEUE_SIZE as usize }>,
+    queue_index: u16,
+    negotiated_features: BlkFeature,
+    limits: BlkLimits,
+    #[cfg(feature = "alloc")]
+    in_flight: &'a mut InFlightTable,
 }
 
-#[derive(FromBytes, Immutable, IntoBytes)]
-#[repr(C)]
-struct BlkConfig {
-    /// Number of 512 Bytes sectors
-    capacity_low: ReadOnly<u32>,
-    capacity_high: ReadOnly<u32>,
-    size_max: ReadOnly<u32>,
-    seg_max: ReadOnly<u32>,
-    cylinders: ReadOnly<u16>,
-    heads: ReadOnly<u8>,
-    sectors: ReadOnly<u8>,
-    blk_size: ReadOnly<u32>,
-    physical_block_exp: ReadOnly<u8>,
-    alignment_offset: ReadOnly<u8>,
-    min_io_size: ReadOnly<u16>,
-    opt_io_size: ReadOnly<u32>,
-    // ... ignored
-}
+impl<H: Hal, T: Transport> BlkQueue<'_, H, T> {
+    /// Adds the given buffers to the queue, notifies the device and waits for it to use them.
+    ///
+    /// Returns [`Error::AlreadyUsed`] if any requests submitted with `submit_*` are in flight, as
+    /// the device might complete them first and they would then be left on the used ring.
+    fn add_notify_wait_pop<'b>(
+        &mut self,
+        inputs: &'b [&'b [u8]],
+        outputs: &'b mut [&'b mut [u8]],
+    ) -> Result<u32> {
+        #[cfg(feature = "alloc")]
+        if self.in_flight.iter().any(Option::is_some) {
+            return Err(Error::AlreadyUsed);
+        }
+        self.queue
+            .add_notify_wait_pop(inputs, outputs, self.transport)
+    }
 
-/// A VirtIO block device request.
-#[repr(C)]
-#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
-pub struct BlkReq {
-    type_: ReqType,
-    reserved: u32,
-    sector: u64,
-}
+    /// Sends the given request to the device and waits for a response, with no extra data.
+    fn request(&mut self, request: BlkReq) -> Result {
+        let mut resp = BlkResp::default();
+        self.add_notify_wait_pop(&[request.as_bytes()], &mut [resp.as_mut_bytes()])?;
+        resp.status.into()
+    }
 
-impl Default for BlkReq {
-    fn default() -> Self {
-        Self {
-            type_: ReqType::In,
-            reserved: 0,
-            sector: 0,
+    /// Sends the given request to the device and waits for a response, including the given data.
+    ///
+    /// The data is split into segments no larger than the device's maximum segment size. Returns
+    /// [`Error::InvalidParam`] if this needs more than [`MAX_SEGMENTS`] segments.
+    fn request_read(&mut self, request: BlkReq, data: &mut [u8]) -> Result {
+        let mut resp = BlkResp::default();
+        let mut outputs: [&mut [u8]; MAX_SEGMENTS + 1] = Default::default();
+        let mut count = 0;
+        for segment in data.chunks_mut(self.limits.max_segment_size) {
+            *outputs.get_mut(count).ok_or(Error::InvalidParam)? = segment;
+            count += 1;
         }
+        *outputs.get_mut(count).ok_or(Error::InvalidParam)? = resp.as_mut_bytes();
+        self.add_notify_wait_pop(&[request.as_bytes()], &mut outputs[..=count])?;
+        resp.status.into()
     }
-}
 
-/// Response of a VirtIOBlk request.
+    /// Sends the given request and data to the device and waits for a response.
+    ///
+    /// The data is split into segments as for [`request_read`](Self::request_read).
+    fn request_write(&mut self, request: BlkReq, data: &[u8]) -> Result {
+        let mut resp = BlkResp::default();
+        let (inputs, count) = self.write_segments(request.as_bytes(), data)?;
+        self.add_notify_wait_pop(&inputs[..count], &mut [resp.as_mut_bytes()])?;
+        resp.status.into()
+    }
+
+    /// Returns the device-readable part of a request, consisting of the given header followed by
+    /// the given data split into segments no larger than the device's maximum segment size, and the
+    /// number of entries used.
+    ///
+    /// Returns [`Error::InvalidParam`] if this needs more than [`MAX_SEGMENTS`] segments.
+    fn write_segments<'b>(
+        &self,
+        header: &'b [u8],
+        data: &'b [u8],
+    ) -> Result<([&'b [u8]; MAX_SEGMENTS + 1], usize)> {
+        let mut inputs: [&[u8]; MAX_SEGMENTS + 1] = Default::default();
+        inputs[0] = header;
+        let mut count = 1;
+        for segment in data.chunks(self.limits.max_segment_size) {
+            *inputs.get_mut(count).ok_or(Error::InvalidParam)? = segment;
+            count += 1;
+        }
+        Ok((inputs, count))
+    }
+
+    /// Sends a zone management request of the given type for the zone starting at the given
+    /// sector, with no extra data.
+    fn request_zone(&mut self, type_: ReqType, sector: u64) -> Result {
+        if self.limits.zoned.is_none() {
+            return Err(Error::Unsupported);
+        }
+        self.request(BlkReq {
+            type_,
+            reserved: 0,
+            sector,
+        })
+    }
+
+    /// Sends a request of the given type for the given ranges, after checking them against the
+    /// limits reported by the device.
+    fn request_ranges(
+        &mut self,
+        type_: ReqType,
+        limits: Option<RangeLimits>,
+        ranges: &[BlkRange],
+    ) -> Result {
+        let limits = limits.ok_or(Error::Unsupported)?;
+        limits.check(ranges, type_ == ReqType::WriteZeroes)?;
+        self.request_write(
+            BlkReq {
+                type_,
+                ..Default::default()
+            },
+            ranges.as_bytes(),
+        )
+    }
+
+    /// Gets the lifetime and wear information of the device.
+    ///
+    /// See [`VirtIOBlk::lifetime`].
+    pub fn lifetime(&mut self) -> Result<BlkLifetime> {
+        if !self.negotiated_features.contains(BlkFeature::LIFETIME) {
+            return Err(Error::Unsupported);
+        }
+        let mut lifetime = BlkLifetime::default();
+        self.request_read(
+            BlkReq {
+                type_: ReqType::GetLifetime,
+                ..Default::default()
+            },
+            lifetime.as_mut_bytes(),
+        )?;
+        Ok(lifetime)
+    }
+
+    /// Requests the device to flush any pending writes to storage.
+    ///
+    /// See [`VirtIOBlk::flush`].
+    pub fn flush(&mut self) -> Result {
+        if self.negotiated_features.contains(BlkFeature::FLUSH) {
+            self.request(BlkReq {
+                type_: ReqType::Flush,
+                ..Default::default()
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Gets the device ID.
+    ///
+    /// See [`VirtIOBlk::device_id`].
+    pub fn device_id(&mut self, id: &mut [u8; 20]) -> Result<usize> {
+        self.request_read(
+            BlkReq {
+                type_: ReqType::GetId,
+                ..Default::default()
+            },
+            id,
+        )?;
+
+        let length = id.iter().position(|&x| x == 0).unwrap_or(20);
+        Ok(length)
+    }
+
+    /// Tells the device that the given ranges of sectors are no longer in use.
+    ///
+    /// See [`VirtIOBlk::discard`].
+    pub fn discard(&mut self, ranges: &[BlkRange]) -> Result {
+        self.request_ranges(ReqType::Discard, self.limits.discard, ranges)
+    }
+
+    /// Writes zeroes to the given ranges of sectors.
+    ///
+    /// See [`VirtIOBlk::write_zeroes`].
+    pub fn write_zeroes(&mut self, ranges: &[BlkRange]) -> Result {
+        self.request_ranges(ReqType::WriteZeroes, self.limits.write_zeroes, ranges)
+    }
+
+    /// Securely erases the given ranges of sectors.
+    ///
+    /// See [`VirtIOBlk::secure_erase`].
+    pub fn secure_erase(&mut self, ranges: &[BlkRange]) -> Result {
+        self.request_ranges(ReqType::SecureErase, self.limits.secure_erase, ranges)
+    }
+
+    /// Reports the zones of a zoned device, starting from the zone containing the given sector.
+    ///
+    /// See [`VirtIOBlk::report_zones`].
+    pub fn report_zones(&mut self, sector: u64, zones: &mut [BlkZone]) -> Result<usize> {
+        if self.limits.zoned.is_none() {
+            return Err(Error::Unsupported);
+        }
+        // Don't ask for more zones than fit in a single segment.
+        let max_zones = self.limits.max_segment_size / size_of::<BlkZone>();
+        let zones_len = zones.len().min(max_zones);
+        let zones = &mut zones[..zones_len];
+        if zones.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+
+        let request = BlkReq {
+            type_: ReqType::ZoneReport,
+            reserved: 0,
+            sector,
+        };
+        let mut header = ZoneReportHeader::default();
+        let mut resp = BlkResp::default();
+        self.add_notify_wait_pop(
+            &[request.as_bytes()],
+            &mut [
+                header.as_mut_bytes(),
+                zones.as_mut_bytes(),
+                resp.as_mut_bytes(),
+            ],
+        )?;
+        Result::from(resp.status)?;
+        Ok(usize::try_from(header.nr_zones)
+            .unwrap_or(usize::MAX)
+            .min(zones.len()))
+    }
+
+    /// Explicitly opens the zone starting at the given sector.
+    ///
+    /// See [`VirtIOBlk::zone_open`].
+    pub fn zone_open(&mut self, sector: u64) -> Result {
+        self.request_zone(ReqType::ZoneOpen, sector)
+    }
+
+    /// Closes the zone starting at the given sector.
+    ///
+    /// See [`VirtIOBlk::zone_close`].
+    pub fn zone_close(&mut self, sector: u64) -> Result {
+        self.request_zone(ReqType::ZoneClose, sector)
+    }
+
+    /// Finishes the zone starting at the given sector.
+    ///
+    /// See [`VirtIOBlk::zone_finish`].
+    pub fn zone_finish(&mut self, sector: u64) -> Result {
+        self.request_zone(ReqType::ZoneFinish, sector)
+    }
+
+    /// Resets the write pointer of the zone starting at the given sector.
+    ///
+    /// See [`VirtIOBlk::zone_reset`].
+    pub fn zone_reset(&mut self, sector: u64) -> Result {
+        self.request_zone(ReqType::ZoneReset, sector)
+    }
+
+    /// Resets the write pointers of all the sequential zones of the device.
+    ///
+    /// See [`VirtIOBlk::zone_reset_all`].
+    pub fn zone_reset_all(&mut self) -> Result {
+        self.request_zone(ReqType::ZoneResetAll, 0)
+    }
+
+    /// Appends the contents of the given buffer to the zone starting at the given sector.
+    ///
+    /// See [`VirtIOBlk::zone_append`].
+    pub fn zone_append(&mut self, sector: u64, buf: &[u8]) -> Result<u64> {
+        let zoned = self.limits.zoned.ok_or(Error::Unsupported)?;
+        if buf.is_empty()
+            || !buf.len().is_multiple_of(self.limits.block_size as usize)
+            || buf.len() / SECTOR_SIZE > zoned.max_append_sectors as usize
+        {
+            return Err(Error::InvalidParam);
+        }
+
+        let request = BlkReq {
+            type_: ReqType::ZoneAppend,
+            reserved: 0,
+            sector,
+        };
+        let mut append_sector = 0u64;
+        let mut resp = BlkResp::default();
+        let (inputs, count) = self.write_segments(request.as_bytes(), buf)?;
+        self.add_notify_wait_pop(
+            &inputs[..count],
+            &mut [append_sector.as_mut_bytes(), resp.as_mut_bytes()],
+        )?;
+        Result::from(resp.status)?;
+        Ok(append_sector)
+    }
+
+    /// Reads one or more blocks into the given buffer.
+    ///
+    /// See [`VirtIOBlk::read_blocks`].
+    pub fn read_blocks(&mut self, block_id: usize, buf: &mut [u8]) -> Result {
+        assert_ne!(buf.len(), 0);
+        assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        self.limits.check_aligned(block_id, buf.len())?;
+        let mut sector = block_id as u64;
+        for chunk in buf.chunks_mut(self.limits.max_request_size()) {
+            self.request_read(
+                BlkReq {
+                    type_: ReqType::In,
+                    reserved: 0,
+                    sector,
+                },
+                chunk,
+            )?;
+            sector += (chunk.len() / SECTOR_SIZE) as u64;
+        }
+        Ok(())
+    }
+
+    /// Submits a request to read one or more blocks, but returns immediately without waiting for
+    /// the read to complete.
+    ///
+    /// See [`VirtIOBlk::read_blocks_nb`].
+    ///
+    /// # Safety
+    ///
+    /// See [`VirtIOBlk::read_blocks_nb`].
+    pub unsafe fn read_blocks_nb(
+        &mut self,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &mut [u8],
+        resp: &mut BlkResp,
+    ) -> Result<u16> {
+        assert_ne!(buf.len(), 0);
+        assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        self.limits.check_aligned(block_id, buf.len())?;
+        if buf.len() > self.limits.max_segment_size {
+            return Err(Error::InvalidParam);
+        }
+        *req = BlkReq {
+            type_: ReqType::In,
+            reserved: 0,
+            sector: block_id as u64,
+        };
+        let token = self
+            .queue
+            .add(&[req.as_bytes()], &mut [buf, resp.as_mut_bytes()])?;
+        if self.queue.should_notify() {
+            self.transport.notify(self.queue_index);
+        }
+        Ok(token)
+    }
+
+    /// Completes a read operation which was started by `read_blocks_nb`.
+    ///
+    /// See [`VirtIOBlk::complete_read_blocks`].
+    ///
+    /// # Safety
+    ///
+    /// See [`VirtIOBlk::complete_read_blocks`].
+    pub unsafe fn complete_read_blocks(
+        &mut self,
+        token: u16,
+        req: &BlkReq,
+        buf: &mut [u8],
+        resp: &mut BlkResp,
+    ) -> Result<()> {
+        self.queue
+            .pop_used(token, &[req.as_bytes()], &mut [buf, resp.as_mut_bytes()])?;
+        resp.status.into()
+    }
+
+    /// Writes the contents of the given buffer to a block or blocks.
+    ///
+    /// See [`VirtIOBlk::write_blocks`].
+    pub fn write_blocks(&mut self, block_id: usize, buf: &[u8]) -> Result {
+        assert_ne!(buf.len(), 0);
+        assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        self.limits.check_aligned(block_id, buf.len())?;
+        let mut sector = block_id as u64;
+        for chunk in buf.chunks(self.limits.max_request_size()) {
+            self.request_write(
+                BlkReq {
+                    type_: ReqType::Out,
+                    sector,
+                    ..Default::default()
+                },
+                chunk,
+            )?;
+            sector += (chunk.len() / SECTOR_SIZE) as u64;
+        }
+        Ok(())
+    }
+
+    /// Submits a request to write one or more blocks, but returns immediately without waiting for
+    /// the write to complete.
+    ///
+    /// See [`VirtIOBlk::write_blocks_nb`].
+    ///
+    /// # Safety
+    ///
+    /// See [`VirtIOBlk::write_blocks_nb`].
+    pub unsafe fn write_blocks_nb(
+        &mut self,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &[u8],
+        resp: &mut BlkResp,
+    ) -> Result<u16> {
+        assert_ne!(buf.len(), 0);
+        assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        self.limits.check_aligned(block_id, buf.len())?;
+        if buf.len() > self.limits.max_segment_size {
+            return Err(Error::InvalidParam);
+        }
+        *req = BlkReq {
+            type_: ReqType::Out,
+            reserved: 0,
+            sector: block_id as u64,
+        };
+        let token = self
+            .queue
+            .add(&[req.as_bytes(), buf], &mut [resp.as_mut_bytes()])?;
+        if self.queue.should_notify() {
+            self.transport.notify(self.queue_index);
+        }
+        Ok(token)
+    }
+
+    /// Completes a write operation which was started by `write_blocks_nb`.
+    ///
+    /// See [`VirtIOBlk::complete_write_blocks`].
+    ///
+    /// # Safety
+    ///
+    /// See [`VirtIOBlk::complete_write_blocks`].
+    pub unsafe fn complete_write_blocks(
+        &mut self,
+        token: u16,
+        req: &BlkReq,
+        buf: &[u8],
+        resp: &mut BlkResp,
+    ) -> Result<()> {
+        self.queue
+            .pop_used(token, &[req.as_bytes(), buf], &mut [resp.as_mut_bytes()])?;
+        resp.status.into()
+    }
+
+    /// Fetches the token of the next completed request from the used ring and returns it, without
+    /// removing it from the used ring. If there are no pending completed requests returns `None`.
+    ///
+    /// See [`VirtIOBlk::peek_used`].
+    pub fn peek_used(&mut self) -> Option<u16> {
+        self.queue.peek_used()
+    }
+
+    /// Submits a request to read one or more blocks into the given buffer.
+    ///
+    /// See [`VirtIOBlk::submit_read`].
+    #[cfg(feature = "alloc")]
+    pub fn submit_read(
+        &mut self,
+        block_id: usize,
+        buf: Box<[u8]>,
+    ) -> core::result::Result<u16, SubmitError> {
+        self.submit(ReqType::In, block_id, buf)
+    }
+
+    /// Submits a request to write the contents of the given buffer to one or more blocks.
+    ///
+    /// See [`VirtIOBlk::submit_write`].
+    #[cfg(feature = "alloc")]
+    pub fn submit_write(
+        &mut self,
+        block_id: usize,
+        buf: Box<[u8]>,
+    ) -> core::result::Result<u16, SubmitError> {
+        self.submit(ReqType::Out, block_id, buf)
+    }
+
+    /// Submits a request to flush any pending writes to storage.
+    ///
+    /// See [`VirtIOBlk::submit_flush`].
+    #[cfg(feature = "alloc")]
+    pub fn submit_flush(&mut self) -> Result<u16> {
+        if !self.negotiated_features.contains(BlkFeature::FLUSH) {
+            return Err(Error::Unsupported);
+        }
+        self.submit(ReqType::Flush, 0, Box::default())
+            .map_err(|e| e.error)
+    }
+
+    /// Calls the given callback for each completed request.
+    ///
+    /// See [`VirtIOBlk::poll_completions`].
+    #[cfg(feature = "alloc")]
+    pub fn poll_completions(&mut self, mut callback: impl FnMut(BlkCompletion)) -> usize {
+        let mut count = 0;
+        while let Some(token) = self.queue.peek_used() {
+            let Some(mut request) = self
+                .in_flight
+                .get_mut(usize::from(token))
+                .and_then(Option::take)
+            else {
+                // This wasn't submitted with `submit_*`, so we don't have its buffers to pop it.
+                break;
+            };
+            // SAFETY: The request was added to the queue with these same buffers when it was
+            // submitted, and they have been owned by the in-flight table since then.
+            let result = unsafe { request.pop_from(token, self.queue) }
+                .and_then(|_| request.resp.status.into());
+            callback(BlkCompletion {
+                token,
+                result,
+                buf: request.buf,
+            });
+            count += 1;
+        }
+        count
+    }
+
+    /// Checks the given buffer and submits a request of the given type for it, storing it in the
+    /// in-flight table until it completes.
+    #[cfg(feature = "alloc")]
+    fn submit(
+        &mut self,
+        type_: ReqType,
+        block_id: usize,
+        buf: Box<[u8]>,
+    ) -> core::result::Result<u16, SubmitError> {
+        if type_ != ReqType::Flush {
+            if let Err(error) = self.check_buffer(block_id, buf.len()) {
+                return Err(SubmitError { error, buf });
+            }
+        }
+        let mut request = InFlightRequest {
+            req: Box::new(BlkReq {
+                type_,
+                reserved: 0,
+                sector: block_id as u64,
+            }),
+            resp: Box::default(),
+            buf,
+        };
+        // SAFETY: The request header, response and buffer are heap allocations which are owned by
+        // the in-flight table until the request is popped in `poll_completions`, or the device is
+        // reset, so they remain valid and are not otherwise accessed until then.
+        let token = match unsafe { request.add_to(self.queue) } {
+            Ok(token) => token,
+            Err(error) => {
+                return Err(SubmitError {
+                    error,
+                    buf: request.buf,
+                })
+            }
+        };
+        if self.queue.should_notify() {
+            self.transport.notify(self.queue_index);
+        }
+        self.in_flight[usize::from(token)] = Some(request);
+        Ok(token)
+    }
+
+    /// Checks that a buffer of the given length can be sent as a single segment to read or write
+    /// starting at the given block.
+    #[cfg(feature = "alloc")]
+    fn check_buffer(&self, block_id: usize, len: usize) -> Result {
+        if len == 0 || !len.is_multiple_of(SECTOR_SIZE) || len > self.limits.max_segment_size {
+            return Err(Error::InvalidParam);
+        }
+        self.limits.check_aligned(block_id, len)
+    }
+}
+
+/// A request submitted with one of the `submit_*` methods, which has not yet completed.
+#[cfg(feature = "alloc")]
+struct InFlightRequest {
+    req: Box<BlkReq>,
+    resp: Box<BlkResp>,
+    buf: Box<[u8]>,
+}
+
+#[cfg(feature = "alloc")]
+impl InFlightRequest {
+    /// Adds the request to the given queue, returning its token.
+    ///
+    /// # Safety
+    ///
+    /// The request must not be dropped or otherwise accessed until it has been popped from the
+    /// queue with `pop_from`, or the device has been reset.
+    unsafe fn add_to<H: Hal, const SIZE: usize>(
+        &mut self,
+        queue: &mut VirtQueue<H, SIZE>,
+    ) -> Result<u16> {
+        // SAFETY: Delegated to the caller.
+        unsafe {
+            match self.req.type_ {
+                ReqType::In => queue.add(
+                    &[self.req.as_bytes()],
+                    &mut [&mut self.buf, self.resp.as_mut_bytes()],
+                ),
+                ReqType::Out => queue.add(
+                    &[self.req.as_bytes(), &self.buf],
+                    &mut [self.resp.as_mut_bytes()],
+                ),
+                _ => queue.add(&[self.req.as_bytes()], &mut [self.resp.as_mut_bytes()]),
+            }
+        }
+    }
+
+    /// Pops the request from the given queue, once the device has used it.
+    ///
+    /// # Safety
+    ///
+    /// The request must previously have been added to the same queue with `add_to`, which
+    /// returned the given token.
+    unsafe fn pop_from<H: Hal, const SIZE: usize>(
+        &mut self,
+        token: u16,
+        queue: &mut VirtQueue<H, SIZE>,
+    ) -> Result<u32> {
+        // SAFETY: Delegated to the caller.
+        unsafe {
+            match self.req.type_ {
+                ReqType::In => queue.pop_used(
+                    token,
+                    &[self.req.as_bytes()],
+                    &mut [&mut self.buf, self.resp.as_mut_bytes()],
+                ),
+                ReqType::Out => queue.pop_used(
+                    token,
+                    &[self.req.as_bytes(), &self.buf],
+                    &mut [self.resp.as_mut_bytes()],
+                ),
+                _ => queue.pop_used(
+                    token,
+                    &[self.req.as_bytes()],
+                    &mut [self.resp.as_mut_bytes()],
+                ),
+            }
+        }
+    }
+}
+
+/// A completed request, reported by [`VirtIOBlk::poll_completions`].
+#[cfg(feature = "alloc")]
+#[derive(Debug)]
+pub struct BlkCompletion {
+    /// The token which was returned when the request was submitted.
+    pub token: u16,
+    /// The result of the request.
+    pub result: Result,
+    /// The buffer which was passed when the request was submitted. For a successful read this
+    /// contains the data read.
+    pub buf: Box<[u8]>,
+}
+
+/// An error submitting a request with one of the `submit_*` methods, which returns ownership of
+/// the buffer to the caller.
+#[cfg(feature = "alloc")]
+#[derive(Debug)]
+pub struct SubmitError {
+    /// The reason the request couldn't be submitted.
+    pub error: Error,
+    /// The buffer which was passed to the `submit_*` method.
+    pub buf: Box<[u8]>,
+}
+
+#[derive(FromBytes, Immutable, IntoBytes)]
+#[repr(C)]
+struct BlkConfig {
+    /// Number of 512 Bytes sectors
+    capacity_low: ReadOnly<u32>,
+    capacity_high: ReadOnly<u32>,
+    size_max: ReadOnly<u32>,
+    seg_max: ReadOnly<u32>,
+    cylinders: ReadOnly<u16>,
+    heads: ReadOnly<u8>,
+    sectors: ReadOnly<u8>,
+    blk_size: ReadOnly<u32>,
+    physical_block_exp: ReadOnly<u8>,
+    alignment_offset: ReadOnly<u8>,
+    min_io_size: ReadOnly<u16>,
+    opt_io_size: ReadOnly<u32>,
+    writeback: ReadWrite<u8>,
+    unused0: ReadOnly<u8>,
+    num_queues: ReadOnly<u16>,
+    max_discard_sectors: ReadOnly<u32>,
+    max_discard_seg: ReadOnly<u32>,
+    discard_sector_alignment: ReadOnly<u32>,
+    max_write_zeroes_sectors: ReadOnly<u32>,
+    max_write_zeroes_seg: ReadOnly<u32>,
+    write_zeroes_may_unmap: ReadOnly<u8>,
+    unused1: ReadOnly<[u8; 3]>,
+    max_secure_erase_sectors: ReadOnly<u32>,
+    max_secure_erase_seg: ReadOnly<u32>,
+    secure_erase_sector_alignment: ReadOnly<u32>,
+    zone_sectors: ReadOnly<u32>,
+    max_open_zones: ReadOnly<u32>,
+    max_active_zones: ReadOnly<u32>,
+    max_append_sectors: ReadOnly<u32>,
+    write_granularity: ReadOnly<u32>,
+    model: ReadOnly<u8>,
+    unused2: ReadOnly<[u8; 3]>,
+}
+
+/// The characteristics of a zoned block device, from the `VIRTIO_BLK_F_ZONED` feature.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ZonedCharacteristics {
+    /// The size of each zone, in 512 byte sectors.
+    pub zone_sectors: u32,
+    /// The maximum number of zones which can be open at once, or 0 for no limit.
+    pub max_open_zones: u32,
+    /// The maximum number of zones which can be active at once, or 0 for no limit.
+    pub max_active_zones: u32,
+    /// The maximum size of a zone append request, in 512 byte sectors.
+    pub max_append_sectors: u32,
+    /// The offset and size of writes to sequential zones must be a multiple of this many bytes.
+    pub write_granularity: u32,
+    /// The zoned model of the device.
+    pub model: ZonedModel,
+}
+
+/// The zoned model of a block device.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ZonedModel(u8);
+
+impl ZonedModel {
+    /// The device is not zoned.
+    pub const NONE: ZonedModel = ZonedModel(0);
+    /// Host-managed: sequential zones must be written sequentially.
+    pub const HOST_MANAGED: ZonedModel = ZonedModel(1);
+    /// Host-aware: sequential zones should be written sequentially, but random writes are allowed.
+    pub const HOST_AWARE: ZonedModel = ZonedModel(2);
+}
+
+/// The header of a zone report, which is followed by the zone descriptors.
+#[repr(C)]
+#[derive(Debug, FromBytes, Immutable, IntoBytes, KnownLayout)]
+struct ZoneReportHeader {
+    nr_zones: u64,
+    reserved: [u8; 56],
+}
+
+impl Default for ZoneReportHeader {
+    fn default() -> Self {
+        Self::new_zeroed()
+    }
+}
+
+/// A descriptor for a single zone of a zoned block device, as returned by
+/// [`VirtIOBlk::report_zones`].
+#[repr(C)]
+#[derive(Clone, Debug, Eq, FromBytes, Immutable, IntoBytes, KnownLayout, PartialEq)]
+pub struct BlkZone {
+    capacity: u64,
+    start: u64,
+    write_pointer: u64,
+    zone_type: ZoneType,
+    state: ZoneState,
+    reserved: [u8; 38],
+}
+
+impl Default for BlkZone {
+    fn default() -> Self {
+        Self::new_zeroed()
+    }
+}
+
+impl BlkZone {
+    /// Returns the number of sectors in the zone which can be written.
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Returns the first sector of the zone.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Returns the sector of the zone's write pointer.
+    pub fn write_pointer(&self) -> u64 {
+        self.write_pointer
+    }
+
+    /// Returns the type of the zone.
+    pub fn zone_type(&self) -> ZoneType {
+        self.zone_type
+    }
+
+    /// Returns the state of the zone.
+    pub fn state(&self) -> ZoneState {
+        self.state
+    }
+}
+
+/// The type of a zone.
+#[repr(transparent)]
+#[derive(Copy, Clone, Debug, Eq, FromBytes, Immutable, IntoBytes, KnownLayout, PartialEq)]
+pub struct ZoneType(u8);
+
+impl ZoneType {
+    /// Conventional zone, which can be written randomly.
+    pub const CONVENTIONAL: ZoneType = ZoneType(1);
+    /// Sequential write required.
+    pub const SEQUENTIAL_WRITE_REQUIRED: ZoneType = ZoneType(2);
+    /// Sequential write preferred.
+    pub const SEQUENTIAL_WRITE_PREFERRED: ZoneType = ZoneType(3);
+}
+
+/// The state of a zone.
+#[repr(transparent)]
+#[derive(Copy, Clone, Debug, Eq, FromBytes, Immutable, IntoBytes, KnownLayout, PartialEq)]
+pub struct ZoneState(u8);
+
+impl ZoneState {
+    /// Conventional zone, which has no write pointer.
+    pub const NOT_WRITE_POINTER: ZoneState = ZoneState(0);
+    /// Empty.
+    pub const EMPTY: ZoneState = ZoneState(1);
+    /// Implicitly opened.
+    pub const IMPLICITLY_OPEN: ZoneState = ZoneState(2);
+    /// Explicitly opened.
+    pub const EXPLICITLY_OPEN: ZoneState = ZoneState(3);
+    /// Closed.
+    pub const CLOSED: ZoneState = ZoneState(4);
+    /// Read-only.
+    pub const READ_ONLY: ZoneState = ZoneState(13);
+    /// Full.
+    pub const FULL: ZoneState = ZoneState(14);
+    /// Offline.
+    pub const OFFLINE: ZoneState = ZoneState(15);
+}
+
+/// An error reported by a zoned block device.
+#[derive(Copy, Clone, Debug, Eq, Error, PartialEq)]
+pub enum ZoneError {
+    /// A write to a sequential zone was not at the zone's write pointer.
+    #[error("Write was not at the zone's write pointer")]
+    UnalignedWritePointer,
+    /// The request would have exceeded the device's limit on open zones.
+    #[error("Too many zones are open")]
+    OpenResource,
+    /// The request would have exceeded the device's limit on active zones.
+    #[error("Too many zones are active")]
+    ActiveResource,
+}
+
+/// Limits on the ranges which may be passed in a single discard, write zeroes or secure erase
+/// request.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RangeLimits {
+    /// The maximum number of sectors in a single range.
+    pub max_sectors: u32,
+    /// The maximum number of ranges in a single request.
+    pub max_segments: u32,
+    /// The start and length of each range must be a multiple of this number of sectors.
+    pub sector_alignment: u32,
+}
+
+impl RangeLimits {
+    /// Checks that the given ranges can be sent to the device in a single request.
+    fn check(&self, ranges: &[BlkRange], allow_unmap: bool) -> Result {
+        if ranges.is_empty() || ranges.len() > self.max_segments as usize {
+            return Err(Error::InvalidParam);
+        }
+        for range in ranges {
+            if range.num_sectors == 0
+                || range.num_sectors > self.max_sectors
+                || !range.sector.is_multiple_of(self.sector_alignment.into())
+                || !range.num_sectors.is_multiple_of(self.sector_alignment)
+                || (!allow_unmap && range.flags != 0)
+            {
+                return Err(Error::InvalidParam);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Lifetime and wear information for a block device, as returned by [`VirtIOBlk::lifetime`].
+#[repr(C)]
+#[derive(Clone, Debug, Default, Eq, FromBytes, Immutable, IntoBytes, KnownLayout, PartialEq)]
+pub struct BlkLifetime {
+    pre_eol_info: PreEolInfo,
+    device_lifetime_est_typ_a: u16,
+    device_lifetime_est_typ_b: u16,
+}
+
+impl BlkLifetime {
+    /// Returns how much of the reserved blocks have been consumed.
+    pub fn pre_eol_info(&self) -> PreEolInfo {
+        self.pre_eol_info
+    }
+
+    /// Returns the estimated wear of type A (e.g. SLC) memory, in steps of 10% of its lifetime
+    /// from 0x01 (0-10% used) to 0x0b (exceeded its estimated lifetime), or 0 if undefined.
+    pub fn lifetime_estimate_a(&self) -> u16 {
+        self.device_lifetime_est_typ_a
+    }
+
+    /// Returns the estimated wear of type B (e.g. MLC) memory, in the same units as
+    /// [`lifetime_estimate_a`](Self::lifetime_estimate_a).
+    pub fn lifetime_estimate_b(&self) -> u16 {
+        self.device_lifetime_est_typ_b
+    }
+}
+
+/// How much of the reserved blocks of a device have been consumed.
+#[repr(transparent)]
+#[derive(
+    Copy, Clone, Debug, Default, Eq, FromBytes, Immutable, IntoBytes, KnownLayout, PartialEq,
+)]
+pub struct PreEolInfo(u16);
+
+impl PreEolInfo {
+    /// Undefined.
+    pub const UNDEFINED: PreEolInfo = PreEolInfo(0);
+    /// Normal, less than 80% of reserved blocks consumed.
+    pub const NORMAL: PreEolInfo = PreEolInfo(1);
+    /// Warning, 80% of reserved blocks consumed.
+    pub const WARNING: PreEolInfo = PreEolInfo(2);
+    /// Urgent, 90% of reserved blocks consumed.
+    pub const URGENT: PreEolInfo = PreEolInfo(3);
+}
+
+/// The I/O topology of a block device, from the `VIRTIO_BLK_F_TOPOLOGY` feature.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct BlkTopology {
+    /// The number of logical blocks per physical block, as a power of two.
+    pub physical_block_exp: u8,
+    /// The offset of the first aligned logical block.
+    pub alignment_offset: u8,
+    /// The suggested minimum I/O size, in logical blocks.
+    pub min_io_size: u16,
+    /// The optimal (suggested maximum) I/O size, in logical blocks.
+    pub opt_io_size: u32,
+}
+
+/// The block size and request limits of the device, read from its configuration.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct BlkLimits {
+    /// The logical block size in bytes.
+    block_size: u32,
+    /// The maximum size of a single data segment in bytes.
+    max_segment_size: usize,
+    /// The maximum number of data segments in a single request.
+    max_segments: usize,
+    topology: Option<BlkTopology>,
+    zoned: Option<ZonedCharacteristics>,
+    discard: Option<RangeLimits>,
+    write_zeroes: Option<RangeLimits>,
+    secure_erase: Option<RangeLimits>,
+}
+
+impl BlkLimits {
+    /// Reads the limits for whichever features were negotiated.
+    fn read(transport: &impl Transport, negotiated_features: BlkFeature) -> Result<Self> {
+        let block_size = if negotiated_features.contains(BlkFeature::BLK_SIZE) {
+            let blk_size = read_config!(*transport, BlkConfig, blk_size)?;
+            if blk_size.is_power_of_two() && blk_size as usize >= SECTOR_SIZE {
+                blk_size
+            } else {
+                warn!("Ignoring invalid block size {}", blk_size);
+                SECTOR_SIZE as u32
+            }
+        } else {
+            SECTOR_SIZE as u32
+        };
+        let max_segment_size = if negotiated_features.contains(BlkFeature::SIZE_MAX) {
+            match read_config!(*transport, BlkConfig, size_max)? {
+                0 => usize::MAX,
+                size_max => size_max as usize,
+            }
+        } else {
+            usize::MAX
+        };
+        let max_segments = if negotiated_features.contains(BlkFeature::SEG_MAX) {
+            (read_config!(*transport, BlkConfig, seg_max)? as usize).clamp(1, MAX_SEGMENTS)
+        } else {
+            MAX_SEGMENTS
+        };
+        let topology = if negotiated_features.contains(BlkFeature::TOPOLOGY) {
+            Some(BlkTopology {
+                physical_block_exp: read_config!(*transport, BlkConfig, physical_block_exp)?,
+                alignment_offset: read_config!(*transport, BlkConfig, alignment_offset)?,
+                min_io_size: read_config!(*transport, BlkConfig, min_io_size)?,
+                opt_io_size: read_config!(*transport, BlkConfig, opt_io_size)?,
+            })
+        } else {
+            None
+        };
+        let zoned = if negotiated_features.contains(BlkFeature::ZONED) {
+            Some(ZonedCharacteristics {
+                zone_sectors: read_config!(*transport, BlkConfig, zone_sectors)?,
+                max_open_zones: read_config!(*transport, BlkConfig, max_open_zones)?,
+                max_active_zones: read_config!(*transport, BlkConfig, max_active_zones)?,
+                max_append_sectors: read_config!(*transport, BlkConfig, max_append_sectors)?,
+                write_granularity: read_config!(*transport, BlkConfig, write_granularity)?,
+                model: ZonedModel(read_config!(*transport, BlkConfig, model)?),
+            })
+        } else {
+            None
+        };
+
+        let mut limits = Self {
+            block_size,
+            max_segment_size,
+            max_segments,
+            topology,
+            zoned,
+            discard: None,
+            write_zeroes: None,
+            secure_erase: None,
+        };
+        if negotiated_features.contains(BlkFeature::DISCARD) {
+            limits.discard = Some(RangeLimits {
+                max_sectors: read_config!(*transport, BlkConfig, max_discard_sectors)?,
+                max_segments: read_config!(*transport, BlkConfig, max_discard_seg)?,
+                sector_alignment: read_config!(*transport, BlkConfig, discard_sector_alignment)?
+                    .max(1),
+            });
+        }
+        if negotiated_features.contains(BlkFeature::WRITE_ZEROES) {
+            limits.write_zeroes = Some(RangeLimits {
+                max_sectors: read_config!(*transport, BlkConfig, max_write_zeroes_sectors)?,
+                max_segments: read_config!(*transport, BlkConfig, max_write_zeroes_seg)?,
+                sector_alignment: 1,
+            });
+        }
+        if negotiated_features.contains(BlkFeature::SECURE_ERASE) {
+            limits.secure_erase = Some(RangeLimits {
+                max_sectors: read_config!(*transport, BlkConfig, max_secure_erase_sectors)?,
+                max_segments: read_config!(*transport, BlkConfig, max_secure_erase_seg)?,
+                sector_alignment: read_config!(
+                    *transport,
+                    BlkConfig,
+                    secure_erase_sector_alignment
+                )?
+                .max(1),
+            });
+        }
+        Ok(limits)
+    }
+
+    /// Returns the maximum number of bytes which can be read or written in a single request,
+    /// which is always a multiple of the block size.
+    fn max_request_size(&self) -> usize {
+        let block_size = self.block_size as usize;
+        let max_size = self.max_segment_size.saturating_mul(self.max_segments);
+        (max_size / block_size * block_size).max(block_size)
+    }
+
+    /// Checks that a read or write starting at the given sector with the given length in bytes is
+    /// aligned to the logical block size.
+    fn check_aligned(&self, sector: usize, len: usize) -> Result {
+        let block_size = self.block_size as usize;
+        if sector.is_multiple_of(block_size / SECTOR_SIZE) && len.is_multiple_of(block_size) {
+            Ok(())
+        } else {
+            Err(Error::InvalidParam)
+        }
+    }
+}
+
+/// A range of sectors for a discard, write zeroes or secure erase request.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Eq, FromBytes, Immutable, IntoBytes, KnownLayout, PartialEq)]
+pub struct BlkRange {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+impl BlkRange {
+    /// For write zeroes requests, allows the device to deallocate the sectors.
+    const FLAG_UNMAP: u32 = 1 << 0;
+
+    /// Creates a range of `num_sectors` sectors starting at `sector`.
+    pub fn new(sector: u64, num_sectors: u32) -> Self {
+        Self {
+            sector,
+            num_sectors,
+            flags: 0,
+        }
+    }
+
+    /// Allows the device to deallocate the sectors rather than writing zeroes to them. This is
+    /// only valid for write zeroes requests.
+    pub fn with_unmap(self) -> Self {
+        Self {
+            flags: self.flags | Self::FLAG_UNMAP,
+            ..self
+        }
+    }
+
+    /// Returns the first sector of the range.
+    pub fn sector(&self) -> u64 {
+        self.sector
+    }
+
+    /// Returns the number of sectors in the range.
+    pub fn num_sectors(&self) -> u32 {
+        self.num_sectors
+    }
+}
+
+/// A VirtIO block device request.
+#[repr(C)]
+#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
+pub struct BlkReq {
+    type_: ReqType,
+    reserved: u32,
+    sector: u64,
+}
+
+impl Default for BlkReq {
+    fn default() -> Self {
+        Self {
+            type_: ReqType::In,
+            reserved: 0,
+            sector: 0,
+        }
+    }
+}
+
+/// Response of a VirtIOBlk request.
 #[repr(C)]
 #[derive(Debug, FromBytes, Immutable, IntoBytes, KnownLayout)]
 pub struct BlkResp {
@@ -442,7 +1869,7 @@ impl BlkResp {
 }
 
 #[repr(u32)]
-#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
+#[derive(Copy, Clone, Debug, Eq, Immutable, IntoBytes, KnownLayout, PartialEq)]
 enum ReqType {
     In = 0,
     Out = 1,
@@ -452,6 +1879,13 @@ enum ReqType {
     Discard = 11,
     WriteZeroes = 13,
     SecureErase = 14,
+    ZoneAppend = 15,
+    ZoneReport = 16,
+    ZoneOpen = 18,
+    ZoneClose = 20,
+    ZoneFinish = 22,
+    ZoneReset = 24,
+    ZoneResetAll = 26,
 }
 
 /// Status of a VirtIOBlk request.
@@ -468,6 +1902,52 @@ impl RespStatus {
     pub const UNSUPPORTED: RespStatus = RespStatus(2);
     /// Not ready.
     pub const NOT_READY: RespStatus = RespStatus(3);
+    /// A zoned write was not at the zone's write pointer.
+    pub const ZONE_UNALIGNED_WP: RespStatus = RespStatus(4);
+    /// Too many zones are open.
+    pub const ZONE_OPEN_RESOURCE: RespStatus = RespStatus(5);
+    /// Too many zones are active.
+    pub const ZONE_ACTIVE_RESOURCE: RespStatus = RespStatus(6);
+}
+
+impl TryFrom<u32> for ReqType {
+    type Error = Error;
+
+    fn try_from(value: u32) -> Result<Self> {
+        Ok(match value {
+            0 => Self::In,
+            1 => Self::Out,
+            4 => Self::Flush,
+            8 => Self::GetId,
+            10 => Self::GetLifetime,
+            11 => Self::Discard,
+            13 => Self::WriteZeroes,
+            14 => Self::SecureErase,
+            15 => Self::ZoneAppend,
+            16 => Self::ZoneReport,
+            18 => Self::ZoneOpen,
+            20 => Self::ZoneClose,
+            22 => Self::ZoneFinish,
+            24 => Self::ZoneReset,
+            26 => Self::ZoneResetAll,
+            _ => return Err(Error::Unsupported),
+        })
+    }
+}
+
+impl From<Result> for RespStatus {
+    fn from(result: Result) -> Self {
+        match result {
+            Ok(()) => RespStatus::OK,
+            Err(Error::Unsupported) => RespStatus::UNSUPPORTED,
+            Err(Error::ZoneError(ZoneError::UnalignedWritePointer)) => {
+                RespStatus::ZONE_UNALIGNED_WP
+            }
+            Err(Error::ZoneError(ZoneError::OpenResource)) => RespStatus::ZONE_OPEN_RESOURCE,
+            Err(Error::ZoneError(ZoneError::ActiveResource)) => RespStatus::ZONE_ACTIVE_RESOURCE,
+            Err(_) => RespStatus::IO_ERR,
+        }
+    }
 }
 
 impl From<RespStatus> for Result {
@@ -477,6 +1957,9 @@ impl From<RespStatus> for Result {
             RespStatus::IO_ERR => Err(Error::IoError),
             RespStatus::UNSUPPORTED => Err(Error::Unsupported),
             RespStatus::NOT_READY => Err(Error::NotReady),
+            RespStatus::ZONE_UNALIGNED_WP => Err(ZoneError::UnalignedWritePointer.into()),
+            RespStatus::ZONE_OPEN_RESOURCE => Err(ZoneError::OpenResource.into()),
+            RespStatus::ZONE_ACTIVE_RESOURCE => Err(ZoneError::ActiveResource.into()),
             _ => Err(Error::IoError),
         }
     }
@@ -531,55 +2014,566 @@ bitflags! {
         const LIFETIME      = 1 << 15;
         /// Device can support the secure erase command.
         const SECURE_ERASE  = 1 << 16;
+        /// Device is a zoned block device.
+        const ZONED         = 1 << 17;
+
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // the following since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+
+        // since virtio v1.3
+        const SUSPEND               = 1 << 42;
+    }
+}
+
+/// Storage behind a [`VirtIOBlkDevice`], which serves the requests from the driver.
+///
+/// The device checks that requests are within the [`capacity`](Self::capacity) and don't modify
+/// read-only storage before calling the backend. Errors returned by the backend are reported to
+/// the driver as `VIRTIO_BLK_S_UNSUPP` for [`Error::Unsupported`], or `VIRTIO_BLK_S_IOERR`
+/// otherwise.
+#[cfg(feature = "alloc")]
+pub trait BlkBackend {
+    /// Returns the capacity of the storage, in 512 byte ([`SECTOR_SIZE`]) sectors.
+    fn capacity(&self) -> u64;
+
+    /// Returns whether the storage is read-only, in which case requests which would modify it are
+    /// rejected.
+    fn readonly(&self) -> bool {
+        false
+    }
+
+    /// Reads data starting at the given sector into the given buffer, whose length is a multiple
+    /// of [`SECTOR_SIZE`].
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result;
+
+    /// Writes the given data starting at the given sector. Its length is a multiple of
+    /// [`SECTOR_SIZE`].
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result;
+
+    /// Flushes any cached writes to durable storage.
+    fn flush(&mut self) -> Result {
+        Ok(())
+    }
+
+    /// Returns the ID string of the device, which will be truncated to 20 bytes.
+    fn device_id(&self) -> &[u8] {
+        &[]
+    }
+
+    /// Discards the given range of sectors, which the driver is no longer using.
+    fn discard(&mut self, _sector: u64, _num_sectors: u32) -> Result {
+        Err(Error::Unsupported)
+    }
+
+    /// Writes zeroes to the given range of sectors. If `unmap` is true then the backend may
+    /// deallocate them instead, as long as they read back as zeroes.
+    ///
+    /// By default this writes a sector of zeroes at a time.
+    fn write_zeroes(&mut self, sector: u64, num_sectors: u32, _unmap: bool) -> Result {
+        let zeroes = [0; SECTOR_SIZE];
+        for i in 0..u64::from(num_sectors) {
+            self.write(sector + i, &zeroes)?;
+        }
+        Ok(())
+    }
+}
+
+/// The largest segment which a [`VirtIOBlkDevice`] accepts, which its transport should advertise
+/// as `size_max`.
+pub const DEVICE_SIZE_MAX: u32 = 0x1_0000;
+
+/// The largest number of data segments which a [`VirtIOBlkDevice`] accepts in a request, which its
+/// transport should advertise as `seg_max`.
+pub const DEVICE_SEG_MAX: u32 = MAX_SEGMENTS as u32;
+
+/// The largest amount of data in bytes which a [`VirtIOBlkDevice`] handles in a single request.
+const MAX_DEVICE_REQUEST_SIZE: usize = DEVICE_SIZE_MAX as usize * DEVICE_SEG_MAX as usize;
+
+/// Device-side implementation of a VirtIO block device, which serves requests from the driver
+/// using a [`BlkBackend`].
+///
+/// Requests with more than [`DEVICE_SIZE_MAX`] × [`DEVICE_SEG_MAX`] bytes of data fail with
+/// [`RespStatus::IO_ERR`].
+#[cfg(feature = "alloc")]
+pub struct VirtIOBlkDevice<H: DeviceHal, T: DeviceTransport, B: BlkBackend> {
+    transport: T,
+    queue: DeviceVirtQueue<H, { QUEUE_SIZE as usize }>,
+    backend: B,
+}
+
+#[cfg(feature = "alloc")]
+impl<H: DeviceHal, T: DeviceTransport, B: BlkBackend> VirtIOBlkDevice<H, T, B> {
+    /// Creates a new VirtIO block device serving the given backend.
+    pub fn new(mut transport: T, backend: B) -> Result<Self> {
+        let queue = DeviceVirtQueue::new(&mut transport, 0)?;
+        Ok(Self {
+            transport,
+            queue,
+            backend,
+        })
+    }
+
+    /// Returns a reference to the backend.
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Returns a mutable reference to the backend.
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    /// Handles the next request from the driver, if there is one, writing its status and
+    /// returning it to the driver.
+    ///
+    /// Returns `Ok(true)` if a request was handled or `Ok(false)` if there were none pending.
+    /// Requests which fail are reported to the driver rather than returned as errors, but
+    /// [`Error::InvalidDescriptor`] is returned if the request has nowhere to write its status.
+    pub fn poll(&mut self) -> Result<bool> {
+        let backend = &mut self.backend;
+        let handled = self
+            .queue
+            .poll_chain(&self.transport, |read_buffers, write_buffers| {
+                handle_request(backend, read_buffers, write_buffers)
+            })?;
+        match handled {
+            Some(result) => result.map(|()| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Returns the transport and backend of the device.
+    pub fn into_parts(self) -> (T, B) {
+        (self.transport, self.backend)
+    }
+}
+
+/// Handles a single request with the given device-readable buffers, writing the response to the
+/// given device-writable buffers.
+///
+/// Returns the number of bytes written, and an error if the request was so malformed that the
+/// status couldn't be written.
+#[cfg(feature = "alloc")]
+fn handle_request(
+    backend: &mut impl BlkBackend,
+    read_buffers: &[&[u8]],
+    write_buffers: &mut [&mut [u8]],
+) -> (usize, Result) {
+    let readable_len: usize = read_buffers.iter().map(|buffer| buffer.len()).sum();
+    let writable_len: usize = write_buffers.iter().map(|buffer| buffer.len()).sum();
+    let Some(data_in_len) = writable_len.checked_sub(size_of::<BlkResp>()) else {
+        return (0, Err(Error::InvalidDescriptor));
+    };
+    if readable_len > size_of::<BlkReq>() + MAX_DEVICE_REQUEST_SIZE
+        || data_in_len > MAX_DEVICE_REQUEST_SIZE
+    {
+        // Don't copy requests larger than the device advertises, just report the error in the
+        // status byte at the end of the device-writable buffers.
+        let status = write_buffers
+            .iter_mut()
+            .rev()
+            .find_map(|buffer| buffer.last_mut())
+            .unwrap();
+        *status = RespStatus::IO_ERR.0;
+        return (writable_len, Ok(()));
+    }
+
+    let request = read_buffers.concat();
+    let mut response = vec![0; writable_len];
+    let status = match request.split_at_checked(size_of::<BlkReq>()) {
+        Some((header, data_out)) => {
+            let type_ = u32::from_le_bytes(header[0..4].try_into().unwrap());
+            let sector = u64::from_le_bytes(header[8..16].try_into().unwrap());
+            match ReqType::try_from(type_) {
+                Ok(type_) => handle_request_type(
+                    backend,
+                    type_,
+                    sector,
+                    data_out,
+                    &mut response[..data_in_len],
+                ),
+                Err(_) => RespStatus::UNSUPPORTED,
+            }
+        }
+        None => RespStatus::IO_ERR,
+    };
+    response[data_in_len] = status.0;
+
+    let mut remaining = response.as_slice();
+    for buffer in write_buffers {
+        let (chunk, rest) = remaining.split_at(buffer.len());
+        buffer.copy_from_slice(chunk);
+        remaining = rest;
+    }
+    (writable_len, Ok(()))
+}
+
+/// Handles a request of the given type, and returns the status to report to the driver.
+#[cfg(feature = "alloc")]
+fn handle_request_type(
+    backend: &mut impl BlkBackend,
+    type_: ReqType,
+    sector: u64,
+    data_out: &[u8],
+    data_in: &mut [u8],
+) -> RespStatus {
+    let capacity = backend.capacity();
+    let in_bounds = |sector: u64, num_sectors: u64| {
+        sector
+            .checked_add(num_sectors)
+            .is_some_and(|end| end <= capacity)
+    };
+    let modifies = matches!(
+        type_,
+        ReqType::Out | ReqType::Discard | ReqType::WriteZeroes
+    );
+    if modifies && backend.readonly() {
+        return RespStatus::IO_ERR;
+    }
+    match type_ {
+        ReqType::In => {
+            if !data_in.len().is_multiple_of(SECTOR_SIZE)
+                || !in_bounds(sector, (data_in.len() / SECTOR_SIZE) as u64)
+            {
+                return RespStatus::IO_ERR;
+            }
+            backend.read(sector, data_in).into()
+        }
+        ReqType::Out => {
+            if !data_out.len().is_multiple_of(SECTOR_SIZE)
+                || !in_bounds(sector, (data_out.len() / SECTOR_SIZE) as u64)
+            {
+                return RespStatus::IO_ERR;
+            }
+            backend.write(sector, data_out).into()
+        }
+        ReqType::Flush => backend.flush().into(),
+        ReqType::GetId => {
+            let id = backend.device_id();
+            let len = id.len().min(data_in.len()).min(20);
+            data_in[..len].copy_from_slice(&id[..len]);
+            RespStatus::OK
+        }
+        ReqType::Discard | ReqType::WriteZeroes => {
+            if !data_out.len().is_multiple_of(size_of::<BlkRange>()) {
+                return RespStatus::IO_ERR;
+            }
+            // The ranges may not be aligned within the request, so copy each one out.
+            for range in data_out.chunks_exact(size_of::<BlkRange>()) {
+                let range = BlkRange::read_from_bytes(range).unwrap();
+                let allowed_flags = if type_ == ReqType::WriteZeroes {
+                    BlkRange::FLAG_UNMAP
+                } else {
+                    0
+                };
+                if range.flags & !allowed_flags != 0 {
+                    return RespStatus::UNSUPPORTED;
+                }
+                if !in_bounds(range.sector, range.num_sectors.into()) {
+                    return RespStatus::IO_ERR;
+                }
+                let result = if type_ == ReqType::Discard {
+                    backend.discard(range.sector, range.num_sectors)
+                } else {
+                    backend.write_zeroes(
+                        range.sector,
+                        range.num_sectors,
+                        range.flags & BlkRange::FLAG_UNMAP != 0,
+                    )
+                };
+                if let Err(e) = result {
+                    return Err(e).into();
+                }
+            }
+            RespStatus::OK
+        }
+        _ => RespStatus::UNSUPPORTED,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceStatus, DeviceType,
+        },
+    };
+    use alloc::{sync::Arc, vec, vec::Vec};
+    use core::mem::size_of;
+    use std::{sync::Mutex, thread};
+
+    const QUEUE: u16 = 0;
+
+    /// Returns a config space with the given capacity in sectors and every other field zeroed, for
+    /// tests to override as needed.
+    pub(super) fn blk_config(capacity: u64) -> BlkConfig {
+        let mut config_space = BlkConfig::new_zeroed();
+        config_space.capacity_low = ReadOnly::new(capacity as u32);
+        config_space.capacity_high = ReadOnly::new((capacity >> 32) as u32);
+        config_space
+    }
+
+    #[test]
+    fn config() {
+        let config_space = blk_config(0x02_0000_0042);
+        let state = Arc::new(Mutex::new(State::new(
+            vec![QueueStatus::default()],
+            config_space,
+        )));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: BlkFeature::RO.bits(),
+            state: state.clone(),
+        };
+        let blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        assert_eq!(blk.capacity(), 0x02_0000_0042);
+        assert_eq!(blk.readonly(), true);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn submit_and_poll_completions() {
+        let config_space = blk_config(66);
+        let state = Arc::new(Mutex::new(State::new(
+            vec![QueueStatus::default()],
+            config_space,
+        )));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: BlkFeature::RING_INDIRECT_DESC.bits(),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        // Invalid buffers should be returned along with the error.
+        let error = blk.submit_read(0, vec![0; 100].into()).unwrap_err();
+        assert_eq!(error.error, Error::InvalidParam);
+        assert_eq!(error.buf.len(), 100);
+        assert_eq!(blk.submit_flush(), Err(Error::Unsupported));
+
+        let read_token = blk.submit_read(1, vec![0; SECTOR_SIZE].into()).unwrap();
+        let write_token = blk.submit_write(2, vec![42; SECTOR_SIZE].into()).unwrap();
+        assert_eq!(
+            blk.poll_completions(|_| panic!("Nothing has completed yet")),
+            0
+        );
+        // Blocking requests can't be mixed with submitted requests which are still in flight.
+        assert_eq!(
+            blk.read_blocks(0, &mut [0; SECTOR_SIZE]),
+            Err(Error::AlreadyUsed)
+        );
+
+        // Simulate the device handling both requests.
+        {
+            let mut state = state.lock().unwrap();
+            assert!(
+                state.read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                    assert_eq!(
+                        request,
+                        BlkReq {
+                            type_: ReqType::In,
+                            reserved: 0,
+                            sector: 1
+                        }
+                        .as_bytes()
+                    );
+                    let mut response = vec![0; SECTOR_SIZE];
+                    response[0..9].copy_from_slice(b"Test data");
+                    response.extend_from_slice(
+                        BlkResp {
+                            status: RespStatus::OK,
+                        }
+                        .as_bytes(),
+                    );
+                    response
+                })
+            );
+            assert!(
+                state.read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                    let header_size = size_of::<BlkReq>();
+                    assert_eq!(request.len(), header_size + SECTOR_SIZE);
+                    assert!(request[header_size..].iter().all(|&byte| byte == 42));
+                    BlkResp {
+                        status: RespStatus::IO_ERR,
+                    }
+                    .as_bytes()
+                    .to_owned()
+                })
+            );
+        }
+
+        let mut completions = vec![];
+        assert_eq!(
+            blk.poll_completions(|completion| completions.push(completion)),
+            2
+        );
+        assert_eq!(completions.len(), 2);
+        assert_eq!(completions[0].token, read_token);
+        assert_eq!(completions[0].result, Ok(()));
+        assert_eq!(&completions[0].buf[0..9], b"Test data");
+        assert_eq!(completions[1].token, write_token);
+        assert_eq!(completions[1].result, Err(Error::IoError));
+        assert_eq!(completions[1].buf.len(), SECTOR_SIZE);
+    }
+
+    #[test]
+    fn zoned() {
+        let mut config_space = blk_config(66);
+        config_space.zone_sectors = ReadOnly::new(64);
+        config_space.max_open_zones = ReadOnly::new(4);
+        config_space.max_active_zones = ReadOnly::new(8);
+        config_space.max_append_sectors = ReadOnly::new(16);
+        config_space.write_granularity = ReadOnly::new(512);
+        config_space.model = ReadOnly::new(1);
+        let state = Arc::new(Mutex::new(State::new(
+            vec![QueueStatus::default()],
+            config_space,
+        )));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: (BlkFeature::RING_INDIRECT_DESC | BlkFeature::ZONED).bits(),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        assert_eq!(
+            blk.zoned(),
+            Some(ZonedCharacteristics {
+                zone_sectors: 64,
+                max_open_zones: 4,
+                max_active_zones: 8,
+                max_append_sectors: 16,
+                write_granularity: 512,
+                model: ZonedModel::HOST_MANAGED,
+            })
+        );
+        assert_eq!(
+            blk.zone_append(0, &[0; 17 * SECTOR_SIZE]),
+            Err(Error::InvalidParam)
+        );
+
+        // Start a thread to simulate the device handling a zone report and then a zone append.
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE);
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                    assert_eq!(
+                        request,
+                        BlkReq {
+                            type_: ReqType::ZoneReport,
+                            reserved: 0,
+                            sector: 64,
+                        }
+                        .as_bytes()
+                    );
+                    let mut response = ZoneReportHeader {
+                        nr_zones: 1,
+                        reserved: [0; 56],
+                    }
+                    .as_bytes()
+                    .to_vec();
+                    response.extend_from_slice(
+                        BlkZone {
+                            capacity: 64,
+                            start: 64,
+                            write_pointer: 72,
+                            zone_type: ZoneType::SEQUENTIAL_WRITE_REQUIRED,
+                            state: ZoneState::IMPLICITLY_OPEN,
+                            reserved: [0; 38],
+                        }
+                        .as_bytes(),
+                    );
+                    // Leave the second zone descriptor untouched.
+                    response.extend_from_slice(&[0; size_of::<BlkZone>()]);
+                    response.extend_from_slice(
+                        BlkResp {
+                            status: RespStatus::OK,
+                        }
+                        .as_bytes(),
+                    );
+                    response
+                }));
 
-        // device independent
-        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
-        const ANY_LAYOUT            = 1 << 27; // legacy
-        const RING_INDIRECT_DESC    = 1 << 28;
-        const RING_EVENT_IDX        = 1 << 29;
-        const UNUSED                = 1 << 30; // legacy
-        const VERSION_1             = 1 << 32; // detect legacy
+            State::wait_until_queue_notified(&state, QUEUE);
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                    let header_size = size_of::<BlkReq>();
+                    assert_eq!(
+                        &request[..header_size],
+                        BlkReq {
+                            type_: ReqType::ZoneAppend,
+                            reserved: 0,
+                            sector: 64,
+                        }
+                        .as_bytes()
+                    );
+                    assert_eq!(request.len(), header_size + SECTOR_SIZE);
+                    let mut response = 72u64.as_bytes().to_vec();
+                    response.extend_from_slice(
+                        BlkResp {
+                            status: RespStatus::OK,
+                        }
+                        .as_bytes(),
+                    );
+                    response
+                }));
+        });
 
-        // the following since virtio v1.1
-        const ACCESS_PLATFORM       = 1 << 33;
-        const RING_PACKED           = 1 << 34;
-        const IN_ORDER              = 1 << 35;
-        const ORDER_PLATFORM        = 1 << 36;
-        const SR_IOV                = 1 << 37;
-        const NOTIFICATION_DATA     = 1 << 38;
+        let mut zones = [BlkZone::default(), BlkZone::default()];
+        assert_eq!(blk.report_zones(64, &mut zones), Ok(1));
+        assert_eq!(zones[0].start(), 64);
+        assert_eq!(zones[0].capacity(), 64);
+        assert_eq!(zones[0].write_pointer(), 72);
+        assert_eq!(zones[0].zone_type(), ZoneType::SEQUENTIAL_WRITE_REQUIRED);
+        assert_eq!(zones[0].state(), ZoneState::IMPLICITLY_OPEN);
+
+        assert_eq!(blk.zone_append(64, &[42; SECTOR_SIZE]), Ok(72));
+
+        handle.join().unwrap();
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::{
-        hal::fake::FakeHal,
-        transport::{
-            fake::{FakeTransport, QueueStatus, State},
-            DeviceType,
-        },
-    };
-    use alloc::{sync::Arc, vec};
-    use core::mem::size_of;
-    use std::{sync::Mutex, thread};
+    #[test]
+    fn zone_statuses() {
+        for (status, error) in [
+            (
+                RespStatus::ZONE_UNALIGNED_WP,
+                ZoneError::UnalignedWritePointer,
+            ),
+            (RespStatus::ZONE_OPEN_RESOURCE, ZoneError::OpenResource),
+            (RespStatus::ZONE_ACTIVE_RESOURCE, ZoneError::ActiveResource),
+        ] {
+            assert_eq!(Result::from(status), Err(Error::ZoneError(error)));
+            assert_eq!(RespStatus::from(Err(error.into())), status);
+        }
+    }
 
     #[test]
-    fn config() {
-        let config_space = BlkConfig {
-            capacity_low: ReadOnly::new(0x42),
-            capacity_high: ReadOnly::new(0x02),
-            size_max: ReadOnly::new(0),
-            seg_max: ReadOnly::new(0),
-            cylinders: ReadOnly::new(0),
-            heads: ReadOnly::new(0),
-            sectors: ReadOnly::new(0),
-            blk_size: ReadOnly::new(0),
-            physical_block_exp: ReadOnly::new(0),
-            alignment_offset: ReadOnly::new(0),
-            min_io_size: ReadOnly::new(0),
-            opt_io_size: ReadOnly::new(0),
-        };
+    fn writeback_and_lifetime() {
+        let mut config_space = blk_config(66);
+        config_space.writeback = ReadWrite::new(1);
         let state = Arc::new(Mutex::new(State::new(
             vec![QueueStatus::default()],
             config_space,
@@ -587,31 +2581,63 @@ mod tests {
         let transport = FakeTransport {
             device_type: DeviceType::Block,
             max_queue_size: QUEUE_SIZE.into(),
-            device_features: BlkFeature::RO.bits(),
+            device_features: (BlkFeature::RING_INDIRECT_DESC
+                | BlkFeature::CONFIG_WCE
+                | BlkFeature::LIFETIME)
+                .bits(),
             state: state.clone(),
         };
-        let blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
 
-        assert_eq!(blk.capacity(), 0x02_0000_0042);
-        assert_eq!(blk.readonly(), true);
+        assert_eq!(blk.writeback(), Ok(true));
+        blk.set_writeback(false).unwrap();
+        assert_eq!(state.lock().unwrap().config_space.writeback.0, 0);
+        assert_eq!(blk.writeback(), Ok(false));
+
+        // Start a thread to simulate the device responding to a lifetime request.
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE);
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                    assert_eq!(
+                        request,
+                        BlkReq {
+                            type_: ReqType::GetLifetime,
+                            reserved: 0,
+                            sector: 0
+                        }
+                        .as_bytes()
+                    );
+                    let mut response = BlkLifetime {
+                        pre_eol_info: PreEolInfo::WARNING,
+                        device_lifetime_est_typ_a: 3,
+                        device_lifetime_est_typ_b: 9,
+                    }
+                    .as_bytes()
+                    .to_vec();
+                    response.extend_from_slice(
+                        BlkResp {
+                            status: RespStatus::OK,
+                        }
+                        .as_bytes(),
+                    );
+                    response
+                }));
+        });
+
+        let lifetime = blk.lifetime().unwrap();
+        assert_eq!(lifetime.pre_eol_info(), PreEolInfo::WARNING);
+        assert_eq!(lifetime.lifetime_estimate_a(), 3);
+        assert_eq!(lifetime.lifetime_estimate_b(), 9);
+
+        handle.join().unwrap();
     }
 
     #[test]
     fn read() {
-        let config_space = BlkConfig {
-            capacity_low: ReadOnly::new(66),
-            capacity_high: ReadOnly::new(0),
-            size_max: ReadOnly::new(0),
-            seg_max: ReadOnly::new(0),
-            cylinders: ReadOnly::new(0),
-            heads: ReadOnly::new(0),
-            sectors: ReadOnly::new(0),
-            blk_size: ReadOnly::new(0),
-            physical_block_exp: ReadOnly::new(0),
-            alignment_offset: ReadOnly::new(0),
-            min_io_size: ReadOnly::new(0),
-            opt_io_size: ReadOnly::new(0),
-        };
+        let config_space = blk_config(66);
         let state = Arc::new(Mutex::new(State::new(
             vec![QueueStatus::default()],
             config_space,
@@ -665,22 +2691,463 @@ mod tests {
         handle.join().unwrap();
     }
 
+    #[cfg(feature = "alloc")]
     #[test]
-    fn write() {
-        let config_space = BlkConfig {
-            capacity_low: ReadOnly::new(66),
-            capacity_high: ReadOnly::new(0),
-            size_max: ReadOnly::new(0),
-            seg_max: ReadOnly::new(0),
-            cylinders: ReadOnly::new(0),
-            heads: ReadOnly::new(0),
-            sectors: ReadOnly::new(0),
-            blk_size: ReadOnly::new(0),
-            physical_block_exp: ReadOnly::new(0),
-            alignment_offset: ReadOnly::new(0),
-            min_io_size: ReadOnly::new(0),
-            opt_io_size: ReadOnly::new(0),
+    fn read_fixed_queue_size() {
+        const DEVICE_QUEUE_SIZE: usize = 256;
+        const READS: u64 = 20;
+
+        // Like a legacy PCI device, the device only supports its own, larger, queue size.
+        let config_space = blk_config(66);
+        let mut state = State::new(vec![QueueStatus::default()], config_space);
+        state.requires_exact_queue_size = true;
+        let state = Arc::new(Mutex::new(state));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: DEVICE_QUEUE_SIZE as u32,
+            device_features: 0,
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        assert_eq!(
+            state.lock().unwrap().queues[0].size,
+            DEVICE_QUEUE_SIZE as u32
+        );
+
+        // Make more requests than the driver's queue size, two at a time so that they don't all
+        // use the same descriptors, and check that the ring indices wrap around the device's size.
+        for pair in 0..READS / 2 {
+            let tokens = [
+                blk.submit_read(pair as usize * 2, vec![0; SECTOR_SIZE].into())
+                    .unwrap(),
+                blk.submit_read(pair as usize * 2 + 1, vec![0; SECTOR_SIZE].into())
+                    .unwrap(),
+            ];
+            let mut state = state.lock().unwrap();
+            for _ in 0..2 {
+                assert!(
+                    state.read_write_queue::<DEVICE_QUEUE_SIZE>(QUEUE, |request| {
+                        let sector = u64::from_le_bytes(request[8..16].try_into().unwrap());
+                        let mut response = vec![sector as u8; SECTOR_SIZE];
+                        response.extend_from_slice(
+                            BlkResp {
+                                status: RespStatus::OK,
+                            }
+                            .as_bytes(),
+                        );
+                        response
+                    })
+                );
+            }
+            drop(state);
+
+            let mut completions = vec![];
+            assert_eq!(
+                blk.poll_completions(|completion| completions.push(completion)),
+                2
+            );
+            for (i, completion) in completions.into_iter().enumerate() {
+                assert_eq!(completion.token, tokens[i]);
+                assert_eq!(completion.result, Ok(()));
+                assert_eq!(*completion.buf, [(pair * 2) as u8 + i as u8; SECTOR_SIZE]);
+            }
+        }
+    }
+
+    #[test]
+    fn read_multiqueue() {
+        let mut config_space = blk_config(66);
+        config_space.num_queues = ReadOnly::new(2);
+        let state = Arc::new(Mutex::new(State::new(
+            vec![QueueStatus::default(), QueueStatus::default()],
+            config_space,
+        )));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: (BlkFeature::RING_INDIRECT_DESC | BlkFeature::MQ).bits(),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>, 2>::new(transport).unwrap();
+
+        thread::scope(|scope| {
+            // Simulate the device serving a read request on each queue.
+            scope.spawn(|| {
+                for queue in [0, 1] {
+                    State::wait_until_queue_notified(&state, queue);
+                    assert!(state
+                        .lock()
+                        .unwrap()
+                        .read_write_queue::<{ QUEUE_SIZE as usize }>(queue, |request| {
+                            assert_eq!(
+                                request,
+                                BlkReq {
+                                    type_: ReqType::In,
+                                    reserved: 0,
+                                    sector: 10 + u64::from(queue),
+                                }
+                                .as_bytes()
+                            );
+
+                            let mut response = vec![0; SECTOR_SIZE];
+                            response[0] = queue as u8;
+                            response.extend_from_slice(
+                                BlkResp {
+                                    status: RespStatus::OK,
+                                }
+                                .as_bytes(),
+                            );
+                            response
+                        }));
+                }
+            });
+
+            // Read a block concurrently through each queue.
+            let [mut queue0, mut queue1] = blk.queues();
+            scope.spawn(move || {
+                let mut buffer = [0; 512];
+                queue0.read_blocks(10, &mut buffer).unwrap();
+                assert_eq!(buffer[0], 0);
+            });
+            scope.spawn(move || {
+                let mut buffer = [0; 512];
+                queue1.read_blocks(11, &mut buffer).unwrap();
+                assert_eq!(buffer[0], 1);
+            });
+        });
+    }
+
+    #[test]
+    fn write_split_by_segment_limits() {
+        let mut config_space = blk_config(66);
+        config_space.size_max = ReadOnly::new(4096);
+        config_space.seg_max = ReadOnly::new(2);
+        config_space.blk_size = ReadOnly::new(4096);
+        config_space.physical_block_exp = ReadOnly::new(1);
+        config_space.opt_io_size = ReadOnly::new(16);
+        let state = Arc::new(Mutex::new(State::new(
+            vec![QueueStatus::default()],
+            config_space,
+        )));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: (BlkFeature::RING_INDIRECT_DESC
+                | BlkFeature::SIZE_MAX
+                | BlkFeature::SEG_MAX
+                | BlkFeature::BLK_SIZE
+                | BlkFeature::TOPOLOGY)
+                .bits(),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        assert_eq!(blk.block_size(), 4096);
+        assert_eq!(blk.physical_block_size(), 8192);
+        assert_eq!(
+            blk.topology(),
+            Some(BlkTopology {
+                physical_block_exp: 1,
+                alignment_offset: 0,
+                min_io_size: 0,
+                opt_io_size: 16,
+            })
+        );
+
+        // Writes which aren't aligned to the logical block size should be rejected.
+        assert_eq!(blk.write_blocks(4, &[0; 4096]), Err(Error::InvalidParam));
+        assert_eq!(blk.write_blocks(8, &[0; 512]), Err(Error::InvalidParam));
+
+        // Start a thread to simulate the device handling the two requests which the write should be
+        // split into, each of two segments.
+        let handle = thread::spawn(move || {
+            for (sector, fill) in [(8, 1), (24, 3)] {
+                State::wait_until_queue_notified(&state, QUEUE);
+                assert!(state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                        let header_size = size_of::<BlkReq>();
+                        assert_eq!(
+                            &request[..header_size],
+                            BlkReq {
+                                type_: ReqType::Out,
+                                reserved: 0,
+                                sector,
+                            }
+                            .as_bytes()
+                        );
+                        assert_eq!(request.len(), header_size + 8192);
+                        assert!(request[header_size..header_size + 4096]
+                            .iter()
+                            .all(|&byte| byte == fill));
+                        assert!(request[header_size + 4096..]
+                            .iter()
+                            .all(|&byte| byte == fill + 1));
+
+                        BlkResp {
+                            status: RespStatus::OK,
+                        }
+                        .as_bytes()
+                        .to_owned()
+                    }));
+            }
+        });
+
+        let mut buffer = [0; 16384];
+        for (i, chunk) in buffer.chunks_mut(4096).enumerate() {
+            chunk.fill(i as u8 + 1);
+        }
+        blk.write_blocks(8, &buffer).unwrap();
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn discard() {
+        let mut config_space = blk_config(66);
+        config_space.max_discard_sectors = ReadOnly::new(32);
+        config_space.max_discard_seg = ReadOnly::new(2);
+        config_space.discard_sector_alignment = ReadOnly::new(8);
+        let state = Arc::new(Mutex::new(State::new(
+            vec![QueueStatus::default()],
+            config_space,
+        )));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: (BlkFeature::RING_INDIRECT_DESC | BlkFeature::DISCARD).bits(),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        assert_eq!(
+            blk.discard_limits(),
+            Some(RangeLimits {
+                max_sectors: 32,
+                max_segments: 2,
+                sector_alignment: 8,
+            })
+        );
+        assert_eq!(blk.write_zeroes_limits(), None);
+
+        // Requests which the device doesn't support or which exceed its limits should be rejected
+        // without being sent.
+        assert_eq!(
+            blk.write_zeroes(&[BlkRange::new(0, 8)]),
+            Err(Error::Unsupported)
+        );
+        assert_eq!(blk.discard(&[]), Err(Error::InvalidParam));
+        assert_eq!(
+            blk.discard(&[BlkRange::new(0, 40)]),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(
+            blk.discard(&[BlkRange::new(4, 8)]),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(
+            blk.discard(&[BlkRange::new(0, 8).with_unmap()]),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(
+            blk.discard(&[
+                BlkRange::new(0, 8),
+                BlkRange::new(16, 8),
+                BlkRange::new(32, 8)
+            ]),
+            Err(Error::InvalidParam)
+        );
+
+        // Start a thread to simulate the device waiting for a discard request.
+        let ranges = [BlkRange::new(8, 16), BlkRange::new(48, 8)];
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE);
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                    let mut expected = BlkReq {
+                        type_: ReqType::Discard,
+                        reserved: 0,
+                        sector: 0,
+                    }
+                    .as_bytes()
+                    .to_vec();
+                    expected.extend_from_slice(ranges.as_bytes());
+                    assert_eq!(request, expected);
+
+                    BlkResp {
+                        status: RespStatus::OK,
+                    }
+                    .as_bytes()
+                    .to_owned()
+                }));
+        });
+
+        blk.discard(&ranges).unwrap();
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn multiqueue_unsupported() {
+        let mut config_space = blk_config(66);
+        config_space.num_queues = ReadOnly::new(1);
+        let state = Arc::new(Mutex::new(State::new(
+            vec![QueueStatus::default(), QueueStatus::default()],
+            config_space,
+        )));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: BlkFeature::MQ.bits(),
+            state: state.clone(),
+        };
+        assert_eq!(
+            VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>, 2>::new(transport).err(),
+            Some(Error::Unsupported)
+        );
+    }
+
+    #[test]
+    fn suspend_resume() {
+        let config_space = blk_config(66);
+        let state = Arc::new(Mutex::new(State::new(
+            vec![QueueStatus::default()],
+            config_space,
+        )));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: BlkFeature::SUSPEND.bits(),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        let live_status = DeviceStatus::ACKNOWLEDGE
+            | DeviceStatus::DRIVER
+            | DeviceStatus::FEATURES_OK
+            | DeviceStatus::DRIVER_OK;
+
+        blk.suspend().unwrap();
+        assert_eq!(
+            state.lock().unwrap().status,
+            live_status | DeviceStatus::SUSPEND
+        );
+
+        blk.resume().unwrap();
+        assert_eq!(state.lock().unwrap().status, live_status);
+    }
+
+    #[test]
+    fn suspend_unsupported() {
+        let config_space = blk_config(66);
+        let state = Arc::new(Mutex::new(State::new(
+            vec![QueueStatus::default()],
+            config_space,
+        )));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: 0,
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        assert_eq!(blk.suspend(), Err(Error::Unsupported));
+        assert!(!state.lock().unwrap().status.contains(DeviceStatus::SUSPEND));
+    }
+
+    #[test]
+    fn into_transport() {
+        let config_space = blk_config(66);
+        let state = Arc::new(Mutex::new(State::new(
+            vec![QueueStatus::default()],
+            config_space,
+        )));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: 0,
+            state: state.clone(),
+        };
+        let blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        assert_ne!(
+            state.lock().unwrap().queues[usize::from(QUEUE)].descriptors,
+            0
+        );
+
+        // Taking the transport back should reset the device and unset the queue.
+        let transport = blk.into_transport();
+        {
+            let state = state.lock().unwrap();
+            assert_eq!(state.status, DeviceStatus::empty());
+            assert_eq!(state.queues[usize::from(QUEUE)].descriptors, 0);
+        }
+
+        // The transport can then be used by a new driver.
+        let blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        assert_eq!(blk.capacity(), 66);
+        drop(blk);
+        assert_eq!(state.lock().unwrap().status, DeviceStatus::empty());
+    }
+
+    #[test]
+    fn reset_after_device_needs_reset() {
+        let config_space = blk_config(66);
+        let state = Arc::new(Mutex::new(State::new(
+            vec![QueueStatus::default()],
+            config_space,
+        )));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: 0,
+            state: state.clone(),
         };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        assert!(!blk.needs_reset());
+        let old_descriptors = state.lock().unwrap().queues[usize::from(QUEUE)].descriptors;
+
+        // Start a thread to simulate the device failing while handling a read request.
+        let handle = thread::spawn({
+            let state = state.clone();
+            move || {
+                State::wait_until_queue_notified(&state, QUEUE);
+                state.lock().unwrap().status |= DeviceStatus::DEVICE_NEEDS_RESET;
+            }
+        });
+
+        let mut buffer = [0; 512];
+        assert_eq!(
+            blk.read_blocks(42, &mut buffer),
+            Err(Error::DeviceNeedsReset)
+        );
+        handle.join().unwrap();
+        // The device must have been reset before the request returned, so that it can't access the
+        // buffer any more.
+        assert_eq!(state.lock().unwrap().status, DeviceStatus::empty());
+        assert!(blk.needs_reset());
+
+        blk.reset().unwrap();
+        assert!(!blk.needs_reset());
+        assert_eq!(blk.capacity(), 66);
+        let state = state.lock().unwrap();
+        assert_eq!(
+            state.status,
+            DeviceStatus::ACKNOWLEDGE
+                | DeviceStatus::DRIVER
+                | DeviceStatus::FEATURES_OK
+                | DeviceStatus::DRIVER_OK
+        );
+        assert_ne!(state.queues[usize::from(QUEUE)].descriptors, 0);
+        assert_ne!(
+            state.queues[usize::from(QUEUE)].descriptors,
+            old_descriptors
+        );
+    }
+
+    #[test]
+    fn write() {
+        let config_space = blk_config(66);
         let state = Arc::new(Mutex::new(State::new(
             vec![QueueStatus::default()],
             config_space,
@@ -741,20 +3208,7 @@ mod tests {
 
     #[test]
     fn flush() {
-        let config_space = BlkConfig {
-            capacity_low: ReadOnly::new(66),
-            capacity_high: ReadOnly::new(0),
-            size_max: ReadOnly::new(0),
-            seg_max: ReadOnly::new(0),
-            cylinders: ReadOnly::new(0),
-            heads: ReadOnly::new(0),
-            sectors: ReadOnly::new(0),
-            blk_size: ReadOnly::new(0),
-            physical_block_exp: ReadOnly::new(0),
-            alignment_offset: ReadOnly::new(0),
-            min_io_size: ReadOnly::new(0),
-            opt_io_size: ReadOnly::new(0),
-        };
+        let config_space = blk_config(66);
         let state = Arc::new(Mutex::new(State::new(
             vec![QueueStatus::default()],
             config_space,
@@ -807,20 +3261,7 @@ mod tests {
 
     #[test]
     fn device_id() {
-        let config_space = BlkConfig {
-            capacity_low: ReadOnly::new(66),
-            capacity_high: ReadOnly::new(0),
-            size_max: ReadOnly::new(0),
-            seg_max: ReadOnly::new(0),
-            cylinders: ReadOnly::new(0),
-            heads: ReadOnly::new(0),
-            sectors: ReadOnly::new(0),
-            blk_size: ReadOnly::new(0),
-            physical_block_exp: ReadOnly::new(0),
-            alignment_offset: ReadOnly::new(0),
-            min_io_size: ReadOnly::new(0),
-            opt_io_size: ReadOnly::new(0),
-        };
+        let config_space = blk_config(66);
         let state = Arc::new(Mutex::new(State::new(
             vec![QueueStatus::default()],
             config_space,
@@ -872,4 +3313,148 @@ mod tests {
 
         handle.join().unwrap();
     }
+
+    /// Tests which use the device-side implementation, which needs `alloc`.
+    #[cfg(feature = "alloc")]
+    pub(super) mod device {
+        use super::*;
+        use core::sync::atomic::{AtomicBool, Ordering};
+        use std::thread::JoinHandle;
+
+        /// A [`VirtIOBlkDevice`] serving requests on another thread until it is stopped.
+        pub(in crate::device::blk) struct DeviceThread<B: BlkBackend> {
+            stop: Arc<AtomicBool>,
+            handle: JoinHandle<VirtIOBlkDevice<FakeHal, FakeTransport<BlkConfig>, B>>,
+        }
+
+        impl<B: BlkBackend> DeviceThread<B> {
+            /// Stops the device thread and returns the device.
+            pub(in crate::device::blk) fn stop(
+                self,
+            ) -> VirtIOBlkDevice<FakeHal, FakeTransport<BlkConfig>, B> {
+                self.stop.store(true, Ordering::SeqCst);
+                self.handle.join().unwrap()
+            }
+        }
+
+        /// Starts a device serving the given backend on another thread, and returns a driver connected
+        /// to it.
+        pub(in crate::device::blk) fn spawn_device<B: BlkBackend + Send + 'static>(
+            backend: B,
+            device_features: BlkFeature,
+        ) -> (
+            VirtIOBlk<FakeHal, FakeTransport<BlkConfig>>,
+            DeviceThread<B>,
+        ) {
+            let mut config_space = blk_config(backend.capacity());
+            config_space.size_max = ReadOnly::new(DEVICE_SIZE_MAX);
+            config_space.seg_max = ReadOnly::new(DEVICE_SEG_MAX);
+            let state = Arc::new(Mutex::new(State::new(
+                vec![QueueStatus::default()],
+                config_space,
+            )));
+            let transport = FakeTransport {
+                device_type: DeviceType::Block,
+                max_queue_size: QUEUE_SIZE.into(),
+                device_features: (device_features | BlkFeature::SIZE_MAX | BlkFeature::SEG_MAX)
+                    .bits(),
+                state: state.clone(),
+            };
+            let device_transport = FakeTransport {
+                state: state.clone(),
+                ..transport
+            };
+            let blk = VirtIOBlk::new(transport).unwrap();
+            let mut device = VirtIOBlkDevice::new(device_transport, backend).unwrap();
+
+            let stop = Arc::new(AtomicBool::new(false));
+            let handle = thread::spawn({
+                let stop = stop.clone();
+                move || {
+                    while !stop.load(Ordering::SeqCst) {
+                        if !device.poll().unwrap() {
+                            thread::yield_now();
+                        }
+                    }
+                    device
+                }
+            });
+            (blk, DeviceThread { stop, handle })
+        }
+
+        #[test]
+        fn device_rejects_oversized_request() {
+            let mut backend = RamDisk::new(4096);
+            let request = BlkReq {
+                type_: ReqType::In,
+                reserved: 0,
+                sector: 0,
+            };
+            let mut data = vec![0xff; MAX_DEVICE_REQUEST_SIZE + SECTOR_SIZE];
+            let mut resp = BlkResp::default();
+            let (_, result) = handle_request(
+                &mut backend,
+                &[request.as_bytes()],
+                &mut [&mut data, resp.as_mut_bytes()],
+            );
+            assert_eq!(result, Ok(()));
+            assert_eq!(resp.status, RespStatus::IO_ERR);
+            // The data shouldn't have been touched.
+            assert!(data.iter().all(|&byte| byte == 0xff));
+        }
+
+        #[test]
+        fn device_serves_driver() {
+            let config_space = blk_config(4);
+            let state = Arc::new(Mutex::new(State::new(
+                vec![QueueStatus::default()],
+                config_space,
+            )));
+            let transport = FakeTransport {
+                device_type: DeviceType::Block,
+                max_queue_size: QUEUE_SIZE.into(),
+                device_features: 0,
+                state: state.clone(),
+            };
+            let device_transport = FakeTransport {
+                state: state.clone(),
+                ..transport
+            };
+            let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+            let mut backend = RamDisk::new(4);
+            backend.set_device_id(b"ramdisk");
+            let mut device =
+                VirtIOBlkDevice::<FakeHal, _, _>::new(device_transport, backend).unwrap();
+
+            let handle = thread::spawn(move || {
+                let mut handled = 0;
+                while handled < 4 {
+                    if device.poll().unwrap() {
+                        handled += 1;
+                    } else {
+                        thread::yield_now();
+                    }
+                }
+                device
+            });
+
+            blk.write_blocks(1, &[42; SECTOR_SIZE * 2]).unwrap();
+            let mut buffer = [0; SECTOR_SIZE];
+            blk.read_blocks(2, &mut buffer).unwrap();
+            assert_eq!(buffer, [42; SECTOR_SIZE]);
+            let mut id = [0; 20];
+            assert_eq!(blk.device_id(&mut id), Ok(7));
+            assert_eq!(&id[..7], b"ramdisk");
+            // Reads beyond the end of the disk should fail.
+            assert_eq!(blk.read_blocks(4, &mut buffer), Err(Error::IoError));
+
+            let device = handle.join().unwrap();
+            assert!(device.backend().data()[..SECTOR_SIZE]
+                .iter()
+                .all(|&byte| byte == 0));
+            assert!(device.backend().data()[SECTOR_SIZE..SECTOR_SIZE * 3]
+                .iter()
+                .all(|&byte| byte == 42));
+        }
+    }
 }
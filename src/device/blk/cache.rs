@@ -0,0 +1,386 @@
+//! A write-back block cache for [`VirtIOBlk`].
+
+use super::{VirtIOBlk, SECTOR_SIZE};
+use crate::{hal::Hal, transport::Transport, Error, Result};
+use alloc::{boxed::Box, collections::BTreeMap, vec};
+
+/// A write-back cache in front of a [`VirtIOBlk`], to avoid a round trip to the device for every
+/// small read or write.
+///
+/// The disk is divided into fixed-size pages, and up to a fixed number of them are kept in memory.
+/// When a new page is needed the least recently used one is evicted, and written back to the
+/// device first if it has been modified. On a read miss the following pages may also be read
+/// ahead in the same request, see [`set_read_ahead`](Self::set_read_ahead).
+///
+/// Writes only reach the device when their page is evicted or [`flush`](Self::flush) is called,
+/// so callers must flush before dropping the cache or they will be lost.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers_and_devices::{Error, Hal};
+/// # use virtio_drivers_and_devices::transport::Transport;
+/// use virtio_drivers_and_devices::device::blk::{BlkCache, VirtIOBlk, SECTOR_SIZE};
+///
+/// # fn example<HalImpl: Hal, T: Transport>(blk: VirtIOBlk<HalImpl, T>) -> Result<(), Error> {
+/// // Cache up to 64 pages of 4 KiB, reading ahead one page on every miss.
+/// let mut cache = BlkCache::new(blk, 4096, 64)?;
+/// cache.set_read_ahead(1);
+///
+/// let mut buf = [0; SECTOR_SIZE];
+/// cache.read_blocks(3, &mut buf)?;
+/// buf[0] = 42;
+/// cache.write_blocks(3, &buf)?;
+/// cache.flush()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct BlkCache<H: Hal, T: Transport, const QUEUES: usize = 1> {
+    blk: VirtIOBlk<H, T, QUEUES>,
+    /// The size of each page, in bytes.
+    page_size: usize,
+    /// The maximum number of pages to keep in memory.
+    max_pages: usize,
+    /// The number of extra pages to read after a missed page.
+    read_ahead: usize,
+    /// The cached pages, indexed by page number.
+    pages: BTreeMap<u64, CachePage>,
+    /// Incremented on every access, to track which page was least recently used.
+    clock: u64,
+}
+
+/// A page of the disk held in a [`BlkCache`].
+struct CachePage {
+    /// The contents of the page. This is shorter than the page size for the last page of a disk
+    /// whose size isn't a whole number of pages.
+    data: Box<[u8]>,
+    /// Whether the page has been modified since it was read or written back.
+    dirty: bool,
+    /// The value of the cache's clock when the page was last accessed.
+    last_used: u64,
+}
+
+impl<H: Hal, T: Transport, const QUEUES: usize> BlkCache<H, T, QUEUES> {
+    /// Creates a new cache in front of the given block device, holding up to `max_pages` pages of
+    /// `page_size` bytes each.
+    ///
+    /// The page size must be a non-zero multiple of the device's
+    /// [`block_size`](VirtIOBlk::block_size), and `max_pages` must be non-zero, or this returns
+    /// [`Error::InvalidParam`].
+    pub fn new(blk: VirtIOBlk<H, T, QUEUES>, page_size: usize, max_pages: usize) -> Result<Self> {
+        let block_size = blk.block_size() as usize;
+        if page_size == 0
+            || !page_size.is_multiple_of(block_size)
+            || !page_size.is_multiple_of(SECTOR_SIZE)
+            || max_pages == 0
+        {
+            return Err(Error::InvalidParam);
+        }
+        Ok(Self {
+            blk,
+            page_size,
+            max_pages,
+            read_ahead: 0,
+            pages: BTreeMap::new(),
+            clock: 0,
+        })
+    }
+
+    /// Sets the number of pages following a missed page to read in the same request, if they
+    /// aren't already cached. This is limited to one less than the size of the cache.
+    pub fn set_read_ahead(&mut self, pages: usize) {
+        self.read_ahead = pages.min(self.max_pages - 1);
+    }
+
+    /// Returns the underlying block device.
+    pub fn blk(&self) -> &VirtIOBlk<H, T, QUEUES> {
+        &self.blk
+    }
+
+    /// Returns the underlying block device, discarding the cache.
+    ///
+    /// Any modified pages are lost, so [`flush`](Self::flush) should be called first.
+    pub fn into_inner(self) -> VirtIOBlk<H, T, QUEUES> {
+        self.blk
+    }
+
+    /// Gets the capacity of the block device, in 512 byte ([`SECTOR_SIZE`]) sectors.
+    pub fn capacity(&self) -> u64 {
+        self.blk.capacity()
+    }
+
+    /// Reads one or more sectors into the given buffer, from the cache where possible.
+    ///
+    /// The buffer length must be a non-zero multiple of [`SECTOR_SIZE`] and lie within the disk,
+    /// or this returns [`Error::InvalidParam`]. Unlike [`VirtIOBlk::read_blocks`] it needn't be
+    /// aligned to the device's block size.
+    pub fn read_blocks(&mut self, block_id: usize, mut buf: &mut [u8]) -> Result {
+        let mut sector = self.check_range(block_id, buf.len())?;
+        while !buf.is_empty() {
+            let (index, offset) = self.page_position(sector);
+            let page = self.page(index, true)?;
+            let len = (page.data.len() - offset).min(buf.len());
+            let (chunk, rest) = buf.split_at_mut(len);
+            chunk.copy_from_slice(&page.data[offset..offset + len]);
+            sector += (len / SECTOR_SIZE) as u64;
+            buf = rest;
+        }
+        Ok(())
+    }
+
+    /// Writes the given buffer to one or more sectors in the cache. They will be written to the
+    /// device when their pages are evicted or [`flush`](Self::flush) is called.
+    ///
+    /// The buffer must satisfy the same conditions as for [`read_blocks`](Self::read_blocks). If
+    /// the device is read-only then this returns [`Error::Unsupported`].
+    pub fn write_blocks(&mut self, block_id: usize, mut buf: &[u8]) -> Result {
+        if self.blk.readonly() {
+            return Err(Error::Unsupported);
+        }
+        let mut sector = self.check_range(block_id, buf.len())?;
+        while !buf.is_empty() {
+            let (index, offset) = self.page_position(sector);
+            let len = (self.page_len(index) - offset).min(buf.len());
+            // There's no need to read the page from the device if it's all being overwritten.
+            let whole_page = offset == 0 && len == self.page_len(index);
+            let page = self.page(index, !whole_page)?;
+            let (chunk, rest) = buf.split_at(len);
+            page.data[offset..offset + len].copy_from_slice(chunk);
+            page.dirty = true;
+            sector += (len / SECTOR_SIZE) as u64;
+            buf = rest;
+        }
+        Ok(())
+    }
+
+    /// Writes all modified pages back to the device, then requests the device to flush them to
+    /// storage.
+    pub fn flush(&mut self) -> Result {
+        let page_sectors = self.page_sectors();
+        for (&index, page) in self.pages.iter_mut().filter(|(_, page)| page.dirty) {
+            self.blk
+                .write_blocks((index * page_sectors) as usize, &page.data)?;
+            page.dirty = false;
+        }
+        self.blk.flush()
+    }
+
+    /// Checks that the given range of sectors is non-empty and lies within the disk, and returns
+    /// the first sector.
+    fn check_range(&self, block_id: usize, len: usize) -> Result<u64> {
+        let sector = block_id as u64;
+        if len == 0
+            || !len.is_multiple_of(SECTOR_SIZE)
+            || sector
+                .checked_add((len / SECTOR_SIZE) as u64)
+                .is_none_or(|end| end > self.capacity())
+        {
+            return Err(Error::InvalidParam);
+        }
+        Ok(sector)
+    }
+
+    fn page_sectors(&self) -> u64 {
+        (self.page_size / SECTOR_SIZE) as u64
+    }
+
+    /// Returns the index of the page containing the given sector, and the byte offset of the
+    /// sector within it.
+    fn page_position(&self, sector: u64) -> (u64, usize) {
+        let page_sectors = self.page_sectors();
+        (
+            sector / page_sectors,
+            (sector % page_sectors) as usize * SECTOR_SIZE,
+        )
+    }
+
+    /// Returns the length in bytes of the given page, which may be short at the end of the disk.
+    fn page_len(&self, index: u64) -> usize {
+        let start = index * self.page_sectors();
+        let sectors = self
+            .capacity()
+            .saturating_sub(start)
+            .min(self.page_sectors());
+        sectors as usize * SECTOR_SIZE
+    }
+
+    /// Returns the given page, marking it as most recently used.
+    ///
+    /// If the page isn't cached then this makes room for it and either reads it from the device
+    /// along with any pages to read ahead if `fill` is true, or fills it with zeroes if the caller
+    /// is about to overwrite all of it.
+    fn page(&mut self, index: u64, fill: bool) -> Result<&mut CachePage> {
+        self.clock += 1;
+        let clock = self.clock;
+        if !self.pages.contains_key(&index) {
+            if fill {
+                self.read_pages(index)?;
+            } else {
+                self.make_room(1)?;
+                self.pages.insert(
+                    index,
+                    CachePage {
+                        data: vec![0; self.page_len(index)].into_boxed_slice(),
+                        dirty: false,
+                        last_used: clock,
+                    },
+                );
+            }
+        }
+        let page = self.pages.get_mut(&index).unwrap();
+        page.last_used = clock;
+        Ok(page)
+    }
+
+    /// Reads the given page from the device, along with as many of the following pages as should
+    /// be read ahead, stopping at the first one which is already cached or at the end of the disk.
+    fn read_pages(&mut self, index: u64) -> Result {
+        let count = (1..=self.read_ahead as u64)
+            .take_while(|&i| self.page_len(index + i) > 0 && !self.pages.contains_key(&(index + i)))
+            .count()
+            + 1;
+        let len = (0..count as u64).map(|i| self.page_len(index + i)).sum();
+        let mut data = vec![0; len];
+        self.blk
+            .read_blocks((index * self.page_sectors()) as usize, &mut data)?;
+
+        self.make_room(count)?;
+        let mut offset = 0;
+        for i in 0..count as u64 {
+            let len = self.page_len(index + i);
+            let page = CachePage {
+                data: data[offset..offset + len].into(),
+                dirty: false,
+                last_used: self.clock,
+            };
+            self.pages.insert(index + i, page);
+            offset += len;
+        }
+        Ok(())
+    }
+
+    /// Evicts the least recently used pages until there is room for `count` more, writing them
+    /// back to the device first if they have been modified.
+    fn make_room(&mut self, count: usize) -> Result {
+        let page_sectors = self.page_sectors();
+        while self.pages.len() + count > self.max_pages {
+            let (&index, page) = self
+                .pages
+                .iter()
+                .min_by_key(|(_, page)| page.last_used)
+                .unwrap();
+            if page.dirty {
+                self.blk
+                    .write_blocks((index * page_sectors) as usize, &page.data)?;
+            }
+            self.pages.remove(&index);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::blk::{tests::device::spawn_device, BlkBackend, BlkFeature, RamDisk};
+    use alloc::{sync::Arc, vec::Vec};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A RAM disk which counts the requests it serves.
+    struct CountingDisk {
+        disk: RamDisk,
+        reads: Arc<AtomicUsize>,
+        writes: Arc<AtomicUsize>,
+        flushes: Arc<AtomicUsize>,
+    }
+
+    impl BlkBackend for CountingDisk {
+        fn capacity(&self) -> u64 {
+            self.disk.capacity()
+        }
+
+        fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            self.disk.read(sector, buf)
+        }
+
+        fn write(&mut self, sector: u64, buf: &[u8]) -> Result {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            self.disk.write(sector, buf)
+        }
+
+        fn flush(&mut self) -> Result {
+            self.flushes.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn cache_read_write_flush() {
+        // A 9 sector disk, so that the last 2 sector page is short.
+        let initial: Vec<u8> = (0..9).flat_map(|i| [i; SECTOR_SIZE]).collect();
+        let reads = Arc::new(AtomicUsize::new(0));
+        let writes = Arc::new(AtomicUsize::new(0));
+        let flushes = Arc::new(AtomicUsize::new(0));
+        let (blk, device) = spawn_device(
+            CountingDisk {
+                disk: RamDisk::from_vec(initial).unwrap(),
+                reads: reads.clone(),
+                writes: writes.clone(),
+                flushes: flushes.clone(),
+            },
+            BlkFeature::FLUSH,
+        );
+
+        // Pages of 2 sectors, at most 3 of them cached.
+        let mut cache = BlkCache::new(blk, SECTOR_SIZE * 2, 3).unwrap();
+        cache.set_read_ahead(1);
+
+        // A miss on page 0 should read ahead page 1 in the same request.
+        let mut buf = [0; SECTOR_SIZE * 2];
+        cache.read_blocks(1, &mut buf[..SECTOR_SIZE]).unwrap();
+        assert_eq!(buf[..SECTOR_SIZE], [1; SECTOR_SIZE]);
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+        cache.read_blocks(2, &mut buf).unwrap();
+        assert_eq!(buf[..SECTOR_SIZE], [2; SECTOR_SIZE]);
+        assert_eq!(buf[SECTOR_SIZE..], [3; SECTOR_SIZE]);
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+
+        // A partial write of page 2 must read it first, along with page 3, evicting page 0.
+        cache.write_blocks(5, &[0xbb; SECTOR_SIZE]).unwrap();
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+        // Overwriting all of the short last page doesn't need a read, and evicts page 1.
+        cache.write_blocks(8, &[0xaa; SECTOR_SIZE]).unwrap();
+        cache.read_blocks(8, &mut buf[..SECTOR_SIZE]).unwrap();
+        assert_eq!(buf[..SECTOR_SIZE], [0xaa; SECTOR_SIZE]);
+        cache.read_blocks(4, &mut buf).unwrap();
+        assert_eq!(buf[..SECTOR_SIZE], [4; SECTOR_SIZE]);
+        assert_eq!(buf[SECTOR_SIZE..], [0xbb; SECTOR_SIZE]);
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+        assert_eq!(writes.load(Ordering::SeqCst), 0);
+
+        // Reading page 0 again must miss and read ahead page 1, evicting page 3 which is clean and
+        // then page 4 which must be written back.
+        cache.read_blocks(0, &mut buf).unwrap();
+        assert_eq!(buf[SECTOR_SIZE..], [1; SECTOR_SIZE]);
+        assert_eq!(reads.load(Ordering::SeqCst), 3);
+        assert_eq!(writes.load(Ordering::SeqCst), 1);
+
+        // Flushing writes back the remaining dirty page and then flushes the device.
+        cache.flush().unwrap();
+        assert_eq!(writes.load(Ordering::SeqCst), 2);
+        assert_eq!(flushes.load(Ordering::SeqCst), 1);
+        cache.flush().unwrap();
+        assert_eq!(writes.load(Ordering::SeqCst), 2);
+        assert_eq!(flushes.load(Ordering::SeqCst), 2);
+
+        assert_eq!(cache.read_blocks(8, &mut buf), Err(Error::InvalidParam));
+        assert_eq!(cache.write_blocks(0, &[]), Err(Error::InvalidParam));
+
+        let device = device.stop();
+        let data = device.backend().disk.data();
+        assert_eq!(data[SECTOR_SIZE * 4..SECTOR_SIZE * 5], [4; SECTOR_SIZE]);
+        assert_eq!(data[SECTOR_SIZE * 5..SECTOR_SIZE * 6], [0xbb; SECTOR_SIZE]);
+        assert_eq!(data[SECTOR_SIZE * 8..], [0xaa; SECTOR_SIZE]);
+    }
+}
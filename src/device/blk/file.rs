@@ -0,0 +1,209 @@
+//! Raw image file storage for the device-side block implementation.
+
+use super::{BlkBackend, SECTOR_SIZE};
+use crate::{Error, Result};
+use log::warn;
+use std::{
+    fs::File,
+    io,
+    os::{fd::AsRawFd, unix::fs::FileExt},
+};
+
+/// The size of the buffer used to write zeroes when they can't be written by deallocating space.
+const ZEROES_CHUNK_SIZE: usize = 64 * SECTOR_SIZE;
+
+/// A [`BlkBackend`] which stores the disk contents in a raw image file.
+///
+/// Flushes are passed on to the host with `fsync`, and discards punch holes in the file so that the
+/// host filesystem can reclaim the space.
+#[derive(Debug)]
+pub struct FileBackend {
+    file: File,
+    capacity: u64,
+    readonly: bool,
+}
+
+impl FileBackend {
+    /// Creates a new backend for the given image file. Any partial sector at the end of the file
+    /// is ignored.
+    ///
+    /// If `readonly` is true then the device will reject writes; the file may then be opened
+    /// read-only.
+    pub fn new(file: File, readonly: bool) -> io::Result<Self> {
+        let capacity = file.metadata()?.len() / SECTOR_SIZE as u64;
+        Ok(Self {
+            file,
+            capacity,
+            readonly,
+        })
+    }
+
+    /// Returns the underlying file, consuming the backend.
+    pub fn into_file(self) -> File {
+        self.file
+    }
+
+    /// Returns the byte offset and length of the given range of sectors, checking that it is
+    /// within the image.
+    fn byte_range(&self, sector: u64, len: u64) -> Result<(u64, u64)> {
+        let sectors = len / SECTOR_SIZE as u64;
+        if sector
+            .checked_add(sectors)
+            .is_none_or(|end| end > self.capacity)
+        {
+            return Err(Error::InvalidParam);
+        }
+        Ok((sector * SECTOR_SIZE as u64, len))
+    }
+
+    /// Calls `fallocate` on the given range of sectors with the given mode.
+    fn fallocate(&self, mode: libc::c_int, sector: u64, num_sectors: u32) -> Result {
+        let (offset, len) = self.byte_range(sector, u64::from(num_sectors) * SECTOR_SIZE as u64)?;
+        let offset = libc::off_t::try_from(offset).map_err(|_| Error::InvalidParam)?;
+        let len = libc::off_t::try_from(len).map_err(|_| Error::InvalidParam)?;
+        // SAFETY: `fallocate` doesn't access any memory, and the file descriptor is valid for as
+        // long as `self.file` is.
+        if unsafe { libc::fallocate(self.file.as_raw_fd(), mode, offset, len) } < 0 {
+            return Err(io_error(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+}
+
+impl BlkBackend for FileBackend {
+    fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn readonly(&self) -> bool {
+        self.readonly
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result {
+        let (offset, _) = self.byte_range(sector, buf.len() as u64)?;
+        self.file.read_exact_at(buf, offset).map_err(io_error)
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result {
+        let (offset, _) = self.byte_range(sector, buf.len() as u64)?;
+        self.file.write_all_at(buf, offset).map_err(io_error)
+    }
+
+    fn flush(&mut self) -> Result {
+        self.file.sync_all().map_err(io_error)
+    }
+
+    fn discard(&mut self, sector: u64, num_sectors: u32) -> Result {
+        self.fallocate(
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            sector,
+            num_sectors,
+        )
+    }
+
+    fn write_zeroes(&mut self, sector: u64, num_sectors: u32, unmap: bool) -> Result {
+        // Holes read back as zeroes, so punch one if allowed. Otherwise, or if the filesystem
+        // doesn't support it, ask the filesystem to zero the range and finally fall back to
+        // writing zeroes.
+        if unmap && self.discard(sector, num_sectors).is_ok() {
+            return Ok(());
+        }
+        if self
+            .fallocate(
+                libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE,
+                sector,
+                num_sectors,
+            )
+            .is_ok()
+        {
+            return Ok(());
+        }
+
+        let (mut offset, mut len) =
+            self.byte_range(sector, u64::from(num_sectors) * SECTOR_SIZE as u64)?;
+        let zeroes = [0; ZEROES_CHUNK_SIZE];
+        while len > 0 {
+            let chunk_len = len.min(ZEROES_CHUNK_SIZE as u64);
+            self.file
+                .write_all_at(&zeroes[..chunk_len as usize], offset)
+                .map_err(io_error)?;
+            offset += chunk_len;
+            len -= chunk_len;
+        }
+        Ok(())
+    }
+}
+
+/// Logs the given I/O error from the host and converts it to the corresponding VirtIO error.
+pub(super) fn io_error(error: io::Error) -> Error {
+    if error.kind() == io::ErrorKind::Unsupported {
+        Error::Unsupported
+    } else {
+        warn!("Block backend I/O error: {}", error);
+        Error::IoError
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs::OpenOptions, path::PathBuf};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("virtio-blk-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn read_write_discard() {
+        let path = temp_path("file-backend.img");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(8 * SECTOR_SIZE as u64 + 100).unwrap();
+        let mut backend = FileBackend::new(file, false).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(backend.capacity(), 8);
+        assert!(!backend.readonly());
+
+        backend.write(2, &[42; SECTOR_SIZE * 3]).unwrap();
+        backend.flush().unwrap();
+        let mut buf = [0; SECTOR_SIZE * 4];
+        backend.read(1, &mut buf).unwrap();
+        assert!(buf[..SECTOR_SIZE].iter().all(|&byte| byte == 0));
+        assert!(buf[SECTOR_SIZE..].iter().all(|&byte| byte == 42));
+
+        // Not every filesystem supports punching holes, but if it succeeds it must zero the range.
+        match backend.discard(3, 1) {
+            Ok(()) => {
+                backend.read(3, &mut buf[..SECTOR_SIZE]).unwrap();
+                assert!(buf[..SECTOR_SIZE].iter().all(|&byte| byte == 0));
+            }
+            Err(e) => assert_eq!(e, Error::Unsupported),
+        }
+        backend.write_zeroes(4, 1, false).unwrap();
+        backend.read(2, &mut buf[..SECTOR_SIZE * 3]).unwrap();
+        assert!(buf[..SECTOR_SIZE].iter().all(|&byte| byte == 42));
+        assert!(buf[SECTOR_SIZE * 2..SECTOR_SIZE * 3]
+            .iter()
+            .all(|&byte| byte == 0));
+
+        // Accesses beyond the end of the image should fail.
+        assert_eq!(
+            backend.read(7, &mut buf[..SECTOR_SIZE * 2]),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(
+            backend.write(8, &[0; SECTOR_SIZE]),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(
+            backend.into_file().metadata().unwrap().len(),
+            8 * SECTOR_SIZE as u64 + 100
+        );
+    }
+}
@@ -0,0 +1,854 @@
+//! MBR and GPT partition tables on a [`VirtIOBlk`].
+
+use super::{VirtIOBlk, SECTOR_SIZE};
+use crate::{hal::Hal, transport::Transport, Error, Result};
+use alloc::{string::String, vec, vec::Vec};
+use core::fmt::{self, Display, Formatter};
+use log::warn;
+use zerocopy::{
+    byteorder::little_endian::{U16, U32, U64},
+    FromBytes, Immutable, IntoBytes, KnownLayout,
+};
+
+/// The boot signature at the end of an MBR.
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+
+/// The MBR partition type of a protective MBR in front of a GPT.
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+
+/// MBR partition types for extended partitions, which contain a chain of logical partitions.
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+
+/// The maximum number of logical partitions we will follow in an extended partition, to avoid
+/// following loops forever.
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
+/// The signature at the start of a GPT header.
+const GPT_SIGNATURE: [u8; 8] = *b"EFI PART";
+
+/// The maximum size of a GPT partition entry array we will read.
+const MAX_GPT_ENTRIES_SIZE: usize = 1024 * 1024;
+
+/// An error reading a partition table.
+#[derive(Clone, Copy, Debug, Eq, Error, PartialEq)]
+pub enum PartitionError {
+    /// The disk has neither an MBR nor a GPT.
+    #[error("No MBR or GPT partition table found")]
+    NotFound,
+    /// The disk has a protective MBR, but neither the primary nor the backup GPT is valid.
+    #[error("Neither the primary nor the backup GPT is valid")]
+    InvalidGpt,
+    /// Reading from the block device failed.
+    #[error("Error reading partition table: {0}")]
+    Blk(#[from] Error),
+}
+
+/// The type of partition table on a disk.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PartitionScheme {
+    /// A DOS-style master boot record, possibly with logical partitions.
+    Mbr,
+    /// A GUID partition table.
+    Gpt {
+        /// The GUID of the disk.
+        disk_guid: Guid,
+    },
+}
+
+/// A GUID as used in a GPT, stored in its on-disk mixed-endian form.
+#[derive(
+    Clone, Copy, Debug, Default, Eq, FromBytes, Hash, Immutable, IntoBytes, KnownLayout, PartialEq,
+)]
+#[repr(transparent)]
+pub struct Guid([u8; 16]);
+
+impl Guid {
+    /// The GUID with all bits zero, which marks an unused GPT partition entry.
+    pub const ZERO: Self = Self([0; 16]);
+
+    /// The partition type GUID of an EFI system partition, C12A7328-F81F-11D2-BA4B-00A0C93EC93B.
+    pub const EFI_SYSTEM: Self = Self::new(
+        0xc12a7328,
+        0xf81f,
+        0x11d2,
+        [0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b],
+    );
+
+    /// Creates a GUID from its fields, in the order they are written in the usual text form.
+    pub const fn new(data1: u32, data2: u16, data3: u16, data4: [u8; 8]) -> Self {
+        let data1 = data1.to_le_bytes();
+        let data2 = data2.to_le_bytes();
+        let data3 = data3.to_le_bytes();
+        Self([
+            data1[0], data1[1], data1[2], data1[3], data2[0], data2[1], data3[0], data3[1],
+            data4[0], data4[1], data4[2], data4[3], data4[4], data4[5], data4[6], data4[7],
+        ])
+    }
+
+    /// Returns the GUID in its on-disk form.
+    pub const fn to_bytes(self) -> [u8; 16] {
+        self.0
+    }
+}
+
+impl Display for Guid {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8],
+            b[9],
+        )?;
+        for byte in &b[10..] {
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// The type and other details of a partition, depending on the partition scheme.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PartitionKind {
+    /// A primary or logical MBR partition.
+    Mbr {
+        /// The MBR partition type.
+        partition_type: u8,
+        /// Whether the partition is marked as bootable.
+        bootable: bool,
+    },
+    /// A GPT partition.
+    Gpt {
+        /// The GUID identifying the type of the partition.
+        type_guid: Guid,
+        /// The GUID of the partition itself.
+        unique_guid: Guid,
+        /// The partition attribute flags.
+        attributes: u64,
+        /// The name of the partition.
+        name: String,
+    },
+}
+
+/// A partition found in a partition table.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Partition {
+    /// The partition number, starting from 1. MBR logical partitions are numbered from 5.
+    pub number: usize,
+    /// The first sector of the partition, in 512 byte ([`SECTOR_SIZE`]) sectors.
+    pub start_sector: u64,
+    /// The size of the partition, in 512 byte ([`SECTOR_SIZE`]) sectors.
+    pub num_sectors: u64,
+    /// The type and other details of the partition.
+    pub kind: PartitionKind,
+}
+
+impl Partition {
+    /// Returns a handle to access the partition on the given block device, which must be the one
+    /// the partition table was read from.
+    pub fn handle<'a, H: Hal, T: Transport, const QUEUES: usize>(
+        &self,
+        blk: &'a mut VirtIOBlk<H, T, QUEUES>,
+    ) -> PartitionHandle<'a, H, T, QUEUES> {
+        PartitionHandle {
+            blk,
+            start_sector: self.start_sector,
+            num_sectors: self.num_sectors,
+        }
+    }
+}
+
+/// The partition table of a disk.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PartitionTable {
+    /// The type of partition table.
+    pub scheme: PartitionScheme,
+    /// The partitions in the table, in order of partition number. Unused entries are skipped.
+    pub partitions: Vec<Partition>,
+}
+
+impl PartitionTable {
+    /// Reads the partition table from the given block device.
+    ///
+    /// If the disk has a protective MBR then the GPT is read, falling back to the backup GPT at
+    /// the end of the disk if the primary header or partition entries fail their CRC checks.
+    /// Otherwise the MBR is read, including any logical partitions.
+    pub fn read<H: Hal, T: Transport, const QUEUES: usize>(
+        blk: &mut VirtIOBlk<H, T, QUEUES>,
+    ) -> core::result::Result<Self, PartitionError> {
+        let mut reader = LbaReader::new(blk);
+        let lba0 = reader.read(0, 1)?;
+        let (mbr, _) = Mbr::ref_from_prefix(&lba0).unwrap();
+        let has_mbr = mbr.signature == MBR_SIGNATURE;
+        if has_mbr
+            && !mbr
+                .entries
+                .iter()
+                .any(|entry| entry.partition_type == MBR_TYPE_GPT_PROTECTIVE)
+        {
+            return Ok(Self {
+                scheme: PartitionScheme::Mbr,
+                partitions: read_mbr(&mut reader, mbr)?,
+            });
+        }
+
+        match read_gpt(&mut reader, 1)? {
+            Some(table) => return Ok(table),
+            None if has_mbr => warn!("Primary GPT is invalid, trying backup"),
+            // Without an MBR signature this is probably not a partitioned disk at all.
+            None => return Err(PartitionError::NotFound),
+        }
+        let last_lba = reader.num_lbas().saturating_sub(1);
+        read_gpt(&mut reader, last_lba)?.ok_or(PartitionError::InvalidGpt)
+    }
+}
+
+/// A handle to access a single partition of a [`VirtIOBlk`], returned by [`Partition::handle`].
+///
+/// Sector numbers are relative to the start of the partition, and accesses beyond its end are
+/// rejected.
+pub struct PartitionHandle<'a, H: Hal, T: Transport, const QUEUES: usize = 1> {
+    blk: &'a mut VirtIOBlk<H, T, QUEUES>,
+    start_sector: u64,
+    num_sectors: u64,
+}
+
+impl<H: Hal, T: Transport, const QUEUES: usize> PartitionHandle<'_, H, T, QUEUES> {
+    /// Gets the capacity of the partition, in 512 byte ([`SECTOR_SIZE`]) sectors.
+    pub fn capacity(&self) -> u64 {
+        self.num_sectors
+    }
+
+    /// Reads one or more blocks of the partition into the given buffer.
+    ///
+    /// Returns [`Error::InvalidParam`] if the buffer is empty or the read would extend beyond the
+    /// end of the partition.
+    /// Otherwise see [`VirtIOBlk::read_blocks`].
+    pub fn read_blocks(&mut self, block_id: usize, buf: &mut [u8]) -> Result {
+        let sector = self.device_sector(block_id, buf.len())?;
+        self.blk.read_blocks(sector, buf)
+    }
+
+    /// Writes the contents of the given buffer to one or more blocks of the partition.
+    ///
+    /// Returns [`Error::InvalidParam`] if the buffer is empty or the write would extend beyond the
+    /// end of the partition.
+    /// Otherwise see [`VirtIOBlk::write_blocks`].
+    pub fn write_blocks(&mut self, block_id: usize, buf: &[u8]) -> Result {
+        let sector = self.device_sector(block_id, buf.len())?;
+        self.blk.write_blocks(sector, buf)
+    }
+
+    /// Requests the device to flush any pending writes to storage.
+    ///
+    /// This applies to the whole device, not only the partition.
+    pub fn flush(&mut self) -> Result {
+        self.blk.flush()
+    }
+
+    /// Checks that the given range of the partition is non-empty and lies within it, and returns the corresponding
+    /// sector of the device.
+    fn device_sector(&self, block_id: usize, len: usize) -> Result<usize> {
+        let sector = block_id as u64;
+        if len == 0
+            || sector
+                .checked_add(len.div_ceil(SECTOR_SIZE) as u64)
+                .is_none_or(|end| end > self.num_sectors)
+        {
+            return Err(Error::InvalidParam);
+        }
+        usize::try_from(self.start_sector + sector).map_err(|_| Error::InvalidParam)
+    }
+}
+
+/// Reads whole logical blocks from a block device. Partition tables address the disk in units of
+/// the device's logical block size, which may be larger than [`SECTOR_SIZE`].
+struct LbaReader<'a, H: Hal, T: Transport, const QUEUES: usize> {
+    blk: &'a mut VirtIOBlk<H, T, QUEUES>,
+    lba_size: usize,
+}
+
+impl<'a, H: Hal, T: Transport, const QUEUES: usize> LbaReader<'a, H, T, QUEUES> {
+    fn new(blk: &'a mut VirtIOBlk<H, T, QUEUES>) -> Self {
+        let lba_size = blk.block_size() as usize;
+        Self { blk, lba_size }
+    }
+
+    fn sectors_per_lba(&self) -> u64 {
+        (self.lba_size / SECTOR_SIZE) as u64
+    }
+
+    /// Returns the number of logical blocks on the disk.
+    fn num_lbas(&self) -> u64 {
+        self.blk.capacity() / self.sectors_per_lba()
+    }
+
+    /// Converts a range of logical blocks to 512 byte sectors, checking that it is within the
+    /// disk.
+    fn to_sectors(&self, lba: u64, count: u64) -> Option<(u64, u64)> {
+        if lba.checked_add(count)? > self.num_lbas() {
+            return None;
+        }
+        Some((lba * self.sectors_per_lba(), count * self.sectors_per_lba()))
+    }
+
+    /// Reads the given number of logical blocks starting at the given one.
+    fn read(&mut self, lba: u64, count: usize) -> Result<Vec<u8>> {
+        if count == 0 {
+            return Err(Error::InvalidParam);
+        }
+        let (sector, _) = self
+            .to_sectors(lba, count as u64)
+            .ok_or(Error::InvalidParam)?;
+        let mut buf = vec![0; count * self.lba_size];
+        self.blk.read_blocks(sector as usize, &mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// A DOS-style master boot record, or extended boot record.
+#[derive(FromBytes, Immutable, IntoBytes, KnownLayout)]
+#[repr(C)]
+struct Mbr {
+    bootstrap: [u8; 446],
+    entries: [MbrEntry; 4],
+    signature: [u8; 2],
+}
+
+/// A partition entry in an MBR.
+#[derive(Clone, Copy, FromBytes, Immutable, IntoBytes, KnownLayout)]
+#[repr(C)]
+struct MbrEntry {
+    status: u8,
+    chs_first: [u8; 3],
+    partition_type: u8,
+    chs_last: [u8; 3],
+    first_lba: U32,
+    num_lbas: U32,
+}
+
+impl MbrEntry {
+    fn is_used(&self) -> bool {
+        self.partition_type != 0 && self.num_lbas.get() != 0
+    }
+}
+
+/// Reads the primary partitions from the given MBR, and the logical partitions from any extended
+/// partition.
+fn read_mbr<H: Hal, T: Transport, const QUEUES: usize>(
+    reader: &mut LbaReader<H, T, QUEUES>,
+    mbr: &Mbr,
+) -> Result<Vec<Partition>> {
+    let mut partitions = Vec::new();
+    let mut extended = None;
+    for (i, entry) in mbr.entries.iter().enumerate() {
+        if !entry.is_used() {
+            continue;
+        }
+        if MBR_TYPES_EXTENDED.contains(&entry.partition_type) {
+            extended = Some(u64::from(entry.first_lba.get()));
+        } else if let Some(partition) = mbr_partition(reader, i + 1, 0, entry) {
+            partitions.push(partition);
+        }
+    }
+
+    // Each extended boot record describes one logical partition relative to itself, and links to
+    // the next relative to the start of the extended partition.
+    if let Some(extended_start) = extended {
+        let mut ebr_lba = extended_start;
+        for number in 5..5 + MAX_LOGICAL_PARTITIONS {
+            let data = reader.read(ebr_lba, 1)?;
+            let (ebr, _) = Mbr::ref_from_prefix(&data).unwrap();
+            if ebr.signature != MBR_SIGNATURE {
+                warn!("Invalid extended boot record at LBA {}", ebr_lba);
+                break;
+            }
+            if ebr.entries[0].is_used() {
+                if let Some(partition) = mbr_partition(reader, number, ebr_lba, &ebr.entries[0]) {
+                    partitions.push(partition);
+                }
+            }
+            let next = &ebr.entries[1];
+            if !next.is_used() {
+                break;
+            }
+            ebr_lba = extended_start + u64::from(next.first_lba.get());
+        }
+    }
+    Ok(partitions)
+}
+
+/// Converts the given MBR entry, whose start is relative to `base_lba`, to a [`Partition`].
+fn mbr_partition<H: Hal, T: Transport, const QUEUES: usize>(
+    reader: &LbaReader<H, T, QUEUES>,
+    number: usize,
+    base_lba: u64,
+    entry: &MbrEntry,
+) -> Option<Partition> {
+    let Some((start_sector, num_sectors)) = reader.to_sectors(
+        base_lba + u64::from(entry.first_lba.get()),
+        entry.num_lbas.get().into(),
+    ) else {
+        warn!(
+            "MBR partition {} extends beyond the end of the disk",
+            number
+        );
+        return None;
+    };
+    Some(Partition {
+        number,
+        start_sector,
+        num_sectors,
+        kind: PartitionKind::Mbr {
+            partition_type: entry.partition_type,
+            bootable: entry.status & 0x80 != 0,
+        },
+    })
+}
+
+/// A GPT header.
+#[derive(FromBytes, Immutable, IntoBytes, KnownLayout)]
+#[repr(C)]
+struct GptHeader {
+    signature: [u8; 8],
+    revision: U32,
+    header_size: U32,
+    header_crc32: U32,
+    reserved: U32,
+    my_lba: U64,
+    alternate_lba: U64,
+    first_usable_lba: U64,
+    last_usable_lba: U64,
+    disk_guid: Guid,
+    partition_entry_lba: U64,
+    num_partition_entries: U32,
+    partition_entry_size: U32,
+    partition_entries_crc32: U32,
+}
+
+/// A GPT partition entry. Entries may be larger than this, in which case the rest is ignored.
+#[derive(FromBytes, Immutable, IntoBytes, KnownLayout)]
+#[repr(C)]
+struct GptEntry {
+    type_guid: Guid,
+    unique_guid: Guid,
+    first_lba: U64,
+    last_lba: U64,
+    attributes: U64,
+    name: [U16; 36],
+}
+
+/// Reads and validates the GPT whose header is at the given LBA.
+///
+/// Returns `None` if the header or partition entries are invalid.
+fn read_gpt<H: Hal, T: Transport, const QUEUES: usize>(
+    reader: &mut LbaReader<H, T, QUEUES>,
+    header_lba: u64,
+) -> Result<Option<PartitionTable>> {
+    let data = reader.read(header_lba, 1)?;
+    let (header, _) = GptHeader::ref_from_prefix(&data).unwrap();
+    let header_size = header.header_size.get() as usize;
+    if header.signature != GPT_SIGNATURE
+        || !(size_of::<GptHeader>()..=data.len()).contains(&header_size)
+        || header.my_lba.get() != header_lba
+    {
+        warn!("No valid GPT header at LBA {}", header_lba);
+        return Ok(None);
+    }
+    let mut header_bytes = data[..header_size].to_vec();
+    // The CRC is calculated with the CRC field itself zeroed.
+    header_bytes[16..20].fill(0);
+    if crc32(&header_bytes) != header.header_crc32.get() {
+        warn!("GPT header at LBA {} has a bad CRC", header_lba);
+        return Ok(None);
+    }
+
+    let entry_size = header.partition_entry_size.get() as usize;
+    let num_entries = header.num_partition_entries.get() as usize;
+    let Some(entries_size) = num_entries
+        .checked_mul(entry_size)
+        .filter(|&size| size <= MAX_GPT_ENTRIES_SIZE)
+    else {
+        warn!("GPT partition entry array is too large");
+        return Ok(None);
+    };
+    if entry_size < size_of::<GptEntry>() || !entry_size.is_power_of_two() {
+        warn!("Invalid GPT partition entry size {}", entry_size);
+        return Ok(None);
+    }
+    let entries = if entries_size == 0 {
+        Vec::new()
+    } else {
+        let entries_lbas = entries_size.div_ceil(reader.lba_size);
+        let Ok(entries) = reader.read(header.partition_entry_lba.get(), entries_lbas) else {
+            warn!("Failed to read GPT partition entries");
+            return Ok(None);
+        };
+        entries
+    };
+    if crc32(&entries[..entries_size]) != header.partition_entries_crc32.get() {
+        warn!(
+            "GPT partition entries for header at LBA {} have a bad CRC",
+            header_lba
+        );
+        return Ok(None);
+    }
+
+    let mut partitions = Vec::new();
+    for (i, entry) in entries[..entries_size].chunks_exact(entry_size).enumerate() {
+        let (entry, _) = GptEntry::ref_from_prefix(entry).unwrap();
+        if entry.type_guid == Guid::ZERO {
+            continue;
+        }
+        let number = i + 1;
+        let (first_lba, last_lba) = (entry.first_lba.get(), entry.last_lba.get());
+        let Some((start_sector, num_sectors)) = last_lba
+            .checked_sub(first_lba)
+            .and_then(|len| reader.to_sectors(first_lba, len.checked_add(1)?))
+        else {
+            warn!("GPT partition {} has an invalid range", number);
+            continue;
+        };
+        let name_len = entry
+            .name
+            .iter()
+            .position(|c| c.get() == 0)
+            .unwrap_or(entry.name.len());
+        let name = char::decode_utf16(entry.name[..name_len].iter().map(|c| c.get()))
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+        partitions.push(Partition {
+            number,
+            start_sector,
+            num_sectors,
+            kind: PartitionKind::Gpt {
+                type_guid: entry.type_guid,
+                unique_guid: entry.unique_guid,
+                attributes: entry.attributes.get(),
+                name,
+            },
+        });
+    }
+    Ok(Some(PartitionTable {
+        scheme: PartitionScheme::Gpt {
+            disk_guid: header.disk_guid,
+        },
+        partitions,
+    }))
+}
+
+/// The lookup table for the CRC-32 used by GPT, with the reflected polynomial 0xEDB88320.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Calculates the CRC-32 of the given data, as used by GPT.
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &byte| {
+        CRC32_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::blk::{tests::device::spawn_device, BlkFeature, RamDisk};
+    use alloc::string::ToString;
+    use zerocopy::FromZeros;
+
+    const DISK_SECTORS: usize = 64;
+
+    /// An arbitrary partition type GUID for Linux filesystem data.
+    const LINUX_DATA: Guid = Guid::new(
+        0x0fc63daf,
+        0x8483,
+        0x4772,
+        [0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d, 0xe4],
+    );
+
+    fn sector(disk: &mut [u8], sector: usize) -> &mut [u8] {
+        &mut disk[sector * SECTOR_SIZE..(sector + 1) * SECTOR_SIZE]
+    }
+
+    fn write_mbr(disk: &mut [u8], lba: usize, entries: &[(u8, u32, u32)]) {
+        let (mbr, _) = Mbr::mut_from_prefix(sector(disk, lba)).unwrap();
+        for (entry, &(partition_type, first_lba, num_lbas)) in mbr.entries.iter_mut().zip(entries) {
+            entry.partition_type = partition_type;
+            entry.first_lba.set(first_lba);
+            entry.num_lbas.set(num_lbas);
+        }
+        mbr.signature = MBR_SIGNATURE;
+    }
+
+    fn gpt_entry(type_guid: Guid, first_lba: u64, last_lba: u64, name: &str) -> GptEntry {
+        let mut entry = GptEntry::new_zeroed();
+        entry.type_guid = type_guid;
+        entry.unique_guid = Guid::new(first_lba as u32, 0, 0, [0; 8]);
+        entry.first_lba.set(first_lba);
+        entry.last_lba.set(last_lba);
+        for (c, unit) in entry.name.iter_mut().zip(name.encode_utf16()) {
+            c.set(unit);
+        }
+        entry
+    }
+
+    /// Writes a GPT header at the given LBA and up to four partition entries in the following or
+    /// preceding LBA.
+    fn write_gpt(disk: &mut [u8], header_lba: u64, alternate_lba: u64, entries: &[GptEntry]) {
+        let entries_lba = if header_lba == 1 { 2 } else { header_lba - 1 };
+        let entries_bytes = entries.as_bytes();
+        sector(disk, entries_lba as usize)[..entries_bytes.len()].copy_from_slice(entries_bytes);
+
+        let mut header = GptHeader::new_zeroed();
+        header.signature = GPT_SIGNATURE;
+        header.revision.set(0x0001_0000);
+        header.header_size.set(size_of::<GptHeader>() as u32);
+        header.my_lba.set(header_lba);
+        header.alternate_lba.set(alternate_lba);
+        header.first_usable_lba.set(3);
+        header.last_usable_lba.set(DISK_SECTORS as u64 - 3);
+        header.disk_guid = Guid::new(0x12345678, 0x9abc, 0xdef0, [1, 2, 3, 4, 5, 6, 7, 8]);
+        header.partition_entry_lba.set(entries_lba);
+        header.num_partition_entries.set(entries.len() as u32);
+        header
+            .partition_entry_size
+            .set(size_of::<GptEntry>() as u32);
+        header.partition_entries_crc32.set(crc32(entries_bytes));
+        header.header_crc32.set(crc32(header.as_bytes()));
+        sector(disk, header_lba as usize)[..size_of::<GptHeader>()]
+            .copy_from_slice(header.as_bytes());
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn guid_display() {
+        assert_eq!(
+            Guid::EFI_SYSTEM.to_string(),
+            "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"
+        );
+        assert_eq!(Guid::EFI_SYSTEM.to_bytes()[..4], [0x28, 0x73, 0x2a, 0xc1]);
+    }
+
+    #[test]
+    fn gpt_backup() {
+        let mut disk = vec![0; DISK_SECTORS * SECTOR_SIZE];
+        write_mbr(
+            &mut disk,
+            0,
+            &[(MBR_TYPE_GPT_PROTECTIVE, 1, DISK_SECTORS as u32 - 1)],
+        );
+        let entries = [
+            gpt_entry(Guid::EFI_SYSTEM, 10, 19, "EFI"),
+            GptEntry::new_zeroed(),
+            gpt_entry(LINUX_DATA, 20, 39, "root"),
+            // Entries beyond the end of the disk are ignored.
+            gpt_entry(LINUX_DATA, 40, DISK_SECTORS as u64, "bad"),
+        ];
+        write_gpt(&mut disk, 1, DISK_SECTORS as u64 - 1, &entries);
+        write_gpt(&mut disk, DISK_SECTORS as u64 - 1, 1, &entries);
+        // Corrupt the primary header so that the backup must be used.
+        sector(&mut disk, 1)[60] ^= 0xff;
+        sector(&mut disk, 25).fill(42);
+
+        let (mut blk, device) = spawn_device(RamDisk::from_vec(disk).unwrap(), BlkFeature::empty());
+        let table = PartitionTable::read(&mut blk).unwrap();
+        assert_eq!(
+            table.scheme,
+            PartitionScheme::Gpt {
+                disk_guid: Guid::new(0x12345678, 0x9abc, 0xdef0, [1, 2, 3, 4, 5, 6, 7, 8]),
+            }
+        );
+        assert_eq!(
+            table.partitions,
+            vec![
+                Partition {
+                    number: 1,
+                    start_sector: 10,
+                    num_sectors: 10,
+                    kind: PartitionKind::Gpt {
+                        type_guid: Guid::EFI_SYSTEM,
+                        unique_guid: Guid::new(10, 0, 0, [0; 8]),
+                        attributes: 0,
+                        name: "EFI".into(),
+                    },
+                },
+                Partition {
+                    number: 3,
+                    start_sector: 20,
+                    num_sectors: 20,
+                    kind: PartitionKind::Gpt {
+                        type_guid: LINUX_DATA,
+                        unique_guid: Guid::new(20, 0, 0, [0; 8]),
+                        attributes: 0,
+                        name: "root".into(),
+                    },
+                },
+            ]
+        );
+
+        // Accesses through a handle are relative to the partition, and can't go beyond it.
+        let mut root = table.partitions[1].handle(&mut blk);
+        assert_eq!(root.capacity(), 20);
+        let mut buf = [0; SECTOR_SIZE * 2];
+        root.read_blocks(5, &mut buf[..SECTOR_SIZE]).unwrap();
+        assert_eq!(buf[..SECTOR_SIZE], [42; SECTOR_SIZE]);
+        root.write_blocks(18, &[7; SECTOR_SIZE * 2]).unwrap();
+        assert_eq!(root.read_blocks(19, &mut buf), Err(Error::InvalidParam));
+        assert_eq!(
+            root.write_blocks(20, &buf[..SECTOR_SIZE]),
+            Err(Error::InvalidParam)
+        );
+        // Empty accesses are rejected rather than passed on to the device.
+        assert_eq!(root.read_blocks(0, &mut []), Err(Error::InvalidParam));
+        assert_eq!(root.write_blocks(0, &[]), Err(Error::InvalidParam));
+
+        let device = device.stop();
+        let disk = device.backend().data();
+        assert_eq!(
+            disk[38 * SECTOR_SIZE..40 * SECTOR_SIZE],
+            [7; SECTOR_SIZE * 2]
+        );
+        assert_eq!(disk[40 * SECTOR_SIZE..41 * SECTOR_SIZE], [0; SECTOR_SIZE]);
+    }
+
+    #[test]
+    fn gpt_entry_covering_everything() {
+        let mut disk = vec![0; DISK_SECTORS * SECTOR_SIZE];
+        write_mbr(
+            &mut disk,
+            0,
+            &[(MBR_TYPE_GPT_PROTECTIVE, 1, DISK_SECTORS as u32 - 1)],
+        );
+        let entries = [
+            // The length of this entry doesn't fit in a u64, so it should be ignored.
+            gpt_entry(LINUX_DATA, 0, u64::MAX, "huge"),
+            gpt_entry(LINUX_DATA, 10, 19, "data"),
+            GptEntry::new_zeroed(),
+            GptEntry::new_zeroed(),
+        ];
+        write_gpt(&mut disk, 1, DISK_SECTORS as u64 - 1, &entries);
+
+        let (mut blk, device) = spawn_device(RamDisk::from_vec(disk).unwrap(), BlkFeature::empty());
+        let table = PartitionTable::read(&mut blk).unwrap();
+        assert_eq!(table.partitions.len(), 1);
+        assert_eq!(table.partitions[0].number, 2);
+        assert_eq!(table.partitions[0].start_sector, 10);
+        assert_eq!(table.partitions[0].num_sectors, 10);
+        device.stop();
+    }
+
+    #[test]
+    fn gpt_without_entries() {
+        let mut disk = vec![0; DISK_SECTORS * SECTOR_SIZE];
+        write_mbr(
+            &mut disk,
+            0,
+            &[(MBR_TYPE_GPT_PROTECTIVE, 1, DISK_SECTORS as u32 - 1)],
+        );
+        write_gpt(&mut disk, 1, DISK_SECTORS as u64 - 1, &[]);
+
+        let (mut blk, device) = spawn_device(RamDisk::from_vec(disk).unwrap(), BlkFeature::empty());
+        let table = PartitionTable::read(&mut blk).unwrap();
+        assert!(matches!(table.scheme, PartitionScheme::Gpt { .. }));
+        assert_eq!(table.partitions, vec![]);
+        device.stop();
+    }
+
+    #[test]
+    fn invalid_gpt() {
+        let mut disk = vec![0; DISK_SECTORS * SECTOR_SIZE];
+        let (mut blk, device) = spawn_device(
+            RamDisk::from_vec(disk.clone()).unwrap(),
+            BlkFeature::empty(),
+        );
+        assert_eq!(
+            PartitionTable::read(&mut blk),
+            Err(PartitionError::NotFound)
+        );
+        device.stop();
+
+        // A protective MBR without a valid GPT.
+        write_mbr(
+            &mut disk,
+            0,
+            &[(MBR_TYPE_GPT_PROTECTIVE, 1, DISK_SECTORS as u32 - 1)],
+        );
+        let (mut blk, device) = spawn_device(RamDisk::from_vec(disk).unwrap(), BlkFeature::empty());
+        assert_eq!(
+            PartitionTable::read(&mut blk),
+            Err(PartitionError::InvalidGpt)
+        );
+        device.stop();
+    }
+
+    #[test]
+    fn mbr_logical() {
+        let mut disk = vec![0; DISK_SECTORS * SECTOR_SIZE];
+        write_mbr(&mut disk, 0, &[(0x83, 1, 9), (0x0f, 20, 40)]);
+        sector(&mut disk, 0)[446] = 0x80;
+        // Two logical partitions, each preceded by its extended boot record.
+        write_mbr(&mut disk, 20, &[(0x83, 2, 8), (0x05, 15, 20)]);
+        write_mbr(&mut disk, 35, &[(0x82, 1, 10)]);
+
+        let (mut blk, device) = spawn_device(RamDisk::from_vec(disk).unwrap(), BlkFeature::empty());
+        let table = PartitionTable::read(&mut blk).unwrap();
+        device.stop();
+        assert_eq!(table.scheme, PartitionScheme::Mbr);
+        assert_eq!(
+            table.partitions,
+            vec![
+                Partition {
+                    number: 1,
+                    start_sector: 1,
+                    num_sectors: 9,
+                    kind: PartitionKind::Mbr {
+                        partition_type: 0x83,
+                        bootable: true,
+                    },
+                },
+                Partition {
+                    number: 5,
+                    start_sector: 22,
+                    num_sectors: 8,
+                    kind: PartitionKind::Mbr {
+                        partition_type: 0x83,
+                        bootable: false,
+                    },
+                },
+                Partition {
+                    number: 6,
+                    start_sector: 36,
+                    num_sectors: 10,
+                    kind: PartitionKind::Mbr {
+                        partition_type: 0x82,
+                        bootable: false,
+                    },
+                },
+            ]
+        );
+    }
+}
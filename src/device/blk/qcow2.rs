@@ -0,0 +1,487 @@
+//! Read-only qcow2 image storage for the device-side block implementation.
+//!
+//! Versions 2 and 3 of the format are supported, including chains of backing files. Encrypted
+//! images, compressed clusters and external data files are not.
+
+use super::{file::io_error, BlkBackend, FileBackend, SECTOR_SIZE};
+use crate::{Error, Result};
+use alloc::{boxed::Box, vec, vec::Vec};
+use log::warn;
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io,
+    os::unix::{ffi::OsStrExt, fs::FileExt},
+    path::Path,
+};
+use zerocopy::{
+    byteorder::big_endian::{U32, U64},
+    FromBytes, FromZeros, Immutable, IntoBytes, KnownLayout,
+};
+
+/// The magic number at the start of every qcow2 image ("QFI\xfb").
+const QCOW2_MAGIC: u32 = 0x5146_49fb;
+
+/// The size of the version 2 header. Version 3 adds the remaining fields of [`Qcow2Header`].
+const HEADER_V2_SIZE: usize = 72;
+
+/// The maximum length of a backing file name.
+const MAX_BACKING_FILE_NAME: u32 = 1023;
+
+/// The maximum number of images in a backing file chain, to avoid following loops forever.
+const MAX_BACKING_CHAIN: usize = 16;
+
+/// The maximum number of L1 table entries we will load, as in QEMU.
+const MAX_L1_SIZE: u32 = 32 * 1024 * 1024 / 8;
+
+/// The bits of an L1 or L2 table entry which hold the host offset of the table or cluster.
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+
+/// L2 entry flag: the cluster is compressed.
+const L2_COMPRESSED: u64 = 1 << 62;
+
+/// L2 entry flag (version 3 only): the cluster reads as all zeroes.
+const L2_ZERO: u64 = 1 << 0;
+
+/// Incompatible feature: the image was not closed cleanly, so refcounts may be wrong. This
+/// doesn't matter for reading.
+const INCOMPAT_DIRTY: u64 = 1 << 0;
+
+/// Incompatible feature: compressed clusters use a compression type other than zlib. We don't
+/// read compressed clusters anyway.
+const INCOMPAT_COMPRESSION_TYPE: u64 = 1 << 3;
+
+/// The incompatible features which we can safely ignore.
+const SUPPORTED_INCOMPAT_FEATURES: u64 = INCOMPAT_DIRTY | INCOMPAT_COMPRESSION_TYPE;
+
+/// An error opening a qcow2 image.
+#[derive(Clone, Copy, Debug, Eq, Error, PartialEq)]
+pub enum Qcow2Error {
+    /// The file doesn't start with the qcow2 magic number.
+    #[error("Image doesn't have the qcow2 magic number")]
+    BadMagic,
+    /// The image uses a version of the format other than 2 or 3.
+    #[error("Unsupported qcow2 version {0}")]
+    UnsupportedVersion(u32),
+    /// The image has incompatible features which we don't support.
+    #[error("Unsupported qcow2 incompatible features {0:#x}")]
+    UnsupportedFeatures(u64),
+    /// The image is encrypted.
+    #[error("Encrypted qcow2 images are not supported")]
+    Encrypted,
+    /// The cluster size is out of range.
+    #[error("Invalid qcow2 cluster bits {0}")]
+    InvalidClusterBits(u32),
+    /// The L1 table is too large.
+    #[error("qcow2 L1 table of {0} entries is too large")]
+    L1TableTooLarge(u32),
+    /// The backing file name is too long.
+    #[error("qcow2 backing file name of {0} bytes is too long")]
+    BackingFileNameTooLong(u32),
+    /// The chain of backing files is too long, or contains a loop.
+    #[error("qcow2 backing file chain is longer than {MAX_BACKING_CHAIN} images")]
+    BackingChainTooLong,
+    /// Reading the image failed.
+    #[error("I/O error reading qcow2 image: {0}")]
+    Io(io::ErrorKind),
+}
+
+impl From<io::Error> for Qcow2Error {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error.kind())
+    }
+}
+
+/// The header at the start of a qcow2 image, with all fields big-endian.
+#[derive(FromBytes, Immutable, IntoBytes, KnownLayout)]
+#[repr(C)]
+struct Qcow2Header {
+    magic: U32,
+    version: U32,
+    backing_file_offset: U64,
+    backing_file_size: U32,
+    cluster_bits: U32,
+    size: U64,
+    crypt_method: U32,
+    l1_size: U32,
+    l1_table_offset: U64,
+    refcount_table_offset: U64,
+    refcount_table_clusters: U32,
+    nb_snapshots: U32,
+    snapshots_offset: U64,
+    // Version 3 only.
+    incompatible_features: U64,
+    compatible_features: U64,
+    autoclear_features: U64,
+    refcount_order: U32,
+    header_length: U32,
+}
+
+/// Where the data for a guest cluster comes from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Cluster {
+    /// The cluster is stored in the image at the given host offset.
+    Data(u64),
+    /// The cluster reads as zeroes.
+    Zero,
+    /// The cluster isn't allocated in this image, so comes from the backing file if any.
+    Unallocated,
+}
+
+/// The backing file of a qcow2 image.
+#[derive(Debug)]
+enum Backing {
+    Raw(FileBackend),
+    Qcow2(Box<Qcow2Backend>),
+}
+
+impl Backing {
+    fn backend(&mut self) -> &mut dyn BlkBackend {
+        match self {
+            Self::Raw(backend) => backend,
+            Self::Qcow2(backend) => backend.as_mut(),
+        }
+    }
+}
+
+/// A read-only [`BlkBackend`] which serves the contents of a qcow2 image.
+///
+/// Clusters which aren't allocated in the image are read from its backing file if it has one,
+/// which may itself be a raw or qcow2 image. Relative backing file names are resolved relative to
+/// the directory containing the image.
+#[derive(Debug)]
+pub struct Qcow2Backend {
+    file: File,
+    cluster_bits: u32,
+    /// The virtual size of the disk, in sectors.
+    capacity: u64,
+    l1_table: Vec<u64>,
+    /// The most recently used L2 table, along with its host offset.
+    l2_cache: Option<(u64, Vec<u64>)>,
+    backing: Option<Backing>,
+}
+
+impl Qcow2Backend {
+    /// Opens the qcow2 image at the given path, along with its chain of backing files.
+    pub fn open(path: impl AsRef<Path>) -> core::result::Result<Self, Qcow2Error> {
+        Self::open_chain(path.as_ref(), 0)
+    }
+
+    fn open_chain(path: &Path, depth: usize) -> core::result::Result<Self, Qcow2Error> {
+        if depth >= MAX_BACKING_CHAIN {
+            return Err(Qcow2Error::BackingChainTooLong);
+        }
+        let file = File::open(path)?;
+
+        let mut header = Qcow2Header::new_zeroed();
+        file.read_exact_at(&mut header.as_mut_bytes()[..HEADER_V2_SIZE], 0)?;
+        if header.magic.get() != QCOW2_MAGIC {
+            return Err(Qcow2Error::BadMagic);
+        }
+        match header.version.get() {
+            2 => {}
+            3 => {
+                file.read_exact_at(
+                    &mut header.as_mut_bytes()[HEADER_V2_SIZE..],
+                    HEADER_V2_SIZE as u64,
+                )?;
+                let unsupported = header.incompatible_features.get() & !SUPPORTED_INCOMPAT_FEATURES;
+                if unsupported != 0 {
+                    return Err(Qcow2Error::UnsupportedFeatures(unsupported));
+                }
+            }
+            version => return Err(Qcow2Error::UnsupportedVersion(version)),
+        }
+        if header.crypt_method.get() != 0 {
+            return Err(Qcow2Error::Encrypted);
+        }
+        let cluster_bits = header.cluster_bits.get();
+        if !(9..=21).contains(&cluster_bits) {
+            return Err(Qcow2Error::InvalidClusterBits(cluster_bits));
+        }
+        let l1_size = header.l1_size.get();
+        if l1_size > MAX_L1_SIZE {
+            return Err(Qcow2Error::L1TableTooLarge(l1_size));
+        }
+
+        let mut l1_table = vec![U64::ZERO; l1_size as usize];
+        file.read_exact_at(l1_table.as_mut_bytes(), header.l1_table_offset.get())?;
+        let l1_table = l1_table.into_iter().map(U64::get).collect();
+
+        let backing = if header.backing_file_offset.get() == 0 {
+            None
+        } else {
+            let name_size = header.backing_file_size.get();
+            if name_size > MAX_BACKING_FILE_NAME {
+                return Err(Qcow2Error::BackingFileNameTooLong(name_size));
+            }
+            let mut name = vec![0; name_size as usize];
+            file.read_exact_at(&mut name, header.backing_file_offset.get())?;
+            let backing_path = path
+                .parent()
+                .unwrap_or(Path::new(""))
+                .join(OsStr::from_bytes(&name));
+            Some(Self::open_backing(&backing_path, depth + 1)?)
+        };
+
+        Ok(Self {
+            file,
+            cluster_bits,
+            capacity: header.size.get() / SECTOR_SIZE as u64,
+            l1_table,
+            l2_cache: None,
+            backing,
+        })
+    }
+
+    /// Opens the given backing file, detecting whether it is a qcow2 or raw image.
+    fn open_backing(path: &Path, depth: usize) -> core::result::Result<Backing, Qcow2Error> {
+        let file = File::open(path)?;
+        let mut magic = [0; 4];
+        if file.read_exact_at(&mut magic, 0).is_ok() && u32::from_be_bytes(magic) == QCOW2_MAGIC {
+            Ok(Backing::Qcow2(Box::new(Self::open_chain(path, depth)?)))
+        } else {
+            Ok(Backing::Raw(FileBackend::new(file, true)?))
+        }
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    /// Looks up where the guest cluster containing the given guest offset is stored.
+    fn cluster(&mut self, offset: u64) -> Result<Cluster> {
+        // Each L2 table fills one cluster with 8 byte entries.
+        let l2_bits = self.cluster_bits - 3;
+        let Some(&l1_entry) = usize::try_from(offset >> (self.cluster_bits + l2_bits))
+            .ok()
+            .and_then(|index| self.l1_table.get(index))
+        else {
+            return Ok(Cluster::Unallocated);
+        };
+        let l2_offset = l1_entry & OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(Cluster::Unallocated);
+        }
+
+        let l2_index = ((offset >> self.cluster_bits) & ((1 << l2_bits) - 1)) as usize;
+        let l2_entry = self.l2_table(l2_offset)?[l2_index];
+        if l2_entry & L2_COMPRESSED != 0 {
+            warn!("Compressed qcow2 clusters are not supported");
+            return Err(Error::Unsupported);
+        }
+        let host_offset = l2_entry & OFFSET_MASK;
+        Ok(if l2_entry & L2_ZERO != 0 {
+            Cluster::Zero
+        } else if host_offset == 0 {
+            Cluster::Unallocated
+        } else {
+            Cluster::Data(host_offset)
+        })
+    }
+
+    /// Returns the L2 table at the given host offset, loading it if it isn't already cached.
+    fn l2_table(&mut self, l2_offset: u64) -> Result<&[u64]> {
+        if !matches!(&self.l2_cache, Some((offset, _)) if *offset == l2_offset) {
+            let mut table = vec![U64::ZERO; self.cluster_size() as usize / 8];
+            self.file
+                .read_exact_at(table.as_mut_bytes(), l2_offset)
+                .map_err(io_error)?;
+            self.l2_cache = Some((l2_offset, table.into_iter().map(U64::get).collect()));
+        }
+        Ok(&self.l2_cache.as_ref().unwrap().1)
+    }
+
+    /// Reads the given unallocated part of the disk from the backing file, or fills it with zeroes
+    /// if there is no backing file or it is too short.
+    fn read_backing(&mut self, offset: u64, buf: &mut [u8]) -> Result {
+        let mut available = 0;
+        if let Some(backing) = &mut self.backing {
+            let backing = backing.backend();
+            let backing_size = backing.capacity() * SECTOR_SIZE as u64;
+            available = backing_size.saturating_sub(offset).min(buf.len() as u64) as usize;
+            if available > 0 {
+                backing.read(offset / SECTOR_SIZE as u64, &mut buf[..available])?;
+            }
+        }
+        buf[available..].fill(0);
+        Ok(())
+    }
+}
+
+impl BlkBackend for Qcow2Backend {
+    fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn readonly(&self) -> bool {
+        true
+    }
+
+    fn read(&mut self, sector: u64, mut buf: &mut [u8]) -> Result {
+        if sector
+            .checked_add((buf.len() / SECTOR_SIZE) as u64)
+            .is_none_or(|end| end > self.capacity)
+        {
+            return Err(Error::InvalidParam);
+        }
+        let mut offset = sector * SECTOR_SIZE as u64;
+        while !buf.is_empty() {
+            let offset_in_cluster = offset & (self.cluster_size() - 1);
+            let len = (self.cluster_size() - offset_in_cluster).min(buf.len() as u64) as usize;
+            let (chunk, rest) = buf.split_at_mut(len);
+            match self.cluster(offset)? {
+                Cluster::Data(host_offset) => self
+                    .file
+                    .read_exact_at(chunk, host_offset + offset_in_cluster)
+                    .map_err(io_error)?,
+                Cluster::Zero => chunk.fill(0),
+                Cluster::Unallocated => self.read_backing(offset, chunk)?,
+            }
+            offset += len as u64;
+            buf = rest;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, _sector: u64, _buf: &[u8]) -> Result {
+        Err(Error::Unsupported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const CLUSTER_SIZE: usize = 512;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("virtio-blk-{}-{}", std::process::id(), name))
+    }
+
+    /// Builds a version 3 image with 512 byte clusters and the given L2 entries, with the L1 table
+    /// in cluster 1, the L2 table in cluster 2 and a data cluster filled with `data` in cluster 3.
+    fn build_image(size: u64, backing_file: Option<&str>, l2_entries: &[u64], data: u8) -> Vec<u8> {
+        let mut image = vec![0; CLUSTER_SIZE * 4];
+        let mut header = Qcow2Header::new_zeroed();
+        header.magic.set(QCOW2_MAGIC);
+        header.version.set(3);
+        header.cluster_bits.set(9);
+        header.size.set(size);
+        header.l1_size.set(1);
+        header.l1_table_offset.set(CLUSTER_SIZE as u64);
+        header.header_length.set(size_of::<Qcow2Header>() as u32);
+        if let Some(backing_file) = backing_file {
+            header
+                .backing_file_offset
+                .set(size_of::<Qcow2Header>() as u64);
+            header.backing_file_size.set(backing_file.len() as u32);
+            image[size_of::<Qcow2Header>()..][..backing_file.len()]
+                .copy_from_slice(backing_file.as_bytes());
+        }
+        image[..size_of::<Qcow2Header>()].copy_from_slice(header.as_bytes());
+        image[CLUSTER_SIZE..][..8].copy_from_slice(&(CLUSTER_SIZE as u64 * 2).to_be_bytes());
+        for (i, entry) in l2_entries.iter().enumerate() {
+            image[CLUSTER_SIZE * 2 + i * 8..][..8].copy_from_slice(&entry.to_be_bytes());
+        }
+        image[CLUSTER_SIZE * 3..].fill(data);
+        image
+    }
+
+    #[test]
+    fn read_backing_chain() {
+        let raw_name = format!("virtio-blk-{}-base.img", std::process::id());
+        let middle_name = format!("virtio-blk-{}-middle.qcow2", std::process::id());
+        let raw_path = std::env::temp_dir().join(&raw_name);
+        let middle_path = std::env::temp_dir().join(&middle_name);
+        let top_path = temp_path("top.qcow2");
+
+        // The raw base image is shorter than the others.
+        let mut raw = vec![7; SECTOR_SIZE * 5];
+        raw[SECTOR_SIZE * 4..].fill(8);
+        std::fs::write(&raw_path, &raw).unwrap();
+        // The middle image has sector 1 allocated, and sector 2 explicitly zeroed.
+        let data_offset = CLUSTER_SIZE as u64 * 3;
+        std::fs::write(
+            &middle_path,
+            build_image(
+                SECTOR_SIZE as u64 * 6,
+                Some(&raw_name),
+                &[0, data_offset, L2_ZERO],
+                2,
+            ),
+        )
+        .unwrap();
+        // The top image has sector 0 allocated.
+        std::fs::write(
+            &top_path,
+            build_image(
+                SECTOR_SIZE as u64 * 6,
+                Some(&middle_name),
+                &[data_offset],
+                1,
+            ),
+        )
+        .unwrap();
+
+        let backend = Qcow2Backend::open(&top_path);
+        std::fs::remove_file(&raw_path).unwrap();
+        std::fs::remove_file(&middle_path).unwrap();
+        std::fs::remove_file(&top_path).unwrap();
+        let mut backend = backend.unwrap();
+
+        assert_eq!(backend.capacity(), 6);
+        assert!(backend.readonly());
+        let mut buf = vec![0xff; SECTOR_SIZE * 6];
+        backend.read(0, &mut buf).unwrap();
+        let sectors: Vec<u8> = buf.chunks(SECTOR_SIZE).map(|sector| sector[0]).collect();
+        assert_eq!(sectors, [1, 2, 0, 7, 8, 0]);
+        for sector in buf.chunks(SECTOR_SIZE) {
+            assert!(sector.iter().all(|&byte| byte == sector[0]));
+        }
+
+        assert_eq!(
+            backend.read(5, &mut buf[..SECTOR_SIZE * 2]),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(
+            backend.write(0, &buf[..SECTOR_SIZE]),
+            Err(Error::Unsupported)
+        );
+    }
+
+    #[test]
+    fn reject_unsupported() {
+        let path = temp_path("unsupported.qcow2");
+
+        std::fs::write(&path, [0; CLUSTER_SIZE * 4]).unwrap();
+        assert_eq!(Qcow2Backend::open(&path).unwrap_err(), Qcow2Error::BadMagic);
+
+        let mut image = build_image(SECTOR_SIZE as u64, None, &[], 0);
+        image[32..36].copy_from_slice(&1u32.to_be_bytes());
+        std::fs::write(&path, &image).unwrap();
+        assert_eq!(
+            Qcow2Backend::open(&path).unwrap_err(),
+            Qcow2Error::Encrypted
+        );
+
+        let mut image = build_image(SECTOR_SIZE as u64, None, &[], 0);
+        image[72..80].copy_from_slice(&(1u64 << 2).to_be_bytes());
+        std::fs::write(&path, &image).unwrap();
+        assert_eq!(
+            Qcow2Backend::open(&path).unwrap_err(),
+            Qcow2Error::UnsupportedFeatures(1 << 2)
+        );
+
+        // A compressed cluster can't be read, but the rest of the image can.
+        let image = build_image(SECTOR_SIZE as u64 * 2, None, &[L2_COMPRESSED], 0);
+        std::fs::write(&path, &image).unwrap();
+        let mut backend = Qcow2Backend::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut buf = [0xff; SECTOR_SIZE];
+        assert_eq!(backend.read(0, &mut buf), Err(Error::Unsupported));
+        backend.read(1, &mut buf).unwrap();
+        assert_eq!(buf, [0; SECTOR_SIZE]);
+    }
+}
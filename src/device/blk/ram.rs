@@ -0,0 +1,101 @@
+//! In-memory storage for the device-side block implementation.
+
+use super::{BlkBackend, SECTOR_SIZE};
+use crate::{Error, Result};
+use alloc::{vec, vec::Vec};
+
+/// A [`BlkBackend`] which stores the disk contents in memory.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RamDisk {
+    data: Vec<u8>,
+    readonly: bool,
+    device_id: Vec<u8>,
+}
+
+impl RamDisk {
+    /// Creates a new RAM disk of the given number of 512 byte sectors, filled with zeroes.
+    pub fn new(sectors: usize) -> Self {
+        Self {
+            data: vec![0; sectors * SECTOR_SIZE],
+            readonly: false,
+            device_id: Vec::new(),
+        }
+    }
+
+    /// Creates a RAM disk with the given initial contents, whose length must be a multiple of
+    /// [`SECTOR_SIZE`].
+    pub fn from_vec(data: Vec<u8>) -> Result<Self> {
+        if !data.len().is_multiple_of(SECTOR_SIZE) {
+            return Err(Error::InvalidParam);
+        }
+        Ok(Self {
+            data,
+            readonly: false,
+            device_id: Vec::new(),
+        })
+    }
+
+    /// Sets whether the disk is read-only.
+    pub fn set_readonly(&mut self, readonly: bool) {
+        self.readonly = readonly;
+    }
+
+    /// Sets the ID string which the disk reports to the driver, which will be truncated to 20 bytes.
+    pub fn set_device_id(&mut self, device_id: &[u8]) {
+        self.device_id = device_id.to_vec();
+    }
+
+    /// Returns the current contents of the disk.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns the contents of the disk, consuming it.
+    pub fn into_vec(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// Returns the part of the disk starting at the given sector with the given length in bytes.
+    fn range(&mut self, sector: u64, len: usize) -> Result<&mut [u8]> {
+        let start = usize::try_from(sector)
+            .ok()
+            .and_then(|sector| sector.checked_mul(SECTOR_SIZE))
+            .ok_or(Error::InvalidParam)?;
+        let end = start.checked_add(len).ok_or(Error::InvalidParam)?;
+        self.data.get_mut(start..end).ok_or(Error::InvalidParam)
+    }
+}
+
+impl BlkBackend for RamDisk {
+    fn capacity(&self) -> u64 {
+        (self.data.len() / SECTOR_SIZE) as u64
+    }
+
+    fn readonly(&self) -> bool {
+        self.readonly
+    }
+
+    fn device_id(&self) -> &[u8] {
+        &self.device_id
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result {
+        buf.copy_from_slice(self.range(sector, buf.len())?);
+        Ok(())
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result {
+        self.range(sector, buf.len())?.copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn discard(&mut self, sector: u64, num_sectors: u32) -> Result {
+        self.write_zeroes(sector, num_sectors, true)
+    }
+
+    fn write_zeroes(&mut self, sector: u64, num_sectors: u32, _unmap: bool) -> Result {
+        self.range(sector, num_sectors as usize * SECTOR_SIZE)?
+            .fill(0);
+        Ok(())
+    }
+}
@@ -1,6 +1,16 @@
 //! Common part shared across all the devices.
 
+use crate::{
+    hal::{Hal, PhysAddr},
+    transport::{
+        wait_for_reset, DeviceStatus, DeviceType, InterruptStatus, SharedMemoryRegion, Transport,
+    },
+    Result,
+};
 use bitflags::bitflags;
+use core::{mem::ManuallyDrop, ptr};
+use log::warn;
+use zerocopy::{FromBytes, Immutable, IntoBytes};
 
 bitflags! {
     #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
@@ -20,5 +30,193 @@ bitflags! {
         const ORDER_PLATFORM        = 1 << 36;
         const SR_IOV                = 1 << 37;
         const NOTIFICATION_DATA     = 1 << 38;
+
+        // since virtio v1.3
+        const SUSPEND               = 1 << 42;
+    }
+}
+
+/// The transport of a driver, which resets the device and unsets the driver's queues when dropped.
+///
+/// Drivers keep this as their first field, so that it is dropped before their queues and other DMA
+/// buffers are freed, and the device can't access them afterwards.
+pub(crate) struct DriverTransport<T: Transport> {
+    transport: T,
+    /// The queues which the driver has set up.
+    queues: DriverQueues,
+}
+
+/// The queues which a driver has set up, to be unset when it is torn down.
+enum DriverQueues {
+    /// The queues with the given indices.
+    List(&'static [u16]),
+    /// Queues `0..n`, for drivers with a variable number of queues.
+    Count(u16),
+}
+
+impl<T: Transport> DriverTransport<T> {
+    /// Wraps the given transport, which has been initialised with the given queues.
+    pub fn new(transport: T, queues: &'static [u16]) -> Self {
+        Self {
+            transport,
+            queues: DriverQueues::List(queues),
+        }
+    }
+
+    /// Wraps the given transport, which has been initialised with queues `0..num_queues`.
+    pub fn with_queue_count(transport: T, num_queues: u16) -> Self {
+        Self {
+            transport,
+            queues: DriverQueues::Count(num_queues),
+        }
+    }
+
+    /// Resets the device, waits for the reset to complete, and unsets all the driver's queues.
+    ///
+    /// After this the device won't access any of the driver's queues or buffers. If the reset
+    /// doesn't complete, e.g. because the device has been removed, then this logs a warning and
+    /// gives up waiting rather than hanging.
+    pub fn teardown(&mut self) {
+        self.transport.set_status(DeviceStatus::empty());
+        if !wait_for_reset(&self.transport) {
+            warn!(
+                "{:?} device didn't complete reset, status {:?}",
+                self.transport.device_type(),
+                self.transport.get_status()
+            );
+        }
+        match self.queues {
+            DriverQueues::List(queues) => {
+                for &queue in queues {
+                    self.transport.queue_unset(queue);
+                }
+            }
+            DriverQueues::Count(num_queues) => {
+                for queue in 0..num_queues {
+                    self.transport.queue_unset(queue);
+                }
+            }
+        }
+    }
+
+    /// Tears down the device and returns the underlying transport, so that it can be used again.
+    pub fn into_inner(self) -> T {
+        let mut this = ManuallyDrop::new(self);
+        this.teardown();
+        // SAFETY: `this` is never used or dropped again, and `queues` doesn't need to be dropped, so
+        // the transport is moved out exactly once.
+        unsafe { ptr::read(&this.transport) }
+    }
+}
+
+impl<T: Transport> Drop for DriverTransport<T> {
+    fn drop(&mut self) {
+        self.teardown();
+    }
+}
+
+impl<T: Transport> Transport for DriverTransport<T> {
+    fn device_type(&self) -> DeviceType {
+        self.transport.device_type()
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        self.transport.read_device_features()
+    }
+
+    fn write_driver_features(&mut self, driver_features: u64) {
+        self.transport.write_driver_features(driver_features)
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u32 {
+        self.transport.max_queue_size(queue)
+    }
+
+    fn notify(&self, queue: u16) {
+        self.transport.notify(queue)
+    }
+
+    fn get_status(&self) -> DeviceStatus {
+        self.transport.get_status()
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        self.transport.set_status(status)
+    }
+
+    fn try_set_status(&mut self, status: DeviceStatus) -> Result<Option<DeviceStatus>> {
+        self.transport.try_set_status(status)
+    }
+
+    fn set_guest_page_size(&mut self, guest_page_size: u32) {
+        self.transport.set_guest_page_size(guest_page_size)
+    }
+
+    fn requires_legacy_layout(&self) -> bool {
+        self.transport.requires_legacy_layout()
+    }
+
+    fn requires_exact_queue_size(&self) -> bool {
+        self.transport.requires_exact_queue_size()
+    }
+
+    fn needs_reset(&self) -> bool {
+        self.transport.needs_reset()
+    }
+
+    fn reset_shared(&self) -> bool {
+        self.transport.reset_shared()
+    }
+
+    fn queue_set(
+        &mut self,
+        queue: u16,
+        size: u32,
+        descriptors: PhysAddr,
+        driver_area: PhysAddr,
+        device_area: PhysAddr,
+    ) {
+        self.transport
+            .queue_set(queue, size, descriptors, driver_area, device_area)
+    }
+
+    fn queue_unset(&mut self, queue: u16) {
+        self.transport.queue_unset(queue)
+    }
+
+    fn queue_used(&mut self, queue: u16) -> bool {
+        self.transport.queue_used(queue)
+    }
+
+    fn ack_interrupt(&mut self) -> InterruptStatus {
+        self.transport.ack_interrupt()
+    }
+
+    unsafe fn ack_interrupt_raw(ptr: *mut Self) -> InterruptStatus {
+        // SAFETY: The caller promised that `ptr` points to a valid `DriverTransport`, so this gives a
+        // valid pointer to the field.
+        let transport = unsafe { &raw mut (*ptr).transport };
+        // SAFETY: Delegated to the caller.
+        unsafe { T::ack_interrupt_raw(transport) }
+    }
+
+    fn read_config_generation(&self) -> u32 {
+        self.transport.read_config_generation()
+    }
+
+    fn read_config_space<C: FromBytes>(&self, offset: usize) -> Result<C> {
+        self.transport.read_config_space(offset)
+    }
+
+    fn write_config_space<C: IntoBytes + Immutable>(
+        &mut self,
+        offset: usize,
+        value: C,
+    ) -> Result<()> {
+        self.transport.write_config_space(offset, value)
+    }
+
+    fn shared_memory_region<H: Hal>(&mut self, id: u8) -> Option<SharedMemoryRegion> {
+        self.transport.shared_memory_region::<H>(id)
     }
 }
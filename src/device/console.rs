@@ -3,6 +3,7 @@
 #[cfg(feature = "embedded-io")]
 mod embedded_io;
 
+use super::common::DriverTransport;
 use crate::config::{read_config, write_config, ReadOnly, WriteOnly};
 use crate::hal::Hal;
 use crate::queue::VirtQueue;
@@ -48,7 +49,7 @@ const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX
 /// # }
 /// ```
 pub struct VirtIOConsole<H: Hal, T: Transport> {
-    transport: T,
+    transport: DriverTransport<T>,
     negotiated_features: Features,
     receiveq: VirtQueue<H, QUEUE_SIZE>,
     transmitq: VirtQueue<H, QUEUE_SIZE>,
@@ -112,7 +113,10 @@ impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
 
         transport.finish_init();
         let mut console = VirtIOConsole {
-            transport,
+            transport: DriverTransport::new(
+                transport,
+                &[QUEUE_RECEIVEQ_PORT_0, QUEUE_TRANSMITQ_PORT_0],
+            ),
             negotiated_features,
             receiveq,
             transmitq,
@@ -125,6 +129,13 @@ impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
         Ok(console)
     }
 
+    /// Resets the device and returns its transport, so that it can be used by another driver.
+    ///
+    /// Any requests which were submitted but not yet completed are abandoned.
+    pub fn into_transport(self) -> T {
+        self.transport.into_inner()
+    }
+
     /// Returns the size of the console, if the device supports reporting this.
     pub fn size(&self) -> Result<Option<Size>> {
         if self.negotiated_features.contains(Features::SIZE) {
@@ -259,15 +270,6 @@ impl<H: Hal, T: Transport> Write for VirtIOConsole<H, T> {
     }
 }
 
-impl<H: Hal, T: Transport> Drop for VirtIOConsole<H, T> {
-    fn drop(&mut self) {
-        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
-        // after they have been freed.
-        self.transport.queue_unset(QUEUE_RECEIVEQ_PORT_0);
-        self.transport.queue_unset(QUEUE_TRANSMITQ_PORT_0);
-    }
-}
-
 #[derive(FromBytes, Immutable, IntoBytes)]
 #[repr(C)]
 struct Config {
@@ -1,5 +1,6 @@
 //! Driver for VirtIO GPU devices.
 
+use super::common::DriverTransport;
 use crate::config::{read_config, ReadOnly, WriteOnly};
 use crate::hal::{BufferDirection, Dma, DmaMemory, Hal};
 use crate::queue::VirtQueue;
@@ -23,7 +24,7 @@ const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX
 /// In 2D mode the virtio-gpu device provides support for ARGB Hardware cursors
 /// and multiple scanouts (aka heads).
 pub struct VirtIOGpu<H: Hal, T: Transport> {
-    transport: T,
+    transport: DriverTransport<T>,
     rect: Option<Rect>,
     /// DMA area of frame buffer.
     frame_buffer_dma: Option<Dma<H>>,
@@ -71,7 +72,7 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
         transport.finish_init();
 
         Ok(VirtIOGpu {
-            transport,
+            transport: DriverTransport::new(transport, &[QUEUE_TRANSMIT, QUEUE_CURSOR]),
             frame_buffer_dma: None,
             cursor_buffer_dma: None,
             rect: None,
@@ -82,6 +83,13 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
         })
     }
 
+    /// Resets the device and returns its transport, so that it can be used by another driver.
+    ///
+    /// Any requests which were submitted but not yet completed are abandoned.
+    pub fn into_transport(self) -> T {
+        self.transport.into_inner()
+    }
+
     /// Acknowledge interrupt.
     pub fn ack_interrupt(&mut self) -> InterruptStatus {
         self.transport.ack_interrupt()
@@ -296,15 +304,6 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
     }
 }
 
-impl<H: Hal, T: Transport> Drop for VirtIOGpu<H, T> {
-    fn drop(&mut self) {
-        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
-        // after they have been freed.
-        self.transport.queue_unset(QUEUE_TRANSMIT);
-        self.transport.queue_unset(QUEUE_CURSOR);
-    }
-}
-
 #[repr(C)]
 struct Config {
     /// Signals pending events to the driver。
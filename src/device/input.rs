@@ -1,6 +1,6 @@
 //! Driver for VirtIO input devices.
 
-use super::common::Feature;
+use super::common::{DriverTransport, Feature};
 use crate::config::{read_config, write_config, ReadOnly, WriteOnly};
 use crate::hal::Hal;
 use crate::queue::VirtQueue;
@@ -17,7 +17,7 @@ use zerocopy::{FromBytes, FromZeros, Immutable, IntoBytes, KnownLayout};
 /// Device behavior mirrors that of the evdev layer in Linux,
 /// making pass-through implementations on top of evdev easy.
 pub struct VirtIOInput<H: Hal, T: Transport> {
-    transport: T,
+    transport: DriverTransport<T>,
     event_queue: VirtQueue<H, QUEUE_SIZE>,
     status_queue: VirtQueue<H, QUEUE_SIZE>,
     event_buf: Box<[InputEvent; 32]>,
@@ -54,13 +54,20 @@ impl<H: Hal, T: Transport> VirtIOInput<H, T> {
         transport.finish_init();
 
         Ok(VirtIOInput {
-            transport,
+            transport: DriverTransport::new(transport, &[QUEUE_EVENT, QUEUE_STATUS]),
             event_queue,
             status_queue,
             event_buf,
         })
     }
 
+    /// Resets the device and returns its transport, so that it can be used by another driver.
+    ///
+    /// Any requests which were submitted but not yet completed are abandoned.
+    pub fn into_transport(self) -> T {
+        self.transport.into_inner()
+    }
+
     /// Acknowledge interrupt and process events.
     pub fn ack_interrupt(&mut self) -> InterruptStatus {
         self.transport.ack_interrupt()
@@ -210,15 +217,6 @@ unsafe impl<H: Hal, T: Transport + Sync> Sync for VirtIOInput<H, T> where
 {
 }
 
-impl<H: Hal, T: Transport> Drop for VirtIOInput<H, T> {
-    fn drop(&mut self) {
-        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
-        // after they have been freed.
-        self.transport.queue_unset(QUEUE_EVENT);
-        self.transport.queue_unset(QUEUE_STATUS);
-    }
-}
-
 const CONFIG_DATA_MAX_LENGTH: usize = 128;
 
 /// Select value used for [`VirtIOInput::query_config_select()`].
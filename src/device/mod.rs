@@ -10,6 +10,8 @@ pub mod input;
 
 pub mod net;
 
+#[cfg(feature = "alloc")]
+pub mod probe;
 pub mod rng;
 
 pub mod socket;
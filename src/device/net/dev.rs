@@ -1,4 +1,5 @@
 use alloc::vec;
+use core::mem::replace;
 
 use super::net_buf::{RxBuffer, TxBuffer};
 use super::{EthernetAddress, VirtIONetRaw};
@@ -41,6 +42,57 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE>
         Ok(VirtIONet { inner, rx_buffers })
     }
 
+    /// Resets the device and returns its transport, so that it can be used by another driver.
+    ///
+    /// The receive buffers are freed once the device has been reset.
+    pub fn into_transport(self) -> T {
+        self.inner.into_transport()
+    }
+
+    /// Returns whether the device has reported an error from which it can't recover, so must be
+    /// [`reset`](Self::reset) before it can be used again.
+    ///
+    /// This should be checked after a configuration change interrupt. [`send`](Self::send) fails
+    /// with [`Error::DeviceNeedsReset`] if this happens while it is waiting.
+    pub fn needs_reset(&self) -> bool {
+        self.inner.needs_reset()
+    }
+
+    /// Resets the device and reinitialises it.
+    ///
+    /// Any packets which the device had received into buffers which haven't yet been returned by
+    /// [`receive`](Self::receive) are dropped, and the receive buffers are submitted to the device
+    /// again. Receive buffers which the caller currently owns may still be recycled afterwards as
+    /// usual.
+    pub fn reset(&mut self) -> Result {
+        self.inner.reset()?;
+
+        const NONE_BUF: Option<RxBuffer> = None;
+        let rx_buffers = replace(&mut self.rx_buffers, [NONE_BUF; QUEUE_SIZE]);
+        for mut rx_buf in rx_buffers.into_iter().flatten() {
+            // SAFETY: The buffer lives as long as the queue.
+            let token = unsafe { self.inner.receive_begin(rx_buf.as_bytes_mut())? };
+            rx_buf.idx = token;
+            self.rx_buffers[token as usize] = Some(rx_buf);
+        }
+        Ok(())
+    }
+
+    /// Waits for any packets being transmitted to complete and then suspends the device.
+    ///
+    /// See [`VirtIONetRaw::suspend`].
+    pub fn suspend(&mut self) -> Result {
+        self.inner.suspend()
+    }
+
+    /// Resumes the device after it was suspended with [`suspend`](Self::suspend), and notifies the
+    /// receive queue so that the device picks up the receive buffers which are already posted.
+    ///
+    /// See [`VirtIONetRaw::resume`].
+    pub fn resume(&mut self) -> Result {
+        self.inner.resume()
+    }
+
     /// Acknowledge interrupt.
     pub fn ack_interrupt(&mut self) -> InterruptStatus {
         self.inner.ack_interrupt()
@@ -1,9 +1,10 @@
 use super::{Config, EthernetAddress, Features, VirtioNetHdr, VirtioNetHdrLegacy};
 use super::{MIN_BUFFER_LEN, QUEUE_RECEIVE, QUEUE_TRANSMIT, SUPPORTED_FEATURES};
 use crate::config::read_config;
+use crate::device::common::DriverTransport;
 use crate::hal::Hal;
 use crate::queue::VirtQueue;
-use crate::transport::{InterruptStatus, Transport};
+use crate::transport::{DeviceStatus, InterruptStatus, Transport};
 use crate::{Error, Result};
 use core::mem::size_of;
 use log::{debug, info, warn};
@@ -18,33 +19,64 @@ use zerocopy::IntoBytes;
 ///
 /// [`VirtIONet`]: super::VirtIONet
 pub struct VirtIONetRaw<H: Hal, T: Transport, const QUEUE_SIZE: usize> {
-    transport: T,
+    transport: DriverTransport<T>,
     mac: EthernetAddress,
     recv_queue: VirtQueue<H, QUEUE_SIZE>,
     send_queue: VirtQueue<H, QUEUE_SIZE>,
     /// Whether `num_buffers` is missing in the `virtio_net_hdr` struct.
     pub(crate) legacy_header: bool,
+    negotiated_features: Features,
 }
 
 impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZE> {
     /// Create a new VirtIO-Net driver.
     pub fn new(mut transport: T) -> Result<Self> {
+        let (negotiated_features, mac, send_queue, recv_queue) = Self::init(&mut transport)?;
+        Ok(VirtIONetRaw {
+            transport: DriverTransport::new(transport, &[QUEUE_RECEIVE, QUEUE_TRANSMIT]),
+            mac,
+            recv_queue,
+            send_queue,
+            legacy_header: Self::uses_legacy_header(negotiated_features),
+            negotiated_features,
+        })
+    }
+
+    /// Resets the device and returns its transport, so that it can be used by another driver.
+    ///
+    /// Any requests which were submitted but not yet completed are abandoned.
+    pub fn into_transport(self) -> T {
+        self.transport.into_inner()
+    }
+
+    /// Negotiates features, reads the configuration and sets up the queues, leaving the device ready
+    /// to use.
+    ///
+    /// Returns the negotiated features, MAC address, and transmit and receive queues.
+    fn init(
+        transport: &mut impl Transport,
+    ) -> Result<(
+        Features,
+        EthernetAddress,
+        VirtQueue<H, QUEUE_SIZE>,
+        VirtQueue<H, QUEUE_SIZE>,
+    )> {
         let negotiated_features = transport.begin_init(SUPPORTED_FEATURES)?;
         info!("negotiated_features {:?}", negotiated_features);
 
         // Read configuration space.
-        let mac = transport.read_consistent(|| read_config!(transport, Config, mac))?;
-        let status = read_config!(transport, Config, status)?;
+        let mac = transport.read_consistent(|| read_config!(*transport, Config, mac))?;
+        let status = read_config!(*transport, Config, status)?;
         debug!("Got MAC={:02x?}, status={:?}", mac, status);
 
         let send_queue = VirtQueue::new(
-            &mut transport,
+            transport,
             QUEUE_TRANSMIT,
             negotiated_features.contains(Features::RING_INDIRECT_DESC),
             negotiated_features.contains(Features::RING_EVENT_IDX),
         )?;
         let recv_queue = VirtQueue::new(
-            &mut transport,
+            transport,
             QUEUE_RECEIVE,
             negotiated_features.contains(Features::RING_INDIRECT_DESC),
             negotiated_features.contains(Features::RING_EVENT_IDX),
@@ -52,14 +84,70 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
 
         transport.finish_init();
 
-        Ok(VirtIONetRaw {
-            transport,
-            mac,
-            recv_queue,
-            send_queue,
-            legacy_header: !negotiated_features.contains(Features::VERSION_1)
-                && !negotiated_features.contains(Features::MRG_RXBUF),
-        })
+        Ok((negotiated_features, mac, send_queue, recv_queue))
+    }
+
+    /// Returns whether `num_buffers` is missing in the `virtio_net_hdr` struct with the given
+    /// negotiated features.
+    fn uses_legacy_header(negotiated_features: Features) -> bool {
+        !negotiated_features.contains(Features::VERSION_1)
+            && !negotiated_features.contains(Features::MRG_RXBUF)
+    }
+
+    /// Returns whether the device has reported an error from which it can't recover, so must be
+    /// [`reset`](Self::reset) before it can be used again.
+    ///
+    /// This should be checked after a configuration change interrupt. [`send`](Self::send) fails
+    /// with [`Error::DeviceNeedsReset`] if this happens while it is waiting.
+    pub fn needs_reset(&self) -> bool {
+        // A request which was waiting when the device failed will have reset it already.
+        self.transport.needs_reset()
+            || !self
+                .transport
+                .get_status()
+                .contains(DeviceStatus::DRIVER_OK)
+    }
+
+    /// Resets the device and reinitialises it, including renegotiating features and rereading the
+    /// MAC address.
+    ///
+    /// Any transmit or receive requests which were submitted but not yet completed are abandoned:
+    /// the device won't complete them, and their tokens are no longer valid. Once this returns
+    /// their buffers may be reused, and receive buffers must be submitted again.
+    pub fn reset(&mut self) -> Result {
+        self.transport.teardown();
+        let (negotiated_features, mac, send_queue, recv_queue) = Self::init(&mut self.transport)?;
+        self.mac = mac;
+        self.send_queue = send_queue;
+        self.recv_queue = recv_queue;
+        self.legacy_header = Self::uses_legacy_header(negotiated_features);
+        self.negotiated_features = negotiated_features;
+        Ok(())
+    }
+
+    /// Waits for any packets being transmitted to complete and then suspends the device, e.g.
+    /// before taking a snapshot of the guest or entering a sleep state.
+    ///
+    /// Receive buffers stay in the receive queue while the device is suspended. No packets may be
+    /// sent until the device is [resumed](Self::resume).
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support `VIRTIO_F_SUSPEND`.
+    pub fn suspend(&mut self) -> Result {
+        if !self.negotiated_features.contains(Features::SUSPEND) {
+            return Err(Error::Unsupported);
+        }
+        self.send_queue.wait_until_idle(&self.transport)?;
+        self.transport.suspend()
+    }
+
+    /// Resumes the device after it was suspended with [`suspend`](Self::suspend).
+    ///
+    /// The receive queue is notified again, so that the device picks up any receive buffers which
+    /// were posted while it was suspended.
+    pub fn resume(&mut self) -> Result {
+        self.transport.resume()?;
+        self.transport.notify(QUEUE_RECEIVE);
+        Ok(())
     }
 
     /// Acknowledge interrupt.
@@ -300,12 +388,3 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
         unsafe { self.receive_complete(token, rx_buf) }
     }
 }
-
-impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> Drop for VirtIONetRaw<H, T, QUEUE_SIZE> {
-    fn drop(&mut self) {
-        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
-        // after they have been freed.
-        self.transport.queue_unset(QUEUE_RECEIVE);
-        self.transport.queue_unset(QUEUE_TRANSMIT);
-    }
-}
@@ -77,6 +77,7 @@ bitflags! {
         const RING_INDIRECT_DESC = 1 << 28;
         const RING_EVENT_IDX = 1 << 29;
         const VERSION_1 = 1 << 32; // legacy
+        const SUSPEND = 1 << 42;
     }
 }
 
@@ -200,4 +201,5 @@ const SUPPORTED_FEATURES: Features = Features::MAC
     .union(Features::STATUS)
     .union(Features::RING_EVENT_IDX)
     .union(Features::RING_INDIRECT_DESC)
-    .union(Features::VERSION_1);
+    .union(Features::VERSION_1)
+    .union(Features::SUSPEND);
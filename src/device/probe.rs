@@ -0,0 +1,273 @@
+//! Generic probing of VirtIO devices, constructing the appropriate driver for whatever type of
+//! device is behind a transport.
+//!
+//! This saves every user of the crate from writing the same `match transport.device_type()` block
+//! after discovering devices. [`probe`] takes any [`Transport`], such as a
+//! [`SomeTransport`](crate::transport::SomeTransport), initialises the matching driver and returns
+//! it as a [`VirtIODevice`]. Anything that needs to vary by device type can be customised with a
+//! [`ProbeHooks`] implementation.
+
+use super::{
+    blk::VirtIOBlk, console::VirtIOConsole, gpu::VirtIOGpu, input::VirtIOInput, net::VirtIONet,
+    rng::VirtIORng, socket::VirtIOSocket, sound::VirtIOSound,
+};
+use crate::{
+    transport::{DeviceType, Transport},
+    Error, Hal, LockFactory,
+};
+use alloc::boxed::Box;
+use core::fmt::{self, Debug, Formatter};
+
+/// The queue size used for network devices constructed by [`probe`].
+pub const NET_QUEUE_SIZE: usize = 16;
+
+/// The default receive buffer length used for network devices constructed by [`probe`].
+pub const DEFAULT_NET_BUFFER_LEN: usize = 2048;
+
+/// An initialised driver for some type of VirtIO device, returned by [`probe`].
+///
+/// `L` is the lock factory used by the socket driver. The drivers are boxed, as some of them are
+/// quite large.
+pub enum VirtIODevice<H: Hal, T: Transport, L: LockFactory> {
+    /// A block device.
+    Block(Box<VirtIOBlk<H, T>>),
+    /// A console device.
+    Console(Box<VirtIOConsole<H, T>>),
+    /// An entropy source.
+    EntropySource(Box<VirtIORng<H, T>>),
+    /// A GPU.
+    Gpu(Box<VirtIOGpu<H, T>>),
+    /// An input device.
+    Input(Box<VirtIOInput<H, T>>),
+    /// A network device.
+    Network(Box<VirtIONet<H, T, NET_QUEUE_SIZE>>),
+    /// A socket device.
+    Socket(Box<VirtIOSocket<H, T, L>>),
+    /// A sound device.
+    Sound(Box<VirtIOSound<H, T>>),
+}
+
+impl<H: Hal, T: Transport, L: LockFactory> VirtIODevice<H, T, L> {
+    /// Returns the type of the device.
+    pub fn device_type(&self) -> DeviceType {
+        match self {
+            Self::Block(_) => DeviceType::Block,
+            Self::Console(_) => DeviceType::Console,
+            Self::EntropySource(_) => DeviceType::EntropySource,
+            Self::Gpu(_) => DeviceType::GPU,
+            Self::Input(_) => DeviceType::Input,
+            Self::Network(_) => DeviceType::Network,
+            Self::Socket(_) => DeviceType::Socket,
+            Self::Sound(_) => DeviceType::Sound,
+        }
+    }
+
+    /// Resets the device and returns its transport, so that it can be used by another driver.
+    pub fn into_transport(self) -> T {
+        match self {
+            Self::Block(device) => device.into_transport(),
+            Self::Console(device) => device.into_transport(),
+            Self::EntropySource(device) => device.into_transport(),
+            Self::Gpu(device) => device.into_transport(),
+            Self::Input(device) => device.into_transport(),
+            Self::Network(device) => device.into_transport(),
+            Self::Socket(device) => device.into_transport(),
+            Self::Sound(device) => device.into_transport(),
+        }
+    }
+}
+
+impl<H: Hal, T: Transport, L: LockFactory> Debug for VirtIODevice<H, T, L> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("VirtIODevice")
+            .field(&self.device_type())
+            .finish()
+    }
+}
+
+/// Hooks to customise how [`probe`] constructs drivers.
+///
+/// All methods have default implementations, so implementations only need to override those they
+/// care about. `()` implements this trait with all the defaults.
+pub trait ProbeHooks<H: Hal, T: Transport, L: LockFactory> {
+    /// Returns whether a driver should be constructed for a device of the given type.
+    ///
+    /// If this returns false then [`probe`] fails with [`ProbeError::Unsupported`], giving the
+    /// transport back to the caller.
+    fn accept(&mut self, device_type: DeviceType) -> bool {
+        let _ = device_type;
+        true
+    }
+
+    /// Returns the receive buffer length to use for a network device.
+    fn net_buffer_len(&mut self) -> usize {
+        DEFAULT_NET_BUFFER_LEN
+    }
+
+    /// Configures a newly initialised driver before it is returned from [`probe`].
+    ///
+    /// If this returns an error then the driver is dropped and [`probe`] fails with
+    /// [`ProbeError::Driver`].
+    fn configure(&mut self, device: &mut VirtIODevice<H, T, L>) -> Result<(), Error> {
+        let _ = device;
+        Ok(())
+    }
+}
+
+impl<H: Hal, T: Transport, L: LockFactory> ProbeHooks<H, T, L> for () {}
+
+/// An error probing a VirtIO device.
+#[derive(Error)]
+pub enum ProbeError<T> {
+    /// There is no driver for the type of device, or the hooks declined it. The transport is
+    /// returned so that the caller can do something else with it.
+    #[error("No driver for device type {device_type:?}")]
+    Unsupported {
+        /// The type of the device.
+        device_type: DeviceType,
+        /// The transport for the device.
+        transport: T,
+    },
+    /// The driver failed to initialise the device, or the hooks failed to configure it.
+    #[error("Error initialising driver: {0}")]
+    Driver(Error),
+}
+
+impl<T> Debug for ProbeError<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unsupported { device_type, .. } => f
+                .debug_struct("Unsupported")
+                .field("device_type", device_type)
+                .finish_non_exhaustive(),
+            Self::Driver(e) => f.debug_tuple("Driver").field(e).finish(),
+        }
+    }
+}
+
+impl<T> From<Error> for ProbeError<T> {
+    fn from(e: Error) -> Self {
+        Self::Driver(e)
+    }
+}
+
+/// Constructs and initialises the appropriate driver for the device behind the given transport.
+///
+/// `hooks` may decline device types and customise drivers; pass `&mut ()` for the defaults.
+pub fn probe<H: Hal, T: Transport, L: LockFactory>(
+    transport: T,
+    hooks: &mut impl ProbeHooks<H, T, L>,
+) -> Result<VirtIODevice<H, T, L>, ProbeError<T>> {
+    let device_type = transport.device_type();
+    if !hooks.accept(device_type) {
+        return Err(ProbeError::Unsupported {
+            device_type,
+            transport,
+        });
+    }
+    let mut device = match device_type {
+        DeviceType::Block => VirtIODevice::Block(Box::new(VirtIOBlk::new(transport)?)),
+        DeviceType::Console => VirtIODevice::Console(Box::new(VirtIOConsole::new(transport)?)),
+        DeviceType::EntropySource => {
+            VirtIODevice::EntropySource(Box::new(VirtIORng::new(transport)?))
+        }
+        DeviceType::GPU => VirtIODevice::Gpu(Box::new(VirtIOGpu::new(transport)?)),
+        DeviceType::Input => VirtIODevice::Input(Box::new(VirtIOInput::new(transport)?)),
+        DeviceType::Network => {
+            VirtIODevice::Network(Box::new(VirtIONet::new(transport, hooks.net_buffer_len())?))
+        }
+        DeviceType::Socket => VirtIODevice::Socket(Box::new(VirtIOSocket::new(transport)?)),
+        DeviceType::Sound => VirtIODevice::Sound(Box::new(VirtIOSound::new(transport)?)),
+        _ => {
+            return Err(ProbeError::Unsupported {
+                device_type,
+                transport,
+            })
+        }
+    };
+    hooks.configure(&mut device)?;
+    Ok(device)
+}
+
+#[cfg(all(test, feature = "spin"))]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceStatus,
+        },
+        SpinLockFactory,
+    };
+    use alloc::{sync::Arc, vec};
+    use std::sync::Mutex;
+
+    type Device = VirtIODevice<FakeHal, FakeTransport<()>, SpinLockFactory>;
+
+    fn transport(device_type: DeviceType) -> FakeTransport<()> {
+        FakeTransport {
+            device_type,
+            max_queue_size: 8,
+            device_features: 0,
+            state: Arc::new(Mutex::new(State::new(vec![QueueStatus::default()], ()))),
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordingHooks {
+        configured: Option<DeviceType>,
+    }
+
+    impl ProbeHooks<FakeHal, FakeTransport<()>, SpinLockFactory> for RecordingHooks {
+        fn accept(&mut self, device_type: DeviceType) -> bool {
+            device_type != DeviceType::Console
+        }
+
+        fn configure(&mut self, device: &mut Device) -> Result<(), Error> {
+            self.configured = Some(device.device_type());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn probe_rng() {
+        let transport = transport(DeviceType::EntropySource);
+        let state = transport.state.clone();
+        let mut hooks = RecordingHooks::default();
+        let device: Device = probe(transport, &mut hooks).unwrap();
+
+        assert!(matches!(device, VirtIODevice::EntropySource(_)));
+        assert_eq!(hooks.configured, Some(DeviceType::EntropySource));
+        assert!(state
+            .lock()
+            .unwrap()
+            .status
+            .contains(DeviceStatus::DRIVER_OK));
+    }
+
+    #[test]
+    fn probe_unsupported() {
+        let Err(ProbeError::Unsupported {
+            device_type,
+            transport,
+        }) = probe::<FakeHal, _, SpinLockFactory>(transport(DeviceType::Crypto), &mut ())
+        else {
+            panic!("Expected unsupported error");
+        };
+        assert_eq!(device_type, DeviceType::Crypto);
+        assert_eq!(transport.device_type, DeviceType::Crypto);
+    }
+
+    #[test]
+    fn probe_declined_by_hooks() {
+        let mut hooks = RecordingHooks::default();
+        let Err(ProbeError::Unsupported { device_type, .. }) =
+            probe(transport(DeviceType::Console), &mut hooks)
+        else {
+            panic!("Expected unsupported error");
+        };
+        assert_eq!(device_type, DeviceType::Console);
+        assert_eq!(hooks.configured, None);
+    }
+}
@@ -1,5 +1,5 @@
 //! Driver for VirtIO random number generator devices.
-use super::common::Feature;
+use super::common::{DriverTransport, Feature};
 use crate::{
     queue::VirtQueue,
     transport::{InterruptStatus, Transport},
@@ -15,7 +15,7 @@ const SUPPORTED_FEATURES: Feature = Feature::RING_INDIRECT_DESC
 
 /// Driver for a VirtIO random number generator device.
 pub struct VirtIORng<H: Hal, T: Transport> {
-    transport: T,
+    transport: DriverTransport<T>,
     queue: VirtQueue<H, QUEUE_SIZE>,
 }
 
@@ -30,7 +30,17 @@ impl<H: Hal, T: Transport> VirtIORng<H, T> {
             feat.contains(Feature::RING_EVENT_IDX),
         )?;
         transport.finish_init();
-        Ok(Self { transport, queue })
+        Ok(Self {
+            transport: DriverTransport::new(transport, &[QUEUE_IDX]),
+            queue,
+        })
+    }
+
+    /// Resets the device and returns its transport, so that it can be used by another driver.
+    ///
+    /// Any requests which were submitted but not yet completed are abandoned.
+    pub fn into_transport(self) -> T {
+        self.transport.into_inner()
     }
 
     /// Request random bytes from the device to be stored into `dst`.
@@ -56,9 +66,3 @@ impl<H: Hal, T: Transport> VirtIORng<H, T> {
         self.transport.ack_interrupt()
     }
 }
-
-impl<H: Hal, T: Transport> Drop for VirtIORng<H, T> {
-    fn drop(&mut self) {
-        self.transport.queue_unset(QUEUE_IDX);
-    }
-}
@@ -213,6 +213,9 @@ bitflags! {
         const ORDER_PLATFORM        = 1 << 36;
         const SR_IOV                = 1 << 37;
         const NOTIFICATION_DATA     = 1 << 38;
+
+        // since virtio v1.3
+        const SUSPEND               = 1 << 42;
     }
 }
 
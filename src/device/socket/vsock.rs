@@ -9,10 +9,11 @@ use super::protocol::{
 };
 use super::DEFAULT_RX_BUFFER_SIZE;
 use crate::config::read_config;
+use crate::device::common::DriverTransport;
 use crate::hal::{DeviceHal, Hal};
 use crate::queue::{owning::OwningQueue, DeviceVirtQueue, VirtQueue};
 use crate::transport::{DeviceTransport, InterruptStatus, Transport};
-use crate::{Lock, LockFactory, Result};
+use crate::{Error, Lock, LockFactory, Result};
 use alloc::sync::Arc;
 use core::mem::size_of;
 use log::debug;
@@ -25,7 +26,8 @@ const EVENT_QUEUE_IDX: u16 = 2;
 pub(crate) const QUEUE_SIZE: usize = 8;
 const SUPPORTED_FEATURES: Feature = Feature::RING_EVENT_IDX
     .union(Feature::RING_INDIRECT_DESC)
-    .union(Feature::VERSION_1);
+    .union(Feature::VERSION_1)
+    .union(Feature::SUSPEND);
 
 /// Information about a particular vsock connection.
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
@@ -231,7 +233,7 @@ pub struct VirtIOSocket<
     L: LockFactory,
     const RX_BUFFER_SIZE: usize = DEFAULT_RX_BUFFER_SIZE,
 > {
-    transport: T,
+    transport: DriverTransport<T>,
     /// Virtqueue to receive packets.
     rx: L::Lock<OwningQueue<H, QUEUE_SIZE, RX_BUFFER_SIZE>>,
     tx: L::Lock<VirtQueue<H, { QUEUE_SIZE }>>,
@@ -240,18 +242,7 @@ pub struct VirtIOSocket<
     /// The guest_cid field contains the guest’s context ID, which uniquely identifies
     /// the device for its lifetime. The upper 32 bits of the CID are reserved and zeroed.
     guest_cid: u64,
-}
-
-impl<H: Hal, T: Transport, L: LockFactory, const RX_BUFFER_SIZE: usize> Drop
-    for VirtIOSocket<H, T, L, RX_BUFFER_SIZE>
-{
-    fn drop(&mut self) {
-        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
-        // after they have been freed.
-        self.transport.queue_unset(RX_QUEUE_IDX);
-        self.transport.queue_unset(TX_QUEUE_IDX);
-        self.transport.queue_unset(EVENT_QUEUE_IDX);
-    }
+    negotiated_features: Feature,
 }
 
 impl<H: Hal, T: Transport, L: LockFactory, const RX_BUFFER_SIZE: usize>
@@ -298,14 +289,48 @@ impl<H: Hal, T: Transport, L: LockFactory, const RX_BUFFER_SIZE: usize>
         }
 
         Ok(Self {
-            transport,
+            transport: DriverTransport::new(
+                transport,
+                &[RX_QUEUE_IDX, TX_QUEUE_IDX, EVENT_QUEUE_IDX],
+            ),
             rx: L::Lock::new(rx),
             tx: L::Lock::new(tx),
             event: L::Lock::new(event),
             guest_cid,
+            negotiated_features,
         })
     }
 
+    /// Resets the device and returns its transport, so that it can be used by another driver.
+    ///
+    /// Any requests which were submitted but not yet completed are abandoned.
+    pub fn into_transport(self) -> T {
+        self.transport.into_inner()
+    }
+
+    /// Waits for any packets being transmitted to complete and then suspends the device, e.g.
+    /// before taking a snapshot of the guest or entering a sleep state.
+    ///
+    /// No packets may be sent until the device is [resumed](Self::resume).
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support `VIRTIO_F_SUSPEND`.
+    pub fn suspend(&mut self) -> Result {
+        if !self.negotiated_features.contains(Feature::SUSPEND) {
+            return Err(Error::Unsupported);
+        }
+        self.tx.lock().wait_until_idle(&self.transport)?;
+        self.transport.suspend()
+    }
+
+    /// Resumes the device after it was suspended with [`suspend`](Self::suspend).
+    ///
+    /// The receive queue is notified again, so that the device picks up the receive buffers.
+    pub fn resume(&mut self) -> Result {
+        self.transport.resume()?;
+        self.transport.notify(RX_QUEUE_IDX);
+        Ok(())
+    }
+
     /// Returns the CID which has been assigned to this guest.
     pub fn guest_cid(&self) -> u64 {
         self.guest_cid
@@ -414,7 +439,7 @@ impl<H: Hal, T: Transport, L: LockFactory, const RX_BUFFER_SIZE: usize>
         // VirtIOSocket so this gives a valid pointer to the field.
         let transport_ptr = unsafe { &raw mut (*ptr).transport };
         // SAFETY: delegated to the caller
-        unsafe { T::ack_interrupt_raw(transport_ptr) }
+        unsafe { DriverTransport::<T>::ack_interrupt_raw(transport_ptr) }
     }
 }
 
@@ -675,4 +700,37 @@ mod tests {
             .unwrap();
         assert_eq!(socket.guest_cid(), 0x00_0000_0042);
     }
+
+    #[test]
+    fn ack_interrupt() {
+        let config_space = VirtioVsockConfig {
+            guest_cid_low: ReadOnly::new(66),
+            guest_cid_high: ReadOnly::new(0),
+        };
+        let state = Arc::new(Mutex::new(State::new(
+            vec![
+                QueueStatus::default(),
+                QueueStatus::default(),
+                QueueStatus::default(),
+            ],
+            config_space,
+        )));
+        let transport = FakeTransport {
+            device_type: DeviceType::Socket,
+            max_queue_size: 32,
+            device_features: 0,
+            state: state.clone(),
+        };
+        let mut socket =
+            VirtIOSocket::<FakeHal, FakeTransport<VirtioVsockConfig>, SpinLockFactory>::new(
+                transport,
+            )
+            .unwrap();
+
+        state.lock().unwrap().interrupt_pending = true;
+        // SAFETY: The socket is valid and isn't being used concurrently.
+        let status = unsafe { VirtIOSocket::ack_interrupt(&mut socket) };
+        assert!(status == InterruptStatus::QUEUE_INTERRUPT);
+        assert!(!state.lock().unwrap().interrupt_pending);
+    }
 }
@@ -3,7 +3,7 @@
 #[cfg(test)]
 mod fake;
 
-use super::common::Feature;
+use super::common::{DriverTransport, Feature};
 use crate::{
     config::{read_config, ReadOnly},
     queue::{owning::OwningQueue, VirtQueue},
@@ -29,7 +29,7 @@ use zerocopy::{FromBytes, FromZeros, Immutable, IntoBytes, KnownLayout};
 ///
 /// Currently, only audio playback functionality has been implemented.
 pub struct VirtIOSound<H: Hal, T: Transport> {
-    transport: T,
+    transport: DriverTransport<T>,
 
     control_queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
     event_queue: OwningQueue<H, { QUEUE_SIZE as usize }, { size_of::<VirtIOSndEvent>() }>,
@@ -120,7 +120,15 @@ impl<H: Hal, T: Transport> VirtIOSound<H, T> {
         }
 
         Ok(VirtIOSound {
-            transport,
+            transport: DriverTransport::new(
+                transport,
+                &[
+                    CONTROL_QUEUE_IDX,
+                    EVENT_QUEUE_IDX,
+                    TX_QUEUE_IDX,
+                    RX_QUEUE_IDX,
+                ],
+            ),
             control_queue,
             event_queue,
             tx_queue,
@@ -142,6 +150,13 @@ impl<H: Hal, T: Transport> VirtIOSound<H, T> {
         })
     }
 
+    /// Resets the device and returns its transport, so that it can be used by another driver.
+    ///
+    /// Any requests which were submitted but not yet completed are abandoned.
+    pub fn into_transport(self) -> T {
+        self.transport.into_inner()
+    }
+
     /// Total jack num.
     pub fn jacks(&self) -> u32 {
         self.jacks
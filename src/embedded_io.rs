@@ -1,6 +1,9 @@
 //! Implementation of `embedded-io::Error' trait for `Error`.
 
-use crate::{device::socket::SocketError, Error};
+use crate::{
+    device::{blk::ZoneError, socket::SocketError},
+    Error,
+};
 use embedded_io::ErrorKind;
 
 impl embedded_io::Error for Error {
@@ -23,13 +26,18 @@ impl embedded_io::Error for Error {
                 | SocketError::UnexpectedDataInPacket
                 | SocketError::RecycledWrongBuffer => ErrorKind::Other,
             },
+            Error::ZoneError(e) => match e {
+                ZoneError::UnalignedWritePointer => ErrorKind::InvalidInput,
+                ZoneError::OpenResource | ZoneError::ActiveResource => ErrorKind::Other,
+            },
             Error::QueueFull
             | Error::NotReady
             | Error::WrongToken
             | Error::AlreadyUsed
             | Error::IoError
             | Error::ConfigSpaceTooSmall
-            | Error::ConfigSpaceMissing => ErrorKind::Other,
+            | Error::ConfigSpaceMissing
+            | Error::DeviceNeedsReset => ErrorKind::Other,
         }
     }
 }
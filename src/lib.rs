@@ -41,7 +41,7 @@
 //! # }
 //! ```
 
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 #![deny(unused_must_use, missing_docs, clippy::undocumented_unsafe_blocks)]
 #![allow(clippy::identity_op)]
 #![allow(dead_code)]
@@ -59,7 +59,7 @@ pub mod transport;
 mod volatile;
 
 use core::ptr::{self, NonNull};
-use device::socket::SocketError;
+use device::{blk::ZoneError, socket::SocketError};
 use thiserror::Error;
 
 pub use self::hal::{BufferDirection, DeviceHal, Hal, PhysAddr};
@@ -109,6 +109,13 @@ pub enum Error {
     /// Invalid descriptor or descriptor chain.
     #[error("Popped an invalid descriptor or descriptor chain")]
     InvalidDescriptor,
+    /// The device has set `DEVICE_NEEDS_RESET` in its status, so it must be reset and
+    /// reinitialised before it can be used again.
+    #[error("Device needs to be reset")]
+    DeviceNeedsReset,
+    /// Error from a zoned block device.
+    #[error("Error from the zoned block device: {0}")]
+    ZoneError(#[from] ZoneError),
 }
 
 #[cfg(feature = "alloc")]
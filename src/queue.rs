@@ -4,7 +4,7 @@
 pub mod owning;
 
 use crate::hal::{BufferDirection, DeviceDma, DeviceHal, Dma, DmaMemory, Hal, PhysAddr};
-use crate::transport::{DeviceTransport, Transport};
+use crate::transport::{wait_for_reset, DeviceTransport, Transport};
 use crate::{align_up, nonnull_slice_from_raw_parts, pages, Error, Result, PAGE_SIZE};
 #[cfg(feature = "alloc")]
 use alloc::boxed::Box;
@@ -15,7 +15,7 @@ use bitflags::bitflags;
 use core::cmp::min;
 use core::convert::TryInto;
 use core::hint::spin_loop;
-use core::mem::{forget, size_of, take};
+use core::mem::{forget, offset_of, size_of, take};
 #[cfg(test)]
 use core::ptr;
 use core::ptr::NonNull;
@@ -27,7 +27,9 @@ use zerocopy::{FromBytes, FromZeros, Immutable, IntoBytes, KnownLayout};
 /// Each device can have zero or more virtqueues.
 ///
 /// * `SIZE`: The size of the queue. This is both the number of descriptors, and the number of slots
-///   in the available and used rings. It must be a power of 2 and fit in a [`u16`].
+///   in the available and used rings. It must be a power of 2 and fit in a [`u16`]. If the
+///   transport doesn't let the driver choose the queue size, as for legacy PCI devices, then the
+///   rings have as many slots as the device requires, but only `SIZE` descriptors are used.
 #[derive(Debug)]
 pub struct VirtQueue<H: Hal, const SIZE: usize> {
     /// DMA guard
@@ -49,6 +51,9 @@ pub struct VirtQueue<H: Hal, const SIZE: usize> {
 
     /// The index of queue
     queue_idx: u16,
+    /// The number of slots in the available and used rings, and of entries in the descriptor
+    /// table. This is `SIZE` unless the transport requires a larger size.
+    ring_size: u16,
     /// The number of descriptors currently in use.
     num_used: u16,
     /// The head desc index of the free list.
@@ -88,20 +93,29 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         if transport.queue_used(idx) {
             return Err(Error::AlreadyUsed);
         }
-        if transport.max_queue_size(idx) < SIZE as u32 {
+        let max_queue_size = transport.max_queue_size(idx);
+        if max_queue_size < SIZE as u32 {
             return Err(Error::InvalidParam);
         }
         let size = SIZE as u16;
+        let ring_size = if transport.requires_exact_queue_size() {
+            u16::try_from(max_queue_size)
+                .ok()
+                .filter(|ring_size| ring_size.is_power_of_two())
+                .ok_or(Error::InvalidParam)?
+        } else {
+            size
+        };
 
         let layout = if transport.requires_legacy_layout() {
-            VirtQueueLayout::allocate_legacy(size)?
+            VirtQueueLayout::allocate_legacy(ring_size)?
         } else {
-            VirtQueueLayout::allocate_flexible(size)?
+            VirtQueueLayout::allocate_flexible(ring_size)?
         };
 
         transport.queue_set(
             idx,
-            size.into(),
+            ring_size.into(),
             layout.descriptors_paddr(),
             layout.driver_area_paddr(),
             layout.device_area_paddr(),
@@ -131,6 +145,7 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
             avail,
             used,
             queue_idx: idx,
+            ring_size,
             num_used: 0,
             free_head: 0,
             desc_shadow,
@@ -186,10 +201,11 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         #[cfg(not(feature = "alloc"))]
         let head = self.add_direct(inputs, outputs);
 
-        let avail_slot = self.avail_idx & (SIZE as u16 - 1);
-        // SAFETY: `self.avail` is properly aligned, dereferenceable and initialised.
+        let avail_slot = self.avail_idx & (self.ring_size - 1);
+        // SAFETY: `avail_slot` is less than `ring_size`, so the pointer is properly aligned,
+        // dereferenceable and initialised.
         unsafe {
-            (*self.avail.as_ptr()).ring[avail_slot as usize] = head;
+            *self.avail_ring_slot(avail_slot) = head;
         }
 
         // Write barrier so that device sees changes to descriptor table and available ring before
@@ -304,14 +320,21 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
     /// This assumes that the device isn't processing any other buffers at the same time.
     ///
     /// The buffers must not be empty.
+    ///
+    /// If the device sets `DEVICE_NEEDS_RESET` while the buffers are outstanding then this resets
+    /// the device with [`Transport::reset_shared`], reclaims the buffers and returns
+    /// [`Error::DeviceNeedsReset`]. The driver must then reinitialise the device and recreate its
+    /// queues before using it again. If the transport can't reset the device this way, or the
+    /// reset doesn't complete, then this keeps waiting for the buffers to be used.
     pub fn add_notify_wait_pop<'a>(
         &mut self,
         inputs: &'a [&'a [u8]],
         outputs: &'a mut [&'a mut [u8]],
         transport: &impl Transport,
     ) -> Result<u32> {
-        // SAFETY: We don't return until the same token has been popped, so the buffers remain
-        // valid and are not otherwise accessed until then.
+        // SAFETY: We don't return until either the same token has been popped, or the device has
+        // been reset so that it won't access the buffers any more and they have been reclaimed, so
+        // the buffers remain valid and are not otherwise accessed until then.
         let token = unsafe { self.add(inputs, outputs) }?;
 
         // Notify the queue.
@@ -321,6 +344,14 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
 
         // Wait until there is at least one element in the used ring.
         while !self.can_pop() {
+            if transport.needs_reset() && transport.reset_shared() && wait_for_reset(transport) {
+                // The device has given up processing requests and has now been reset, so it won't
+                // access the buffers again.
+                // SAFETY: These are the same buffers as we passed to `add` above, they are still
+                // valid, and the device is no longer accessing them.
+                unsafe { self.recycle_descriptors(token, inputs, outputs) };
+                return Err(Error::DeviceNeedsReset);
+            }
             spin_loop();
         }
 
@@ -350,9 +381,9 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
     /// This will be false if the device has supressed notifications.
     pub fn should_notify(&self) -> bool {
         if self.event_idx {
-            // SAFETY: `self.used` points to a valid, aligned, initialised, dereferenceable, readable
-            // instance of `UsedRing`.
-            let avail_event = unsafe { (*self.used.as_ptr()).avail_event.load(Ordering::Acquire) };
+            // SAFETY: `avail_event` returns a valid, aligned, initialised, dereferenceable, readable
+            // pointer.
+            let avail_event = unsafe { (*self.avail_event()).load(Ordering::Acquire) };
             self.avail_idx >= avail_event.wrapping_add(1)
         } else {
             // SAFETY: `self.used` points to a valid, aligned, initialised, dereferenceable, readable
@@ -379,14 +410,36 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         self.last_used_idx != unsafe { (*self.used.as_ptr()).idx.load(Ordering::Acquire) }
     }
 
+    /// Returns whether the device has used all the buffers which have been made available to it,
+    /// i.e. none are still in flight. Used buffers may still be waiting to be popped.
+    pub fn is_idle(&self) -> bool {
+        // SAFETY: `self.used` points to a valid, aligned, initialised, dereferenceable, readable
+        // instance of `UsedRing`.
+        self.avail_idx == unsafe { (*self.used.as_ptr()).idx.load(Ordering::Acquire) }
+    }
+
+    /// Waits until the device has used all the buffers which have been made available to it.
+    ///
+    /// Returns [`Error::DeviceNeedsReset`] without waiting any further if the device sets
+    /// `DEVICE_NEEDS_RESET`.
+    pub fn wait_until_idle(&self, transport: &impl Transport) -> Result {
+        while !self.is_idle() {
+            if transport.needs_reset() {
+                return Err(Error::DeviceNeedsReset);
+            }
+            spin_loop();
+        }
+        Ok(())
+    }
+
     /// Returns the descriptor index (a.k.a. token) of the next used element without popping it, or
     /// `None` if the used ring is empty.
     pub fn peek_used(&self) -> Option<u16> {
         if self.can_pop() {
-            let last_used_slot = self.last_used_idx & (SIZE as u16 - 1);
-            // SAFETY: `self.used` points to a valid, aligned, initialised, dereferenceable,
-            // readable instance of `UsedRing`.
-            Some(unsafe { (*self.used.as_ptr()).ring[last_used_slot as usize].id as u16 })
+            let last_used_slot = self.last_used_idx & (self.ring_size - 1);
+            // SAFETY: `last_used_slot` is less than `ring_size`, so the pointer is valid, aligned,
+            // initialised, dereferenceable and readable.
+            Some(unsafe { (*self.used_ring_elem(last_used_slot)).id as u16 })
         } else {
             None
         }
@@ -519,14 +572,15 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         }
 
         // Get the index of the start of the descriptor chain for the next element in the used ring.
-        let last_used_slot = self.last_used_idx & (SIZE as u16 - 1);
+        let last_used_slot = self.last_used_idx & (self.ring_size - 1);
         let index;
         let len;
-        // SAFETY: `self.used` points to a valid, aligned, initialised, dereferenceable, readable
-        // instance of `UsedRing`.
+        // SAFETY: `last_used_slot` is less than `ring_size`, so the pointer is valid, aligned,
+        // initialised, dereferenceable and readable.
         unsafe {
-            index = (*self.used.as_ptr()).ring[last_used_slot as usize].id as u16;
-            len = (*self.used.as_ptr()).ring[last_used_slot as usize].len;
+            let elem = self.used_ring_elem(last_used_slot);
+            index = (*elem).id as u16;
+            len = (*elem).len;
         }
 
         if index != token {
@@ -541,17 +595,48 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         self.last_used_idx = self.last_used_idx.wrapping_add(1);
 
         if self.event_idx {
-            // SAFETY: `self.avail` points to a valid, aligned, initialised, dereferenceable,
-            // readable instance of `AvailRing`.
+            // SAFETY: `used_event` returns a valid, aligned, initialised, dereferenceable pointer.
             unsafe {
-                (*self.avail.as_ptr())
-                    .used_event
-                    .store(self.last_used_idx, Ordering::Release);
+                (*self.used_event()).store(self.last_used_idx, Ordering::Release);
             }
         }
 
         Ok(len)
     }
+
+    /// Returns a pointer to the given slot of the available ring, which must be less than
+    /// `ring_size` for the pointer to be valid.
+    fn avail_ring_slot(&self, slot: u16) -> *mut u16 {
+        // The ring may have more than `SIZE` slots, so index it by pointer arithmetic rather than
+        // through the `AvailRing<SIZE>` type.
+        self.avail
+            .as_ptr()
+            .cast::<u8>()
+            .wrapping_add(offset_of!(AvailRing<SIZE>, ring))
+            .cast::<u16>()
+            .wrapping_add(usize::from(slot))
+    }
+
+    /// Returns a pointer to the `used_event` field after the last slot of the available ring.
+    fn used_event(&self) -> *const AtomicU16 {
+        self.avail_ring_slot(self.ring_size).cast()
+    }
+
+    /// Returns a pointer to the given element of the used ring, which must be less than
+    /// `ring_size` for the pointer to be valid.
+    fn used_ring_elem(&self, slot: u16) -> *const UsedElem {
+        self.used
+            .as_ptr()
+            .cast::<u8>()
+            .wrapping_add(offset_of!(UsedRing<SIZE>, ring))
+            .cast::<UsedElem>()
+            .wrapping_add(usize::from(slot))
+    }
+
+    /// Returns a pointer to the `avail_event` field after the last element of the used ring.
+    fn avail_event(&self) -> *const AtomicU16 {
+        self.used_ring_elem(self.ring_size).cast()
+    }
 }
 
 // SAFETY: None of the virt queue resources are tied to a particular thread.
@@ -755,6 +840,34 @@ impl<H: DeviceHal, const SIZE: usize> DeviceVirtQueue<H, SIZE> {
         unreachable!("device virtqueue polling requires alloc feature")
     }
 
+    /// Pops the next chain of buffers from the avail vring, if there is one, and passes its
+    /// device-readable and device-writable buffers to the handler.
+    ///
+    /// The handler returns the number of bytes it wrote to the device-writable buffers along with
+    /// a value to return. The chain is then returned to the used vring, and the transport notified
+    /// if necessary. Returns `Ok(None)` without calling the handler if no chain is available.
+    #[cfg(feature = "alloc")]
+    pub fn poll_chain<T>(
+        &mut self,
+        transport: &impl DeviceTransport,
+        handler: impl FnOnce(&[&[u8]], &mut [&mut [u8]]) -> (usize, T),
+    ) -> Result<Option<T>> {
+        // SAFETY: The buffers are only passed to the handler, and are not accessed again once the
+        // chain has been returned to the used vring.
+        let Some(mut popped) = (unsafe { self.pop_avail()? }) else {
+            return Ok(None);
+        };
+
+        let (written, result) = handler(&popped.read_buffers, &mut popped.write_buffers);
+
+        self.add_used(popped.head, written);
+
+        if self.should_notify() {
+            transport.notify(self.queue_idx);
+        }
+        Ok(Some(result))
+    }
+
     fn add_used(&mut self, head: u16, head_len: usize) {
         let last_used_slot = self.last_used_idx & (SIZE as u16 - 1);
         // SAFETY: self.used is properly aligned, dereferenceable and initialised instance of
@@ -85,6 +85,11 @@ impl<C: FromBytes + Immutable + IntoBytes + Send> Transport for FakeTransport<C>
         self.state.lock().unwrap().status = status;
     }
 
+    fn reset_shared(&self) -> bool {
+        self.state.lock().unwrap().status = DeviceStatus::empty();
+        true
+    }
+
     fn set_guest_page_size(&mut self, guest_page_size: u32) {
         self.state.lock().unwrap().guest_page_size = guest_page_size;
     }
@@ -93,6 +98,10 @@ impl<C: FromBytes + Immutable + IntoBytes + Send> Transport for FakeTransport<C>
         false
     }
 
+    fn requires_exact_queue_size(&self) -> bool {
+        self.state.lock().unwrap().requires_exact_queue_size
+    }
+
     fn queue_set(
         &mut self,
         queue: u16,
@@ -121,7 +130,15 @@ impl<C: FromBytes + Immutable + IntoBytes + Send> Transport for FakeTransport<C>
     }
 
     fn ack_interrupt(&mut self) -> InterruptStatus {
-        let mut state = self.state.lock().unwrap();
+        // SAFETY: `self` is a valid `FakeTransport`.
+        unsafe { Self::ack_interrupt_raw(self) }
+    }
+
+    unsafe fn ack_interrupt_raw(ptr: *mut Self) -> InterruptStatus {
+        // SAFETY: The caller promised that `ptr` points to a valid `FakeTransport`. The state is
+        // behind a mutex, so only a shared reference is needed.
+        let state = unsafe { &(*ptr).state };
+        let mut state = state.lock().unwrap();
         let pending = state.interrupt_pending;
         if pending {
             state.interrupt_pending = false;
@@ -184,6 +201,8 @@ pub struct State<C> {
     pub queues: Vec<QueueStatus>,
     /// The config generation which the transport should report.
     pub config_generation: u32,
+    /// Whether queues must use exactly the maximum queue size, as for legacy PCI devices.
+    pub requires_exact_queue_size: bool,
     /// The state of the transport's VirtIO configuration space.
     pub config_space: C,
 }
@@ -197,6 +216,7 @@ impl<C> Debug for State<C> {
             .field("interrupt_pending", &self.interrupt_pending)
             .field("queues", &self.queues)
             .field("config_generation", &self.config_generation)
+            .field("requires_exact_queue_size", &self.requires_exact_queue_size)
             .field("config_space", &"...")
             .finish()
     }
@@ -212,6 +232,7 @@ impl<C> State<C> {
             interrupt_pending: false,
             queues,
             config_generation: 0,
+            requires_exact_queue_size: false,
             config_space,
         }
     }
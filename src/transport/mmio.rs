@@ -1,8 +1,14 @@
 //! MMIO transport for VirtIO.
 
-use super::{DeviceStatus, DeviceType, Transport};
+pub mod cmdline;
+#[cfg(feature = "fdt")]
+pub mod fdt;
+
+use self::cmdline::DeviceParameterError;
+use super::{DeviceStatus, DeviceType, SharedMemoryRegion, Transport};
 use crate::{
     align_up,
+    hal::Hal,
     queue::Descriptor,
     transport::InterruptStatus,
     volatile::{volread, volwrite, ReadOnly, Volatile, WriteOnly},
@@ -66,6 +72,9 @@ pub enum MmioError {
     /// The MMIO region size was smaller than the header size we expect.
     #[error("MMIO region too small")]
     MmioRegionTooSmall,
+    /// A `virtio_mmio.device` kernel command-line parameter was malformed.
+    #[error("Invalid virtio_mmio.device parameter: {0}")]
+    InvalidDeviceParameter(#[from] DeviceParameterError),
 }
 
 /// MMIO Device Register Interface, both legacy and modern.
@@ -206,7 +215,26 @@ pub struct VirtIOHeader {
     queue_device_high: WriteOnly<u32>,
 
     /// Reserved
-    __r9: [ReadOnly<u32>; 21],
+    __r9: ReadOnly<u32>,
+
+    /// Shared memory region selector
+    ///
+    /// Writing to this register selects the shared memory region that the following operations on
+    /// the SHMLen and SHMBase registers apply to.
+    shm_sel: WriteOnly<u32>,
+
+    /// Shared memory region length
+    ///
+    /// The length of the selected region, or -1 if there is no region with the selected ID.
+    shm_len_low: ReadOnly<u32>,
+    shm_len_high: ReadOnly<u32>,
+
+    /// Shared memory region base address
+    shm_base_low: ReadOnly<u32>,
+    shm_base_high: ReadOnly<u32>,
+
+    /// Reserved
+    __r10: [ReadOnly<u32>; 15],
 
     config_generation: ReadOnly<u32>,
 }
@@ -256,9 +284,21 @@ impl VirtIOHeader {
             queue_device_low: Default::default(),
             queue_device_high: Default::default(),
             __r9: Default::default(),
+            shm_sel: Default::default(),
+            shm_len_low: ReadOnly::new(u32::MAX),
+            shm_len_high: ReadOnly::new(u32::MAX),
+            shm_base_low: Default::default(),
+            shm_base_high: Default::default(),
+            __r10: Default::default(),
             config_generation: Default::default(),
         }
     }
+
+    /// Sets the interrupt status reported by a fake header, for use in unit tests.
+    #[cfg(test)]
+    pub fn set_interrupt_status(&mut self, interrupt_status: u32) {
+        self.interrupt_status = ReadOnly::new(interrupt_status);
+    }
 }
 
 /// MMIO Device Register Interface.
@@ -298,6 +338,34 @@ impl MmioTransport {
         })
     }
 
+    /// Maps the MMIO region at the given physical address with the HAL and constructs a transport
+    /// for it.
+    ///
+    /// Returns `Ok(None)` if the device ID is zero, which is used for placeholder regions with no
+    /// device behind them.
+    ///
+    /// # Safety
+    ///
+    /// The region must be a real VirtIO MMIO device, and nothing else may access it for the
+    /// lifetime of the returned transport.
+    pub(crate) unsafe fn probe<H: Hal>(address: u64, size: u64) -> Result<Option<Self>, MmioError> {
+        let (Ok(address), Ok(size)) = (usize::try_from(address), usize::try_from(size)) else {
+            return Err(MmioError::MmioRegionTooSmall);
+        };
+        if size < size_of::<VirtIOHeader>() {
+            return Err(MmioError::MmioRegionTooSmall);
+        }
+        // SAFETY: The caller promised that the region is a VirtIO MMIO device.
+        let header = unsafe { H::mmio_phys_to_virt(address, size) }.cast();
+        // SAFETY: The caller promised that the region is a VirtIO MMIO device, and the HAL mapped
+        // it for us.
+        match unsafe { Self::new(header, size) } {
+            Ok(transport) => Ok(Some(transport)),
+            Err(MmioError::ZeroDeviceId) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
     /// Gets the version of the VirtIO MMIO transport.
     pub fn version(&self) -> MmioVersion {
         self.version
@@ -372,6 +440,14 @@ impl Transport for MmioTransport {
         }
     }
 
+    fn reset_shared(&self) -> bool {
+        // SAFETY: `self.header` points to a valid VirtIO MMIO region.
+        unsafe {
+            volwrite!(self.header, status, DeviceStatus::empty());
+        }
+        true
+    }
+
     fn set_guest_page_size(&mut self, guest_page_size: u32) {
         match self.version {
             MmioVersion::Legacy => {
@@ -486,11 +562,29 @@ impl Transport for MmioTransport {
     }
 
     fn ack_interrupt(&mut self) -> InterruptStatus {
-        // SAFETY: `self.header` points to a valid VirtIO MMIO region.
+        // SAFETY: `self` is a valid `MmioTransport`, and as we have a `&mut Self` it can't be used
+        // to call `ack_interrupt_raw` concurrently.
+        unsafe { Self::ack_interrupt_raw(self) }
+    }
+
+    /// Acknowledges an interrupt using a pointer to the `MmioTransport`.
+    ///
+    /// This only reads the header pointer from the `MmioTransport`, which never changes after it
+    /// is constructed, so it doesn't need a mutable reference.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a valid `MmioTransport` instance. The caller must ensure that it isn't
+    /// used to call either this function or `ack_interrupt` concurrently.
+    unsafe fn ack_interrupt_raw(ptr: *mut Self) -> InterruptStatus {
+        // SAFETY: The caller promised that `ptr` points to a valid `MmioTransport`, and the header
+        // field is never modified after construction.
+        let header = unsafe { (*ptr).header };
+        // SAFETY: `header` points to a valid VirtIO MMIO region.
         unsafe {
-            let interrupt = volread!(self.header, interrupt_status);
+            let interrupt = volread!(header, interrupt_status);
             if interrupt != 0 {
-                volwrite!(self.header, interrupt_ack, interrupt);
+                volwrite!(header, interrupt_ack, interrupt);
                 InterruptStatus::from_bits_truncate(interrupt)
             } else {
                 InterruptStatus::empty()
@@ -550,6 +644,27 @@ impl Transport for MmioTransport {
             Ok(())
         }
     }
+
+    fn shared_memory_region<H: Hal>(&mut self, id: u8) -> Option<SharedMemoryRegion> {
+        if self.version == MmioVersion::Legacy {
+            // Shared memory regions were added after the legacy interface.
+            return None;
+        }
+        // SAFETY: `self.header` points to a valid VirtIO MMIO region.
+        let (len, base) = unsafe {
+            volwrite!(self.header, shm_sel, id.into());
+            let len = u64::from(volread!(self.header, shm_len_low))
+                | u64::from(volread!(self.header, shm_len_high)) << 32;
+            let base = u64::from(volread!(self.header, shm_base_low))
+                | u64::from(volread!(self.header, shm_base_high)) << 32;
+            (len, base)
+        };
+        if len == u64::MAX {
+            return None;
+        }
+        // SAFETY: The device reported this region.
+        unsafe { SharedMemoryRegion::map::<H>(base, len) }
+    }
 }
 
 impl Drop for MmioTransport {
@@ -558,3 +673,79 @@ impl Drop for MmioTransport {
         self.set_status(DeviceStatus::empty())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::fake::FakeHal;
+    use core::mem::offset_of;
+
+    #[test]
+    fn header_layout() {
+        assert_eq!(offset_of!(VirtIOHeader, queue_device_high), 0x0a4);
+        assert_eq!(offset_of!(VirtIOHeader, shm_sel), 0x0ac);
+        assert_eq!(offset_of!(VirtIOHeader, shm_len_low), 0x0b0);
+        assert_eq!(offset_of!(VirtIOHeader, shm_base_low), 0x0b8);
+        assert_eq!(offset_of!(VirtIOHeader, config_generation), 0x0fc);
+        assert_eq!(size_of::<VirtIOHeader>(), CONFIG_SPACE_OFFSET);
+    }
+
+    #[test]
+    fn no_shared_memory_region() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        // SAFETY: The header is valid for the lifetime of the transport.
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        assert_eq!(transport.shared_memory_region::<FakeHal>(0), None);
+    }
+
+    #[test]
+    fn shared_memory_region() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        header.shm_len_low = ReadOnly::new(0x20_0000);
+        header.shm_len_high = ReadOnly::new(0);
+        header.shm_base_low = ReadOnly::new(0x8000_0000);
+        header.shm_base_high = ReadOnly::new(0x1);
+        // SAFETY: The header is valid for the lifetime of the transport.
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+
+        let region = transport.shared_memory_region::<FakeHal>(2).unwrap();
+        assert_eq!(region.paddr, 0x1_8000_0000);
+        assert_eq!(region.region.len(), 0x20_0000);
+        assert_eq!(region.region.cast::<u8>().as_ptr() as usize, 0x1_8000_0000);
+        drop(transport);
+        assert_eq!(header.shm_sel.0, 2);
+    }
+
+    #[test]
+    fn ack_interrupt_raw() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        header.set_interrupt_status(0b11);
+        // SAFETY: The header is valid for the lifetime of the transport.
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+
+        // SAFETY: The transport is valid and isn't being used concurrently.
+        let status = unsafe { MmioTransport::ack_interrupt_raw(&mut transport) };
+        assert!(status.contains(InterruptStatus::QUEUE_INTERRUPT));
+        assert!(status.contains(InterruptStatus::DEVICE_CONFIGURATION_INTERRUPT));
+        drop(transport);
+        assert_eq!(header.interrupt_ack.0, 0b11);
+    }
+
+    #[test]
+    fn legacy_has_no_shared_memory_regions() {
+        let mut header = VirtIOHeader::make_fake_header(LEGACY_VERSION, 1, 0, 0, 4);
+        header.shm_len_low = ReadOnly::new(0x1000);
+        header.shm_len_high = ReadOnly::new(0);
+        // SAFETY: The header is valid for the lifetime of the transport.
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        assert_eq!(transport.shared_memory_region::<FakeHal>(0), None);
+    }
+}
@@ -0,0 +1,356 @@
+//! Discovery of VirtIO MMIO devices from kernel command-line parameters.
+//!
+//! VMMs such as Firecracker which don't provide a device tree instead describe each VirtIO MMIO
+//! device with a `virtio_mmio.device=<size>@<baseaddr>:<irq>[:<id>]` parameter, in the format
+//! understood by Linux.
+//!
+//! Ref: Linux drivers/virtio/virtio_mmio.c
+
+use super::{MmioError, MmioTransport};
+use crate::{hal::Hal, Error};
+use core::marker::PhantomData;
+
+/// The name of the parameter describing a VirtIO MMIO device.
+const DEVICE_PARAMETER: &str = "virtio_mmio.device";
+
+/// A reason why a `virtio_mmio.device` parameter couldn't be parsed.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum DeviceParameterError {
+    /// The size was missing, zero or not a number.
+    #[error("invalid size")]
+    InvalidSize,
+    /// The base address was missing or not a number.
+    #[error("invalid base address")]
+    InvalidBaseAddress,
+    /// The IRQ number was missing or not a number.
+    #[error("invalid IRQ")]
+    InvalidIrq,
+    /// The device ID after the IRQ number was not a number.
+    #[error("invalid device ID")]
+    InvalidId,
+    /// There were unexpected characters after the end of the parameter value.
+    #[error("unexpected trailing characters")]
+    TrailingCharacters,
+}
+
+/// A VirtIO MMIO device described by a `virtio_mmio.device` command-line parameter.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CmdlineDevice {
+    /// The physical address of the MMIO region.
+    pub address: u64,
+    /// The size of the MMIO region in bytes.
+    pub size: u64,
+    /// The IRQ number of the device.
+    pub irq: u32,
+    /// The platform device ID, if one was given.
+    pub id: Option<u32>,
+}
+
+impl CmdlineDevice {
+    /// Parses the value of a `virtio_mmio.device` parameter, i.e. `<size>@<baseaddr>:<irq>[:<id>]`.
+    ///
+    /// As in Linux, the size may have a `K`, `M`, `G`, `T`, `P` or `E` suffix, and the size and
+    /// base address may be given in hexadecimal with a `0x` prefix or octal with a `0` prefix.
+    pub fn parse(value: &str) -> Result<Self, MmioError> {
+        let (size, rest) = parse_size(value).ok_or(DeviceParameterError::InvalidSize)?;
+        if size == 0 {
+            return Err(DeviceParameterError::InvalidSize.into());
+        }
+        let (address, rest) = rest
+            .strip_prefix('@')
+            .and_then(parse_number)
+            .ok_or(DeviceParameterError::InvalidBaseAddress)?;
+        let (irq, rest) = rest
+            .strip_prefix(':')
+            .and_then(parse_decimal)
+            .ok_or(DeviceParameterError::InvalidIrq)?;
+        let (id, rest) = match rest.strip_prefix(':') {
+            Some(rest) => {
+                let (id, rest) = parse_decimal(rest).ok_or(DeviceParameterError::InvalidId)?;
+                (Some(id), rest)
+            }
+            None => (None, rest),
+        };
+        if !rest.is_empty() {
+            return Err(DeviceParameterError::TrailingCharacters.into());
+        }
+        Ok(Self {
+            address,
+            size,
+            irq: irq
+                .try_into()
+                .map_err(|_| DeviceParameterError::InvalidIrq)?,
+            id: id
+                .map(u32::try_from)
+                .transpose()
+                .map_err(|_| DeviceParameterError::InvalidId)?,
+        })
+    }
+
+    /// Constructs an MMIO transport for the device.
+    ///
+    /// Returns `Ok(None)` if the device ID is zero, which is used for placeholder regions with no
+    /// device behind them.
+    ///
+    /// # Safety
+    ///
+    /// The parameter must describe a real VirtIO MMIO device, and nothing else may access its MMIO
+    /// region for the lifetime of the returned transport.
+    pub unsafe fn transport<H: Hal>(&self) -> Result<Option<MmioTransport>, MmioError> {
+        // SAFETY: The caller promised that the parameter describes a VirtIO MMIO device.
+        unsafe { MmioTransport::probe::<H>(self.address, self.size) }
+    }
+}
+
+/// Returns an iterator over the devices described by `virtio_mmio.device` parameters in the given
+/// kernel command line.
+///
+/// Other parameters are ignored. Each item is either the parsed device or the reason its
+/// parameter was malformed.
+pub fn cmdline_devices(cmdline: &str) -> CmdlineDevices<'_> {
+    CmdlineDevices {
+        parameters: cmdline.split_ascii_whitespace(),
+    }
+}
+
+/// Returns an iterator over MMIO transports for the devices described by `virtio_mmio.device`
+/// parameters in the given kernel command line, skipping placeholders with a device ID of zero.
+///
+/// Each item is either the device along with the result of constructing a transport for it, or
+/// the reason the device's parameter was malformed.
+///
+/// # Safety
+///
+/// The command line must accurately describe the system, and nothing else may access the MMIO
+/// regions of the VirtIO devices for the lifetime of the returned transports.
+pub unsafe fn cmdline_transports<H: Hal>(cmdline: &str) -> CmdlineTransports<'_, H> {
+    CmdlineTransports {
+        devices: cmdline_devices(cmdline),
+        _hal: PhantomData,
+    }
+}
+
+/// An iterator over the devices described by a kernel command line, returned by
+/// [`cmdline_devices`].
+#[derive(Clone, Debug)]
+pub struct CmdlineDevices<'a> {
+    parameters: core::str::SplitAsciiWhitespace<'a>,
+}
+
+impl Iterator for CmdlineDevices<'_> {
+    type Item = Result<CmdlineDevice, MmioError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.parameters.find_map(|parameter| {
+            let (name, value) = parameter.split_once('=')?;
+            // Linux treats dashes and underscores in parameter names as equivalent.
+            let is_device = name.len() == DEVICE_PARAMETER.len()
+                && name
+                    .bytes()
+                    .zip(DEVICE_PARAMETER.bytes())
+                    .all(|(a, b)| a == b || (a == b'-' && b == b'_'));
+            is_device.then(|| CmdlineDevice::parse(value))
+        })
+    }
+}
+
+/// An iterator over MMIO transports for the devices described by a kernel command line, returned
+/// by [`cmdline_transports`].
+#[derive(Debug)]
+pub struct CmdlineTransports<'a, H: Hal> {
+    devices: CmdlineDevices<'a>,
+    _hal: PhantomData<H>,
+}
+
+impl<H: Hal> Iterator for CmdlineTransports<'_, H> {
+    type Item = Result<(CmdlineDevice, Result<MmioTransport, MmioError>), MmioError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for device in self.devices.by_ref() {
+            let device = match device {
+                Ok(device) => device,
+                Err(e) => return Some(Err(e)),
+            };
+            // SAFETY: The caller of `cmdline_transports` promised that the command line is
+            // accurate and that nothing else accesses the devices.
+            match unsafe { device.transport::<H>() } {
+                Ok(None) => continue,
+                Ok(Some(transport)) => return Some(Ok((device, Ok(transport)))),
+                Err(e) => return Some(Ok((device, Err(e)))),
+            }
+        }
+        None
+    }
+}
+
+/// Parses a size with an optional binary suffix, like Linux's `memparse`.
+fn parse_size(s: &str) -> Option<(u64, &str)> {
+    let (value, rest) = parse_number(s)?;
+    let shift = match rest.as_bytes().first().map(u8::to_ascii_uppercase) {
+        Some(b'K') => 10,
+        Some(b'M') => 20,
+        Some(b'G') => 30,
+        Some(b'T') => 40,
+        Some(b'P') => 50,
+        Some(b'E') => 60,
+        _ => return Some((value, rest)),
+    };
+    let scaled = value.checked_mul(1 << shift)?;
+    Some((scaled, &rest[1..]))
+}
+
+/// Parses an unsigned number with its base given by its prefix, like C's `strtoull` with base 0.
+///
+/// Returns the number and the rest of the string after it.
+fn parse_number(s: &str) -> Option<(u64, &str)> {
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        parse_digits(hex, 16)
+    } else if s.starts_with('0') {
+        parse_digits(s, 8)
+    } else {
+        parse_digits(s, 10)
+    }
+}
+
+/// Parses an unsigned decimal number, returning it and the rest of the string after it.
+fn parse_decimal(s: &str) -> Option<(u64, &str)> {
+    parse_digits(s, 10)
+}
+
+fn parse_digits(s: &str, radix: u32) -> Option<(u64, &str)> {
+    let len = s.find(|c: char| !c.is_digit(radix)).unwrap_or(s.len());
+    let value = u64::from_str_radix(&s[..len], radix).ok()?;
+    Some((value, &s[len..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            mmio::{VirtIOHeader, MODERN_VERSION},
+            DeviceType, Transport,
+        },
+    };
+    use alloc::{format, vec::Vec};
+    use core::mem::size_of;
+
+    #[test]
+    fn parse_device() {
+        assert_eq!(
+            CmdlineDevice::parse("4K@0xd0000000:5").unwrap(),
+            CmdlineDevice {
+                address: 0xd0000000,
+                size: 0x1000,
+                irq: 5,
+                id: None,
+            }
+        );
+        assert_eq!(
+            CmdlineDevice::parse("0x200@0100000:12:3").unwrap(),
+            CmdlineDevice {
+                address: 0o100000,
+                size: 0x200,
+                irq: 12,
+                id: Some(3),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_malformed() {
+        let error = |value| match CmdlineDevice::parse(value).unwrap_err() {
+            MmioError::InvalidDeviceParameter(e) => e,
+            e => panic!("Unexpected error {e:?}"),
+        };
+        assert_eq!(error(""), DeviceParameterError::InvalidSize);
+        assert_eq!(error("0@0x1000:5"), DeviceParameterError::InvalidSize);
+        assert_eq!(error("100000E@0x1000:5"), DeviceParameterError::InvalidSize);
+        assert_eq!(error("4K"), DeviceParameterError::InvalidBaseAddress);
+        assert_eq!(error("4K@zz:5"), DeviceParameterError::InvalidBaseAddress);
+        assert_eq!(error("4K@0x1000"), DeviceParameterError::InvalidIrq);
+        assert_eq!(
+            error("4K@0x1000:0x5"),
+            DeviceParameterError::TrailingCharacters
+        );
+        assert_eq!(
+            error("4K@0x1000:5000000000"),
+            DeviceParameterError::InvalidIrq
+        );
+        assert_eq!(error("4K@0x1000:5:"), DeviceParameterError::InvalidId);
+        assert_eq!(
+            error("4K@0x1000:5:1,"),
+            DeviceParameterError::TrailingCharacters
+        );
+    }
+
+    #[test]
+    fn find_devices() {
+        let devices: Vec<_> = cmdline_devices(
+            "console=ttyS0 virtio_mmio.device=4K@0x1000:5 reboot=k \
+             virtio-mmio.device=4K@0x2000:6:1 virtio_mmio.device=bad virtio_mmio.devices=x",
+        )
+        .collect();
+        assert_eq!(
+            devices,
+            [
+                Ok(CmdlineDevice {
+                    address: 0x1000,
+                    size: 0x1000,
+                    irq: 5,
+                    id: None,
+                }),
+                Ok(CmdlineDevice {
+                    address: 0x2000,
+                    size: 0x1000,
+                    irq: 6,
+                    id: Some(1),
+                }),
+                Err(DeviceParameterError::InvalidSize.into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn transports_skip_placeholders() {
+        let placeholder = VirtIOHeader::make_fake_header(MODERN_VERSION, 0, 0, 0, 4);
+        let block =
+            VirtIOHeader::make_fake_header(MODERN_VERSION, DeviceType::Block as u32, 0, 0, 4);
+        let cmdline = format!(
+            "virtio_mmio.device={size:#x}@{placeholder:p}:5 \
+             virtio_mmio.device={size:#x}@{block:p}:6 \
+             virtio_mmio.device=16@0x1000:7 \
+             virtio_mmio.device=bad",
+            size = size_of::<VirtIOHeader>(),
+            placeholder = &placeholder,
+            block = &block,
+        );
+
+        // SAFETY: The command line describes the fake headers, which live longer than the
+        // transports, apart from the last device which is too small to be probed.
+        let transports: Vec<_> = unsafe { cmdline_transports::<FakeHal>(&cmdline) }.collect();
+        assert_eq!(transports.len(), 3);
+        let (device, transport) = transports[0].as_ref().unwrap();
+        assert_eq!(device.irq, 6);
+        assert_eq!(transport.as_ref().unwrap().device_type(), DeviceType::Block);
+        let (device, transport) = transports[1].as_ref().unwrap();
+        assert_eq!(
+            device,
+            &CmdlineDevice {
+                address: 0x1000,
+                size: 16,
+                irq: 7,
+                id: None,
+            }
+        );
+        assert_eq!(
+            transport.as_ref().unwrap_err(),
+            &MmioError::MmioRegionTooSmall
+        );
+        assert_eq!(
+            transports[2].as_ref().unwrap_err(),
+            &MmioError::InvalidDeviceParameter(DeviceParameterError::InvalidSize)
+        );
+    }
+}
@@ -0,0 +1,579 @@
+//! Discovery of VirtIO MMIO devices from a flattened device tree.
+//!
+//! This is a minimal parser for the device tree blob format, which only understands as much as is
+//! needed to find `virtio,mmio` nodes. It doesn't allocate.
+//!
+//! Ref: Devicetree Specification 5 Flattened Devicetree (DTB) Format
+
+use super::{MmioError, MmioTransport};
+use crate::{hal::Hal, Error};
+use core::{marker::PhantomData, str};
+
+/// The magic value at the start of a device tree blob.
+const FDT_MAGIC: u32 = 0xd00dfeed;
+/// The oldest blob version which we understand, which is the first to have a size for the structure
+/// block.
+const MIN_VERSION: u32 = 17;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+/// The maximum depth of nesting of nodes which we support.
+const MAX_DEPTH: usize = 32;
+
+/// The `compatible` string for VirtIO MMIO devices.
+const VIRTIO_MMIO_COMPATIBLE: &str = "virtio,mmio";
+
+/// An error parsing a flattened device tree.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum FdtError {
+    /// The blob didn't start with the device tree magic value.
+    #[error("Invalid FDT magic value {0:#010x}")]
+    BadMagic(u32),
+    /// The blob is of a version which we don't understand.
+    #[error("Unsupported FDT version {0}")]
+    UnsupportedVersion(u32),
+    /// The blob was truncated or some offset or length in it was out of bounds.
+    #[error("FDT truncated or offset out of bounds")]
+    Truncated,
+    /// The structure block contained an unexpected token.
+    #[error("Unexpected FDT token {0:#x}")]
+    UnexpectedToken(u32),
+    /// Nodes were nested more deeply than we support.
+    #[error("FDT nodes nested too deeply")]
+    TooDeep,
+    /// A node or property name wasn't a valid string.
+    #[error("Invalid string in FDT")]
+    InvalidString,
+    /// A `reg` property used more address or size cells than we support.
+    #[error("Unsupported number of cells in reg property")]
+    UnsupportedCells,
+}
+
+/// A `virtio,mmio` node found in a device tree.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VirtioMmioNode<'a> {
+    /// The name of the node, e.g. `virtio_mmio@a000000`.
+    pub name: &'a str,
+    /// The physical address of the MMIO region, from the first entry of the `reg` property.
+    pub address: u64,
+    /// The size of the MMIO region in bytes, from the first entry of the `reg` property.
+    pub size: u64,
+    /// The raw contents of the `interrupts` property.
+    interrupts: &'a [u8],
+}
+
+impl<'a> VirtioMmioNode<'a> {
+    /// Returns the cells of the node's interrupt specifier, from its `interrupts` property.
+    ///
+    /// How many cells make up each interrupt and what they mean depends on the interrupt
+    /// controller; for an Arm GIC there are three, the type, number and flags.
+    pub fn interrupts(&self) -> impl Iterator<Item = u32> + 'a {
+        self.interrupts
+            .chunks_exact(4)
+            .map(|cell| u32::from_be_bytes(cell.try_into().unwrap()))
+    }
+
+    /// Constructs an MMIO transport for the device.
+    ///
+    /// Returns `Ok(None)` if the device ID is zero, which is used for placeholder nodes with no
+    /// device behind them.
+    ///
+    /// # Safety
+    ///
+    /// The node must describe a real VirtIO MMIO device, and nothing else may access its MMIO
+    /// region for the lifetime of the returned transport.
+    pub unsafe fn transport<H: Hal>(&self) -> Result<Option<MmioTransport>, MmioError> {
+        // SAFETY: The caller promised that the node describes a VirtIO MMIO device.
+        unsafe { MmioTransport::probe::<H>(self.address, self.size) }
+    }
+}
+
+/// Returns an iterator over all enabled `virtio,mmio` nodes in the given device tree blob.
+///
+/// The whole blob is checked up front, so the iterator itself can't fail.
+pub fn virtio_mmio_nodes(fdt: &[u8]) -> Result<VirtioMmioNodes<'_>, FdtError> {
+    let walker = Walker::new(fdt)?;
+    let mut check = walker.clone();
+    while check.next_node()?.is_some() {}
+    Ok(VirtioMmioNodes { walker })
+}
+
+/// Returns an iterator over MMIO transports for all enabled `virtio,mmio` nodes in the given device
+/// tree blob, skipping placeholder nodes with a device ID of zero.
+///
+/// # Safety
+///
+/// The device tree must accurately describe the system, and nothing else may access the MMIO
+/// regions of the VirtIO devices for the lifetime of the returned transports.
+pub unsafe fn virtio_mmio_transports<H: Hal>(
+    fdt: &[u8],
+) -> Result<VirtioMmioTransports<'_, H>, FdtError> {
+    Ok(VirtioMmioTransports {
+        nodes: virtio_mmio_nodes(fdt)?,
+        _hal: PhantomData,
+    })
+}
+
+/// An iterator over the `virtio,mmio` nodes of a device tree, returned by [`virtio_mmio_nodes`].
+#[derive(Clone, Debug)]
+pub struct VirtioMmioNodes<'a> {
+    walker: Walker<'a>,
+}
+
+impl<'a> Iterator for VirtioMmioNodes<'a> {
+    type Item = VirtioMmioNode<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // The blob was already checked, so there can't be an error.
+        self.walker.next_node().ok().flatten()
+    }
+}
+
+/// An iterator over MMIO transports for the `virtio,mmio` nodes of a device tree, returned by
+/// [`virtio_mmio_transports`].
+///
+/// Each item is the node along with the result of constructing a transport for it.
+#[derive(Debug)]
+pub struct VirtioMmioTransports<'a, H: Hal> {
+    nodes: VirtioMmioNodes<'a>,
+    _hal: PhantomData<H>,
+}
+
+impl<'a, H: Hal> Iterator for VirtioMmioTransports<'a, H> {
+    type Item = (VirtioMmioNode<'a>, Result<MmioTransport, MmioError>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for node in self.nodes.by_ref() {
+            // SAFETY: The caller of `virtio_mmio_transports` promised that the device tree is
+            // accurate and that nothing else accesses the devices.
+            match unsafe { node.transport::<H>() } {
+                Ok(None) => continue,
+                Ok(Some(transport)) => return Some((node, Ok(transport))),
+                Err(e) => return Some((node, Err(e))),
+            }
+        }
+        None
+    }
+}
+
+/// The properties of a node which we care about.
+#[derive(Clone, Debug, Default)]
+struct NodeProperties<'a> {
+    name: &'a str,
+    compatible: &'a [u8],
+    reg: Option<&'a [u8]>,
+    interrupts: &'a [u8],
+    status: Option<&'a [u8]>,
+}
+
+impl NodeProperties<'_> {
+    fn is_enabled_virtio_mmio(&self) -> bool {
+        let compatible = self
+            .compatible
+            .split(|&b| b == 0)
+            .any(|s| s == VIRTIO_MMIO_COMPATIBLE.as_bytes());
+        let enabled = self
+            .status
+            .is_none_or(|status| matches!(status, b"okay\0" | b"ok\0"));
+        compatible && enabled
+    }
+}
+
+/// A cursor over the structure block of a device tree blob.
+#[derive(Clone, Debug)]
+struct Walker<'a> {
+    structure: &'a [u8],
+    strings: &'a [u8],
+    offset: usize,
+    /// The number of nodes currently open.
+    depth: usize,
+    /// The `#address-cells` and `#size-cells` of each open node, which apply to its children.
+    cells: [(u32, u32); MAX_DEPTH],
+    /// The properties of the most recently opened node, if it hasn't yet been checked.
+    pending: Option<NodeProperties<'a>>,
+}
+
+impl<'a> Walker<'a> {
+    fn new(fdt: &'a [u8]) -> Result<Self, FdtError> {
+        let magic = read_u32(fdt, 0)?;
+        if magic != FDT_MAGIC {
+            return Err(FdtError::BadMagic(magic));
+        }
+        let total_size = read_u32(fdt, 4)? as usize;
+        let off_dt_struct = read_u32(fdt, 8)? as usize;
+        let off_dt_strings = read_u32(fdt, 12)? as usize;
+        let version = read_u32(fdt, 20)?;
+        if version < MIN_VERSION {
+            return Err(FdtError::UnsupportedVersion(version));
+        }
+        let size_dt_strings = read_u32(fdt, 32)? as usize;
+        let size_dt_struct = read_u32(fdt, 36)? as usize;
+        let fdt = fdt.get(..total_size).ok_or(FdtError::Truncated)?;
+        Ok(Self {
+            structure: slice(fdt, off_dt_struct, size_dt_struct)?,
+            strings: slice(fdt, off_dt_strings, size_dt_strings)?,
+            offset: 0,
+            depth: 0,
+            cells: [(2, 1); MAX_DEPTH],
+            pending: None,
+        })
+    }
+
+    /// Advances to the next enabled `virtio,mmio` node, and returns it.
+    fn next_node(&mut self) -> Result<Option<VirtioMmioNode<'a>>, FdtError> {
+        loop {
+            if self.offset >= self.structure.len() {
+                return Ok(None);
+            }
+            let token = read_u32(self.structure, self.offset)?;
+            if matches!(token, FDT_BEGIN_NODE | FDT_END_NODE | FDT_END) {
+                // The properties of the pending node are complete, so check it before moving on. NOPs
+                // may appear between properties, so don't count as the end of them.
+                if let Some(properties) = self.pending.take() {
+                    if let Some(node) = self.virtio_mmio_node(&properties)? {
+                        return Ok(Some(node));
+                    }
+                }
+            }
+            self.offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = read_str(self.structure, self.offset)?;
+                    self.offset = align4(self.offset + name.len() + 1);
+                    if self.depth == MAX_DEPTH {
+                        return Err(FdtError::TooDeep);
+                    }
+                    self.cells[self.depth] = (2, 1);
+                    self.depth += 1;
+                    self.pending = Some(NodeProperties {
+                        name,
+                        ..Default::default()
+                    });
+                }
+                FDT_END_NODE => {
+                    self.depth = self
+                        .depth
+                        .checked_sub(1)
+                        .ok_or(FdtError::UnexpectedToken(token))?;
+                }
+                FDT_PROP => {
+                    let len = read_u32(self.structure, self.offset)? as usize;
+                    let name_offset = read_u32(self.structure, self.offset + 4)? as usize;
+                    let value = slice(self.structure, self.offset + 8, len)?;
+                    self.offset = align4(self.offset + 8 + len);
+                    let name = read_str(self.strings, name_offset)?;
+                    if self.depth == 0 {
+                        return Err(FdtError::UnexpectedToken(token));
+                    }
+                    let cells = &mut self.cells[self.depth - 1];
+                    match name {
+                        "#address-cells" => cells.0 = read_u32(value, 0)?,
+                        "#size-cells" => cells.1 = read_u32(value, 0)?,
+                        _ => {}
+                    }
+                    if let Some(properties) = &mut self.pending {
+                        match name {
+                            "compatible" => properties.compatible = value,
+                            "reg" => properties.reg = Some(value),
+                            "interrupts" => properties.interrupts = value,
+                            "status" => properties.status = Some(value),
+                            _ => {}
+                        }
+                    }
+                }
+                FDT_NOP => {}
+                FDT_END => {
+                    self.offset = self.structure.len();
+                }
+                _ => return Err(FdtError::UnexpectedToken(token)),
+            }
+        }
+    }
+
+    /// Returns the given node if it is an enabled `virtio,mmio` node.
+    ///
+    /// This must be called while the node is still the innermost open node.
+    fn virtio_mmio_node(
+        &self,
+        properties: &NodeProperties<'a>,
+    ) -> Result<Option<VirtioMmioNode<'a>>, FdtError> {
+        if !properties.is_enabled_virtio_mmio() {
+            return Ok(None);
+        }
+        let Some(reg) = properties.reg else {
+            return Ok(None);
+        };
+        // The node's `reg` is interpreted according to its parent's cell sizes.
+        let (address_cells, size_cells) = if self.depth >= 2 {
+            self.cells[self.depth - 2]
+        } else {
+            (2, 1)
+        };
+        let address = read_cells(reg, 0, address_cells)?;
+        let size = read_cells(reg, address_cells as usize * 4, size_cells)?;
+        Ok(Some(VirtioMmioNode {
+            name: properties.name,
+            address,
+            size,
+            interrupts: properties.interrupts,
+        }))
+    }
+}
+
+fn align4(offset: usize) -> usize {
+    offset.next_multiple_of(4)
+}
+
+fn slice(bytes: &[u8], offset: usize, len: usize) -> Result<&[u8], FdtError> {
+    bytes
+        .get(offset..offset.checked_add(len).ok_or(FdtError::Truncated)?)
+        .ok_or(FdtError::Truncated)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, FdtError> {
+    Ok(u32::from_be_bytes(
+        slice(bytes, offset, 4)?.try_into().unwrap(),
+    ))
+}
+
+/// Reads a value of one or two cells.
+fn read_cells(bytes: &[u8], offset: usize, cells: u32) -> Result<u64, FdtError> {
+    match cells {
+        1 => Ok(read_u32(bytes, offset)?.into()),
+        2 => {
+            Ok(u64::from(read_u32(bytes, offset)?) << 32 | u64::from(read_u32(bytes, offset + 4)?))
+        }
+        _ => Err(FdtError::UnsupportedCells),
+    }
+}
+
+/// Reads a NUL-terminated string.
+fn read_str(bytes: &[u8], offset: usize) -> Result<&str, FdtError> {
+    let bytes = bytes.get(offset..).ok_or(FdtError::Truncated)?;
+    let len = bytes
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or(FdtError::Truncated)?;
+    str::from_utf8(&bytes[..len]).map_err(|_| FdtError::InvalidString)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            mmio::{VirtIOHeader, MODERN_VERSION},
+            DeviceType, Transport,
+        },
+    };
+    use alloc::{vec, vec::Vec};
+    use core::mem::size_of;
+
+    /// Builds a device tree blob.
+    #[derive(Default)]
+    struct FdtBuilder {
+        structure: Vec<u8>,
+        strings: Vec<u8>,
+    }
+
+    impl FdtBuilder {
+        fn token(&mut self, token: u32) {
+            self.structure.extend_from_slice(&token.to_be_bytes());
+        }
+
+        fn pad(&mut self) {
+            self.structure.resize(align4(self.structure.len()), 0);
+        }
+
+        fn begin_node(&mut self, name: &str) -> &mut Self {
+            self.token(FDT_BEGIN_NODE);
+            self.structure.extend_from_slice(name.as_bytes());
+            self.structure.push(0);
+            self.pad();
+            self
+        }
+
+        fn end_node(&mut self) -> &mut Self {
+            self.token(FDT_END_NODE);
+            self
+        }
+
+        fn nop(&mut self) -> &mut Self {
+            self.token(FDT_NOP);
+            self
+        }
+
+        fn property(&mut self, name: &str, value: &[u8]) -> &mut Self {
+            self.token(FDT_PROP);
+            self.token(value.len() as u32);
+            self.token(self.strings.len() as u32);
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+            self.structure.extend_from_slice(value);
+            self.pad();
+            self
+        }
+
+        fn cells(&mut self, name: &str, cells: &[u32]) -> &mut Self {
+            let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+            self.property(name, &value)
+        }
+
+        fn virtio_mmio(&mut self, name: &str, reg: &[u32]) -> &mut Self {
+            self.begin_node(name)
+                .property("compatible", b"virtio,mmio\0")
+                .cells("reg", reg)
+        }
+
+        fn build(&mut self) -> Vec<u8> {
+            self.token(FDT_END);
+            let header_size = 40;
+            let off_dt_struct = header_size;
+            let off_dt_strings = off_dt_struct + self.structure.len();
+            let total_size = off_dt_strings + self.strings.len();
+            let header = [
+                FDT_MAGIC,
+                total_size as u32,
+                off_dt_struct as u32,
+                off_dt_strings as u32,
+                // Memory reservation map, which we don't read.
+                0,
+                MIN_VERSION,
+                16,
+                0,
+                self.strings.len() as u32,
+                self.structure.len() as u32,
+            ];
+            let mut blob: Vec<u8> = header.iter().flat_map(|word| word.to_be_bytes()).collect();
+            blob.extend_from_slice(&self.structure);
+            blob.extend_from_slice(&self.strings);
+            blob
+        }
+    }
+
+    #[test]
+    fn bad_magic() {
+        assert_eq!(
+            virtio_mmio_nodes(&[0; 40]).unwrap_err(),
+            FdtError::BadMagic(0)
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        let mut blob = FdtBuilder::default()
+            .begin_node("")
+            .virtio_mmio("virtio_mmio@a000000", &[0, 0xa000000, 0, 0x200])
+            .end_node()
+            .end_node()
+            .build();
+        blob.truncate(blob.len() - 8);
+        assert_eq!(virtio_mmio_nodes(&blob).unwrap_err(), FdtError::Truncated);
+    }
+
+    #[test]
+    fn find_nodes() {
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .cells("#address-cells", &[2])
+            .cells("#size-cells", &[2])
+            .virtio_mmio("virtio_mmio@a000000", &[0, 0xa000000, 0, 0x200])
+            .cells("interrupts", &[0, 16, 1])
+            .end_node()
+            .virtio_mmio("virtio_mmio@a000200", &[0, 0xa000200, 0, 0x200])
+            .property("status", b"disabled\0")
+            .end_node()
+            .begin_node("serial@9000000")
+            .property("compatible", b"arm,pl011\0arm,primecell\0")
+            .cells("reg", &[0, 0x9000000, 0, 0x1000])
+            .end_node()
+            .begin_node("soc")
+            .cells("#address-cells", &[1])
+            .cells("#size-cells", &[1])
+            .begin_node("virtio@1000")
+            .property("compatible", b"vendor,thing\0virtio,mmio\0")
+            .cells("reg", &[0x1000, 0x100])
+            .property("status", b"okay\0")
+            .end_node()
+            .end_node()
+            .end_node()
+            .build();
+
+        let nodes: Vec<_> = virtio_mmio_nodes(&blob).unwrap().collect();
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0].name, "virtio_mmio@a000000");
+        assert_eq!(nodes[0].address, 0xa000000);
+        assert_eq!(nodes[0].size, 0x200);
+        assert_eq!(nodes[0].interrupts().collect::<Vec<_>>(), vec![0, 16, 1]);
+        assert_eq!(nodes[1].name, "virtio@1000");
+        assert_eq!(nodes[1].address, 0x1000);
+        assert_eq!(nodes[1].size, 0x100);
+        assert_eq!(nodes[1].interrupts().count(), 0);
+    }
+
+    #[test]
+    fn nop_between_properties() {
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .cells("#address-cells", &[1])
+            .cells("#size-cells", &[1])
+            .begin_node("virtio_mmio@a000000")
+            .property("compatible", b"virtio,mmio\0")
+            .nop()
+            .cells("reg", &[0xa000000, 0x200])
+            .nop()
+            .nop()
+            .cells("interrupts", &[0, 16, 1])
+            .end_node()
+            .end_node()
+            .build();
+
+        let nodes: Vec<_> = virtio_mmio_nodes(&blob).unwrap().collect();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].name, "virtio_mmio@a000000");
+        assert_eq!(nodes[0].address, 0xa000000);
+        assert_eq!(nodes[0].size, 0x200);
+        assert_eq!(nodes[0].interrupts().collect::<Vec<_>>(), vec![0, 16, 1]);
+    }
+
+    #[test]
+    fn transports_skip_placeholders() {
+        let mut placeholder = VirtIOHeader::make_fake_header(MODERN_VERSION, 0, 0, 0, 4);
+        let mut block =
+            VirtIOHeader::make_fake_header(MODERN_VERSION, DeviceType::Block as u32, 0, 0, 4);
+        let reg = |header: &mut VirtIOHeader| {
+            let address = header as *mut VirtIOHeader as u64;
+            [
+                (address >> 32) as u32,
+                address as u32,
+                0,
+                size_of::<VirtIOHeader>() as u32,
+            ]
+        };
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .cells("#address-cells", &[2])
+            .cells("#size-cells", &[2])
+            .virtio_mmio("placeholder", &reg(&mut placeholder))
+            .end_node()
+            .virtio_mmio("block", &reg(&mut block))
+            .end_node()
+            .end_node()
+            .build();
+
+        // SAFETY: The device tree describes the fake headers, which live longer than the
+        // transports.
+        let transports: Vec<_> = unsafe { virtio_mmio_transports::<FakeHal>(&blob) }
+            .unwrap()
+            .collect();
+        assert_eq!(transports.len(), 1);
+        let (node, transport) = &transports[0];
+        assert_eq!(node.name, "block");
+        assert_eq!(transport.as_ref().unwrap().device_type(), DeviceType::Block);
+    }
+}
@@ -4,13 +4,15 @@
 pub mod fake;
 pub mod mmio;
 pub mod pci;
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub mod shm;
 mod some;
 #[cfg(target_arch = "x86_64")]
 pub mod x86_64;
 
-use crate::{Error, PhysAddr, Result, PAGE_SIZE};
+use crate::{nonnull_slice_from_raw_parts, Error, Hal, PhysAddr, Result, PAGE_SIZE};
 use bitflags::{bitflags, Flags};
-use core::{fmt::Debug, ops::BitAnd};
+use core::{fmt::Debug, hint::spin_loop, ops::BitAnd, ptr::NonNull};
 use log::debug;
 pub use some::SomeTransport;
 use zerocopy::{FromBytes, Immutable, IntoBytes};
@@ -77,6 +79,14 @@ pub trait Transport: Send + Sync {
     /// Ref: 2.6.2 Legacy Interfaces: A Note on Virtqueue Layout
     fn requires_legacy_layout(&self) -> bool;
 
+    /// Returns whether queues must be exactly the size returned by
+    /// [`max_queue_size`](Self::max_queue_size), rather than any size up to it.
+    ///
+    /// This is the case for legacy PCI devices, where the driver can't choose the queue size.
+    fn requires_exact_queue_size(&self) -> bool {
+        false
+    }
+
     /// Sets up the given queue.
     fn queue_set(
         &mut self,
@@ -98,17 +108,16 @@ pub trait Transport: Send + Sync {
     /// Returns true on success.
     fn ack_interrupt(&mut self) -> InterruptStatus;
 
-    // TODO: Implement this method soundly for the existing Transport impls in this crate
     /// Acknowledges an interrupt using a raw pointer.
     ///
-    /// This is useful when you cannot soundly get a mutable reference to the Transport impl.
+    /// This is useful when you cannot soundly get a mutable reference to the Transport impl, such
+    /// as in an interrupt handler. Implementations must not write through `ptr` or create a mutable
+    /// reference from it, as other references to the transport may exist.
     ///
     /// # Safety
     ///
     /// `ptr` must point to an initialized Transport impl which is ready to acknowledge interrupts.
-    unsafe fn ack_interrupt_raw(_ptr: *mut Self) -> InterruptStatus {
-        todo!("sound ack_interrupt_raw implementations are transport-specific")
-    }
+    unsafe fn ack_interrupt_raw(ptr: *mut Self) -> InterruptStatus;
 
     /// Begins initializing the device.
     ///
@@ -166,6 +175,53 @@ pub trait Transport: Send + Sync {
         );
     }
 
+    /// Returns whether the device has set `DEVICE_NEEDS_RESET` in its status, indicating that it
+    /// has experienced an error from which it can't recover without being reset.
+    ///
+    /// Devices usually send a configuration change interrupt when they set this.
+    fn needs_reset(&self) -> bool {
+        self.get_status().contains(DeviceStatus::DEVICE_NEEDS_RESET)
+    }
+
+    /// Starts resetting the device through a shared reference, by writing 0 to the device status.
+    ///
+    /// This lets a queue stop a failed device while buffers are still shared with it, without
+    /// exclusive access to the transport. The caller must then wait for the reset to complete with
+    /// [`get_status`](Self::get_status) before assuming that the device won't access them.
+    ///
+    /// Returns false if the transport doesn't support this, which is the default.
+    fn reset_shared(&self) -> bool {
+        false
+    }
+
+    /// Asks the device to suspend, and waits until it has done so.
+    ///
+    /// This must only be called once the device is live, and if `VIRTIO_F_SUSPEND` has been
+    /// negotiated. While the device is suspended it won't process any virtqueues or send any
+    /// notifications, so the driver should first wait for any requests in flight to complete.
+    ///
+    /// Returns [`Error::NotReady`] if the device doesn't suspend within a bounded time.
+    fn suspend(&mut self) -> Result {
+        let status = self.get_status();
+        if !status.contains(DeviceStatus::DRIVER_OK) {
+            return Err(Error::NotReady);
+        }
+        self.set_status(status | DeviceStatus::SUSPEND);
+        wait_for_suspend(self, true)
+    }
+
+    /// Asks a suspended device to resume, and waits until it has done so.
+    ///
+    /// Returns [`Error::NotReady`] if the device doesn't resume within a bounded time.
+    fn resume(&mut self) -> Result {
+        let status = self.get_status();
+        if !status.contains(DeviceStatus::SUSPEND) {
+            return Ok(());
+        }
+        self.set_status(status - DeviceStatus::SUSPEND);
+        wait_for_suspend(self, false)
+    }
+
     /// Reads the configuration space generation.
     fn read_config_generation(&self) -> u32;
 
@@ -179,6 +235,17 @@ pub trait Transport: Send + Sync {
         value: T,
     ) -> Result<()>;
 
+    /// Returns the shared memory region with the given ID, mapped with [`Hal::mmio_phys_to_virt`],
+    /// or `None` if the device doesn't have a region with that ID.
+    ///
+    /// The default implementation is for transports which don't support shared memory regions, and
+    /// always returns `None`.
+    ///
+    /// Ref: 2.10 Shared Memory Regions
+    fn shared_memory_region<H: Hal>(&mut self, _id: u8) -> Option<SharedMemoryRegion> {
+        None
+    }
+
     /// Safely reads multiple fields from config space by ensuring that the config generation is the
     /// same before and after all reads, and retrying if not.
     fn read_consistent<T>(&self, f: impl Fn() -> Result<T>) -> Result<T> {
@@ -193,6 +260,73 @@ pub trait Transport: Send + Sync {
     }
 }
 
+/// A shared memory region exposed by a device, such as a virtio-fs DAX window or the host-visible
+/// memory of a virtio-gpu device.
+///
+/// Ref: 2.10 Shared Memory Regions
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SharedMemoryRegion {
+    /// The physical address of the start of the region.
+    pub paddr: PhysAddr,
+    /// The region, as mapped by [`Hal::mmio_phys_to_virt`].
+    pub region: NonNull<[u8]>,
+}
+
+impl SharedMemoryRegion {
+    /// Maps the region of the given length at the given physical address, or returns `None` if it
+    /// doesn't fit in the address space.
+    ///
+    /// # Safety
+    ///
+    /// The `paddr` and `len` must describe a shared memory region which the device exposes.
+    pub(crate) unsafe fn map<H: Hal>(paddr: u64, len: u64) -> Option<Self> {
+        let paddr = PhysAddr::try_from(paddr).ok()?;
+        let len = usize::try_from(len).ok()?;
+        paddr.checked_add(len)?;
+        // SAFETY: The caller promised that this is a region of device memory.
+        let ptr = unsafe { H::mmio_phys_to_virt(paddr, len) };
+        Some(Self {
+            paddr,
+            region: nonnull_slice_from_raw_parts(ptr, len),
+        })
+    }
+}
+
+/// The number of times to poll the device status while waiting for a reset, suspend or resume to
+/// complete, before giving up on the device.
+const STATUS_POLLS: usize = 1_000_000;
+
+/// Waits until the device status reads as 0 after the driver has written 0 to it, to reset it.
+///
+/// Returns false if this doesn't happen within a bounded number of polls, e.g. because the device
+/// has been removed and every read returns all ones.
+pub(crate) fn wait_for_reset(transport: &(impl Transport + ?Sized)) -> bool {
+    for _ in 0..STATUS_POLLS {
+        if transport.get_status() == DeviceStatus::empty() {
+            return true;
+        }
+        spin_loop();
+    }
+    false
+}
+
+/// Waits until the device reports that it is suspended or not, according to `suspended`.
+///
+/// Returns [`Error::NotReady`] if this doesn't happen within a bounded number of polls.
+fn wait_for_suspend(transport: &(impl Transport + ?Sized), suspended: bool) -> Result {
+    for _ in 0..STATUS_POLLS {
+        let status = transport.get_status();
+        if status.contains(DeviceStatus::DEVICE_NEEDS_RESET) {
+            return Err(Error::DeviceNeedsReset);
+        }
+        if status.contains(DeviceStatus::SUSPEND) == suspended {
+            return Ok(());
+        }
+        spin_loop();
+    }
+    Err(Error::NotReady)
+}
+
 bitflags! {
     /// The device status field. Writing 0 into this field resets the device.
     #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
@@ -220,6 +354,10 @@ bitflags! {
         /// Indicates that the device has experienced an error from which it
         /// can’t recover.
         const DEVICE_NEEDS_RESET = 64;
+
+        /// Set by the driver to ask the device to suspend, and then by the device once it has
+        /// done so. Only valid if `VIRTIO_F_SUSPEND` has been negotiated.
+        const SUSPEND = 16;
     }
 }
 
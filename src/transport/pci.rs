@@ -1,31 +1,37 @@
 //! PCI transport for VirtIO.
 
 pub mod bus;
+#[cfg(test)]
+pub mod fake;
+pub mod legacy;
 
 use self::bus::{
-    ConfigurationAccess, DeviceFunction, DeviceFunctionInfo, PciError, PciRoot, PCI_CAP_ID_VNDR,
+    ConfigurationAccess, DeviceFunction, DeviceFunctionInfo, MsixInfo, PciError, PciRoot,
+    PCI_CAP_ID_VNDR,
 };
-use super::{DeviceStatus, DeviceType, Transport};
+use super::{DeviceStatus, DeviceType, SharedMemoryRegion, Transport};
 use crate::{
     hal::{Hal, PhysAddr},
     nonnull_slice_from_raw_parts,
     transport::InterruptStatus,
-    volatile::{
-        volread, volwrite, ReadOnly, Volatile, VolatileReadable, VolatileWritable, WriteOnly,
-    },
+    volatile::{volread, volwrite, ReadOnly, Volatile},
     Error,
 };
 use core::{
+    fmt::Debug,
+    hint::spin_loop,
     mem::{align_of, size_of},
     ptr::NonNull,
+    sync::atomic::{AtomicBool, Ordering},
 };
+use log::warn;
 use zerocopy::{FromBytes, Immutable, IntoBytes};
 
 /// The PCI vendor ID for VirtIO devices.
 pub const VIRTIO_VENDOR_ID: u16 = 0x1af4;
 
 /// The offset to add to a VirtIO device ID to get the corresponding PCI device ID.
-const PCI_DEVICE_ID_OFFSET: u16 = 0x1040;
+pub(crate) const PCI_DEVICE_ID_OFFSET: u16 = 0x1040;
 
 const TRANSITIONAL_NETWORK: u16 = 0x1000;
 const TRANSITIONAL_BLOCK: u16 = 0x1001;
@@ -43,6 +49,16 @@ pub(crate) const CAP_BAR_OFFSET_OFFSET: u8 = 8;
 pub(crate) const CAP_LENGTH_OFFSET: u8 = 12;
 /// The offset of the`notify_off_multiplier` field within `virtio_pci_notify_cap`.
 pub(crate) const CAP_NOTIFY_OFF_MULTIPLIER_OFFSET: u8 = 16;
+/// The offset of the `offset_hi` field within `virtio_pci_cap64`.
+const CAP_OFFSET_HIGH_OFFSET: u8 = 16;
+/// The offset of the `length_hi` field within `virtio_pci_cap64`.
+const CAP_LENGTH_HIGH_OFFSET: u8 = 20;
+
+/// The maximum number of shared memory regions which `PciTransport` keeps track of.
+const MAX_SHARED_MEMORY_REGIONS: usize = 4;
+
+/// The maximum number of queues for which `PciTransport` caches the notification offset.
+const MAX_CACHED_NOTIFY_OFFSETS: usize = 64;
 
 /// Common configuration.
 pub const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
@@ -52,6 +68,11 @@ pub const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
 pub const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
 /// Device specific configuration.
 pub const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+/// Shared memory region.
+pub const VIRTIO_PCI_CAP_SHARED_MEMORY_CFG: u8 = 8;
+
+/// The MSI-X vector value which disables interrupts for a queue or for configuration changes.
+pub const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
 
 pub(crate) fn device_type(pci_device_id: u16) -> DeviceType {
     match pci_device_id {
@@ -79,23 +100,154 @@ pub fn virtio_device_type(device_function_info: &DeviceFunctionInfo) -> Option<D
     None
 }
 
-/// PCI transport for VirtIO.
+/// Reads a field of the common configuration structure through some [`RegionAccess`].
+macro_rules! configread {
+    ($common_cfg:expr, $field:ident) => {
+        $common_cfg.read(core::mem::offset_of!(CommonCfg, $field))
+    };
+}
+
+/// Writes a field of the common configuration structure through some [`RegionAccess`].
+macro_rules! configwrite {
+    ($common_cfg:expr, $field:ident, $value:expr) => {
+        $common_cfg.write(core::mem::offset_of!(CommonCfg, $field), $value)
+    };
+}
+
+/// Access to a VirtIO structure within some BAR of a PCI device function.
+///
+/// This abstracts over how the BAR is accessed, so that the same [`GenericPciTransport`] logic can
+/// be used both for BARs mapped into the driver's address space ([`MmioRegion`]) and for BARs which
+/// must be accessed some other way, such as via hypercalls.
+pub trait RegionAccess: Debug + Send + Sync {
+    /// Returns the size of the region in bytes.
+    fn size(&self) -> usize;
+
+    /// Reads a value from the given offset within the region.
+    ///
+    /// Panics if the value would extend beyond the end of the region.
+    fn read<T: FromBytes>(&self, offset: usize) -> T;
+
+    /// Writes a value to the given offset within the region.
+    ///
+    /// Panics if the value would extend beyond the end of the region.
+    fn write<T: IntoBytes + Immutable>(&self, offset: usize, value: T);
+
+    /// Maps a shared memory region of the device at the given physical address, if the driver can
+    /// access BARs directly with this kind of region.
+    ///
+    /// # Safety
+    ///
+    /// The physical address range must be a shared memory region reported by the device.
+    unsafe fn map_shared_memory<H: Hal>(paddr: u64, length: u64) -> Option<SharedMemoryRegion> {
+        // SAFETY: Our caller promised that this is a shared memory region of the device.
+        unsafe { SharedMemoryRegion::map::<H>(paddr, length) }
+    }
+}
+
+/// A region of a BAR which is mapped into the driver's address space and accessed with volatile
+/// reads and writes.
+#[derive(Debug)]
+pub struct MmioRegion {
+    base: NonNull<u8>,
+    size: usize,
+}
+
+impl MmioRegion {
+    /// Maps the given physical address range of a BAR with the HAL, checking that it is aligned to
+    /// the given alignment.
+    ///
+    /// # Safety
+    ///
+    /// The physical address range must be a valid MMIO region which nothing else accesses.
+    pub unsafe fn map<H: Hal>(
+        paddr: PhysAddr,
+        size: usize,
+        alignment: usize,
+    ) -> Result<Self, VirtioPciError> {
+        // SAFETY: Our caller promised that the paddr and size describe a valid MMIO region.
+        let base = unsafe { H::mmio_phys_to_virt(paddr, size) };
+        if !(base.as_ptr() as usize).is_multiple_of(alignment) {
+            return Err(VirtioPciError::Misaligned {
+                address: base.as_ptr() as usize,
+                alignment,
+            });
+        }
+        Ok(Self { base, size })
+    }
+
+    fn pointer<T>(&self, offset: usize) -> *mut T {
+        assert!(offset + size_of::<T>() <= self.size);
+        // SAFETY: We just checked that the offset is within the region.
+        let ptr = unsafe { self.base.as_ptr().add(offset) };
+        assert!((ptr as usize).is_multiple_of(align_of::<T>()));
+        ptr.cast()
+    }
+}
+
+impl RegionAccess for MmioRegion {
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn read<T: FromBytes>(&self, offset: usize) -> T {
+        // SAFETY: The region is valid MMIO, and `pointer` checks that the access is within it and
+        // aligned.
+        unsafe { self.pointer::<T>(offset).read_volatile() }
+    }
+
+    fn write<T: IntoBytes + Immutable>(&self, offset: usize, value: T) {
+        // SAFETY: The region is valid MMIO, and `pointer` checks that the access is within it and
+        // aligned.
+        unsafe { self.pointer::<T>(offset).write_volatile(value) }
+    }
+}
+
+// SAFETY: MMIO can be done from any thread or CPU core.
+unsafe impl Send for MmioRegion {}
+
+// SAFETY: MMIO can be done concurrently from different CPU cores, and the device is responsible for
+// handling that.
+unsafe impl Sync for MmioRegion {}
+
+/// PCI transport for VirtIO, generic over how the VirtIO structures in its BARs are accessed.
+///
+/// Use [`PciTransport`] for BARs mapped into the driver's address space.
 ///
 /// Ref: 4.1 Virtio Over PCI Bus
 #[derive(Debug)]
-pub struct PciTransport {
+pub struct GenericPciTransport<R: RegionAccess> {
     device_type: DeviceType,
     /// The bus, device and function identifier for the VirtIO device.
     device_function: DeviceFunction,
     /// The common configuration structure within some BAR.
-    common_cfg: NonNull<CommonCfg>,
+    common_cfg: R,
     /// The start of the queue notification region within some BAR.
-    notify_region: NonNull<[WriteOnly<u16>]>,
+    notify_region: R,
     notify_off_multiplier: u32,
     /// The ISR status register within some BAR.
-    isr_status: NonNull<Volatile<u8>>,
+    isr_status: R,
     /// The VirtIO device-specific configuration within some BAR.
-    config_space: Option<NonNull<[u32]>>,
+    config_space: Option<R>,
+    /// The shared memory regions of the device.
+    shared_memory: [Option<SharedMemoryInfo>; MAX_SHARED_MEMORY_REGIONS],
+    /// The `queue_notify_off` of each queue which has been set up, so that notifying a queue
+    /// doesn't need to select it first.
+    notify_offsets: [Option<u16>; MAX_CACHED_NOTIFY_OFFSETS],
+    /// Held while `notify` selects a queue whose `queue_notify_off` isn't cached and reads it, so
+    /// that concurrent notifications for different queues don't change the selection under it.
+    queue_select_lock: AtomicBool,
+}
+
+/// PCI transport for VirtIO, with the BARs mapped into the driver's address space.
+pub type PciTransport = GenericPciTransport<MmioRegion>;
+
+/// The location of a shared memory region, from a `VIRTIO_PCI_CAP_SHARED_MEMORY_CFG` capability.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct SharedMemoryInfo {
+    id: u8,
+    paddr: u64,
+    length: u64,
 }
 
 impl PciTransport {
@@ -106,6 +258,25 @@ impl PciTransport {
     pub fn new<H: Hal, C: ConfigurationAccess>(
         root: &mut PciRoot<C>,
         device_function: DeviceFunction,
+    ) -> Result<Self, VirtioPciError> {
+        Self::with_regions(root, device_function, |paddr, size, alignment| {
+            // SAFETY: The paddr and size describe a valid MMIO region, at least according to the
+            // PCI bus.
+            unsafe { MmioRegion::map::<H>(paddr, size, alignment) }
+        })
+    }
+}
+
+impl<R: RegionAccess> GenericPciTransport<R> {
+    /// Construct a new PCI VirtIO device driver for the given device function on the given PCI
+    /// root controller, using `map_region` to get access to each VirtIO structure.
+    ///
+    /// `map_region` is called with the physical address, size and required alignment of each
+    /// structure. The PCI device must already have had its BARs allocated.
+    pub fn with_regions<C: ConfigurationAccess>(
+        root: &mut PciRoot<C>,
+        device_function: DeviceFunction,
+        mut map_region: impl FnMut(PhysAddr, usize, usize) -> Result<R, VirtioPciError>,
     ) -> Result<Self, VirtioPciError> {
         let device_vendor = root.configuration_access.read_word(device_function, 0);
         let device_id = (device_vendor >> 16) as u16;
@@ -121,6 +292,8 @@ impl PciTransport {
         let mut notify_off_multiplier = 0;
         let mut isr_cfg = None;
         let mut device_cfg = None;
+        let mut shared_memory_caps = [None; MAX_SHARED_MEMORY_REGIONS];
+        let mut shared_memory_count = 0;
         for capability in root.capabilities(device_function) {
             if capability.id != PCI_CAP_ID_VNDR {
                 continue;
@@ -160,14 +333,51 @@ impl PciTransport {
                 VIRTIO_PCI_CAP_DEVICE_CFG if device_cfg.is_none() => {
                     device_cfg = Some(struct_info);
                 }
+                VIRTIO_PCI_CAP_SHARED_MEMORY_CFG if cap_len >= 24 => {
+                    if shared_memory_count == MAX_SHARED_MEMORY_REGIONS {
+                        warn!("Ignoring shared memory capability, too many regions");
+                        continue;
+                    }
+                    let id = (root
+                        .configuration_access
+                        .read_word(device_function, capability.offset + CAP_BAR_OFFSET)
+                        >> 8) as u8;
+                    let offset_high = root
+                        .configuration_access
+                        .read_word(device_function, capability.offset + CAP_OFFSET_HIGH_OFFSET);
+                    let length_high = root
+                        .configuration_access
+                        .read_word(device_function, capability.offset + CAP_LENGTH_HIGH_OFFSET);
+                    shared_memory_caps[shared_memory_count] = Some((
+                        id,
+                        struct_info.bar,
+                        u64::from(struct_info.offset) | u64::from(offset_high) << 32,
+                        u64::from(struct_info.length) | u64::from(length_high) << 32,
+                    ));
+                    shared_memory_count += 1;
+                }
                 _ => {}
             }
         }
 
-        let common_cfg = get_bar_region::<H, _, _>(
+        // A bad shared memory region shouldn't stop the rest of the device from being used.
+        let mut shared_memory = [None; MAX_SHARED_MEMORY_REGIONS];
+        let mut shared_memory_slots = shared_memory.iter_mut();
+        for (id, bar, offset, length) in shared_memory_caps.into_iter().flatten() {
+            match shared_memory_info(root, device_function, id, bar, offset, length) {
+                Ok(info) => *shared_memory_slots.next().unwrap() = Some(info),
+                Err(e) => warn!(
+                    "Ignoring shared memory region {} in BAR {} at {:#x}+{:#x}: {}",
+                    id, bar, offset, length, e
+                ),
+            }
+        }
+
+        let common_cfg = get_bar_region::<CommonCfg, _, _>(
             root,
             device_function,
             &common_cfg.ok_or(VirtioPciError::MissingCommonConfig)?,
+            &mut map_region,
         )?;
 
         let notify_cfg = notify_cfg.ok_or(VirtioPciError::MissingNotifyConfig)?;
@@ -176,19 +386,22 @@ impl PciTransport {
                 notify_off_multiplier,
             ));
         }
-        let notify_region = get_bar_region_slice::<H, _, _>(root, device_function, &notify_cfg)?;
+        let notify_region =
+            get_bar_region::<u16, _, _>(root, device_function, &notify_cfg, &mut map_region)?;
 
-        let isr_status = get_bar_region::<H, _, _>(
+        let isr_status = get_bar_region::<u8, _, _>(
             root,
             device_function,
             &isr_cfg.ok_or(VirtioPciError::MissingIsrConfig)?,
+            &mut map_region,
         )?;
 
         let config_space = if let Some(device_cfg) = device_cfg {
-            Some(get_bar_region_slice::<H, _, _>(
+            Some(get_bar_region::<u32, _, _>(
                 root,
                 device_function,
                 &device_cfg,
+                &mut map_region,
             )?)
         } else {
             None
@@ -202,78 +415,232 @@ impl PciTransport {
             notify_off_multiplier,
             isr_status,
             config_space,
+            shared_memory,
+            notify_offsets: [None; MAX_CACHED_NOTIFY_OFFSETS],
+            queue_select_lock: AtomicBool::new(false),
         })
     }
+
+    /// Sets the MSI-X vector which the device uses for configuration change interrupts.
+    ///
+    /// `vector` is an index into the MSI-X table of the device function, or
+    /// [`VIRTIO_MSI_NO_VECTOR`] to disable configuration change interrupts. This only has an effect
+    /// once MSI-X has been enabled with [`PciRoot::set_msix_enabled`], after which the device
+    /// doesn't use the ISR status register so there is no need to call
+    /// [`ack_interrupt`](Transport::ack_interrupt).
+    ///
+    /// Returns [`VirtioPciError::MsixVectorRejected`] if the device couldn't allocate resources for
+    /// the vector.
+    pub fn set_config_msix_vector(&mut self, vector: u16) -> Result<(), VirtioPciError> {
+        configwrite!(self.common_cfg, msix_config, vector);
+        check_msix_vector(vector, configread!(self.common_cfg, msix_config))
+    }
+
+    /// Sets the MSI-X vector which the device uses for used buffer notifications on the given
+    /// queue.
+    ///
+    /// This works like [`set_config_msix_vector`](Self::set_config_msix_vector). Different queues
+    /// may use different vectors, e.g. so that their interrupts can be routed to different CPUs.
+    pub fn set_queue_msix_vector(&mut self, queue: u16, vector: u16) -> Result<(), VirtioPciError> {
+        configwrite!(self.common_cfg, queue_select, queue);
+        configwrite!(self.common_cfg, queue_msix_vector, vector);
+        check_msix_vector(vector, configread!(self.common_cfg, queue_msix_vector))
+    }
 }
 
-impl Transport for PciTransport {
-    fn device_type(&self) -> DeviceType {
-        self.device_type
+/// Checks that the device accepted the MSI-X vector which was written.
+///
+/// Ref: 4.1.5.1.2 MSI-X Vector Configuration
+fn check_msix_vector(vector: u16, mapped: u16) -> Result<(), VirtioPciError> {
+    if mapped == vector {
+        Ok(())
+    } else {
+        Err(VirtioPciError::MsixVectorRejected(vector))
     }
+}
 
-    fn read_device_features(&mut self) -> u64 {
-        // SAFETY: The common config pointer is valid and we checked in `get_bar_region` that it
-        // was aligned.
+/// The MSI-X table of a PCI device function.
+///
+/// Each entry gives the message address and data which the device function writes to raise the
+/// interrupt for the corresponding vector.
+#[derive(Debug)]
+pub struct MsixTable {
+    entries: NonNull<[MsixTableEntry]>,
+}
+
+impl MsixTable {
+    /// Maps the MSI-X table of the given device function, as described by
+    /// [`PciRoot::msix_info`].
+    ///
+    /// The BAR containing the table must already have been allocated.
+    pub fn new<H: Hal, C: ConfigurationAccess>(
+        root: &mut PciRoot<C>,
+        device_function: DeviceFunction,
+        msix_info: &MsixInfo,
+    ) -> Result<Self, VirtioPciError> {
+        let region = get_bar_region::<MsixTableEntry, _, _>(
+            root,
+            device_function,
+            &VirtioCapabilityInfo {
+                bar: msix_info.table_bar,
+                offset: msix_info.table_offset,
+                length: u32::from(msix_info.table_size) * size_of::<MsixTableEntry>() as u32,
+            },
+            |paddr, size, alignment| {
+                // SAFETY: The paddr and size describe a valid MMIO region, at least according to
+                // the PCI bus.
+                unsafe { MmioRegion::map::<H>(paddr, size, alignment) }
+            },
+        )?;
+        Ok(Self {
+            entries: nonnull_slice_from_raw_parts(
+                region.base.cast(),
+                region.size / size_of::<MsixTableEntry>(),
+            ),
+        })
+    }
+
+    /// Returns the number of vectors in the table.
+    pub fn table_size(&self) -> u16 {
+        self.entries.len() as u16
+    }
+
+    /// Sets the message address and data for the given vector, and unmasks it.
+    pub fn set_entry(
+        &mut self,
+        vector: u16,
+        address: u64,
+        data: u32,
+    ) -> Result<(), VirtioPciError> {
+        let entry = self.entry(vector)?;
+        // SAFETY: The entry pointer is within the table, which is valid and which we checked in
+        // `get_bar_region` was aligned.
         unsafe {
-            volwrite!(self.common_cfg, device_feature_select, 0);
-            let mut device_features_bits = volread!(self.common_cfg, device_feature) as u64;
-            volwrite!(self.common_cfg, device_feature_select, 1);
-            device_features_bits |= (volread!(self.common_cfg, device_feature) as u64) << 32;
-            device_features_bits
+            // Mask the vector while changing it, so the device can't use a half-written entry.
+            volwrite!(entry, vector_control, MSIX_VECTOR_MASKED);
+            volwrite!(entry, message_address_low, address as u32);
+            volwrite!(entry, message_address_high, (address >> 32) as u32);
+            volwrite!(entry, message_data, data);
+            volwrite!(entry, vector_control, 0);
         }
+        Ok(())
     }
 
-    fn write_driver_features(&mut self, driver_features: u64) {
-        // SAFETY: The common config pointer is valid and we checked in `get_bar_region` that it
-        // was aligned.
+    /// Masks or unmasks the given vector.
+    pub fn set_masked(&mut self, vector: u16, masked: bool) -> Result<(), VirtioPciError> {
+        let entry = self.entry(vector)?;
+        // SAFETY: The entry pointer is within the table, which is valid and which we checked in
+        // `get_bar_region` was aligned.
         unsafe {
-            volwrite!(self.common_cfg, driver_feature_select, 0);
-            volwrite!(self.common_cfg, driver_feature, driver_features as u32);
-            volwrite!(self.common_cfg, driver_feature_select, 1);
+            let control = volread!(entry, vector_control);
             volwrite!(
-                self.common_cfg,
-                driver_feature,
-                (driver_features >> 32) as u32
+                entry,
+                vector_control,
+                if masked {
+                    control | MSIX_VECTOR_MASKED
+                } else {
+                    control & !MSIX_VECTOR_MASKED
+                }
             );
         }
+        Ok(())
     }
 
-    fn max_queue_size(&mut self, queue: u16) -> u32 {
-        // SAFETY: The common config pointer is valid and we checked in `get_bar_region` that it
-        // was aligned.
-        unsafe {
-            volwrite!(self.common_cfg, queue_select, queue);
-            volread!(self.common_cfg, queue_size).into()
+    fn entry(&self, vector: u16) -> Result<NonNull<MsixTableEntry>, VirtioPciError> {
+        if vector >= self.table_size() {
+            return Err(VirtioPciError::MsixVectorOutOfRange(vector));
         }
+        // SAFETY: We just checked that the vector is within the table.
+        Ok(unsafe {
+            self.entries
+                .cast::<MsixTableEntry>()
+                .add(usize::from(vector))
+        })
+    }
+}
+
+// SAFETY: MMIO can be done from any thread or CPU core.
+unsafe impl Send for MsixTable {}
+
+// SAFETY: `&MsixTable` only allows reading the table size.
+unsafe impl Sync for MsixTable {}
+
+/// The Mask Bit of the Vector Control field of an MSI-X table entry.
+const MSIX_VECTOR_MASKED: u32 = 1 << 0;
+
+/// An entry in the MSI-X table.
+#[repr(C)]
+struct MsixTableEntry {
+    message_address_low: Volatile<u32>,
+    message_address_high: Volatile<u32>,
+    message_data: Volatile<u32>,
+    vector_control: Volatile<u32>,
+}
+
+impl<R: RegionAccess> Transport for GenericPciTransport<R> {
+    fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        configwrite!(self.common_cfg, device_feature_select, 0u32);
+        let device_features_low: u32 = configread!(self.common_cfg, device_feature);
+        configwrite!(self.common_cfg, device_feature_select, 1u32);
+        let device_features_high: u32 = configread!(self.common_cfg, device_feature);
+        (device_features_high as u64) << 32 | device_features_low as u64
+    }
+
+    fn write_driver_features(&mut self, driver_features: u64) {
+        configwrite!(self.common_cfg, driver_feature_select, 0u32);
+        configwrite!(self.common_cfg, driver_feature, driver_features as u32);
+        configwrite!(self.common_cfg, driver_feature_select, 1u32);
+        configwrite!(
+            self.common_cfg,
+            driver_feature,
+            (driver_features >> 32) as u32
+        );
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u32 {
+        configwrite!(self.common_cfg, queue_select, queue);
+        let queue_size: u16 = configread!(self.common_cfg, queue_size);
+        queue_size.into()
     }
 
     fn notify(&self, queue: u16) {
-        // SAFETY: The common config and notify region pointers are valid and we checked in
-        // `get_bar_region` that they were aligned.
-        unsafe {
-            volwrite!(self.common_cfg, queue_select, queue);
-            // TODO: Consider caching this somewhere (per queue).
-            let queue_notify_off = volread!(self.common_cfg, queue_notify_off);
+        let queue_notify_off = match self.notify_offsets.get(usize::from(queue)) {
+            Some(Some(queue_notify_off)) => *queue_notify_off,
+            _ => {
+                while self
+                    .queue_select_lock
+                    .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+                    .is_err()
+                {
+                    spin_loop();
+                }
+                configwrite!(self.common_cfg, queue_select, queue);
+                let queue_notify_off = configread!(self.common_cfg, queue_notify_off);
+                self.queue_select_lock.store(false, Ordering::Release);
+                queue_notify_off
+            }
+        };
 
-            let offset_bytes = usize::from(queue_notify_off) * self.notify_off_multiplier as usize;
-            let index = offset_bytes / size_of::<u16>();
-            (&raw mut (*self.notify_region.as_ptr())[index]).vwrite(queue);
-        }
+        let offset_bytes = usize::from(queue_notify_off) * self.notify_off_multiplier as usize;
+        self.notify_region.write(offset_bytes, queue);
     }
 
     fn get_status(&self) -> DeviceStatus {
-        // SAFETY: The common config pointer is valid and we checked in `get_bar_region` that it
-        // was aligned.
-        let status = unsafe { volread!(self.common_cfg, device_status) };
+        let status: u8 = configread!(self.common_cfg, device_status);
         DeviceStatus::from_bits_truncate(status.into())
     }
 
     fn set_status(&mut self, status: DeviceStatus) {
-        // SAFETY: The common config pointer is valid and we checked in `get_bar_region` that it
-        // was aligned.
-        unsafe {
-            volwrite!(self.common_cfg, device_status, status.bits() as u8);
-        }
+        configwrite!(self.common_cfg, device_status, status.bits() as u8);
+    }
+
+    fn reset_shared(&self) -> bool {
+        configwrite!(self.common_cfg, device_status, 0u8);
+        true
     }
 
     fn set_guest_page_size(&mut self, _guest_page_size: u32) {
@@ -292,15 +659,14 @@ impl Transport for PciTransport {
         driver_area: PhysAddr,
         device_area: PhysAddr,
     ) {
-        // SAFETY: The common config pointer is valid and we checked in `get_bar_region` that it
-        // was aligned.
-        unsafe {
-            volwrite!(self.common_cfg, queue_select, queue);
-            volwrite!(self.common_cfg, queue_size, size as u16);
-            volwrite!(self.common_cfg, queue_desc, descriptors as u64);
-            volwrite!(self.common_cfg, queue_driver, driver_area as u64);
-            volwrite!(self.common_cfg, queue_device, device_area as u64);
-            volwrite!(self.common_cfg, queue_enable, 1);
+        configwrite!(self.common_cfg, queue_select, queue);
+        configwrite!(self.common_cfg, queue_size, size as u16);
+        configwrite!(self.common_cfg, queue_desc, descriptors as u64);
+        configwrite!(self.common_cfg, queue_driver, driver_area as u64);
+        configwrite!(self.common_cfg, queue_device, device_area as u64);
+        configwrite!(self.common_cfg, queue_enable, 1u16);
+        if let Some(notify_offset) = self.notify_offsets.get_mut(usize::from(queue)) {
+            *notify_offset = Some(configread!(self.common_cfg, queue_notify_off));
         }
     }
 
@@ -310,48 +676,43 @@ impl Transport for PciTransport {
     }
 
     fn queue_used(&mut self, queue: u16) -> bool {
-        // SAFETY: The common config pointer is valid and we checked in `get_bar_region` that it
-        // was aligned.
-        unsafe {
-            volwrite!(self.common_cfg, queue_select, queue);
-            volread!(self.common_cfg, queue_enable) == 1
-        }
+        configwrite!(self.common_cfg, queue_select, queue);
+        let queue_enable: u16 = configread!(self.common_cfg, queue_enable);
+        queue_enable == 1
     }
 
     fn ack_interrupt(&mut self) -> InterruptStatus {
-        // SAFETY: The pointer is non-null and a valid `PciTransport` instance. Also the
-        // ack_interrupt method takes a `&mut Self` so a `PciTransport` instance cannot be used to
-        // call this function concurrently. The safety requirements on ack_interrupt_raw require its
-        // callers to avoid calling that function concurrently with ack_interrupt with the same
-        // PciTransport instance.
+        // SAFETY: The pointer is non-null and a valid `GenericPciTransport` instance. Also the
+        // ack_interrupt method takes a `&mut Self` so a `GenericPciTransport` instance cannot be
+        // used to call this function concurrently. The safety requirements on ack_interrupt_raw
+        // require its callers to avoid calling that function concurrently with ack_interrupt with
+        // the same GenericPciTransport instance.
         unsafe { Self::ack_interrupt_raw(self as *mut Self) }
     }
 
-    /// Acknowledges an interrupt using a pointer to the PciTransport
+    /// Acknowledges an interrupt using a pointer to the GenericPciTransport
     ///
     /// This function is an alternative to `Transport::ack_interrupt` which does not require a
-    /// mutable reference to the `PciTransport`.
+    /// mutable reference to the `GenericPciTransport`.
     ///
     /// # Safety
     ///
-    /// `ptr` must point to a non-null, properly initialized `PciTransport` instance. Also the
-    /// caller must ensure that any other references or pointers to the `PciTransport` are not used
-    /// to call either this function or `ack_interrupt` concurrently.
+    /// `ptr` must point to a non-null, properly initialized `GenericPciTransport` instance. Also
+    /// the caller must ensure that any other references or pointers to the `GenericPciTransport`
+    /// are not used to call either this function or `ack_interrupt` concurrently.
     unsafe fn ack_interrupt_raw(ptr: *mut Self) -> InterruptStatus {
         // SAFETY: The isr_status field should not change once it has been initialized so reading
         // this field is fine.
-        let isr_status_ptr = unsafe { (*ptr).isr_status };
+        let isr_status_region = unsafe { &(*ptr).isr_status };
 
-        // SAFETY: The common config pointer is valid and we checked in `get_bar_region` that it
-        // was aligned.
         // Reading the ISR status resets it to 0 and causes the device to de-assert the interrupt.
-        let isr_status = unsafe { isr_status_ptr.as_ptr().vread() };
+        let isr_status: u8 = isr_status_region.read(0);
         InterruptStatus::from_bits_retain(isr_status.into())
     }
 
     fn read_config_generation(&self) -> u32 {
-        // SAFETY: self.header points to a valid VirtIO MMIO region.
-        unsafe { volread!(self.common_cfg, config_generation) }.into()
+        let config_generation: u8 = configread!(self.common_cfg, config_generation);
+        config_generation.into()
     }
 
     fn read_config_space<T: FromBytes>(&self, offset: usize) -> Result<T, Error> {
@@ -360,17 +721,14 @@ impl Transport for PciTransport {
             align_of::<T>());
         assert_eq!(offset % align_of::<T>(), 0);
 
-        let config_space = self.config_space.ok_or(Error::ConfigSpaceMissing)?;
-        if config_space.len() * size_of::<u32>() < offset + size_of::<T>() {
+        let config_space = self
+            .config_space
+            .as_ref()
+            .ok_or(Error::ConfigSpaceMissing)?;
+        if config_space.size() < offset + size_of::<T>() {
             Err(Error::ConfigSpaceTooSmall)
         } else {
-            // SAFETY: If we have a config space pointer it must be valid for its length, and we just
-            // checked that the offset and size of the access was within the length.
-            unsafe {
-                Ok((config_space.as_ptr().cast::<T>())
-                    .byte_add(offset)
-                    .read_volatile())
-            }
+            Ok(config_space.read(offset))
         }
     }
 
@@ -384,30 +742,30 @@ impl Transport for PciTransport {
             align_of::<T>());
         assert_eq!(offset % align_of::<T>(), 0);
 
-        let config_space = self.config_space.ok_or(Error::ConfigSpaceMissing)?;
-        if config_space.len() * size_of::<u32>() < offset + size_of::<T>() {
+        let config_space = self
+            .config_space
+            .as_ref()
+            .ok_or(Error::ConfigSpaceMissing)?;
+        if config_space.size() < offset + size_of::<T>() {
             Err(Error::ConfigSpaceTooSmall)
         } else {
-            // SAFETY: If we have a config space pointer it must be valid for its length, and we just
-            // checked that the offset and size of the access was within the length.
-            unsafe {
-                (config_space.as_ptr().cast::<T>())
-                    .byte_add(offset)
-                    .write_volatile(value);
-            }
+            config_space.write(offset, value);
             Ok(())
         }
     }
-}
 
-// SAFETY: MMIO can be done from any thread or CPU core.
-unsafe impl Send for PciTransport {}
-
-// SAFETY: `&PciTransport` only allows MMIO reads or getting the config space, both of which are
-// fine to happen concurrently on different CPU cores.
-unsafe impl Sync for PciTransport {}
+    fn shared_memory_region<H: Hal>(&mut self, id: u8) -> Option<SharedMemoryRegion> {
+        let info = self
+            .shared_memory
+            .iter()
+            .flatten()
+            .find(|info| info.id == id)?;
+        // SAFETY: The device reported this region in its BAR.
+        unsafe { R::map_shared_memory::<H>(info.paddr, info.length) }
+    }
+}
 
-impl Drop for PciTransport {
+impl<R: RegionAccess> Drop for GenericPciTransport<R> {
     fn drop(&mut self) {
         // Reset the device when the transport is dropped.
         self.set_status(DeviceStatus::empty());
@@ -447,11 +805,14 @@ pub(crate) struct VirtioCapabilityInfo {
     pub length: u32,
 }
 
-fn get_bar_region<H: Hal, T, C: ConfigurationAccess>(
+/// Finds the physical address of the given VirtIO structure in a BAR, checks that it fits, and
+/// gets access to it with `map_region`.
+fn get_bar_region<T, R, C: ConfigurationAccess>(
     root: &mut PciRoot<C>,
     device_function: DeviceFunction,
     struct_info: &VirtioCapabilityInfo,
-) -> Result<NonNull<T>, VirtioPciError> {
+    mut map_region: impl FnMut(PhysAddr, usize, usize) -> Result<R, VirtioPciError>,
+) -> Result<R, VirtioPciError> {
     let bar_info = root.bar_info(device_function, struct_info.bar)?;
     let (bar_address, bar_size) = bar_info
         .memory_address_size()
@@ -465,27 +826,36 @@ fn get_bar_region<H: Hal, T, C: ConfigurationAccess>(
         return Err(VirtioPciError::BarOffsetOutOfRange);
     }
     let paddr = bar_address as PhysAddr + struct_info.offset as PhysAddr;
-    // SAFETY: The paddr and size describe a valid MMIO region, at least according to the PCI bus.
-    let vaddr = unsafe { H::mmio_phys_to_virt(paddr, struct_info.length as usize) };
-    if vaddr.as_ptr() as usize % align_of::<T>() != 0 {
-        return Err(VirtioPciError::Misaligned {
-            address: vaddr.as_ptr() as usize,
-            alignment: align_of::<T>(),
-        });
-    }
-    Ok(vaddr.cast())
+    map_region(paddr, struct_info.length as usize, align_of::<T>())
 }
 
-fn get_bar_region_slice<H: Hal, T, C: ConfigurationAccess>(
+/// Finds the physical address of a shared memory region in a BAR, and checks that it fits.
+fn shared_memory_info<C: ConfigurationAccess>(
     root: &mut PciRoot<C>,
     device_function: DeviceFunction,
-    struct_info: &VirtioCapabilityInfo,
-) -> Result<NonNull<[T]>, VirtioPciError> {
-    let ptr = get_bar_region::<H, T, C>(root, device_function, struct_info)?;
-    Ok(nonnull_slice_from_raw_parts(
-        ptr,
-        struct_info.length as usize / size_of::<T>(),
-    ))
+    id: u8,
+    bar: u8,
+    offset: u64,
+    length: u64,
+) -> Result<SharedMemoryInfo, VirtioPciError> {
+    let (bar_address, bar_size) = root
+        .bar_info(device_function, bar)?
+        .memory_address_size()
+        .ok_or(VirtioPciError::UnexpectedIoBar)?;
+    if bar_address == 0 {
+        return Err(VirtioPciError::BarNotAllocated(bar));
+    }
+    if offset
+        .checked_add(length)
+        .is_none_or(|end| end > u64::from(bar_size))
+    {
+        return Err(VirtioPciError::BarOffsetOutOfRange);
+    }
+    Ok(SharedMemoryInfo {
+        id,
+        paddr: bar_address + offset,
+        length,
+    })
 }
 
 /// An error encountered initialising a VirtIO PCI transport.
@@ -510,6 +880,12 @@ pub enum VirtioPciError {
     /// An IO BAR was provided rather than a memory BAR.
     #[error("Unexpected IO BAR (expected memory BAR).")]
     UnexpectedIoBar,
+    /// A memory BAR was provided rather than an IO BAR.
+    #[error("Unexpected memory BAR (expected IO BAR).")]
+    UnexpectedMemoryBar,
+    /// The PCI device ID was not that of a legacy or transitional VirtIO device.
+    #[error("PCI device ID {0:#06x} is not a legacy VirtIO device ID.")]
+    NotLegacyDevice(u16),
     /// A BAR which we need was not allocated an address.
     #[error("Bar {0} not allocated.")]
     BarNotAllocated(u8),
@@ -524,6 +900,12 @@ pub enum VirtioPciError {
         /// The expected alignment in bytes.
         alignment: usize,
     },
+    /// The device couldn't allocate resources for the given MSI-X vector.
+    #[error("Device rejected MSI-X vector {0}.")]
+    MsixVectorRejected(u16),
+    /// The given MSI-X vector is beyond the end of the MSI-X table.
+    #[error("MSI-X vector {0} is outside the MSI-X table.")]
+    MsixVectorOutOfRange(u16),
     /// A generic PCI error,
     #[error(transparent)]
     Pci(PciError),
@@ -544,6 +926,33 @@ unsafe impl Sync for VirtioPciError {}
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::hal::fake::FakeHal;
+    use bus::PCI_CAP_ID_MSIX;
+    use core::mem::offset_of;
+    use fake::{FakeBar, FakePci};
+
+    const DEVICE_FUNCTION: DeviceFunction = DeviceFunction {
+        bus: 0,
+        device: 1,
+        function: 0,
+    };
+
+    /// The offset of the MSI-X table within the BAR of the fake device.
+    const MSIX_TABLE_OFFSET: u32 = 0x800;
+
+    /// Sets up a fake block device with a 4 entry MSI-X table.
+    fn fake_msix_device(bar: &FakeBar) -> (FakePci, PciRoot<FakePci>) {
+        let mut msix = vec![3, 0];
+        msix.extend_from_slice(&MSIX_TABLE_OFFSET.to_le_bytes());
+        msix.extend_from_slice(&(MSIX_TABLE_OFFSET + 0x100).to_le_bytes());
+        let (function, _) = fake::virtio_function(PCI_DEVICE_ID_OFFSET + DeviceType::Block as u16)
+            .with_capability(PCI_CAP_ID_MSIX, &msix);
+        let pci = FakePci::new();
+        pci.add_function(DEVICE_FUNCTION, function);
+        let mut root = PciRoot::new(pci.clone());
+        root.set_bar_64(DEVICE_FUNCTION, 0, bar.address());
+        (pci, root)
+    }
 
     #[test]
     fn transitional_device_ids() {
@@ -608,4 +1017,134 @@ mod tests {
             None
         );
     }
+
+    #[test]
+    fn ack_interrupt_raw() {
+        let mut bar = FakeBar::new();
+        let (_, mut root) = fake_msix_device(&bar);
+        let mut transport = PciTransport::new::<FakeHal, _>(&mut root, DEVICE_FUNCTION).unwrap();
+
+        bar.write(fake::ISR_OFFSET, 0x1u8);
+        // SAFETY: The transport is valid and isn't being used concurrently.
+        let status = unsafe { PciTransport::ack_interrupt_raw(&mut transport) };
+        assert!(status == InterruptStatus::QUEUE_INTERRUPT);
+    }
+
+    #[test]
+    fn msix_vectors() {
+        let bar = FakeBar::new();
+        let (_, mut root) = fake_msix_device(&bar);
+        let mut transport = PciTransport::new::<FakeHal, _>(&mut root, DEVICE_FUNCTION).unwrap();
+
+        transport.set_config_msix_vector(1).unwrap();
+        transport.set_queue_msix_vector(2, 3).unwrap();
+        drop(transport);
+
+        let common_cfg = fake::COMMON_CFG_OFFSET;
+        assert_eq!(
+            bar.read::<u16>(common_cfg + offset_of!(CommonCfg, msix_config)),
+            1
+        );
+        assert_eq!(
+            bar.read::<u16>(common_cfg + offset_of!(CommonCfg, queue_select)),
+            2
+        );
+        assert_eq!(
+            bar.read::<u16>(common_cfg + offset_of!(CommonCfg, queue_msix_vector)),
+            3
+        );
+    }
+
+    #[test]
+    fn msix_vector_rejected() {
+        assert_eq!(check_msix_vector(2, 2), Ok(()));
+        assert_eq!(
+            check_msix_vector(2, VIRTIO_MSI_NO_VECTOR),
+            Err(VirtioPciError::MsixVectorRejected(2))
+        );
+    }
+
+    #[test]
+    fn msix_table() {
+        let bar = FakeBar::new();
+        let (pci, mut root) = fake_msix_device(&bar);
+
+        let msix_info = root.msix_info(DEVICE_FUNCTION).unwrap();
+        assert_eq!(msix_info.table_size, 4);
+        assert_eq!(msix_info.table_bar, 0);
+        assert_eq!(msix_info.table_offset, MSIX_TABLE_OFFSET);
+        assert_eq!(msix_info.pba_bar, 0);
+        assert_eq!(msix_info.pba_offset, MSIX_TABLE_OFFSET + 0x100);
+
+        let mut table =
+            MsixTable::new::<FakeHal, _>(&mut root, DEVICE_FUNCTION, &msix_info).unwrap();
+        assert_eq!(table.table_size(), 4);
+        table.set_entry(1, 0x1_fee0_0000, 0x41).unwrap();
+        table.set_masked(2, true).unwrap();
+        assert_eq!(
+            table.set_entry(4, 0, 0),
+            Err(VirtioPciError::MsixVectorOutOfRange(4))
+        );
+
+        let entry = MSIX_TABLE_OFFSET as usize + 16;
+        assert_eq!(bar.read::<u32>(entry), 0xfee0_0000);
+        assert_eq!(bar.read::<u32>(entry + 4), 0x1);
+        assert_eq!(bar.read::<u32>(entry + 8), 0x41);
+        assert_eq!(bar.read::<u32>(entry + 12), 0);
+        assert_eq!(bar.read::<u32>(entry + 16 + 12), MSIX_VECTOR_MASKED);
+
+        root.set_msix_enabled(DEVICE_FUNCTION, &msix_info, true, false);
+        let message_control = pci.function(DEVICE_FUNCTION).config
+            [usize::from(msix_info.capability_offset) / 4]
+            >> 16;
+        assert_eq!(message_control, 0x8003);
+    }
+
+    #[test]
+    fn shared_memory_regions() {
+        let bar = FakeBar::new();
+        let shared_memory_capability = |id, bar, offset: u64, length: u64| {
+            let mut capability = fake::virtio_capability(
+                VIRTIO_PCI_CAP_SHARED_MEMORY_CFG,
+                bar,
+                offset as u32,
+                length as u32,
+                &[
+                    ((offset >> 32) as u32).to_le_bytes(),
+                    ((length >> 32) as u32).to_le_bytes(),
+                ]
+                .concat(),
+            );
+            // Set the shared memory region ID.
+            capability[3] = id;
+            capability
+        };
+        let mut function = fake::virtio_function(PCI_DEVICE_ID_OFFSET + DeviceType::Memory as u16)
+            .with_memory_bar_64(2, 0x2_0000, true)
+            .with_io_bar(4, 0x100);
+        for capability in [
+            shared_memory_capability(3, 2, 0x1000, 0x4000),
+            // Regions which don't fit in their BAR, or are in an I/O BAR, should be ignored.
+            shared_memory_capability(4, 2, 0x1_0000_0000, 0x1000),
+            shared_memory_capability(5, 2, 0x1_f000, 0x2000),
+            shared_memory_capability(6, 4, 0, 0x10),
+        ] {
+            function = function.with_capability(PCI_CAP_ID_VNDR, &capability).0;
+        }
+        let pci = FakePci::new();
+        pci.add_function(DEVICE_FUNCTION, function);
+        let mut root = PciRoot::new(pci);
+        root.set_bar_64(DEVICE_FUNCTION, 0, bar.address());
+        root.set_bar_64(DEVICE_FUNCTION, 2, 0x80_0000_0000);
+        let mut transport = PciTransport::new::<FakeHal, _>(&mut root, DEVICE_FUNCTION).unwrap();
+
+        assert_eq!(transport.shared_memory_region::<FakeHal>(0), None);
+        let region = transport.shared_memory_region::<FakeHal>(3).unwrap();
+        assert_eq!(region.paddr, 0x80_0000_1000);
+        assert_eq!(region.region.len(), 0x4000);
+        assert_eq!(region.region.cast::<u8>().as_ptr() as usize, 0x80_0000_1000);
+        for id in 4..=6 {
+            assert_eq!(transport.shared_memory_region::<FakeHal>(id), None);
+        }
+    }
 }
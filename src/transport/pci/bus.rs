@@ -20,9 +20,45 @@ const MAX_FUNCTIONS: u8 = 8;
 const STATUS_COMMAND_OFFSET: u8 = 0x04;
 /// The offset in bytes to BAR0 within PCI configuration space.
 const BAR0_OFFSET: u8 = 0x10;
+/// The offset in bytes to the BIST, header type, latency timer and cache line size fields within
+/// PCI configuration space.
+const BIST_TYPE_LATENCY_CACHE_OFFSET: u8 = 0x0c;
+
+/// The offset in bytes to the primary, secondary and subordinate bus numbers of a PCI-to-PCI
+/// bridge.
+const BRIDGE_BUS_NUMBERS_OFFSET: u8 = 0x18;
+/// The offset in bytes to the I/O base and limit registers of a PCI-to-PCI bridge.
+const BRIDGE_IO_BASE_LIMIT_OFFSET: u8 = 0x1c;
+/// The offset in bytes to the memory base and limit registers of a PCI-to-PCI bridge.
+const BRIDGE_MEMORY_BASE_LIMIT_OFFSET: u8 = 0x20;
+/// The offset in bytes to the prefetchable memory base and limit registers of a PCI-to-PCI bridge.
+const BRIDGE_PREFETCHABLE_BASE_LIMIT_OFFSET: u8 = 0x24;
+/// The offset in bytes to the upper 32 bits of the prefetchable memory base of a PCI-to-PCI
+/// bridge.
+const BRIDGE_PREFETCHABLE_BASE_UPPER_OFFSET: u8 = 0x28;
+/// The offset in bytes to the upper 32 bits of the prefetchable memory limit of a PCI-to-PCI
+/// bridge.
+const BRIDGE_PREFETCHABLE_LIMIT_UPPER_OFFSET: u8 = 0x2c;
+/// The offset in bytes to the upper 16 bits of the I/O base and limit of a PCI-to-PCI bridge.
+const BRIDGE_IO_UPPER_OFFSET: u8 = 0x30;
+/// The granularity of the memory windows of a PCI-to-PCI bridge.
+const BRIDGE_MEMORY_GRANULARITY: u64 = 0x100000;
+/// The granularity of the I/O window of a PCI-to-PCI bridge.
+const BRIDGE_IO_GRANULARITY: u64 = 0x1000;
 
 /// ID for vendor-specific PCI capabilities.
 pub const PCI_CAP_ID_VNDR: u8 = 0x09;
+/// ID for the MSI-X PCI capability.
+pub const PCI_CAP_ID_MSIX: u8 = 0x11;
+
+/// The offset of the Table Offset/Table BIR register within the MSI-X capability.
+const MSIX_TABLE_OFFSET: u8 = 4;
+/// The offset of the PBA Offset/PBA BIR register within the MSI-X capability.
+const MSIX_PBA_OFFSET: u8 = 8;
+/// The MSI-X Enable bit of the MSI-X Message Control register.
+const MSIX_ENABLE: u16 = 1 << 15;
+/// The Function Mask bit of the MSI-X Message Control register.
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
 
 bitflags! {
     /// The status register in PCI configuration space.
@@ -88,6 +124,16 @@ pub enum PciError {
     /// The device reported an invalid BAR type.
     #[error("Invalid PCI BAR type")]
     InvalidBarType,
+    /// There was no space left in the appropriate address window for a BAR.
+    #[error("No space to allocate BAR {bar_index} of {device_function} with size {size:#x}")]
+    OutOfAddressSpace {
+        /// The device function whose BAR couldn't be allocated.
+        device_function: DeviceFunction,
+        /// The index of the BAR which couldn't be allocated.
+        bar_index: u8,
+        /// The size of the BAR in bytes.
+        size: u32,
+    },
 }
 
 /// The root complex of a PCI bus.
@@ -162,6 +208,68 @@ impl<C: ConfigurationAccess> PciRoot<C> {
         }
     }
 
+    /// Enumerates PCI devices on the given bus and on all buses behind PCI-to-PCI bridges (including
+    /// PCIe root ports) reachable from it.
+    ///
+    /// This follows the secondary bus numbers already programmed into the bridges, e.g. by
+    /// firmware or by [`assign_bus_numbers`](Self::assign_bus_numbers). Each bus is only visited
+    /// once, even if bridges are misconfigured to form a loop.
+    pub fn enumerate_hierarchy(&self, bus: u8) -> HierarchyIterator<C> {
+        let mut visited = [0; 4];
+        visited[usize::from(bus / 64)] |= 1 << (bus % 64);
+        HierarchyIterator {
+            bus_iterator: self.enumerate_bus(bus),
+            pending: [0; 256],
+            pending_count: 0,
+            visited,
+        }
+    }
+
+    /// Assigns primary, secondary and subordinate bus numbers to all PCI-to-PCI bridges reachable
+    /// from the given bus, depth first, overwriting any existing numbers.
+    ///
+    /// This is only needed on bare metal where firmware hasn't already done so. Returns the highest
+    /// bus number assigned, which is `bus` if there are no bridges.
+    pub fn assign_bus_numbers(&mut self, bus: u8) -> u8 {
+        let mut last_bus = bus;
+        for (device_function, info) in self.enumerate_bus(bus) {
+            if info.header_type != HeaderType::PciPciBridge {
+                continue;
+            }
+            let Some(secondary_bus) = last_bus.checked_add(1) else {
+                warn!("Ran out of bus numbers for bridge {}", device_function);
+                break;
+            };
+            // Set the subordinate bus number to the maximum until the buses behind the bridge have
+            // been numbered, so that configuration accesses to them are forwarded.
+            self.set_bus_numbers(device_function, bus, secondary_bus, 0xff);
+            last_bus = self.assign_bus_numbers(secondary_bus);
+            self.set_bus_numbers(device_function, bus, secondary_bus, last_bus);
+        }
+        last_bus
+    }
+
+    /// Sets the primary, secondary and subordinate bus numbers of the given PCI-to-PCI bridge.
+    fn set_bus_numbers(
+        &mut self,
+        bridge: DeviceFunction,
+        primary: u8,
+        secondary: u8,
+        subordinate: u8,
+    ) {
+        let bus_numbers = self
+            .configuration_access
+            .read_word(bridge, BRIDGE_BUS_NUMBERS_OFFSET);
+        self.configuration_access.write_word(
+            bridge,
+            BRIDGE_BUS_NUMBERS_OFFSET,
+            (bus_numbers & 0xff000000)
+                | u32::from(subordinate) << 16
+                | u32::from(secondary) << 8
+                | u32::from(primary),
+        );
+    }
+
     /// Reads the status and command registers of the given device function.
     pub fn get_status_command(&self, device_function: DeviceFunction) -> (Status, Command) {
         let status_command = self
@@ -190,6 +298,64 @@ impl<C: ConfigurationAccess> PciRoot<C> {
         }
     }
 
+    /// Returns information about the MSI-X capability of the given device function, or `None` if
+    /// it doesn't support MSI-X.
+    pub fn msix_info(&self, device_function: DeviceFunction) -> Option<MsixInfo> {
+        let capability = self
+            .capabilities(device_function)
+            .find(|capability| capability.id == PCI_CAP_ID_MSIX)?;
+        let table = self
+            .configuration_access
+            .read_word(device_function, capability.offset + MSIX_TABLE_OFFSET);
+        let pba = self
+            .configuration_access
+            .read_word(device_function, capability.offset + MSIX_PBA_OFFSET);
+        Some(MsixInfo {
+            capability_offset: capability.offset,
+            table_size: (capability.private_header & 0x7ff) + 1,
+            table_bar: (table & 0x7) as u8,
+            table_offset: table & !0x7,
+            pba_bar: (pba & 0x7) as u8,
+            pba_offset: pba & !0x7,
+        })
+    }
+
+    /// Returns whether MSI-X is enabled for the given device function.
+    pub fn msix_enabled(&self, device_function: DeviceFunction, msix_info: &MsixInfo) -> bool {
+        let header = self
+            .configuration_access
+            .read_word(device_function, msix_info.capability_offset);
+        (header >> 16) as u16 & MSIX_ENABLE != 0
+    }
+
+    /// Enables or disables MSI-X for the given device function.
+    ///
+    /// While MSI-X is enabled the function won't use its INTx# pin. If `function_mask` is true then
+    /// all vectors are masked, regardless of the mask bits in the MSI-X table.
+    pub fn set_msix_enabled(
+        &mut self,
+        device_function: DeviceFunction,
+        msix_info: &MsixInfo,
+        enabled: bool,
+        function_mask: bool,
+    ) {
+        let header = self
+            .configuration_access
+            .read_word(device_function, msix_info.capability_offset);
+        let mut message_control = (header >> 16) as u16 & !(MSIX_ENABLE | MSIX_FUNCTION_MASK);
+        if enabled {
+            message_control |= MSIX_ENABLE;
+        }
+        if function_mask {
+            message_control |= MSIX_FUNCTION_MASK;
+        }
+        self.configuration_access.write_word(
+            device_function,
+            msix_info.capability_offset,
+            (header & 0xffff) | u32::from(message_control) << 16,
+        );
+    }
+
     /// Returns information about all the given device function's BARs.
     pub fn bars(
         &mut self,
@@ -283,6 +449,14 @@ impl<C: ConfigurationAccess> PciRoot<C> {
         );
     }
 
+    /// Reads the header type of the given device function.
+    fn header_type(&self, device_function: DeviceFunction) -> HeaderType {
+        let bist_type_latency_cache = self
+            .configuration_access
+            .read_word(device_function, BIST_TYPE_LATENCY_CACHE_OFFSET);
+        HeaderType::from((bist_type_latency_cache >> 16) as u8 & 0x7f)
+    }
+
     /// Gets the capabilities 'pointer' for the device function, if any.
     fn capabilities_offset(&self, device_function: DeviceFunction) -> Option<u8> {
         let (status, _) = self.get_status_command(device_function);
@@ -294,6 +468,336 @@ impl<C: ConfigurationAccess> PciRoot<C> {
     }
 }
 
+/// A range of PCI bus addresses.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PciWindow {
+    /// The first address in the window.
+    pub start: u64,
+    /// The size of the window in bytes.
+    pub size: u64,
+}
+
+impl PciWindow {
+    /// Creates a new window starting at the given address with the given size in bytes.
+    pub const fn new(start: u64, size: u64) -> Self {
+        Self { start, size }
+    }
+}
+
+/// Assigns addresses to the BARs of PCI device functions, from windows of PCI bus address space
+/// provided by the platform (e.g. the `ranges` property of the host bridge's device tree node).
+///
+/// Addresses are allocated sequentially from each window, aligned to the size of each BAR.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BarAllocator {
+    memory: AddressAllocator,
+    prefetchable: Option<AddressAllocator>,
+    io: Option<AddressAllocator>,
+}
+
+impl BarAllocator {
+    /// Creates a new allocator.
+    ///
+    /// `memory` must lie entirely below 4 GiB, as it is used for 32-bit memory BARs. 64-bit
+    /// prefetchable memory BARs are allocated from `prefetchable` if it is given, or otherwise from
+    /// `memory`. I/O BARs are allocated from `io` if it is given, or otherwise left unassigned.
+    pub fn new(memory: PciWindow, prefetchable: Option<PciWindow>, io: Option<PciWindow>) -> Self {
+        assert!(memory.start + memory.size <= 1 << 32);
+        Self {
+            memory: memory.into(),
+            prefetchable: prefetchable.map(Into::into),
+            io: io.map(Into::into),
+        }
+    }
+
+    /// Allocates the BARs of all device functions on the given bus and on the buses behind any
+    /// PCI-to-PCI bridges on it, programs the bridges' windows to cover them, and enables the
+    /// device functions to decode their BARs and act as bus masters.
+    ///
+    /// Bridges must already have their bus numbers assigned.
+    pub fn allocate_bus<C: ConfigurationAccess>(
+        &mut self,
+        root: &mut PciRoot<C>,
+        bus: u8,
+    ) -> Result<(), PciError> {
+        for (device_function, info) in root.enumerate_bus(bus) {
+            self.allocate_function(root, device_function)?;
+            if info.header_type == HeaderType::PciPciBridge {
+                let bus_numbers = root
+                    .configuration_access
+                    .read_word(device_function, BRIDGE_BUS_NUMBERS_OFFSET);
+                let secondary_bus = (bus_numbers >> 8) as u8;
+                if secondary_bus <= bus {
+                    warn!(
+                        "Bridge {} has invalid secondary bus number {}, skipping",
+                        device_function, secondary_bus
+                    );
+                    continue;
+                }
+                self.allocate_bridge(root, device_function, secondary_bus)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Allocates all the BARs of the given device function, then enables it to decode them and act
+    /// as a bus master.
+    ///
+    /// Decoding is disabled while the BARs are sized and assigned. I/O BARs are left unassigned if
+    /// there is no I/O window, in which case I/O decoding is left disabled.
+    pub fn allocate_function<C: ConfigurationAccess>(
+        &mut self,
+        root: &mut PciRoot<C>,
+        device_function: DeviceFunction,
+    ) -> Result<(), PciError> {
+        let bar_count = match root.header_type(device_function) {
+            HeaderType::Standard => 6,
+            HeaderType::PciPciBridge => 2,
+            header_type => {
+                warn!(
+                    "Not allocating BARs for {} with header type {:?}",
+                    device_function, header_type
+                );
+                return Ok(());
+            }
+        };
+
+        let (_, command) = root.get_status_command(device_function);
+        let mut command = command - (Command::IO_SPACE | Command::MEMORY_SPACE);
+        root.set_command(device_function, command);
+
+        let mut bar_index = 0;
+        while bar_index < bar_count {
+            let info = root.bar_info(device_function, bar_index)?;
+            let next_index = bar_index + if info.takes_two_entries() { 2 } else { 1 };
+            if next_index > bar_count {
+                return Err(PciError::InvalidBarType);
+            }
+            let out_of_space = |size| PciError::OutOfAddressSpace {
+                device_function,
+                bar_index,
+                size,
+            };
+            match info {
+                BarInfo::Memory { size: 0, .. } | BarInfo::IO { size: 0, .. } => {}
+                BarInfo::Memory { size, .. } | BarInfo::IO { size, .. }
+                    if !size.is_power_of_two() =>
+                {
+                    warn!(
+                        "Not allocating BAR {} of {} with invalid size {:#x}",
+                        bar_index, device_function, size
+                    );
+                }
+                BarInfo::Memory {
+                    address_type,
+                    prefetchable,
+                    size,
+                    ..
+                } => {
+                    let size = u64::from(size);
+                    match address_type {
+                        MemoryBarType::Width64 => {
+                            let address = match &mut self.prefetchable {
+                                Some(window) if prefetchable => window.allocate(size, u64::MAX),
+                                _ => self.memory.allocate(size, u64::MAX),
+                            }
+                            .ok_or(out_of_space(size as u32))?;
+                            root.set_bar_64(device_function, bar_index, address);
+                        }
+                        MemoryBarType::Width32 | MemoryBarType::Below1MiB => {
+                            let limit = if address_type == MemoryBarType::Below1MiB {
+                                0x100000
+                            } else {
+                                1 << 32
+                            };
+                            let address = self
+                                .memory
+                                .allocate(size, limit)
+                                .ok_or(out_of_space(size as u32))?;
+                            root.set_bar_32(device_function, bar_index, address as u32);
+                        }
+                    }
+                    command |= Command::MEMORY_SPACE;
+                }
+                BarInfo::IO { size, .. } => {
+                    if let Some(io) = &mut self.io {
+                        let address = io
+                            .allocate(size.into(), 1 << 32)
+                            .ok_or(out_of_space(size))?;
+                        root.set_bar_32(device_function, bar_index, address as u32);
+                        command |= Command::IO_SPACE;
+                    } else {
+                        warn!(
+                            "No I/O window to allocate BAR {} of {}",
+                            bar_index, device_function
+                        );
+                    }
+                }
+            }
+            bar_index = next_index;
+        }
+
+        root.set_command(device_function, command | Command::BUS_MASTER);
+        Ok(())
+    }
+
+    /// Allocates the buses behind the given bridge and programs its windows to cover everything
+    /// allocated for them.
+    fn allocate_bridge<C: ConfigurationAccess>(
+        &mut self,
+        root: &mut PciRoot<C>,
+        bridge: DeviceFunction,
+        secondary_bus: u8,
+    ) -> Result<(), PciError> {
+        let saved = self.clone();
+        self.memory.align(BRIDGE_MEMORY_GRANULARITY);
+        if let Some(prefetchable) = &mut self.prefetchable {
+            prefetchable.align(BRIDGE_MEMORY_GRANULARITY);
+        }
+        if let Some(io) = &mut self.io {
+            io.align(BRIDGE_IO_GRANULARITY);
+        }
+        let start = self.clone();
+
+        self.allocate_bus(root, secondary_bus)?;
+
+        // Work out what was allocated behind the bridge in each window, and give back any unused
+        // alignment padding.
+        let memory = Self::window_since(
+            &mut self.memory,
+            &saved.memory,
+            &start.memory,
+            BRIDGE_MEMORY_GRANULARITY,
+        );
+        let prefetchable = match (
+            &mut self.prefetchable,
+            &saved.prefetchable,
+            &start.prefetchable,
+        ) {
+            (Some(current), Some(saved), Some(start)) => {
+                Self::window_since(current, saved, start, BRIDGE_MEMORY_GRANULARITY)
+            }
+            _ => None,
+        };
+        let io = match (&mut self.io, &saved.io, &start.io) {
+            (Some(current), Some(saved), Some(start)) => {
+                Self::window_since(current, saved, start, BRIDGE_IO_GRANULARITY)
+            }
+            _ => None,
+        };
+
+        let access = &mut root.configuration_access;
+        access.write_word(
+            bridge,
+            BRIDGE_MEMORY_BASE_LIMIT_OFFSET,
+            memory.map_or(0x0000fff0, |(base, limit)| {
+                ((base >> 16) as u32 & 0xfff0) | ((limit >> 16) as u32 & 0xfff0) << 16
+            }),
+        );
+        let (prefetchable_base, prefetchable_limit) = prefetchable.unwrap_or((0xfff00000, 0));
+        access.write_word(
+            bridge,
+            BRIDGE_PREFETCHABLE_BASE_LIMIT_OFFSET,
+            ((prefetchable_base >> 16) as u32 & 0xfff0)
+                | ((prefetchable_limit >> 16) as u32 & 0xfff0) << 16,
+        );
+        access.write_word(
+            bridge,
+            BRIDGE_PREFETCHABLE_BASE_UPPER_OFFSET,
+            (prefetchable_base >> 32) as u32,
+        );
+        access.write_word(
+            bridge,
+            BRIDGE_PREFETCHABLE_LIMIT_UPPER_OFFSET,
+            (prefetchable_limit >> 32) as u32,
+        );
+        let (io_base, io_limit) = io.unwrap_or((0xf000, 0));
+        // Write zeroes to the secondary status register, as its bits are write-1-to-clear.
+        access.write_word(
+            bridge,
+            BRIDGE_IO_BASE_LIMIT_OFFSET,
+            ((io_base >> 8) as u32 & 0xf0) | ((io_limit >> 8) as u32 & 0xf0) << 8,
+        );
+        access.write_word(
+            bridge,
+            BRIDGE_IO_UPPER_OFFSET,
+            (io_base >> 16) as u32 & 0xffff | ((io_limit >> 16) as u32) << 16,
+        );
+
+        let (_, command) = root.get_status_command(bridge);
+        let mut command = command | Command::MEMORY_SPACE | Command::BUS_MASTER;
+        if io.is_some() {
+            command |= Command::IO_SPACE;
+        }
+        root.set_command(bridge, command);
+        Ok(())
+    }
+
+    /// Returns the base and inclusive limit of the window which a bridge needs to cover everything
+    /// allocated from `current` since `start`, or `None` if nothing was allocated, in which case
+    /// `current` is rolled back to `saved` to reclaim the alignment padding.
+    fn window_since(
+        current: &mut AddressAllocator,
+        saved: &AddressAllocator,
+        start: &AddressAllocator,
+        granularity: u64,
+    ) -> Option<(u64, u64)> {
+        if current.next == start.next {
+            *current = saved.clone();
+            None
+        } else {
+            current.align(granularity);
+            Some((start.next, current.next - 1))
+        }
+    }
+}
+
+/// A simple bump allocator for a window of PCI bus address space.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct AddressAllocator {
+    /// The next address which may be allocated.
+    next: u64,
+    /// The address just past the end of the window.
+    end: u64,
+}
+
+impl AddressAllocator {
+    /// Allocates a region of the given size aligned to its size.
+    ///
+    /// The region must end at or below `limit`. Returns `None` if there isn't space, or if the size
+    /// isn't a power of two.
+    fn allocate(&mut self, size: u64, limit: u64) -> Option<u64> {
+        if !size.is_power_of_two() {
+            return None;
+        }
+        let address = self.next.checked_next_multiple_of(size)?;
+        let end = address.checked_add(size)?;
+        if end > self.end.min(limit) {
+            return None;
+        }
+        self.next = end;
+        Some(address)
+    }
+
+    /// Advances the next address to a multiple of the given alignment, without going past the end
+    /// of the window.
+    fn align(&mut self, alignment: u64) {
+        self.next = self
+            .next
+            .checked_next_multiple_of(alignment)
+            .map_or(self.end, |next| next.min(self.end));
+    }
+}
+
+impl From<PciWindow> for AddressAllocator {
+    fn from(window: PciWindow) -> Self {
+        Self {
+            next: window.start,
+            end: window.start + window.size,
+        }
+    }
+}
+
 /// A method to access PCI configuration space for a particular PCI bus.
 pub trait ConfigurationAccess {
     /// Reads 4 bytes from the configuration space.
@@ -528,6 +1032,23 @@ pub struct CapabilityInfo {
     pub private_header: u16,
 }
 
+/// Information about the MSI-X capability of a PCI device function.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct MsixInfo {
+    /// The offset of the capability in the PCI configuration space of the device function.
+    pub capability_offset: u8,
+    /// The number of entries in the MSI-X table.
+    pub table_size: u16,
+    /// The BAR in which the MSI-X table is found.
+    pub table_bar: u8,
+    /// The offset of the MSI-X table within its BAR.
+    pub table_offset: u32,
+    /// The BAR in which the Pending Bit Array is found.
+    pub pba_bar: u8,
+    /// The offset of the Pending Bit Array within its BAR.
+    pub pba_offset: u32,
+}
+
 /// An iterator which enumerates PCI devices and functions on a given bus.
 #[derive(Debug)]
 pub struct BusDeviceIterator<C: ConfigurationAccess> {
@@ -581,6 +1102,59 @@ impl<C: ConfigurationAccess> Iterator for BusDeviceIterator<C> {
     }
 }
 
+/// An iterator which enumerates PCI devices and functions on a bus and all buses behind bridges on
+/// it.
+#[derive(Debug)]
+pub struct HierarchyIterator<C: ConfigurationAccess> {
+    /// The iterator for the bus currently being enumerated.
+    bus_iterator: BusDeviceIterator<C>,
+    /// Secondary buses of bridges found so far, which are still to be enumerated.
+    pending: [u8; 256],
+    pending_count: usize,
+    /// A bitmap of buses which have been enumerated or added to `pending`.
+    visited: [u64; 4],
+}
+
+impl<C: ConfigurationAccess> Iterator for HierarchyIterator<C> {
+    type Item = (DeviceFunction, DeviceFunctionInfo);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((device_function, info)) = self.bus_iterator.next() {
+                if info.header_type == HeaderType::PciPciBridge {
+                    let bus_numbers = self
+                        .bus_iterator
+                        .configuration_access
+                        .read_word(device_function, BRIDGE_BUS_NUMBERS_OFFSET);
+                    let secondary_bus = (bus_numbers >> 8) as u8;
+                    let (word, bit) = (usize::from(secondary_bus / 64), secondary_bus % 64);
+                    if self.visited[word] & (1 << bit) == 0 {
+                        self.visited[word] |= 1 << bit;
+                        self.pending[self.pending_count] = secondary_bus;
+                        self.pending_count += 1;
+                    } else {
+                        warn!(
+                            "Bridge {} has secondary bus {} which was already visited",
+                            device_function, secondary_bus
+                        );
+                    }
+                }
+                return Some((device_function, info));
+            }
+
+            if self.pending_count == 0 {
+                return None;
+            }
+            self.pending_count -= 1;
+            self.bus_iterator.next = DeviceFunction {
+                bus: self.pending[self.pending_count],
+                device: 0,
+                function: 0,
+            };
+        }
+    }
+}
+
 /// An identifier for a PCI bus, device and function.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct DeviceFunction {
@@ -663,3 +1237,278 @@ impl From<u8> for HeaderType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::pci::fake::{FakeFunction, FakePci};
+    use alloc::{vec, vec::Vec};
+
+    const MEMORY_WINDOW: PciWindow = PciWindow::new(0x1000_0000, 0x1000_0000);
+    const PREFETCHABLE_WINDOW: PciWindow = PciWindow::new(0x80_0000_0000, 0x10_0000_0000);
+    const IO_WINDOW: PciWindow = PciWindow::new(0x1000, 0xf000);
+
+    fn device_function(bus: u8, device: u8) -> DeviceFunction {
+        DeviceFunction {
+            bus,
+            device,
+            function: 0,
+        }
+    }
+
+    #[test]
+    fn allocate_function_bars() {
+        let pci = FakePci::new();
+        let device = device_function(0, 1);
+        pci.add_function(
+            device,
+            FakeFunction::new(0x1af4, 0x1042, 0)
+                .with_memory_bar_32(0, 0x1000, false)
+                .with_io_bar(1, 0x40)
+                .with_memory_bar_64(2, 0x10_0000, true)
+                .with_memory_bar_32(4, 0x4000, false),
+        );
+        let mut root = PciRoot::new(pci.clone());
+        let mut allocator =
+            BarAllocator::new(MEMORY_WINDOW, Some(PREFETCHABLE_WINDOW), Some(IO_WINDOW));
+
+        allocator.allocate_bus(&mut root, 0).unwrap();
+
+        let bars = root.bars(device).unwrap();
+        assert_eq!(
+            bars[0].as_ref().unwrap().memory_address_size(),
+            Some((0x1000_0000, 0x1000))
+        );
+        assert_eq!(
+            bars[1],
+            Some(BarInfo::IO {
+                address: 0x1000,
+                size: 0x40
+            })
+        );
+        assert_eq!(
+            bars[2].as_ref().unwrap().memory_address_size(),
+            Some((0x80_0000_0000, 0x10_0000))
+        );
+        assert_eq!(bars[3], None);
+        assert_eq!(
+            bars[4].as_ref().unwrap().memory_address_size(),
+            Some((0x1000_4000, 0x4000))
+        );
+        assert_eq!(
+            bars[5].as_ref().unwrap().memory_address_size(),
+            Some((0, 0))
+        );
+        assert_eq!(
+            root.get_status_command(device).1,
+            Command::IO_SPACE | Command::MEMORY_SPACE | Command::BUS_MASTER
+        );
+    }
+
+    #[test]
+    fn io_bar_without_window() {
+        let pci = FakePci::new();
+        let device = device_function(0, 0);
+        pci.add_function(
+            device,
+            FakeFunction::new(0x1af4, 0x1042, 0)
+                .with_io_bar(0, 0x40)
+                .with_memory_bar_64(1, 0x1000, true),
+        );
+        let mut root = PciRoot::new(pci);
+        let mut allocator = BarAllocator::new(MEMORY_WINDOW, None, None);
+
+        allocator.allocate_bus(&mut root, 0).unwrap();
+
+        let bars = root.bars(device).unwrap();
+        assert_eq!(
+            bars[0],
+            Some(BarInfo::IO {
+                address: 0,
+                size: 0x40
+            })
+        );
+        // Without a prefetchable window the 64-bit BAR goes in the 32-bit window.
+        assert_eq!(
+            bars[1].as_ref().unwrap().memory_address_size(),
+            Some((0x1000_0000, 0x1000))
+        );
+        assert_eq!(
+            root.get_status_command(device).1,
+            Command::MEMORY_SPACE | Command::BUS_MASTER
+        );
+    }
+
+    #[test]
+    fn invalid_bar_size() {
+        let pci = FakePci::new();
+        let device = device_function(0, 0);
+        pci.add_function(
+            device,
+            FakeFunction::new(0x1af4, 0x1042, 0)
+                .with_bar_mask(0, 0xffff_0f00)
+                .with_memory_bar_32(1, 0x1000, false),
+        );
+        let mut root = PciRoot::new(pci);
+        let mut allocator = BarAllocator::new(MEMORY_WINDOW, None, None);
+
+        allocator.allocate_bus(&mut root, 0).unwrap();
+
+        let bars = root.bars(device).unwrap();
+        assert_eq!(
+            bars[0].as_ref().unwrap().memory_address_size(),
+            Some((0, 0xf100))
+        );
+        assert_eq!(
+            bars[1].as_ref().unwrap().memory_address_size(),
+            Some((0x1000_0000, 0x1000))
+        );
+        assert_eq!(allocator.memory.allocate(0x3000, u64::MAX), None);
+    }
+
+    #[test]
+    fn out_of_space() {
+        let pci = FakePci::new();
+        let device = device_function(0, 0);
+        pci.add_function(
+            device,
+            FakeFunction::new(0x1af4, 0x1042, 0)
+                .with_memory_bar_32(0, 0x1000, false)
+                .with_memory_bar_32(1, 0x2000, false),
+        );
+        let mut root = PciRoot::new(pci);
+        let mut allocator = BarAllocator::new(PciWindow::new(0x1000, 0x2000), None, None);
+
+        assert_eq!(
+            allocator.allocate_bus(&mut root, 0),
+            Err(PciError::OutOfAddressSpace {
+                device_function: device,
+                bar_index: 1,
+                size: 0x2000,
+            })
+        );
+    }
+
+    #[test]
+    fn bridge_windows() {
+        let pci = FakePci::new();
+        let bridge = device_function(0, 2);
+        let mut bridge_function = FakeFunction::new(0x1b36, 0x0001, 1).with_class(0x06, 0x04);
+        // Primary bus 0, secondary bus 1, subordinate bus 1.
+        bridge_function.config[BRIDGE_BUS_NUMBERS_OFFSET as usize / 4] = 0x0001_0100;
+        pci.add_function(
+            device_function(0, 1),
+            FakeFunction::new(0x1af4, 0x1041, 0).with_memory_bar_32(0, 0x1000, false),
+        );
+        pci.add_function(bridge, bridge_function);
+        let device = device_function(1, 0);
+        pci.add_function(
+            device,
+            FakeFunction::new(0x1af4, 0x1042, 0)
+                .with_memory_bar_32(0, 0x4000, false)
+                .with_memory_bar_64(1, 0x20_0000, true),
+        );
+        let mut root = PciRoot::new(pci.clone());
+        let mut allocator =
+            BarAllocator::new(MEMORY_WINDOW, Some(PREFETCHABLE_WINDOW), Some(IO_WINDOW));
+
+        allocator.allocate_bus(&mut root, 0).unwrap();
+
+        let bars = root.bars(device).unwrap();
+        assert_eq!(
+            bars[0].as_ref().unwrap().memory_address_size(),
+            Some((0x1010_0000, 0x4000))
+        );
+        assert_eq!(
+            bars[1].as_ref().unwrap().memory_address_size(),
+            Some((0x80_0000_0000, 0x20_0000))
+        );
+
+        let config = pci.function(bridge).config;
+        // Memory window 0x1010_0000-0x101f_ffff.
+        assert_eq!(
+            config[BRIDGE_MEMORY_BASE_LIMIT_OFFSET as usize / 4],
+            0x1010_1010
+        );
+        // Prefetchable window 0x80_0000_0000-0x80_001f_ffff.
+        assert_eq!(
+            config[BRIDGE_PREFETCHABLE_BASE_LIMIT_OFFSET as usize / 4],
+            0x0010_0000
+        );
+        assert_eq!(
+            config[BRIDGE_PREFETCHABLE_BASE_UPPER_OFFSET as usize / 4],
+            0x80
+        );
+        assert_eq!(
+            config[BRIDGE_PREFETCHABLE_LIMIT_UPPER_OFFSET as usize / 4],
+            0x80
+        );
+        // Nothing behind the bridge uses I/O space, so its I/O window is disabled.
+        assert_eq!(config[BRIDGE_IO_BASE_LIMIT_OFFSET as usize / 4], 0x00f0);
+        assert_eq!(
+            root.get_status_command(bridge).1,
+            Command::MEMORY_SPACE | Command::BUS_MASTER
+        );
+    }
+
+    /// Returns a PCI-to-PCI bridge function with the given bus numbers.
+    fn bridge(primary: u8, secondary: u8, subordinate: u8) -> FakeFunction {
+        let mut function = FakeFunction::new(0x1b36, 0x000c, 1).with_class(0x06, 0x04);
+        function.config[BRIDGE_BUS_NUMBERS_OFFSET as usize / 4] =
+            u32::from(subordinate) << 16 | u32::from(secondary) << 8 | u32::from(primary);
+        function
+    }
+
+    #[test]
+    fn enumerate_hierarchy() {
+        let pci = FakePci::new();
+        pci.add_function(device_function(0, 0), FakeFunction::new(0x1af4, 0x1041, 0));
+        pci.add_function(device_function(0, 1), bridge(0, 1, 2));
+        pci.add_function(device_function(0, 2), bridge(0, 3, 3));
+        pci.add_function(device_function(1, 0), bridge(1, 2, 2));
+        pci.add_function(device_function(1, 1), FakeFunction::new(0x1af4, 0x1042, 0));
+        pci.add_function(device_function(2, 0), FakeFunction::new(0x1af4, 0x1043, 0));
+        // A misconfigured bridge pointing back at bus 0 shouldn't cause a loop.
+        pci.add_function(device_function(3, 0), bridge(3, 0, 0));
+        let root = PciRoot::new(pci);
+
+        let found: Vec<_> = root
+            .enumerate_hierarchy(0)
+            .map(|(device_function, info)| (device_function, info.device_id))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (device_function(0, 0), 0x1041),
+                (device_function(0, 1), 0x000c),
+                (device_function(0, 2), 0x000c),
+                (device_function(3, 0), 0x000c),
+                (device_function(1, 0), 0x000c),
+                (device_function(1, 1), 0x1042),
+                (device_function(2, 0), 0x1043),
+            ]
+        );
+    }
+
+    #[test]
+    fn assign_bus_numbers() {
+        let pci = FakePci::new();
+        pci.add_function(device_function(0, 1), bridge(0, 0, 0));
+        pci.add_function(device_function(0, 2), bridge(0, 0, 0));
+        pci.add_function(device_function(1, 0), bridge(0, 0, 0));
+        pci.add_function(device_function(2, 0), FakeFunction::new(0x1af4, 0x1042, 0));
+        let mut root = PciRoot::new(pci.clone());
+
+        assert_eq!(root.assign_bus_numbers(0), 3);
+
+        let bus_numbers = |device_function| {
+            pci.function(device_function).config[BRIDGE_BUS_NUMBERS_OFFSET as usize / 4]
+        };
+        assert_eq!(bus_numbers(device_function(0, 1)), 0x0002_0100);
+        assert_eq!(bus_numbers(device_function(1, 0)), 0x0002_0201);
+        assert_eq!(bus_numbers(device_function(0, 2)), 0x0003_0300);
+        assert!(root
+            .enumerate_hierarchy(0)
+            .any(|(found, _)| found == device_function(2, 0)));
+    }
+}
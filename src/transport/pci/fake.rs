@@ -0,0 +1,330 @@
+//! A fake PCI configuration space for unit tests.
+
+use super::bus::{ConfigurationAccess, DeviceFunction, PCI_CAP_ID_VNDR};
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec, vec::Vec};
+use std::sync::Mutex;
+use zerocopy::{FromBytes, Immutable, IntoBytes};
+
+/// The offset of the capabilities pointer in configuration space.
+const CAPABILITIES_POINTER_OFFSET: usize = 0x34;
+/// The offset at which the first capability added by `FakeFunction::with_capability` is placed.
+const FIRST_CAPABILITY_OFFSET: u8 = 0x40;
+
+/// A fake implementation of [`ConfigurationAccess`], backed by a map of device functions.
+///
+/// Clones share the same state, so tests can keep a handle to inspect what was written.
+#[derive(Clone, Debug, Default)]
+pub struct FakePci {
+    functions: Arc<Mutex<Functions>>,
+}
+
+/// Fake device functions, keyed by bus, device and function number.
+type Functions = BTreeMap<(u8, u8, u8), FakeFunction>;
+
+impl FakePci {
+    /// Creates a new fake PCI bus with no device functions.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the given device function.
+    pub fn add_function(&self, device_function: DeviceFunction, function: FakeFunction) {
+        self.functions.lock().unwrap().insert(
+            (
+                device_function.bus,
+                device_function.device,
+                device_function.function,
+            ),
+            function,
+        );
+    }
+
+    /// Returns a copy of the current configuration space of the given device function.
+    pub fn function(&self, device_function: DeviceFunction) -> FakeFunction {
+        self.functions.lock().unwrap()[&(
+            device_function.bus,
+            device_function.device,
+            device_function.function,
+        )]
+            .clone()
+    }
+}
+
+impl ConfigurationAccess for FakePci {
+    fn read_word(&self, device_function: DeviceFunction, register_offset: u8) -> u32 {
+        assert_eq!(register_offset % 4, 0);
+        self.functions
+            .lock()
+            .unwrap()
+            .get(&(
+                device_function.bus,
+                device_function.device,
+                device_function.function,
+            ))
+            .map_or(0xffffffff, |function| function.read(register_offset))
+    }
+
+    fn write_word(&mut self, device_function: DeviceFunction, register_offset: u8, data: u32) {
+        assert_eq!(register_offset % 4, 0);
+        if let Some(function) = self.functions.lock().unwrap().get_mut(&(
+            device_function.bus,
+            device_function.device,
+            device_function.function,
+        )) {
+            function.write(register_offset, data);
+        }
+    }
+
+    unsafe fn unsafe_clone(&self) -> Self {
+        self.clone()
+    }
+}
+
+/// The configuration space of a fake PCI device function.
+#[derive(Clone, Debug)]
+pub struct FakeFunction {
+    /// The raw contents of configuration space.
+    pub config: [u32; 64],
+    /// For each BAR register, the bits which may be written.
+    bar_masks: [u32; 6],
+    /// For each BAR register, the read-only type bits.
+    bar_flags: [u32; 6],
+    /// The offset at which the next capability will be added.
+    next_capability: u8,
+    /// The offset of the last capability added, to link the next one to.
+    last_capability: Option<u8>,
+}
+
+impl FakeFunction {
+    /// Creates a new function with the given IDs and header type.
+    pub fn new(vendor_id: u16, device_id: u16, header_type: u8) -> Self {
+        let mut config = [0; 64];
+        config[0] = u32::from(device_id) << 16 | u32::from(vendor_id);
+        config[3] = u32::from(header_type) << 16;
+        Self {
+            config,
+            bar_masks: [0; 6],
+            bar_flags: [0; 6],
+            next_capability: FIRST_CAPABILITY_OFFSET,
+            last_capability: None,
+        }
+    }
+
+    /// Sets the class and subclass of the function.
+    pub fn with_class(mut self, class: u8, subclass: u8) -> Self {
+        self.config[2] = u32::from(class) << 24 | u32::from(subclass) << 16;
+        self
+    }
+
+    /// Adds a 32-bit memory BAR of the given size, which must be a power of two.
+    pub fn with_memory_bar_32(self, index: usize, size: u32, prefetchable: bool) -> Self {
+        assert!(size.is_power_of_two());
+        let flags = if prefetchable { 0x8 } else { 0 };
+        self.with_bar(index, !(size - 1) & !0xf, flags)
+    }
+
+    /// Adds a 64-bit memory BAR of the given size, which must be a power of two, using BAR
+    /// registers `index` and `index + 1`.
+    pub fn with_memory_bar_64(self, index: usize, size: u32, prefetchable: bool) -> Self {
+        assert!(size.is_power_of_two());
+        let flags = 0x4 | if prefetchable { 0x8 } else { 0 };
+        self.with_bar(index, !(size - 1) & !0xf, flags)
+            .with_bar(index + 1, 0xffffffff, 0)
+    }
+
+    /// Adds an I/O BAR of the given size, which must be a power of two.
+    pub fn with_io_bar(self, index: usize, size: u32) -> Self {
+        assert!(size.is_power_of_two());
+        self.with_bar(index, !(size - 1) & !0x3, 0x1)
+    }
+
+    /// Adds a 32-bit memory BAR with the given raw mask of writable address bits, which needn't
+    /// describe a valid size.
+    pub fn with_bar_mask(self, index: usize, mask: u32) -> Self {
+        self.with_bar(index, mask & !0xf, 0)
+    }
+
+    fn with_bar(mut self, index: usize, mask: u32, flags: u32) -> Self {
+        self.bar_masks[index] = mask;
+        self.bar_flags[index] = flags;
+        self.config[4 + index] = flags;
+        self
+    }
+
+    /// Adds a capability with the given ID to the end of the capability list.
+    ///
+    /// `body` is the contents of the capability after the ID and next pointer bytes. Returns the
+    /// offset of the new capability.
+    pub fn with_capability(mut self, id: u8, body: &[u8]) -> (Self, u8) {
+        let offset = self.next_capability;
+        let mut bytes = [id, 0].into_iter().chain(body.iter().copied());
+        let words = (2 + body.len()).div_ceil(4);
+        for word in 0..words {
+            let mut value = 0;
+            for byte in 0..4 {
+                value |= u32::from(bytes.next().unwrap_or(0)) << (8 * byte);
+            }
+            self.config[usize::from(offset) / 4 + word] = value;
+        }
+        match self.last_capability {
+            None => {
+                // Set the capabilities list status bit and pointer.
+                self.config[1] |= 1 << 20;
+                self.config[CAPABILITIES_POINTER_OFFSET / 4] = u32::from(offset);
+            }
+            Some(last) => {
+                let word = &mut self.config[usize::from(last) / 4];
+                *word = (*word & !0xff00) | u32::from(offset) << 8;
+            }
+        }
+        self.last_capability = Some(offset);
+        self.next_capability = offset + (words * 4) as u8;
+        (self, offset)
+    }
+
+    fn header_type(&self) -> u8 {
+        (self.config[3] >> 16) as u8 & 0x7f
+    }
+
+    fn bar_count(&self) -> usize {
+        if self.header_type() == 0 {
+            6
+        } else {
+            2
+        }
+    }
+
+    fn read(&self, register_offset: u8) -> u32 {
+        self.config[usize::from(register_offset) / 4]
+    }
+
+    fn write(&mut self, register_offset: u8, data: u32) {
+        let index = usize::from(register_offset) / 4;
+        match register_offset {
+            // IDs, class and revision are read-only.
+            0x00 | 0x08 => {}
+            // Status bits are read-only or write-1-to-clear, so just update the command.
+            0x04 => self.config[index] = (self.config[index] & 0xffff0000) | (data & 0xffff),
+            // The header type is read-only.
+            0x0c => self.config[index] = (self.config[index] & 0x00ff0000) | (data & 0xff00ffff),
+            0x10..=0x27 if index - 4 < self.bar_count() => {
+                let bar = index - 4;
+                self.config[index] = (data & self.bar_masks[bar]) | self.bar_flags[bar];
+            }
+            _ => self.config[index] = data,
+        }
+    }
+}
+
+/// The size of the BAR used by [`virtio_function`] for its VirtIO structures.
+pub const VIRTIO_BAR_SIZE: u32 = 0x1000;
+/// The offset of the common configuration structure within the BAR of [`virtio_function`].
+pub const COMMON_CFG_OFFSET: usize = 0x000;
+/// The offset of the notification region within the BAR of [`virtio_function`].
+pub const NOTIFY_OFFSET: usize = 0x100;
+/// The `notify_off_multiplier` used by [`virtio_function`].
+pub const NOTIFY_OFF_MULTIPLIER: u32 = 4;
+/// The offset of the ISR status register within the BAR of [`virtio_function`].
+pub const ISR_OFFSET: usize = 0x200;
+/// The offset of the device-specific configuration within the BAR of [`virtio_function`].
+pub const DEVICE_CFG_OFFSET: usize = 0x300;
+
+/// Returns the body of a `virtio_pci_cap` structure, after the ID and next pointer.
+pub fn virtio_capability(cfg_type: u8, bar: u8, offset: u32, length: u32, extra: &[u8]) -> Vec<u8> {
+    let cap_len = (16 + extra.len()) as u8;
+    let mut body = vec![cap_len, cfg_type, bar, 0, 0, 0];
+    body.extend_from_slice(&offset.to_le_bytes());
+    body.extend_from_slice(&length.to_le_bytes());
+    body.extend_from_slice(extra);
+    body
+}
+
+/// Returns a modern VirtIO PCI device function with the given device ID, with all its VirtIO
+/// structures in a 64-bit BAR 0 of size [`VIRTIO_BAR_SIZE`].
+pub fn virtio_function(device_id: u16) -> FakeFunction {
+    let function = FakeFunction::new(super::VIRTIO_VENDOR_ID, device_id, 0).with_memory_bar_64(
+        0,
+        VIRTIO_BAR_SIZE,
+        false,
+    );
+    let (function, _) = function.with_capability(
+        PCI_CAP_ID_VNDR,
+        &virtio_capability(
+            super::VIRTIO_PCI_CAP_COMMON_CFG,
+            0,
+            COMMON_CFG_OFFSET as u32,
+            0x38,
+            &[],
+        ),
+    );
+    let (function, _) = function.with_capability(
+        PCI_CAP_ID_VNDR,
+        &virtio_capability(
+            super::VIRTIO_PCI_CAP_NOTIFY_CFG,
+            0,
+            NOTIFY_OFFSET as u32,
+            0x100,
+            &NOTIFY_OFF_MULTIPLIER.to_le_bytes(),
+        ),
+    );
+    let (function, _) = function.with_capability(
+        PCI_CAP_ID_VNDR,
+        &virtio_capability(super::VIRTIO_PCI_CAP_ISR_CFG, 0, ISR_OFFSET as u32, 1, &[]),
+    );
+    let (function, _) = function.with_capability(
+        PCI_CAP_ID_VNDR,
+        &virtio_capability(
+            super::VIRTIO_PCI_CAP_DEVICE_CFG,
+            0,
+            DEVICE_CFG_OFFSET as u32,
+            0x100,
+            &[],
+        ),
+    );
+    function
+}
+
+/// Memory to back the BAR of a fake VirtIO PCI device, aligned to the size of the BAR as a real
+/// BAR address would be.
+#[derive(Debug)]
+pub struct FakeBar(Box<BarMemory>);
+
+impl Default for FakeBar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug)]
+#[repr(C, align(4096))]
+struct BarMemory([u8; VIRTIO_BAR_SIZE as usize]);
+
+impl FakeBar {
+    /// Allocates a new zeroed BAR.
+    pub fn new() -> Self {
+        Self(Box::new(BarMemory([0; VIRTIO_BAR_SIZE as usize])))
+    }
+
+    /// Returns the address of the BAR, which `FakeHal` maps to itself.
+    pub fn address(&self) -> u64 {
+        self.0 .0.as_ptr() as u64
+    }
+
+    /// Reads a value of the given type from the given offset in the BAR.
+    pub fn read<T: FromBytes>(&self, offset: usize) -> T {
+        T::read_from_bytes(&self.bytes(offset, size_of::<T>())).unwrap()
+    }
+
+    /// Writes a value to the given offset in the BAR.
+    pub fn write<T: IntoBytes + Immutable>(&mut self, offset: usize, value: T) {
+        self.0 .0[offset..offset + size_of::<T>()].copy_from_slice(value.as_bytes());
+    }
+
+    fn bytes(&self, offset: usize, len: usize) -> Vec<u8> {
+        let base = self.0 .0.as_ptr();
+        (offset..offset + len)
+            // SAFETY: The offset is within the BAR memory, which is valid for reads.
+            .map(|i| unsafe { base.add(i).read_volatile() })
+            .collect()
+    }
+}
@@ -0,0 +1,668 @@
+//! Legacy VirtIO PCI transport, for devices which only expose the legacy register block in an I/O
+//! BAR.
+//!
+//! Ref: 4.1.4.10 Legacy Interfaces: A Note on PCI Device Layout
+
+use super::{
+    bus::{BarInfo, ConfigurationAccess, DeviceFunction, PciRoot},
+    VirtioPciError, VIRTIO_VENDOR_ID,
+};
+use crate::{
+    align_up,
+    hal::{Hal, PhysAddr},
+    queue::Descriptor,
+    transport::{DeviceStatus, DeviceType, InterruptStatus, SharedMemoryRegion, Transport},
+    Error, PAGE_SIZE,
+};
+use core::{
+    mem::{align_of, size_of},
+    ops::RangeInclusive,
+    ptr::NonNull,
+};
+use zerocopy::{FromBytes, Immutable, IntoBytes};
+
+/// The range of PCI device IDs used by legacy and transitional VirtIO devices.
+pub const LEGACY_DEVICE_IDS: RangeInclusive<u16> = 0x1000..=0x103f;
+
+/// The offset of the subsystem vendor and subsystem ID within PCI configuration space.
+const SUBSYSTEM_OFFSET: u8 = 0x2c;
+
+/// The offset of the device features register within the legacy register block.
+const DEVICE_FEATURES: u16 = 0x00;
+/// The offset of the guest (driver) features register.
+const GUEST_FEATURES: u16 = 0x04;
+/// The offset of the queue address register, which holds the page frame number of the queue.
+const QUEUE_ADDRESS: u16 = 0x08;
+/// The offset of the read-only queue size register.
+const QUEUE_SIZE: u16 = 0x0c;
+/// The offset of the queue select register.
+const QUEUE_SELECT: u16 = 0x0e;
+/// The offset of the queue notify register.
+const QUEUE_NOTIFY: u16 = 0x10;
+/// The offset of the device status register.
+const DEVICE_STATUS: u16 = 0x12;
+/// The offset of the ISR status register.
+const ISR_STATUS: u16 = 0x13;
+/// The offset of the device-specific configuration when MSI-X is disabled.
+const CONFIG_OFFSET: u16 = 0x14;
+/// The offset of the device-specific configuration when MSI-X is enabled, after the MSI-X vector
+/// registers.
+const CONFIG_OFFSET_MSIX: u16 = 0x18;
+
+/// The alignment of the used ring required by the legacy PCI interface, which is also the unit of
+/// the queue address register.
+const QUEUE_ALIGN: usize = 4096;
+
+/// Access to a region of I/O port space, such as the I/O BAR of a legacy VirtIO PCI device.
+///
+/// Offsets are relative to the start of the region, and are always within it.
+pub trait PortIo: Send + Sync {
+    /// Reads a byte from the given offset.
+    fn read8(&self, offset: u16) -> u8;
+
+    /// Reads a 16-bit value from the given offset.
+    fn read16(&self, offset: u16) -> u16;
+
+    /// Reads a 32-bit value from the given offset.
+    fn read32(&self, offset: u16) -> u32;
+
+    /// Writes a byte to the given offset.
+    fn write8(&self, offset: u16, value: u8);
+
+    /// Writes a 16-bit value to the given offset.
+    fn write16(&self, offset: u16, value: u16);
+
+    /// Writes a 32-bit value to the given offset.
+    fn write32(&self, offset: u16, value: u32);
+}
+
+/// [`PortIo`] implementation for platforms which map PCI I/O space into physical memory, such as
+/// the I/O window of a generic ECAM host bridge on aarch64.
+#[derive(Debug)]
+pub struct MmioPortIo {
+    base: NonNull<u8>,
+}
+
+impl MmioPortIo {
+    /// Wraps the I/O region mapped at the given address.
+    ///
+    /// # Safety
+    ///
+    /// `base` must be a valid pointer to an appropriately-mapped MMIO region at least as large as
+    /// the offsets which will be accessed, and must remain valid for the lifetime of the
+    /// `MmioPortIo`. No Rust references may be used to access any of the region.
+    pub unsafe fn new(base: NonNull<u8>) -> Self {
+        Self { base }
+    }
+}
+
+impl PortIo for MmioPortIo {
+    fn read8(&self, offset: u16) -> u8 {
+        // SAFETY: The caller of `MmioPortIo::new` promised that the region is valid MMIO.
+        unsafe { self.base.add(offset.into()).read_volatile() }
+    }
+
+    fn read16(&self, offset: u16) -> u16 {
+        // SAFETY: The caller of `MmioPortIo::new` promised that the region is valid MMIO.
+        unsafe { self.base.add(offset.into()).cast().read_volatile() }
+    }
+
+    fn read32(&self, offset: u16) -> u32 {
+        // SAFETY: The caller of `MmioPortIo::new` promised that the region is valid MMIO.
+        unsafe { self.base.add(offset.into()).cast().read_volatile() }
+    }
+
+    fn write8(&self, offset: u16, value: u8) {
+        // SAFETY: The caller of `MmioPortIo::new` promised that the region is valid MMIO.
+        unsafe { self.base.add(offset.into()).write_volatile(value) }
+    }
+
+    fn write16(&self, offset: u16, value: u16) {
+        // SAFETY: The caller of `MmioPortIo::new` promised that the region is valid MMIO.
+        unsafe { self.base.add(offset.into()).cast().write_volatile(value) }
+    }
+
+    fn write32(&self, offset: u16, value: u32) {
+        // SAFETY: The caller of `MmioPortIo::new` promised that the region is valid MMIO.
+        unsafe { self.base.add(offset.into()).cast().write_volatile(value) }
+    }
+}
+
+// SAFETY: `base` is only used for MMIO, which can happen from any thread or CPU core.
+unsafe impl Send for MmioPortIo {}
+
+// SAFETY: MMIO accesses to the legacy register block may happen concurrently from any CPU core.
+unsafe impl Sync for MmioPortIo {}
+
+/// [`PortIo`] implementation using the x86 `in` and `out` instructions.
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug)]
+pub struct X86PortIo {
+    base: u16,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl X86PortIo {
+    /// Wraps the I/O ports starting at the given base port.
+    ///
+    /// # Safety
+    ///
+    /// The ports from `base` up to the largest offset which will be accessed must belong to a
+    /// device for which it is safe to perform arbitrary reads and writes, and nothing else may
+    /// access them for the lifetime of the `X86PortIo`.
+    pub unsafe fn new(base: u16) -> Self {
+        Self { base }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl PortIo for X86PortIo {
+    fn read8(&self, offset: u16) -> u8 {
+        let value;
+        // SAFETY: The caller of `X86PortIo::new` promised that the ports are safe to access.
+        unsafe {
+            core::arch::asm!("in al, dx", out("al") value, in("dx") self.base + offset, options(nostack, preserves_flags));
+        }
+        value
+    }
+
+    fn read16(&self, offset: u16) -> u16 {
+        let value;
+        // SAFETY: The caller of `X86PortIo::new` promised that the ports are safe to access.
+        unsafe {
+            core::arch::asm!("in ax, dx", out("ax") value, in("dx") self.base + offset, options(nostack, preserves_flags));
+        }
+        value
+    }
+
+    fn read32(&self, offset: u16) -> u32 {
+        let value;
+        // SAFETY: The caller of `X86PortIo::new` promised that the ports are safe to access.
+        unsafe {
+            core::arch::asm!("in eax, dx", out("eax") value, in("dx") self.base + offset, options(nostack, preserves_flags));
+        }
+        value
+    }
+
+    fn write8(&self, offset: u16, value: u8) {
+        // SAFETY: The caller of `X86PortIo::new` promised that the ports are safe to access.
+        unsafe {
+            core::arch::asm!("out dx, al", in("dx") self.base + offset, in("al") value, options(nostack, preserves_flags));
+        }
+    }
+
+    fn write16(&self, offset: u16, value: u16) {
+        // SAFETY: The caller of `X86PortIo::new` promised that the ports are safe to access.
+        unsafe {
+            core::arch::asm!("out dx, ax", in("dx") self.base + offset, in("ax") value, options(nostack, preserves_flags));
+        }
+    }
+
+    fn write32(&self, offset: u16, value: u32) {
+        // SAFETY: The caller of `X86PortIo::new` promised that the ports are safe to access.
+        unsafe {
+            core::arch::asm!("out dx, eax", in("dx") self.base + offset, in("eax") value, options(nostack, preserves_flags));
+        }
+    }
+}
+
+/// Legacy PCI transport for VirtIO, using the register block in I/O BAR 0.
+///
+/// Only the lower 32 feature bits are available, and queues must use the legacy layout with the
+/// size chosen by the device. [`VirtQueue`](crate::queue::VirtQueue) allocates rings of the
+/// device's size for this, while only using as many descriptors as the driver asks for, so
+/// drivers with a smaller queue size still work.
+///
+/// Ref: 4.1.4.10 Legacy Interfaces: A Note on PCI Device Layout
+#[derive(Debug)]
+pub struct LegacyPciTransport<P: PortIo> {
+    device_type: DeviceType,
+    port_io: P,
+    /// The offset of the device-specific configuration within the register block.
+    config_offset: u16,
+    /// The size of the device-specific configuration in bytes.
+    config_size: usize,
+}
+
+impl<P: PortIo> LegacyPciTransport<P> {
+    /// Constructs a new legacy PCI transport for the given device function on the given PCI root
+    /// controller.
+    ///
+    /// The device's I/O BAR 0 must already have been allocated. `port_io` is called with its
+    /// address and size to get an accessor for it.
+    ///
+    /// Whether the device-specific configuration follows the MSI-X vector registers depends on
+    /// whether MSI-X is enabled, so MSI-X must not be enabled or disabled after this is called.
+    pub fn new<C: ConfigurationAccess>(
+        root: &mut PciRoot<C>,
+        device_function: DeviceFunction,
+        port_io: impl FnOnce(u32, u32) -> P,
+    ) -> Result<Self, VirtioPciError> {
+        let device_vendor = root.configuration_access.read_word(device_function, 0);
+        let device_id = (device_vendor >> 16) as u16;
+        let vendor_id = device_vendor as u16;
+        if vendor_id != VIRTIO_VENDOR_ID {
+            return Err(VirtioPciError::InvalidVendorId(vendor_id));
+        }
+        if !LEGACY_DEVICE_IDS.contains(&device_id) {
+            return Err(VirtioPciError::NotLegacyDevice(device_id));
+        }
+        // Legacy devices use the subsystem ID for the VirtIO device ID.
+        let subsystem = root
+            .configuration_access
+            .read_word(device_function, SUBSYSTEM_OFFSET);
+        let device_type = DeviceType::from((subsystem >> 16) as u16);
+
+        let BarInfo::IO { address, size } = root.bar_info(device_function, 0)? else {
+            return Err(VirtioPciError::UnexpectedMemoryBar);
+        };
+        if address == 0 {
+            return Err(VirtioPciError::BarNotAllocated(0));
+        }
+        let msix_enabled = root
+            .msix_info(device_function)
+            .is_some_and(|msix_info| root.msix_enabled(device_function, &msix_info));
+        let config_offset = if msix_enabled {
+            CONFIG_OFFSET_MSIX
+        } else {
+            CONFIG_OFFSET
+        };
+        let config_size = (size as usize).saturating_sub(config_offset.into());
+
+        Ok(Self {
+            device_type,
+            port_io: port_io(address, size),
+            config_offset,
+            config_size,
+        })
+    }
+
+    /// Returns a reference to the underlying port I/O accessor.
+    pub fn port_io(&self) -> &P {
+        &self.port_io
+    }
+
+    /// Checks that an access of the given size at the given offset is within the device-specific
+    /// configuration, and returns the offset of its first byte within the register block.
+    fn config_offset(&self, offset: usize, size: usize) -> Result<u16, Error> {
+        if self.config_size < offset + size {
+            Err(Error::ConfigSpaceTooSmall)
+        } else {
+            Ok(self.config_offset + offset as u16)
+        }
+    }
+}
+
+impl<P: PortIo> Transport for LegacyPciTransport<P> {
+    fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        self.port_io.read32(DEVICE_FEATURES).into()
+    }
+
+    fn write_driver_features(&mut self, driver_features: u64) {
+        self.port_io.write32(GUEST_FEATURES, driver_features as u32);
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u32 {
+        self.port_io.write16(QUEUE_SELECT, queue);
+        self.port_io.read16(QUEUE_SIZE).into()
+    }
+
+    fn notify(&self, queue: u16) {
+        self.port_io.write16(QUEUE_NOTIFY, queue);
+    }
+
+    fn get_status(&self) -> DeviceStatus {
+        DeviceStatus::from_bits_truncate(self.port_io.read8(DEVICE_STATUS).into())
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        self.port_io.write8(DEVICE_STATUS, status.bits() as u8);
+    }
+
+    fn reset_shared(&self) -> bool {
+        self.port_io.write8(DEVICE_STATUS, 0);
+        true
+    }
+
+    fn set_guest_page_size(&mut self, _guest_page_size: u32) {
+        // No-op, the legacy PCI interface always uses 4 KiB pages.
+    }
+
+    fn requires_legacy_layout(&self) -> bool {
+        true
+    }
+
+    fn requires_exact_queue_size(&self) -> bool {
+        // The legacy interface doesn't let the driver choose the queue size.
+        true
+    }
+
+    fn queue_set(
+        &mut self,
+        queue: u16,
+        size: u32,
+        descriptors: PhysAddr,
+        driver_area: PhysAddr,
+        device_area: PhysAddr,
+    ) {
+        assert_eq!(PAGE_SIZE, QUEUE_ALIGN);
+        assert_eq!(
+            driver_area - descriptors,
+            size_of::<Descriptor>() * size as usize
+        );
+        assert_eq!(
+            device_area - descriptors,
+            align_up(
+                size_of::<Descriptor>() * size as usize + size_of::<u16>() * (size as usize + 3)
+            )
+        );
+        let pfn = (descriptors / QUEUE_ALIGN) as u32;
+        assert_eq!(pfn as usize * QUEUE_ALIGN, descriptors);
+
+        self.port_io.write16(QUEUE_SELECT, queue);
+        // `VirtQueue::new` uses the device's size because of `requires_exact_queue_size`.
+        debug_assert_eq!(
+            u32::from(self.port_io.read16(QUEUE_SIZE)),
+            size,
+            "Legacy PCI queue {} must use the size chosen by the device",
+            queue
+        );
+        self.port_io.write32(QUEUE_ADDRESS, pfn);
+    }
+
+    fn queue_unset(&mut self, queue: u16) {
+        self.port_io.write16(QUEUE_SELECT, queue);
+        self.port_io.write32(QUEUE_ADDRESS, 0);
+    }
+
+    fn queue_used(&mut self, queue: u16) -> bool {
+        self.port_io.write16(QUEUE_SELECT, queue);
+        self.port_io.read32(QUEUE_ADDRESS) != 0
+    }
+
+    fn ack_interrupt(&mut self) -> InterruptStatus {
+        // SAFETY: `self` is a valid `LegacyPciTransport`, and as we have a `&mut Self` it can't be
+        // used to call `ack_interrupt_raw` concurrently.
+        unsafe { Self::ack_interrupt_raw(self) }
+    }
+
+    /// Acknowledges an interrupt using a pointer to the `LegacyPciTransport`.
+    ///
+    /// This only needs a shared reference to the port I/O accessor, which never changes after the
+    /// transport is constructed.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a valid `LegacyPciTransport` instance. The caller must ensure that it
+    /// isn't used to call either this function or `ack_interrupt` concurrently.
+    unsafe fn ack_interrupt_raw(ptr: *mut Self) -> InterruptStatus {
+        // SAFETY: The caller promised that `ptr` points to a valid `LegacyPciTransport`, and the
+        // port_io field is never modified after construction.
+        let port_io = unsafe { &(*ptr).port_io };
+        // Reading the ISR status resets it to 0 and causes the device to de-assert the interrupt.
+        InterruptStatus::from_bits_retain(port_io.read8(ISR_STATUS).into())
+    }
+
+    fn read_config_generation(&self) -> u32 {
+        // The legacy interface has no configuration generation.
+        0
+    }
+
+    fn read_config_space<T: FromBytes>(&self, offset: usize) -> Result<T, Error> {
+        assert!(align_of::<T>() <= 4,
+            "Driver expected config space alignment of {} bytes, but VirtIO only guarantees 4 byte alignment.",
+            align_of::<T>());
+        assert_eq!(offset % align_of::<T>(), 0);
+
+        let start = self.config_offset(offset, size_of::<T>())?;
+        let mut value = T::new_zeroed();
+        let bytes = (&raw mut value).cast::<u8>();
+        for i in 0..size_of::<T>() {
+            // SAFETY: `bytes` points to `value`, which is `size_of::<T>()` bytes long, and any byte
+            // values are valid for `T` because it implements `FromBytes`.
+            unsafe { bytes.add(i).write(self.port_io.read8(start + i as u16)) };
+        }
+        Ok(value)
+    }
+
+    fn write_config_space<T: IntoBytes + Immutable>(
+        &mut self,
+        offset: usize,
+        value: T,
+    ) -> Result<(), Error> {
+        assert!(align_of::<T>() <= 4,
+            "Driver expected config space alignment of {} bytes, but VirtIO only guarantees 4 byte alignment.",
+            align_of::<T>());
+        assert_eq!(offset % align_of::<T>(), 0);
+
+        let start = self.config_offset(offset, size_of::<T>())?;
+        for (i, byte) in value.as_bytes().iter().enumerate() {
+            self.port_io.write8(start + i as u16, *byte);
+        }
+        Ok(())
+    }
+
+    fn shared_memory_region<H: Hal>(&mut self, _id: u8) -> Option<SharedMemoryRegion> {
+        // Shared memory regions were added after the legacy interface.
+        None
+    }
+}
+
+impl<P: PortIo> Drop for LegacyPciTransport<P> {
+    fn drop(&mut self) {
+        // Reset the device when the transport is dropped.
+        self.set_status(DeviceStatus::empty());
+        while self.get_status() != DeviceStatus::empty() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        queue::VirtQueue,
+        transport::pci::{
+            bus::PCI_CAP_ID_MSIX,
+            fake::{FakeFunction, FakePci},
+        },
+    };
+    use std::sync::Mutex;
+
+    const DEVICE_FUNCTION: DeviceFunction = DeviceFunction {
+        bus: 0,
+        device: 3,
+        function: 0,
+    };
+    const IO_BAR_ADDRESS: u32 = 0xc040;
+    const IO_BAR_SIZE: u32 = 0x40;
+
+    /// A fake legacy register block, which just stores what is written except that reading the
+    /// ISR status clears it.
+    #[derive(Debug)]
+    struct FakePortIo {
+        registers: Mutex<[u8; IO_BAR_SIZE as usize]>,
+    }
+
+    impl Default for FakePortIo {
+        fn default() -> Self {
+            Self {
+                registers: Mutex::new([0; IO_BAR_SIZE as usize]),
+            }
+        }
+    }
+
+    impl FakePortIo {
+        fn read<const N: usize>(&self, offset: u16) -> [u8; N] {
+            let registers = self.registers.lock().unwrap();
+            let offset = usize::from(offset);
+            registers[offset..offset + N].try_into().unwrap()
+        }
+
+        fn write(&self, offset: u16, bytes: &[u8]) {
+            let mut registers = self.registers.lock().unwrap();
+            let offset = usize::from(offset);
+            registers[offset..offset + bytes.len()].copy_from_slice(bytes);
+        }
+    }
+
+    impl PortIo for FakePortIo {
+        fn read8(&self, offset: u16) -> u8 {
+            let [value] = self.read(offset);
+            if offset == ISR_STATUS {
+                self.write(offset, &[0]);
+            }
+            value
+        }
+
+        fn read16(&self, offset: u16) -> u16 {
+            u16::from_le_bytes(self.read(offset))
+        }
+
+        fn read32(&self, offset: u16) -> u32 {
+            u32::from_le_bytes(self.read(offset))
+        }
+
+        fn write8(&self, offset: u16, value: u8) {
+            self.write(offset, &[value]);
+        }
+
+        fn write16(&self, offset: u16, value: u16) {
+            self.write(offset, &value.to_le_bytes());
+        }
+
+        fn write32(&self, offset: u16, value: u32) {
+            self.write(offset, &value.to_le_bytes());
+        }
+    }
+
+    /// Returns a legacy block device function with its I/O BAR allocated.
+    fn legacy_block_function() -> FakeFunction {
+        let mut function =
+            FakeFunction::new(VIRTIO_VENDOR_ID, 0x1001, 0).with_io_bar(0, IO_BAR_SIZE);
+        function.config[usize::from(SUBSYSTEM_OFFSET) / 4] =
+            u32::from(DeviceType::Block as u16) << 16 | u32::from(VIRTIO_VENDOR_ID);
+        function
+    }
+
+    fn legacy_transport(function: FakeFunction) -> LegacyPciTransport<FakePortIo> {
+        let pci = FakePci::new();
+        pci.add_function(DEVICE_FUNCTION, function);
+        let mut root = PciRoot::new(pci);
+        root.set_bar_32(DEVICE_FUNCTION, 0, IO_BAR_ADDRESS);
+        LegacyPciTransport::new(&mut root, DEVICE_FUNCTION, |address, size| {
+            assert_eq!(address, IO_BAR_ADDRESS);
+            assert_eq!(size, IO_BAR_SIZE);
+            FakePortIo::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn not_legacy_device() {
+        let pci = FakePci::new();
+        pci.add_function(
+            DEVICE_FUNCTION,
+            FakeFunction::new(VIRTIO_VENDOR_ID, 0x1042, 0).with_io_bar(0, IO_BAR_SIZE),
+        );
+        let mut root = PciRoot::new(pci);
+        assert_eq!(
+            LegacyPciTransport::new(&mut root, DEVICE_FUNCTION, |_, _| FakePortIo::default())
+                .unwrap_err(),
+            VirtioPciError::NotLegacyDevice(0x1042)
+        );
+    }
+
+    #[test]
+    fn registers() {
+        let mut transport = legacy_transport(legacy_block_function());
+        assert_eq!(transport.device_type(), DeviceType::Block);
+        assert!(transport.requires_legacy_layout());
+
+        transport.port_io().write32(DEVICE_FEATURES, 0x1234_5678);
+        assert_eq!(transport.read_device_features(), 0x1234_5678);
+        transport.write_driver_features(0xffff_0000_0000_0008);
+        assert_eq!(transport.port_io().read32(GUEST_FEATURES), 0x8);
+
+        transport.set_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER);
+        assert_eq!(
+            transport.get_status(),
+            DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER
+        );
+
+        transport.notify(2);
+        assert_eq!(transport.port_io().read16(QUEUE_NOTIFY), 2);
+
+        transport.port_io().write8(ISR_STATUS, 0x3);
+        assert!(
+            transport.ack_interrupt()
+                == InterruptStatus::QUEUE_INTERRUPT
+                    | InterruptStatus::DEVICE_CONFIGURATION_INTERRUPT
+        );
+        assert!(transport.ack_interrupt() == InterruptStatus::empty());
+
+        transport.port_io().write8(ISR_STATUS, 0x1);
+        // SAFETY: The transport is valid and isn't being used concurrently.
+        let status = unsafe { LegacyPciTransport::ack_interrupt_raw(&mut transport) };
+        assert!(status == InterruptStatus::QUEUE_INTERRUPT);
+    }
+
+    #[test]
+    fn config_space() {
+        let mut transport = legacy_transport(legacy_block_function());
+        transport.port_io().write32(CONFIG_OFFSET, 0x42);
+        assert_eq!(transport.read_config_space::<u32>(0), Ok(0x42));
+        transport.write_config_space::<u16>(4, 0x1234).unwrap();
+        assert_eq!(transport.port_io().read16(CONFIG_OFFSET + 4), 0x1234);
+        assert_eq!(
+            transport.read_config_space::<u32>(IO_BAR_SIZE as usize - 0x14),
+            Err(Error::ConfigSpaceTooSmall)
+        );
+    }
+
+    #[test]
+    fn config_space_after_msix_vectors() {
+        // MSI-X enabled, with a single entry table in BAR 1.
+        let (function, _) = legacy_block_function()
+            .with_memory_bar_32(1, 0x1000, false)
+            .with_capability(PCI_CAP_ID_MSIX, &[0x00, 0x80, 1, 0, 0, 0, 1, 8, 0, 0]);
+        let transport = legacy_transport(function);
+        transport.port_io().write32(CONFIG_OFFSET_MSIX, 0x42);
+        assert_eq!(transport.read_config_space::<u32>(0), Ok(0x42));
+    }
+
+    #[test]
+    fn queue_set() {
+        let mut transport = legacy_transport(legacy_block_function());
+        transport.port_io().write16(QUEUE_SIZE, 4);
+        assert_eq!(transport.max_queue_size(0), 4);
+        assert!(!transport.queue_used(0));
+
+        // A legacy layout queue of size 4 at 0x10000.
+        transport.queue_set(0, 4, 0x10000, 0x10040, 0x11000);
+        assert!(transport.queue_used(0));
+        assert_eq!(transport.port_io().read32(QUEUE_ADDRESS), 0x10);
+
+        transport.queue_unset(0);
+        assert!(!transport.queue_used(0));
+    }
+
+    #[test]
+    fn unusable_queue_size() {
+        let mut transport = legacy_transport(legacy_block_function());
+        assert!(transport.requires_exact_queue_size());
+        // The device's queue size is too small for the driver, or not a power of two.
+        for device_queue_size in [2, 12] {
+            transport.port_io().write16(QUEUE_SIZE, device_queue_size);
+            assert_eq!(
+                VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).err(),
+                Some(Error::InvalidParam)
+            );
+            assert!(!transport.queue_used(0));
+        }
+    }
+}
@@ -0,0 +1,937 @@
+//! Shared-memory transport for running a VirtIO driver and device in separate processes on a Linux
+//! host.
+//!
+//! The driver and device processes both map the same `memfd` region. The first page of the region
+//! holds the transport registers (status, feature bits, queue addresses and config space), and the
+//! rest of it is used as DMA memory which is handed out by [`ShmHal`]. Physical addresses are
+//! offsets into the region, so each process may map it at a different virtual address. A pair of
+//! `eventfd`s carries queue notifications from the driver to the device, and interrupts from the
+//! device to the driver.
+//!
+//! The device process should create the region with [`ShmRegion::new`], publish the device with
+//! [`ShmDeviceTransport::new`] and pass the file descriptors from [`ShmRegion::memfd`],
+//! [`ShmRegion::notify_fd`] and [`ShmRegion::interrupt_fd`] to the driver process, which can then
+//! open the same region with [`ShmRegion::from_fds`] and create a [`ShmTransport`]. Both processes
+//! must register their mapping of the region with [`ShmHal::init`] before creating any queues.
+
+use super::{
+    DeviceStatus, DeviceTransport, DeviceType, InterruptStatus, SharedMemoryRegion, Transport,
+};
+use crate::{pages, BufferDirection, DeviceHal, Error, Hal, PhysAddr, Result, PAGE_SIZE};
+use alloc::{sync::Arc, vec, vec::Vec};
+use core::{
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering},
+};
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+    sync::Mutex,
+};
+use zerocopy::{FromBytes, Immutable, IntoBytes};
+
+/// Magic value written to the start of the region once the device has been published ("vshm").
+const SHM_MAGIC: u32 = 0x6d68_7376;
+
+/// The size of the register block at the start of every region. DMA memory starts after it.
+const HEADER_SIZE: usize = PAGE_SIZE;
+
+/// The maximum number of virtqueues supported by the shared-memory transport.
+pub const SHM_MAX_QUEUES: usize = 8;
+
+/// The maximum size of the device-specific configuration space, in bytes.
+pub const SHM_CONFIG_SPACE_SIZE: usize = 256;
+
+/// The registers for a single virtqueue.
+#[repr(C)]
+struct ShmQueue {
+    /// The maximum queue size supported by the device, or 0 if the queue doesn't exist.
+    max_size: AtomicU32,
+    /// The queue size chosen by the driver.
+    size: AtomicU32,
+    /// Non-zero once the driver has finished setting up the queue.
+    ready: AtomicU32,
+    /// Set by the driver when it notifies the queue, cleared by the device.
+    notified: AtomicU32,
+    descriptors: AtomicU64,
+    driver_area: AtomicU64,
+    device_area: AtomicU64,
+}
+
+/// The register block at the start of the shared region.
+#[repr(C)]
+struct ShmHeader {
+    magic: AtomicU32,
+    device_type: AtomicU32,
+    device_features: AtomicU64,
+    driver_features: AtomicU64,
+    status: AtomicU32,
+    interrupt_status: AtomicU32,
+    config_generation: AtomicU32,
+    config_space_size: AtomicU32,
+    queues: [ShmQueue; SHM_MAX_QUEUES],
+    config_space: [AtomicU8; SHM_CONFIG_SPACE_SIZE],
+}
+
+const _: () = assert!(size_of::<ShmHeader>() <= HEADER_SIZE);
+
+/// An error encountered setting up a shared-memory transport.
+#[derive(Clone, Copy, Debug, Eq, Error, PartialEq)]
+pub enum ShmError {
+    /// The region doesn't contain a published device.
+    #[error("Shared memory region doesn't contain a VirtIO device")]
+    BadMagic,
+    /// The region is too small, or isn't a whole number of pages.
+    #[error("Shared memory region size {0:#x} is too small or not page aligned")]
+    InvalidRegionSize(usize),
+    /// The device has more queues than the transport supports.
+    #[error("{0} queues requested but at most {SHM_MAX_QUEUES} are supported")]
+    TooManyQueues(usize),
+    /// The device config space is larger than the transport supports.
+    #[error("Config space of {0} bytes is larger than {SHM_CONFIG_SPACE_SIZE} bytes")]
+    ConfigSpaceTooLarge(usize),
+    /// [`ShmHal`] already has a region with outstanding allocations.
+    #[error("ShmHal is already in use with another region")]
+    HalInUse,
+    /// A system call failed.
+    #[error("System call failed with errno {0}")]
+    Os(i32),
+}
+
+impl From<io::Error> for ShmError {
+    fn from(error: io::Error) -> Self {
+        Self::Os(error.raw_os_error().unwrap_or(0))
+    }
+}
+
+/// A `memfd` region shared between a driver and a device process, along with the `eventfd`s used
+/// to signal between them.
+#[derive(Debug)]
+pub struct ShmRegion {
+    memfd: File,
+    /// Signalled by the driver when it notifies a queue.
+    notify_fd: File,
+    /// Signalled by the device when it raises an interrupt.
+    interrupt_fd: File,
+    base: NonNull<u8>,
+    size: usize,
+}
+
+// SAFETY: The mapping isn't tied to any particular thread. The header is only accessed through
+// atomics, and any other access to the region goes through the `Hal` safety requirements.
+unsafe impl Send for ShmRegion {}
+
+// SAFETY: `&ShmRegion` only allows atomic access to the header, and returning pointers into the
+// region which require unsafe code to use.
+unsafe impl Sync for ShmRegion {}
+
+impl ShmRegion {
+    /// Creates a new zeroed region of `size` bytes, along with a new pair of `eventfd`s.
+    ///
+    /// `size` must be a multiple of [`PAGE_SIZE`], and include one page for the transport
+    /// registers as well as enough DMA memory for the queues and buffers of the device.
+    pub fn new(size: usize) -> core::result::Result<Self, ShmError> {
+        // SAFETY: The name is a valid NUL-terminated string, and we check the result.
+        let memfd = unsafe { libc::memfd_create(c"virtio-shm".as_ptr(), libc::MFD_CLOEXEC) };
+        let memfd = owned_fd(memfd)?;
+        let len = libc::off_t::try_from(size).map_err(|_| ShmError::InvalidRegionSize(size))?;
+        // SAFETY: `ftruncate` doesn't access any memory of ours.
+        if unsafe { libc::ftruncate(memfd.as_raw_fd(), len) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Self::from_fds(memfd, new_eventfd()?, new_eventfd()?)
+    }
+
+    /// Maps a region which was created by another process with [`ShmRegion::new`], given the
+    /// file descriptors returned by its [`memfd`](Self::memfd), [`notify_fd`](Self::notify_fd) and
+    /// [`interrupt_fd`](Self::interrupt_fd).
+    pub fn from_fds(
+        memfd: OwnedFd,
+        notify_fd: OwnedFd,
+        interrupt_fd: OwnedFd,
+    ) -> core::result::Result<Self, ShmError> {
+        let memfd = File::from(memfd);
+        let size = usize::try_from(memfd.metadata()?.len()).unwrap();
+        if size <= HEADER_SIZE || !size.is_multiple_of(PAGE_SIZE) {
+            return Err(ShmError::InvalidRegionSize(size));
+        }
+        // SAFETY: We're asking for a new mapping at an address of the kernel's choosing, so it
+        // can't alias any existing memory. We check the result before using it.
+        let base = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                memfd.as_raw_fd(),
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(Self {
+            memfd,
+            notify_fd: File::from(notify_fd),
+            interrupt_fd: File::from(interrupt_fd),
+            base: NonNull::new(base.cast()).unwrap(),
+            size,
+        })
+    }
+
+    /// Returns the `memfd` backing the region, to pass to the other process.
+    pub fn memfd(&self) -> BorrowedFd<'_> {
+        self.memfd.as_fd()
+    }
+
+    /// Returns the `eventfd` used by the driver to notify the device.
+    pub fn notify_fd(&self) -> BorrowedFd<'_> {
+        self.notify_fd.as_fd()
+    }
+
+    /// Returns the `eventfd` used by the device to interrupt the driver.
+    pub fn interrupt_fd(&self) -> BorrowedFd<'_> {
+        self.interrupt_fd.as_fd()
+    }
+
+    /// Returns the total size of the region in bytes, including the transport registers.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn header(&self) -> &ShmHeader {
+        // SAFETY: The mapping is page aligned and at least `HEADER_SIZE` bytes long, and every
+        // field of the header is an atomic so may be shared with the other process.
+        unsafe { self.base.cast::<ShmHeader>().as_ref() }
+    }
+
+    /// Returns the registers for the given queue, or `None` if it is beyond the number of queues
+    /// the transport supports.
+    fn queue(&self, queue: u16) -> Option<&ShmQueue> {
+        self.header().queues.get(usize::from(queue))
+    }
+
+    /// Returns the size of the device config space published in the header, which is written by
+    /// the other process so may not be trusted to fit in the header.
+    fn config_space_size(&self) -> usize {
+        (self.header().config_space_size.load(Ordering::Acquire) as usize)
+            .min(SHM_CONFIG_SPACE_SIZE)
+    }
+
+    /// Returns a pointer to the given offset within the region.
+    fn offset_to_ptr(&self, offset: usize, len: usize) -> Option<NonNull<u8>> {
+        if offset < HEADER_SIZE || offset.checked_add(len)? > self.size {
+            return None;
+        }
+        // SAFETY: We just checked that the offset is within the mapping.
+        Some(unsafe { self.base.add(offset) })
+    }
+}
+
+impl Drop for ShmRegion {
+    fn drop(&mut self) {
+        // SAFETY: The mapping was created in `from_fds` with this base and size, and nothing else
+        // refers to it once the region is dropped.
+        unsafe {
+            libc::munmap(self.base.as_ptr().cast(), self.size);
+        }
+    }
+}
+
+fn owned_fd(fd: libc::c_int) -> io::Result<OwnedFd> {
+    if fd < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        // SAFETY: The fd was just returned by a successful system call, and nothing else owns it.
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+}
+
+fn new_eventfd() -> io::Result<OwnedFd> {
+    // SAFETY: `eventfd` doesn't access any memory of ours.
+    owned_fd(unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) })
+}
+
+/// Increments the counter of the given `eventfd`, waking up any waiter.
+fn signal(mut eventfd: &File) {
+    eventfd
+        .write_all(&1u64.to_ne_bytes())
+        .expect("Failed to signal eventfd");
+}
+
+/// Blocks until the given `eventfd` is signalled, and resets it.
+fn wait(mut eventfd: &File) -> core::result::Result<(), ShmError> {
+    let mut counter = [0; 8];
+    eventfd.read_exact(&mut counter)?;
+    Ok(())
+}
+
+/// Driver-side transport over a [`ShmRegion`].
+#[derive(Debug)]
+pub struct ShmTransport {
+    region: Arc<ShmRegion>,
+}
+
+impl ShmTransport {
+    /// Connects to the device which has been published in the given region.
+    pub fn new(region: Arc<ShmRegion>) -> core::result::Result<Self, ShmError> {
+        if region.header().magic.load(Ordering::Acquire) != SHM_MAGIC {
+            return Err(ShmError::BadMagic);
+        }
+        Ok(Self { region })
+    }
+
+    /// Blocks until the device raises an interrupt.
+    ///
+    /// The caller should then call [`ack_interrupt`](Transport::ack_interrupt) to find out what
+    /// the interrupt was for.
+    pub fn wait_for_interrupt(&self) -> core::result::Result<(), ShmError> {
+        wait(&self.region.interrupt_fd)
+    }
+}
+
+impl Transport for ShmTransport {
+    fn device_type(&self) -> DeviceType {
+        self.region
+            .header()
+            .device_type
+            .load(Ordering::Acquire)
+            .into()
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        self.region.header().device_features.load(Ordering::Acquire)
+    }
+
+    fn write_driver_features(&mut self, driver_features: u64) {
+        self.region
+            .header()
+            .driver_features
+            .store(driver_features, Ordering::Release);
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u32 {
+        self.region
+            .queue(queue)
+            .map_or(0, |queue| queue.max_size.load(Ordering::Acquire))
+    }
+
+    fn notify(&self, queue: u16) {
+        let Some(queue) = self.region.queue(queue) else {
+            return;
+        };
+        queue.notified.store(1, Ordering::Release);
+        signal(&self.region.notify_fd);
+    }
+
+    fn get_status(&self) -> DeviceStatus {
+        DeviceStatus::from_bits_truncate(self.region.header().status.load(Ordering::Acquire))
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        self.region
+            .header()
+            .status
+            .store(status.bits(), Ordering::Release);
+    }
+
+    fn reset_shared(&self) -> bool {
+        self.region.header().status.store(0, Ordering::Release);
+        true
+    }
+
+    fn set_guest_page_size(&mut self, _guest_page_size: u32) {
+        // No-op, the shared-memory transport doesn't care.
+    }
+
+    fn requires_legacy_layout(&self) -> bool {
+        false
+    }
+
+    fn queue_set(
+        &mut self,
+        queue: u16,
+        size: u32,
+        descriptors: PhysAddr,
+        driver_area: PhysAddr,
+        device_area: PhysAddr,
+    ) {
+        // `max_queue_size` returns 0 for queues beyond the limit, so no driver can set them up.
+        let Some(queue) = self.region.queue(queue) else {
+            return;
+        };
+        queue.size.store(size, Ordering::Relaxed);
+        queue
+            .descriptors
+            .store(descriptors as u64, Ordering::Relaxed);
+        queue
+            .driver_area
+            .store(driver_area as u64, Ordering::Relaxed);
+        queue
+            .device_area
+            .store(device_area as u64, Ordering::Relaxed);
+        queue.ready.store(1, Ordering::Release);
+    }
+
+    fn queue_unset(&mut self, queue: u16) {
+        let Some(queue) = self.region.queue(queue) else {
+            return;
+        };
+        queue.ready.store(0, Ordering::Release);
+        queue.size.store(0, Ordering::Relaxed);
+        queue.descriptors.store(0, Ordering::Relaxed);
+        queue.driver_area.store(0, Ordering::Relaxed);
+        queue.device_area.store(0, Ordering::Relaxed);
+    }
+
+    fn queue_used(&mut self, queue: u16) -> bool {
+        self.region
+            .queue(queue)
+            .is_some_and(|queue| queue.ready.load(Ordering::Acquire) != 0)
+    }
+
+    fn ack_interrupt(&mut self) -> InterruptStatus {
+        // SAFETY: `self` is a valid `ShmTransport`.
+        unsafe { Self::ack_interrupt_raw(self) }
+    }
+
+    /// Acknowledges an interrupt using a pointer to the `ShmTransport`.
+    ///
+    /// The interrupt status is an atomic in the shared region, so only a shared reference to the
+    /// region is needed.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a valid `ShmTransport` instance.
+    unsafe fn ack_interrupt_raw(ptr: *mut Self) -> InterruptStatus {
+        // SAFETY: The caller promised that `ptr` points to a valid `ShmTransport`, and the region
+        // field is never modified after construction.
+        let region = unsafe { &(*ptr).region };
+        InterruptStatus::from_bits_truncate(
+            region.header().interrupt_status.swap(0, Ordering::AcqRel),
+        )
+    }
+
+    fn read_config_generation(&self) -> u32 {
+        self.region
+            .header()
+            .config_generation
+            .load(Ordering::Acquire)
+    }
+
+    fn read_config_space<T: FromBytes>(&self, offset: usize) -> Result<T> {
+        let header = self.region.header();
+        let size = self.region.config_space_size();
+        if size == 0 {
+            return Err(Error::ConfigSpaceMissing);
+        }
+        if offset
+            .checked_add(size_of::<T>())
+            .is_none_or(|end| end > size)
+        {
+            return Err(Error::ConfigSpaceTooSmall);
+        }
+        let bytes: Vec<u8> = header.config_space[offset..offset + size_of::<T>()]
+            .iter()
+            .map(|byte| byte.load(Ordering::Relaxed))
+            .collect();
+        Ok(T::read_from_bytes(&bytes).unwrap())
+    }
+
+    fn write_config_space<T: IntoBytes + Immutable>(
+        &mut self,
+        offset: usize,
+        value: T,
+    ) -> Result<()> {
+        let header = self.region.header();
+        let size = self.region.config_space_size();
+        if size == 0 {
+            return Err(Error::ConfigSpaceMissing);
+        }
+        if offset
+            .checked_add(size_of::<T>())
+            .is_none_or(|end| end > size)
+        {
+            return Err(Error::ConfigSpaceTooSmall);
+        }
+        for (config, byte) in header.config_space[offset..].iter().zip(value.as_bytes()) {
+            config.store(*byte, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn shared_memory_region<H: Hal>(&mut self, _id: u8) -> Option<SharedMemoryRegion> {
+        // The whole region is already shared, so there are no separate shared memory regions.
+        None
+    }
+}
+
+/// Device-side transport over a [`ShmRegion`].
+#[derive(Debug)]
+pub struct ShmDeviceTransport {
+    region: Arc<ShmRegion>,
+}
+
+impl ShmDeviceTransport {
+    /// Publishes a device in the given region, so that a driver can connect to it.
+    ///
+    /// `queue_sizes` gives the maximum size of each of the device's queues, and `config_space` the
+    /// initial contents of its device-specific configuration space.
+    pub fn new(
+        region: Arc<ShmRegion>,
+        device_type: DeviceType,
+        device_features: u64,
+        queue_sizes: &[u32],
+        config_space: &[u8],
+    ) -> core::result::Result<Self, ShmError> {
+        if queue_sizes.len() > SHM_MAX_QUEUES {
+            return Err(ShmError::TooManyQueues(queue_sizes.len()));
+        }
+        if config_space.len() > SHM_CONFIG_SPACE_SIZE {
+            return Err(ShmError::ConfigSpaceTooLarge(config_space.len()));
+        }
+
+        let header = region.header();
+        header
+            .device_type
+            .store(device_type as u32, Ordering::Relaxed);
+        header
+            .device_features
+            .store(device_features, Ordering::Relaxed);
+        header.driver_features.store(0, Ordering::Relaxed);
+        header.status.store(0, Ordering::Relaxed);
+        header.interrupt_status.store(0, Ordering::Relaxed);
+        header.config_generation.store(0, Ordering::Relaxed);
+        header
+            .config_space_size
+            .store(config_space.len() as u32, Ordering::Relaxed);
+        for (i, queue) in header.queues.iter().enumerate() {
+            queue.max_size.store(
+                queue_sizes.get(i).copied().unwrap_or_default(),
+                Ordering::Relaxed,
+            );
+            queue.ready.store(0, Ordering::Relaxed);
+            queue.notified.store(0, Ordering::Relaxed);
+        }
+        for (config, byte) in header.config_space.iter().zip(config_space) {
+            config.store(*byte, Ordering::Relaxed);
+        }
+        header.magic.store(SHM_MAGIC, Ordering::Release);
+
+        Ok(Self { region })
+    }
+
+    /// Returns the device status most recently written by the driver.
+    pub fn status(&self) -> DeviceStatus {
+        DeviceStatus::from_bits_truncate(self.region.header().status.load(Ordering::Acquire))
+    }
+
+    /// Sets `DEVICE_NEEDS_RESET` in the device status and raises a configuration change
+    /// interrupt.
+    pub fn set_needs_reset(&self) {
+        self.region
+            .header()
+            .status
+            .fetch_or(DeviceStatus::DEVICE_NEEDS_RESET.bits(), Ordering::AcqRel);
+        self.interrupt(InterruptStatus::DEVICE_CONFIGURATION_INTERRUPT);
+    }
+
+    /// Returns the features which the driver accepted.
+    pub fn driver_features(&self) -> u64 {
+        self.region.header().driver_features.load(Ordering::Acquire)
+    }
+
+    /// Returns whether the driver has set up the given queue.
+    pub fn queue_ready(&self, queue: u16) -> bool {
+        self.region
+            .queue(queue)
+            .is_some_and(|queue| queue.ready.load(Ordering::Acquire) != 0)
+    }
+
+    /// Returns whether the driver has notified the given queue since this was last called, and
+    /// clears the notification.
+    pub fn take_notification(&self, queue: u16) -> bool {
+        self.region
+            .queue(queue)
+            .is_some_and(|queue| queue.notified.swap(0, Ordering::AcqRel) != 0)
+    }
+
+    /// Blocks until the driver notifies any queue.
+    pub fn wait_for_notification(&self) -> core::result::Result<(), ShmError> {
+        wait(&self.region.notify_fd)
+    }
+
+    /// Reads bytes from the device config space, e.g. to see values written by the driver.
+    pub fn read_config(&self, offset: usize, data: &mut [u8]) -> Result {
+        let config_space = self.config_space(offset, data.len())?;
+        for (byte, config) in data.iter_mut().zip(config_space) {
+            *byte = config.load(Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Updates the device config space, and notifies the driver of the change.
+    pub fn write_config(&self, offset: usize, data: &[u8]) -> Result {
+        for (config, byte) in self.config_space(offset, data.len())?.iter().zip(data) {
+            config.store(*byte, Ordering::Relaxed);
+        }
+        let header = self.region.header();
+        header.config_generation.fetch_add(1, Ordering::AcqRel);
+        self.interrupt(InterruptStatus::DEVICE_CONFIGURATION_INTERRUPT);
+        Ok(())
+    }
+
+    /// Returns the given range of the config space, if it is within bounds.
+    fn config_space(&self, offset: usize, len: usize) -> Result<&[AtomicU8]> {
+        let size = self.region.config_space_size();
+        if size == 0 {
+            return Err(Error::ConfigSpaceMissing);
+        }
+        if offset.checked_add(len).is_none_or(|end| end > size) {
+            return Err(Error::ConfigSpaceTooSmall);
+        }
+        Ok(&self.region.header().config_space[offset..offset + len])
+    }
+
+    fn interrupt(&self, status: InterruptStatus) {
+        self.region
+            .header()
+            .interrupt_status
+            .fetch_or(status.bits(), Ordering::AcqRel);
+        signal(&self.region.interrupt_fd);
+    }
+}
+
+impl DeviceTransport for ShmDeviceTransport {
+    fn get_client_id(&self) -> u16 {
+        // There is only ever one driver on the other side of the region.
+        0
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u32 {
+        self.region
+            .queue(queue)
+            .map_or(0, |queue| queue.max_size.load(Ordering::Acquire))
+    }
+
+    fn requires_legacy_layout(&self) -> bool {
+        false
+    }
+
+    fn queue_get(&mut self, queue: u16) -> [PhysAddr; 3] {
+        let Some(queue) = self.region.queue(queue) else {
+            return [0; 3];
+        };
+        queue.ready.load(Ordering::Acquire);
+        [
+            queue.descriptors.load(Ordering::Relaxed) as PhysAddr,
+            queue.driver_area.load(Ordering::Relaxed) as PhysAddr,
+            queue.device_area.load(Ordering::Relaxed) as PhysAddr,
+        ]
+    }
+
+    fn notify(&self, _queue: u16) {
+        self.interrupt(InterruptStatus::QUEUE_INTERRUPT);
+    }
+}
+
+/// Page allocator for the DMA part of the registered region.
+struct Arena {
+    region: Arc<ShmRegion>,
+    /// Whether each page after the header is allocated.
+    used: Vec<bool>,
+}
+
+impl Arena {
+    fn alloc(&mut self, pages: usize) -> Option<(PhysAddr, NonNull<u8>)> {
+        if pages == 0 {
+            return None;
+        }
+        let start = self
+            .used
+            .windows(pages)
+            .position(|window| window.iter().all(|used| !used))?;
+        self.used[start..start + pages].fill(true);
+        let paddr = HEADER_SIZE + start * PAGE_SIZE;
+        let vaddr = self.region.offset_to_ptr(paddr, pages * PAGE_SIZE).unwrap();
+        Some((paddr, vaddr))
+    }
+
+    fn dealloc(&mut self, paddr: PhysAddr, pages: usize) {
+        // Failed allocations are returned as address 0, which is within the header.
+        if paddr < HEADER_SIZE {
+            return;
+        }
+        let start = (paddr - HEADER_SIZE) / PAGE_SIZE;
+        self.used[start..start + pages].fill(false);
+    }
+}
+
+static ARENA: Mutex<Option<Arena>> = Mutex::new(None);
+
+fn with_arena<R>(f: impl FnOnce(&mut Arena) -> R) -> R {
+    let mut arena = ARENA.lock().unwrap();
+    f(arena.as_mut().expect("ShmHal::init has not been called"))
+}
+
+/// A [`Hal`] and [`DeviceHal`] which allocates and maps DMA memory within a [`ShmRegion`].
+///
+/// Physical addresses are offsets from the start of the region. The driver and the device must
+/// each call [`ShmHal::init`] with their mapping of the region before using it.
+#[derive(Debug)]
+pub struct ShmHal;
+
+impl ShmHal {
+    /// Registers the region which all DMA memory is allocated from and mapped in.
+    ///
+    /// Returns [`ShmError::HalInUse`] if a different region is already registered and still has
+    /// memory allocated from it.
+    pub fn init(region: Arc<ShmRegion>) -> core::result::Result<(), ShmError> {
+        let mut arena = ARENA.lock().unwrap();
+        if let Some(existing) = arena.as_ref() {
+            if Arc::ptr_eq(&existing.region, &region) {
+                return Ok(());
+            }
+            if existing.used.contains(&true) {
+                return Err(ShmError::HalInUse);
+            }
+        }
+        let pages = (region.size - HEADER_SIZE) / PAGE_SIZE;
+        *arena = Some(Arena {
+            region,
+            used: vec![false; pages],
+        });
+        Ok(())
+    }
+}
+
+// SAFETY: DMA memory is allocated from pages of the shared region which aren't otherwise in use,
+// and is zeroed before being returned.
+unsafe impl Hal for ShmHal {
+    fn dma_alloc(pages: usize, _direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+        with_arena(|arena| match arena.alloc(pages) {
+            Some((paddr, vaddr)) => {
+                // SAFETY: The pages were just allocated from the region so nothing else is using
+                // them.
+                unsafe { vaddr.write_bytes(0, pages * PAGE_SIZE) };
+                (paddr, vaddr)
+            }
+            None => (0, NonNull::dangling()),
+        })
+    }
+
+    unsafe fn dma_dealloc(paddr: PhysAddr, _vaddr: NonNull<u8>, pages: usize) -> i32 {
+        with_arena(|arena| arena.dealloc(paddr, pages));
+        0
+    }
+
+    unsafe fn mmio_phys_to_virt(paddr: PhysAddr, size: usize) -> NonNull<u8> {
+        with_arena(|arena| arena.region.offset_to_ptr(paddr, size))
+            .expect("Address range is outside the shared memory region")
+    }
+
+    unsafe fn share(buffer: NonNull<[u8]>, direction: BufferDirection) -> PhysAddr {
+        assert_ne!(buffer.len(), 0);
+        // The device can only access the shared region, so copy the buffer into it.
+        let (paddr, vaddr) = with_arena(|arena| arena.alloc(pages(buffer.len())))
+            .expect("Shared memory region is out of space");
+        if let BufferDirection::DriverToDevice | BufferDirection::Both = direction {
+            // SAFETY: The caller guarantees that the buffer is valid, and the pages were just
+            // allocated for it.
+            unsafe {
+                buffer
+                    .cast::<u8>()
+                    .copy_to_nonoverlapping(vaddr, buffer.len());
+            }
+        }
+        paddr
+    }
+
+    unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection) {
+        with_arena(|arena| {
+            if let BufferDirection::DeviceToDriver | BufferDirection::Both = direction {
+                let vaddr = arena.region.offset_to_ptr(paddr, buffer.len()).unwrap();
+                // SAFETY: The caller guarantees that the buffer is valid and that `paddr` was
+                // returned by `share` for it, so the shared copy is the same length.
+                unsafe {
+                    buffer
+                        .cast::<u8>()
+                        .copy_from_nonoverlapping(vaddr, buffer.len());
+                }
+            }
+            arena.dealloc(paddr, pages(buffer.len()));
+        });
+    }
+}
+
+impl DeviceHal for ShmHal {
+    unsafe fn dma_map(
+        paddr: PhysAddr,
+        pages: usize,
+        _direction: BufferDirection,
+        _client_id: u16,
+    ) -> Result<NonNull<u8>> {
+        // The driver may only give us addresses within the region.
+        with_arena(|arena| arena.region.offset_to_ptr(paddr, pages * PAGE_SIZE))
+            .ok_or(Error::InvalidParam)
+    }
+
+    unsafe fn dma_unmap(_paddr: PhysAddr, _vaddr: NonNull<u8>, _pages: usize) -> i32 {
+        // The whole region stays mapped, so there's nothing to do.
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{device::common::Feature, device::rng::VirtIORng, queue::DeviceVirtQueue};
+    use std::thread;
+
+    #[test]
+    fn region_too_small() {
+        assert_eq!(
+            ShmRegion::new(HEADER_SIZE).unwrap_err(),
+            ShmError::InvalidRegionSize(HEADER_SIZE)
+        );
+    }
+
+    #[test]
+    fn untrusted_header_values() {
+        let region = Arc::new(ShmRegion::new(4 * PAGE_SIZE).unwrap());
+        let mut device = ShmDeviceTransport::new(
+            region.clone(),
+            DeviceType::EntropySource,
+            Feature::VERSION_1.bits(),
+            &[8],
+            &[0; 8],
+        )
+        .unwrap();
+        // The other process may write anything to the header.
+        region
+            .header()
+            .config_space_size
+            .store(u32::MAX, Ordering::Release);
+        let mut transport = ShmTransport::new(region).unwrap();
+
+        assert_eq!(
+            transport.read_config_space::<u32>(SHM_CONFIG_SPACE_SIZE - 4),
+            Ok(0)
+        );
+        assert_eq!(
+            transport.read_config_space::<u32>(SHM_CONFIG_SPACE_SIZE - 2),
+            Err(Error::ConfigSpaceTooSmall)
+        );
+        assert_eq!(
+            transport.write_config_space(usize::MAX, 0u8),
+            Err(Error::ConfigSpaceTooSmall)
+        );
+
+        let queue = SHM_MAX_QUEUES as u16;
+        assert_eq!(transport.max_queue_size(queue), 0);
+        transport.notify(queue);
+        transport.queue_unset(queue);
+        assert!(!transport.queue_used(queue));
+        assert_eq!(DeviceTransport::max_queue_size(&mut device, queue), 0);
+        assert_eq!(device.queue_get(queue), [0; 3]);
+        assert!(!device.queue_ready(queue));
+        assert!(!device.take_notification(queue));
+    }
+
+    #[test]
+    fn device_config_out_of_bounds() {
+        let region = Arc::new(ShmRegion::new(4 * PAGE_SIZE).unwrap());
+        let device = ShmDeviceTransport::new(
+            region,
+            DeviceType::EntropySource,
+            Feature::VERSION_1.bits(),
+            &[8],
+            &[1, 2, 3, 4],
+        )
+        .unwrap();
+
+        let mut data = [0; 2];
+        assert_eq!(device.read_config(2, &mut data), Ok(()));
+        assert_eq!(data, [3, 4]);
+        assert_eq!(device.write_config(3, &[5]), Ok(()));
+        assert_eq!(device.read_config(3, &mut data[..1]), Ok(()));
+        assert_eq!(data, [5, 4]);
+
+        assert_eq!(
+            device.read_config(3, &mut data),
+            Err(Error::ConfigSpaceTooSmall)
+        );
+        assert_eq!(
+            device.read_config(SHM_CONFIG_SPACE_SIZE + 1, &mut []),
+            Err(Error::ConfigSpaceTooSmall)
+        );
+        assert_eq!(
+            device.write_config(usize::MAX, &[0]),
+            Err(Error::ConfigSpaceTooSmall)
+        );
+    }
+
+    #[test]
+    fn arena_zero_pages() {
+        let region = Arc::new(ShmRegion::new(4 * PAGE_SIZE).unwrap());
+        let mut arena = Arena {
+            region,
+            used: vec![false; 3],
+        };
+        assert!(arena.alloc(0).is_none());
+        arena.dealloc(0, 0);
+        let (paddr, _) = arena.alloc(3).unwrap();
+        assert_eq!(paddr, HEADER_SIZE);
+        assert!(arena.alloc(1).is_none());
+    }
+
+    #[test]
+    fn transports_over_separate_mappings() {
+        // `ShmHal` is global to the process, so DMA buffers for both sides go through the device's
+        // mapping here; only the transports use separate mappings.
+        let device_region = Arc::new(ShmRegion::new(64 * PAGE_SIZE).unwrap());
+        ShmHal::init(device_region.clone()).unwrap();
+        let mut device = ShmDeviceTransport::new(
+            device_region.clone(),
+            DeviceType::EntropySource,
+            Feature::VERSION_1.bits(),
+            &[8],
+            &[],
+        )
+        .unwrap();
+
+        // Map the same region a second time, as another process would.
+        let driver_region = Arc::new(
+            ShmRegion::from_fds(
+                device_region.memfd().try_clone_to_owned().unwrap(),
+                device_region.notify_fd().try_clone_to_owned().unwrap(),
+                device_region.interrupt_fd().try_clone_to_owned().unwrap(),
+            )
+            .unwrap(),
+        );
+        assert_ne!(driver_region.base, device_region.base);
+        let transport = ShmTransport::new(driver_region).unwrap();
+        assert_eq!(transport.device_type(), DeviceType::EntropySource);
+
+        let handle = thread::spawn(move || {
+            while !device.queue_ready(0) || !device.status().contains(DeviceStatus::DRIVER_OK) {
+                thread::yield_now();
+            }
+            assert_eq!(device.driver_features(), Feature::VERSION_1.bits());
+            let mut queue = DeviceVirtQueue::<ShmHal, 8>::new(&mut device, 0).unwrap();
+            device.wait_for_notification().unwrap();
+            assert!(device.take_notification(0));
+            queue
+                .wait_pop_add_notify(&[&[4, 2, 4, 2]], &device)
+                .unwrap();
+        });
+
+        let mut rng = VirtIORng::<ShmHal, ShmTransport>::new(transport).unwrap();
+        let mut entropy = [0; 4];
+        assert_eq!(rng.request_entropy(&mut entropy), Ok(4));
+        assert_eq!(entropy, [4, 2, 4, 2]);
+        handle.join().unwrap();
+        assert!(rng.ack_interrupt() == InterruptStatus::QUEUE_INTERRUPT);
+    }
+}
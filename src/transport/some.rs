@@ -1,9 +1,15 @@
 use zerocopy::{FromBytes, Immutable, IntoBytes};
 
-use super::{mmio::MmioTransport, pci::PciTransport, DeviceStatus, DeviceType, Transport};
-use crate::{transport::InterruptStatus, PhysAddr, Result};
+#[cfg(target_arch = "x86_64")]
+use super::x86_64::HypPciTransport;
+use super::{
+    mmio::MmioTransport, pci::PciTransport, DeviceStatus, DeviceType, SharedMemoryRegion, Transport,
+};
+use crate::{transport::InterruptStatus, Hal, PhysAddr, Result};
+use core::ptr;
 
-/// A wrapper for an arbitrary VirtIO transport, either MMIO or PCI.
+/// A wrapper for an arbitrary VirtIO transport, either MMIO, PCI or (on x86-64) pKVM hypercall-based
+/// PCI.
 #[derive(Debug)]
 pub enum SomeTransport {
     /// An MMIO transport.
@@ -12,7 +18,7 @@ pub enum SomeTransport {
     Pci(PciTransport),
     /// An x86-64 pKVM PCI transport.
     #[cfg(target_arch = "x86_64")]
-    HypPci(super::x86_64::HypPciTransport),
+    HypPci(HypPciTransport),
 }
 
 impl From<MmioTransport> for SomeTransport {
@@ -27,6 +33,13 @@ impl From<PciTransport> for SomeTransport {
     }
 }
 
+#[cfg(target_arch = "x86_64")]
+impl From<HypPciTransport> for SomeTransport {
+    fn from(pci: HypPciTransport) -> Self {
+        Self::HypPci(pci)
+    }
+}
+
 impl Transport for SomeTransport {
     fn device_type(&self) -> DeviceType {
         match self {
@@ -109,6 +122,24 @@ impl Transport for SomeTransport {
         }
     }
 
+    fn reset_shared(&self) -> bool {
+        match self {
+            Self::Mmio(mmio) => mmio.reset_shared(),
+            Self::Pci(pci) => pci.reset_shared(),
+            #[cfg(target_arch = "x86_64")]
+            Self::HypPci(pci) => pci.reset_shared(),
+        }
+    }
+
+    fn requires_exact_queue_size(&self) -> bool {
+        match self {
+            Self::Mmio(mmio) => mmio.requires_exact_queue_size(),
+            Self::Pci(pci) => pci.requires_exact_queue_size(),
+            #[cfg(target_arch = "x86_64")]
+            Self::HypPci(pci) => pci.requires_exact_queue_size(),
+        }
+    }
+
     fn queue_set(
         &mut self,
         queue: u16,
@@ -163,11 +194,21 @@ impl Transport for SomeTransport {
     /// -  the caller must ensure that any other references or pointers to the `SomeTransport`
     ///    are not used to call either this function or `ack_interrupt` concurrently.
     unsafe fn ack_interrupt_raw(ptr: *mut Self) -> InterruptStatus {
-        match *ptr {
-            Self::Mmio(ref mut mmio) => mmio.ack_interrupt(),
-            Self::Pci(ref mut pci) => pci.ack_interrupt(),
-            #[cfg(target_arch = "x86_64")]
-            Self::HypPci(ref mut pci) => pci.ack_interrupt(),
+        // SAFETY: The caller promised that `ptr` points to a valid `SomeTransport`. The variant
+        // never changes, and the inner transports' `ack_interrupt_raw` implementations only read
+        // through the pointers they are given, so it is fine to derive them from a shared
+        // reference.
+        unsafe {
+            match &*ptr {
+                Self::Mmio(mmio) => {
+                    MmioTransport::ack_interrupt_raw(ptr::from_ref(mmio).cast_mut())
+                }
+                Self::Pci(pci) => PciTransport::ack_interrupt_raw(ptr::from_ref(pci).cast_mut()),
+                #[cfg(target_arch = "x86_64")]
+                Self::HypPci(pci) => {
+                    HypPciTransport::ack_interrupt_raw(ptr::from_ref(pci).cast_mut())
+                }
+            }
         }
     }
 
@@ -201,4 +242,35 @@ impl Transport for SomeTransport {
             Self::HypPci(pci) => pci.write_config_space(offset, value),
         }
     }
+
+    fn shared_memory_region<H: Hal>(&mut self, id: u8) -> Option<SharedMemoryRegion> {
+        match self {
+            Self::Mmio(mmio) => mmio.shared_memory_region::<H>(id),
+            Self::Pci(pci) => pci.shared_memory_region::<H>(id),
+            #[cfg(target_arch = "x86_64")]
+            Self::HypPci(pci) => pci.shared_memory_region::<H>(id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::mmio::{VirtIOHeader, MODERN_VERSION};
+    use core::{mem::size_of, ptr::NonNull};
+
+    #[test]
+    fn ack_interrupt_raw_mmio() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        header.set_interrupt_status(0x1);
+        // SAFETY: The header is valid for the lifetime of the transport.
+        let mmio =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        let mut transport = SomeTransport::from(mmio);
+
+        // SAFETY: The transport is valid and isn't being used concurrently.
+        let status = unsafe { SomeTransport::ack_interrupt_raw(&mut transport) };
+        assert!(status == InterruptStatus::QUEUE_INTERRUPT);
+    }
 }
@@ -1,40 +1,53 @@
-use super::hypercalls::{cpuid_signature, hyp_io_read, hyp_io_write};
+use super::{hypercalls::PkvmHypIo, io::HypIo};
 use crate::transport::pci::bus::{Cam, ConfigurationAccess, DeviceFunction};
 
-const PKVM_SIGNATURE: &[u8] = b"PKVM";
-
-/// A PCI configuration access mechanism using hypercalls implemented by the x86-64 pKVM hypervisor.
-pub struct HypCam {
+/// A PCI configuration access mechanism using hypercalls implemented by the hypervisor.
+///
+/// By default this uses the x86-64 pKVM IO hypercalls, but any other [`HypIo`] backend may be used
+/// instead.
+pub struct HypCam<B: HypIo = PkvmHypIo> {
+    io: B,
     /// The physical base address of the PCI root complex.
     phys_base: usize,
     cam: Cam,
 }
 
 impl HypCam {
-    /// Creates a new `HypCam` for the PCI root complex at the given physical base address.
+    /// Creates a new `HypCam` for the PCI root complex at the given physical base address, using
+    /// pKVM hypercalls.
     pub fn new(phys_base: usize, cam: Cam) -> Self {
-        Self { phys_base, cam }
+        Self::with_io(PkvmHypIo, phys_base, cam)
     }
 
     /// Returns whether we are running under pKVM by checking the CPU ID signature.
     pub fn is_pkvm() -> bool {
-        cpuid_signature() == PKVM_SIGNATURE
+        PkvmHypIo::is_available()
+    }
+}
+
+impl<B: HypIo> HypCam<B> {
+    /// Creates a new `HypCam` for the PCI root complex at the given physical base address, using
+    /// the given backend to access it.
+    pub fn with_io(io: B, phys_base: usize, cam: Cam) -> Self {
+        Self { io, phys_base, cam }
     }
 }
 
-impl ConfigurationAccess for HypCam {
+impl<B: HypIo> ConfigurationAccess for HypCam<B> {
     fn read_word(&self, device_function: DeviceFunction, register_offset: u8) -> u32 {
         let address = self.cam.cam_offset(device_function, register_offset);
-        hyp_io_read(self.phys_base + (address as usize), 4) as u32
+        self.io.io_read(self.phys_base + (address as usize), 4) as u32
     }
 
     fn write_word(&mut self, device_function: DeviceFunction, register_offset: u8, data: u32) {
         let address = self.cam.cam_offset(device_function, register_offset);
-        hyp_io_write(self.phys_base + (address as usize), 4, data.into());
+        self.io
+            .io_write(self.phys_base + (address as usize), 4, data.into());
     }
 
     unsafe fn unsafe_clone(&self) -> Self {
         Self {
+            io: self.io.clone(),
             phys_base: self.phys_base,
             cam: self.cam,
         }
@@ -0,0 +1,41 @@
+//! A fake in-memory hypervisor IO backend for unit tests.
+
+use super::io::HypIo;
+use alloc::{collections::BTreeMap, sync::Arc};
+use std::sync::Mutex;
+
+/// A fake implementation of [`HypIo`], backed by sparse memory which reads as zero until written.
+///
+/// Clones share the same memory, so tests can keep a handle to inspect what was written.
+#[derive(Clone, Debug, Default)]
+pub struct FakeHypIo {
+    memory: Arc<Mutex<BTreeMap<usize, u8>>>,
+}
+
+impl FakeHypIo {
+    /// Reads the given number of bytes from the fake memory at the given physical address.
+    pub fn read_bytes(&self, paddr: usize, size: usize) -> u64 {
+        let memory = self.memory.lock().unwrap();
+        (0..size).fold(0, |data, i| {
+            data | u64::from(memory.get(&(paddr + i)).copied().unwrap_or(0)) << (8 * i)
+        })
+    }
+
+    /// Writes the low `size` bytes of `data` to the fake memory at the given physical address.
+    pub fn write_bytes(&self, paddr: usize, size: usize, data: u64) {
+        let mut memory = self.memory.lock().unwrap();
+        for i in 0..size {
+            memory.insert(paddr + i, (data >> (8 * i)) as u8);
+        }
+    }
+}
+
+impl HypIo for FakeHypIo {
+    fn io_read(&self, paddr: usize, size: usize) -> u64 {
+        self.read_bytes(paddr, size)
+    }
+
+    fn io_write(&self, paddr: usize, size: usize, data: u64) {
+        self.write_bytes(paddr, size, data)
+    }
+}
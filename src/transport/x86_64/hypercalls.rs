@@ -1,7 +1,7 @@
 //! Hypercalls for x86-64 pKVM.
 
+use super::io::HypIo;
 use core::arch::asm;
-use zerocopy::{FromBytes, Immutable, IntoBytes};
 
 /// This CPUID returns the signature and should be used to determine if VM is running under pKVM,
 /// KVM or not. See the Linux header `arch/x86/include/uapi/asm/kvm_para.h`.
@@ -13,8 +13,8 @@ const KVM_HC_PKVM_OP: u64 = 20;
 const PKVM_GHC_IOREAD: u64 = KVM_HC_PKVM_OP + 3;
 const PKVM_GHC_IOWRITE: u64 = KVM_HC_PKVM_OP + 4;
 
-/// The maximum number of bytes that can be read or written by a single IO hypercall.
-const HYP_IO_MAX: usize = 8;
+/// The signature returned by the `KVM_CPUID_SIGNATURE` CPUID leaf when running under pKVM.
+const PKVM_SIGNATURE: &[u8] = b"PKVM";
 
 /// Gets the signature CPU ID.
 pub fn cpuid_signature() -> [u8; 4] {
@@ -87,40 +87,26 @@ macro_rules! vmcall {
     };
 }
 
-/// Asks the hypervisor to perform an IO read at the given physical address.
-pub fn hyp_io_read(address: usize, size: usize) -> u64 {
-    vmcall!(PKVM_GHC_IOREAD, address as u64, size as u64)
-}
-
-/// Asks the hypervisor to perform an IO write at the given physical address.
-pub fn hyp_io_write(address: usize, size: usize, data: u64) {
-    vmcall!(PKVM_GHC_IOWRITE, address as u64, size as u64, data);
-}
+/// Accesses device registers with the x86-64 pKVM IO hypercalls.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PkvmHypIo;
 
-/// A region of physical address space which may be accessed by IO read and/or write hypercalls.
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
-pub struct HypIoRegion {
-    /// The physical address of the start of the IO region.
-    pub paddr: usize,
-    /// The size of the IO region in bytes.
-    pub size: usize,
+impl PkvmHypIo {
+    /// Returns whether we are running under pKVM by checking the CPU ID signature, and so whether
+    /// the hypercalls are available.
+    pub fn is_available() -> bool {
+        cpuid_signature() == PKVM_SIGNATURE
+    }
 }
 
-impl HypIoRegion {
-    pub fn read<T: FromBytes>(self, offset: usize) -> T {
-        assert!(offset + size_of::<T>() <= self.size);
-        assert!(size_of::<T>() <= HYP_IO_MAX);
-
-        let data = hyp_io_read(self.paddr + offset, size_of::<T>());
-        T::read_from_prefix(data.as_bytes()).unwrap().0
+impl HypIo for PkvmHypIo {
+    /// Asks the hypervisor to perform an IO read at the given physical address.
+    fn io_read(&self, paddr: usize, size: usize) -> u64 {
+        vmcall!(PKVM_GHC_IOREAD, paddr as u64, size as u64)
     }
 
-    pub fn write<T: IntoBytes + Immutable>(self, offset: usize, value: T) {
-        assert!(offset + size_of::<T>() <= self.size);
-        assert!(size_of::<T>() <= HYP_IO_MAX);
-
-        let mut data = 0;
-        data.as_mut_bytes()[..size_of::<T>()].copy_from_slice(value.as_bytes());
-        hyp_io_write(self.paddr + offset, size_of::<T>(), data);
+    /// Asks the hypervisor to perform an IO write at the given physical address.
+    fn io_write(&self, paddr: usize, size: usize, data: u64) {
+        vmcall!(PKVM_GHC_IOWRITE, paddr as u64, size as u64, data);
     }
 }
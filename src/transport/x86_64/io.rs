@@ -0,0 +1,69 @@
+//! Abstraction over how device registers are accessed via the hypervisor.
+
+use crate::{
+    transport::{pci::RegionAccess, SharedMemoryRegion},
+    Hal,
+};
+use core::fmt::Debug;
+use zerocopy::{FromBytes, Immutable, IntoBytes};
+
+/// The maximum number of bytes that can be read or written by a single IO access.
+pub const HYP_IO_MAX: usize = 8;
+
+/// A mechanism by which the guest asks the hypervisor to access device registers on its behalf,
+/// rather than mapping them directly.
+///
+/// [`PkvmHypIo`](super::PkvmHypIo) implements this with pKVM IO hypercalls, but it could equally
+/// be implemented with some other guest-hypervisor call, or by an in-memory mock for tests.
+pub trait HypIo: Clone + Debug + Send + Sync {
+    /// Reads `size` bytes from the given physical address, returning them in the low bytes of the
+    /// result.
+    ///
+    /// `size` is at most [`HYP_IO_MAX`].
+    fn io_read(&self, paddr: usize, size: usize) -> u64;
+
+    /// Writes the low `size` bytes of `data` to the given physical address.
+    ///
+    /// `size` is at most [`HYP_IO_MAX`].
+    fn io_write(&self, paddr: usize, size: usize, data: u64);
+}
+
+/// A region of physical address space which may be accessed by IO reads and/or writes via some
+/// [`HypIo`] backend.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HypIoRegion<B: HypIo> {
+    /// The backend used to access the region.
+    pub io: B,
+    /// The physical address of the start of the IO region.
+    pub paddr: usize,
+    /// The size of the IO region in bytes.
+    pub size: usize,
+}
+
+impl<B: HypIo> RegionAccess for HypIoRegion<B> {
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn read<T: FromBytes>(&self, offset: usize) -> T {
+        assert!(offset + size_of::<T>() <= self.size);
+        assert!(size_of::<T>() <= HYP_IO_MAX);
+
+        let data = self.io.io_read(self.paddr + offset, size_of::<T>());
+        T::read_from_prefix(data.as_bytes()).unwrap().0
+    }
+
+    fn write<T: IntoBytes + Immutable>(&self, offset: usize, value: T) {
+        assert!(offset + size_of::<T>() <= self.size);
+        assert!(size_of::<T>() <= HYP_IO_MAX);
+
+        let mut data = 0;
+        data.as_mut_bytes()[..size_of::<T>()].copy_from_slice(value.as_bytes());
+        self.io.io_write(self.paddr + offset, size_of::<T>(), data);
+    }
+
+    unsafe fn map_shared_memory<H: Hal>(_paddr: u64, _length: u64) -> Option<SharedMemoryRegion> {
+        // BARs are only accessed through hypercalls, so shared memory regions can't be mapped.
+        None
+    }
+}